@@ -1,10 +1,36 @@
-use crate::detector::{DetectionResult, GpuAllocation};
+use crate::checkpoint::freeze::{FreezeMethod, ProcessController};
+use crate::checkpoint::snapshot::CowSnapshot;
+use crate::detector::{
+    AllocationType, DetectionResult, GpuAllocation, MemoryMapParser, MemoryRegion, ProcessScanner,
+    HUGE_PAGE_SIZE,
+};
+use crate::storage::{CheckpointSink, SinkWriter};
+use crate::utils::checksum::ChecksumWriter;
+use crate::utils::compression::{
+    CompressWriter, Compression, CompressionStats, CompressionThroughput,
+};
+use crate::utils::encryption::{EncryptWriter, EncryptionKey, RecordContext};
+use crate::utils::lock;
+use crate::utils::process_vm::ProcessMemory;
+use crate::utils::progress::{CheckpointEvent, ProgressCallback, TransferProgress};
+use crate::utils::throttle::ThrottledWriter;
+use crate::utils::{is_addressable, window_len};
 use crate::{GpuCheckpointError, Result};
-use indicatif::{ProgressBar, ProgressStyle};
+use memmap2::{Mmap, MmapOptions};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::time::Instant;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing::{debug, info, warn};
 
 /// BAR sliding window size (typically 256MB for most GPUs)
@@ -13,29 +39,321 @@ const BAR_WINDOW_SIZE: usize = 256 * 1024 * 1024;
 /// Checkpoint header magic number
 pub const CHECKPOINT_MAGIC: u32 = 0x47505543; // "GPUC"
 
-/// Version of the checkpoint format
-pub const CHECKPOINT_VERSION: u32 = 1;
+/// Version of the checkpoint format. Version 2 follows each allocation's
+/// data with a CRC32 of it, version 3 extends the header with the
+/// compression of the data, version 4 with the checkpoint's identity and the
+/// base checkpoint of an incremental one, version 5 with flags such as
+/// whether the data is encrypted, version 6 with the flag marking records
+/// of deduplicated windows. Version 7 binds encrypted data to the record
+/// and checkpoint it belongs to.
+pub const CHECKPOINT_VERSION: u32 = 7;
 
-#[derive(Debug)]
+/// Oldest checkpoint format version whose encrypted data restore reads.
+/// Encrypted data of earlier versions is only bound to its position within
+/// the allocation.
+pub const MIN_ENCRYPTED_VERSION: u32 = 7;
+
+/// Oldest checkpoint format version restore still reads
+pub const MIN_CHECKPOINT_VERSION: u32 = 1;
+
+/// Size of the CRC32 trailer after each allocation's data (version 2+)
+pub const CHECKSUM_SIZE: u64 = 4;
+
+/// Size of the checkpoint header (version 4+)
+pub const CHECKPOINT_HEADER_SIZE: u64 = 56;
+
+/// Size of the header of version 3 checkpoints, which end after the
+/// compression and reserved bytes
+const COMPRESSION_HEADER_SIZE: u64 = 40;
+
+/// Size of the header of version 1 and 2 checkpoints, which end after the
+/// timestamp
+const BASE_HEADER_SIZE: u64 = 32;
+
+/// Bytes after the compression and flags bytes (version 3+), left for
+/// fields that readers which do not know them can ignore
+pub const HEADER_RESERVED_SIZE: usize = 5;
+
+/// Size of an `AllocationHeader` on disk
+pub const ALLOCATION_HEADER_SIZE: u64 = 32;
+
+/// Byte offset of `CheckpointHeader::total_size` within the file
+const TOTAL_SIZE_OFFSET: u64 = 16;
+
+/// Header flag: allocation data is encrypted (version 5+)
+pub const HEADER_FLAG_ENCRYPTED: u8 = 1 << 0;
+
+/// Header flag: windows repeated within the checkpoint are stored once
+/// (version 6+)
+pub const HEADER_FLAG_DEDUPLICATED: u8 = 1 << 1;
+
+/// Allocation flag: a length-prefixed JSON `AllocationDescriptor` follows the
+/// allocation header
+pub const ALLOC_FLAG_DESCRIPTOR: u32 = 1 << 0;
+
+/// Directory POSIX shared memory segments live in
+pub const DEFAULT_SHM_DIR: &str = "/dev/shm";
+
+#[derive(Debug, Clone)]
 pub struct BarSlidingCheckpoint {
     /// Size of the BAR window for sliding
     window_size: usize,
 
     /// Progress reporting
     show_progress: bool,
+
+    /// Caller's sink for progress, besides the bar
+    progress_callback: Option<ProgressCallback>,
+
+    /// Caller's channel for lifecycle events
+    events: Option<Sender<CheckpointEvent>>,
+
+    /// Only freeze the process while a copy-on-write snapshot of it is
+    /// forked, and copy from the snapshot (experimental)
+    cow_snapshot: bool,
+
+    /// Shared memory directory IPC segments are read from
+    shm_dir: PathBuf,
+
+    /// Only copy resident pages of each allocation
+    limit_rss: bool,
+
+    /// Leave never-touched pages of anonymous allocations as file holes
+    sparse: bool,
+
+    /// Stop the target while its memory is copied
+    freeze: bool,
+
+    /// How the target is paused while its memory is copied
+    freeze_method: FreezeMethod,
+
+    /// Fault managed allocations over to the host before copying them
+    managed_prefetch: bool,
+
+    /// Read allocations backed by a regular file through a memory map of
+    /// it instead of a buffer when they cannot be copied in the kernel
+    mapped_reads: bool,
+
+    /// Fail instead of only flagging the checkpoint when the GPU memory
+    /// layout changes while it is copied
+    abort_on_layout_change: bool,
+
+    /// How allocation data is compressed on disk
+    compression: Compression,
+
+    /// Windows of an allocation compressed concurrently
+    compression_threads: usize,
+
+    /// Compression done by the current checkpoint, reset when it starts
+    compression_stats: Arc<CompressionStats>,
+
+    /// Key allocation data is encrypted with, after compression
+    encryption_key: Option<EncryptionKey>,
+
+    /// Number of allocations checkpointed concurrently
+    parallelism: usize,
+
+    /// Storage bandwidth writes are paced to in MB/s, 0 for unthrottled
+    bandwidth_limit: u64,
+
+    /// Checkpoint whose contents this one only records the changes to
+    incremental_base: Option<PathBuf>,
+
+    /// Clear the target's soft-dirty bits so a later incremental checkpoint
+    /// only captures windows written after this one
+    track_dirty: bool,
+
+    /// Read the target's memory with `process_vm_readv` where possible
+    process_vm: bool,
+
+    /// Allocations, by start address, whose data another checkpoint of the
+    /// same process group stores
+    stored_elsewhere: HashMap<u64, AllocationRef>,
+
+    /// Store each distinct window once, recording repeats by reference
+    dedup: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct CheckpointHeader {
     pub magic: u32,
     pub version: u32,
     pub pid: u32,
     pub num_allocations: u32,
+
+    /// Bytes of allocation data captured. Written as the detected GPU
+    /// memory and corrected once all allocations are copied.
     pub total_size: u64,
     pub timestamp: u64,
+
+    /// Compression of the allocation data (version 3+)
+    pub compression: Compression,
+
+    /// Identifies the checkpoint to incremental checkpoints taken against
+    /// it (version 4+, 0 before)
+    pub checkpoint_id: u64,
+
+    /// `checkpoint_id` of the checkpoint this one only holds the changes
+    /// since, 0 for a full checkpoint (version 4+)
+    pub base_checkpoint_id: u64,
+
+    /// Allocation data is encrypted (version 5+)
+    pub encrypted: bool,
+
+    /// Records may refer to windows stored earlier in the checkpoint
+    /// instead of holding them (version 6+)
+    pub deduplicated: bool,
+
+    /// Written as zeros and ignored on read, so a later version can store
+    /// new fields here without older builds rejecting its checkpoints
+    /// (version 3+)
+    pub reserved: [u8; HEADER_RESERVED_SIZE],
 }
 
-#[derive(Debug, Clone)]
+impl CheckpointHeader {
+    /// Size of the header on disk, which depends on its version
+    pub fn size(&self) -> u64 {
+        match self.version {
+            4.. => CHECKPOINT_HEADER_SIZE,
+            3 => COMPRESSION_HEADER_SIZE,
+            _ => BASE_HEADER_SIZE,
+        }
+    }
+
+    /// Whether restoring this checkpoint needs its base checkpoint first
+    pub fn is_incremental(&self) -> bool {
+        self.base_checkpoint_id != 0
+    }
+
+    /// Parse a header as written by `BarSlidingCheckpoint`. The version
+    /// determines how many bytes are read.
+    pub fn read_from(input: &mut impl Read) -> Result<Self> {
+        let mut buf4 = [0u8; 4];
+        let mut buf8 = [0u8; 8];
+        let mut read_u32 = |input: &mut dyn Read| -> Result<u32> {
+            input.read_exact(&mut buf4)?;
+            Ok(u32::from_le_bytes(buf4))
+        };
+
+        let magic = read_u32(input)?;
+        let version = read_u32(input)?;
+        let pid = read_u32(input)?;
+        let num_allocations = read_u32(input)?;
+
+        input.read_exact(&mut buf8)?;
+        let total_size = u64::from_le_bytes(buf8);
+        input.read_exact(&mut buf8)?;
+        let timestamp = u64::from_le_bytes(buf8);
+
+        // Version 3 added the compression and reserved bytes, version 5
+        // the flags in one of them
+        let mut reserved = [0u8; HEADER_RESERVED_SIZE];
+        let (compression, flags) = if version >= 3 {
+            input.read_exact(&mut buf8)?;
+            let flags = if version >= 5 { buf8[2] } else { 0 };
+            reserved.copy_from_slice(&buf8[3..]);
+            (Compression::from_header(buf8[0], buf8[1])?, flags)
+        } else {
+            (Compression::None, 0)
+        };
+
+        // Version 4 added the checkpoint identities
+        let (checkpoint_id, base_checkpoint_id) = if version >= 4 {
+            input.read_exact(&mut buf8)?;
+            let checkpoint_id = u64::from_le_bytes(buf8);
+            input.read_exact(&mut buf8)?;
+            (checkpoint_id, u64::from_le_bytes(buf8))
+        } else {
+            (0, 0)
+        };
+
+        Ok(Self {
+            magic,
+            version,
+            pid,
+            num_allocations,
+            total_size,
+            timestamp,
+            compression,
+            checkpoint_id,
+            base_checkpoint_id,
+            encrypted: flags & HEADER_FLAG_ENCRYPTED != 0,
+            deduplicated: flags & HEADER_FLAG_DEDUPLICATED != 0,
+            reserved,
+        })
+    }
+
+    /// Write the header in the layout of its version, the inverse of
+    /// `read_from`. Fields the version does not have are not written.
+    pub fn write_to(&self, output: &mut impl Write) -> Result<()> {
+        output.write_all(&self.magic.to_le_bytes())?;
+        output.write_all(&self.version.to_le_bytes())?;
+        output.write_all(&self.pid.to_le_bytes())?;
+        output.write_all(&self.num_allocations.to_le_bytes())?;
+        output.write_all(&self.total_size.to_le_bytes())?;
+        output.write_all(&self.timestamp.to_le_bytes())?;
+
+        if self.version >= 3 {
+            let (algorithm, level) = self.compression.to_header();
+            let mut flags = 0;
+            if self.version >= 5 {
+                if self.encrypted {
+                    flags |= HEADER_FLAG_ENCRYPTED;
+                }
+                if self.deduplicated {
+                    flags |= HEADER_FLAG_DEDUPLICATED;
+                }
+            }
+            output.write_all(&[algorithm, level, flags])?;
+            output.write_all(&self.reserved)?;
+        }
+
+        if self.version >= 4 {
+            output.write_all(&self.checkpoint_id.to_le_bytes())?;
+            output.write_all(&self.base_checkpoint_id.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reject headers without the checkpoint magic or of a version this
+    /// build cannot read
+    pub fn validate(&self) -> Result<()> {
+        if self.magic != CHECKPOINT_MAGIC {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "Invalid checkpoint magic: 0x{:08x} (expected 0x{:08x})",
+                self.magic, CHECKPOINT_MAGIC
+            )));
+        }
+
+        if !(MIN_CHECKPOINT_VERSION..=CHECKPOINT_VERSION).contains(&self.version) {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "Unsupported checkpoint version: {} (expected {} to {})",
+                self.version, MIN_CHECKPOINT_VERSION, CHECKPOINT_VERSION
+            )));
+        }
+
+        if self.encrypted && self.version < MIN_ENCRYPTED_VERSION {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "Encrypted checkpoints of version {} are not supported (expected {} or later)",
+                self.version, MIN_ENCRYPTED_VERSION
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Size of the checksum trailer after each allocation's data, 0 for
+    /// version 1 files which carry none
+    pub fn checksum_size(&self) -> u64 {
+        if self.version >= 2 {
+            CHECKSUM_SIZE
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct AllocationHeader {
     pub vaddr_start: u64,
     pub vaddr_end: u64,
@@ -44,184 +362,2027 @@ pub struct AllocationHeader {
     pub flags: u32,
 }
 
-impl Default for BarSlidingCheckpoint {
-    fn default() -> Self {
-        Self {
-            window_size: BAR_WINDOW_SIZE,
-            show_progress: true,
-        }
+impl AllocationHeader {
+    /// Parse the header of an allocation record, the inverse of `write_to`
+    pub fn read_from(input: &mut impl Read) -> Result<Self> {
+        let mut buf = [0u8; ALLOCATION_HEADER_SIZE as usize];
+        input.read_exact(&mut buf)?;
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+
+        Ok(Self {
+            vaddr_start: u64_at(0),
+            vaddr_end: u64_at(8),
+            size: u64_at(16),
+            device_id: u32_at(24),
+            flags: u32_at(28),
+        })
     }
-}
 
-impl BarSlidingCheckpoint {
-    pub fn new() -> Self {
-        Self::default()
+    /// Write the header of an allocation record
+    pub fn write_to(&self, output: &mut impl Write) -> Result<()> {
+        output.write_all(&self.vaddr_start.to_le_bytes())?;
+        output.write_all(&self.vaddr_end.to_le_bytes())?;
+        output.write_all(&self.size.to_le_bytes())?;
+        output.write_all(&self.device_id.to_le_bytes())?;
+        output.write_all(&self.flags.to_le_bytes())?;
+        Ok(())
     }
+}
 
-    pub fn with_window_size(mut self, size: usize) -> Self {
-        self.window_size = size;
-        self
+/// Optional self-describing information stored after an `AllocationHeader`
+/// when `ALLOC_FLAG_DESCRIPTOR` is set
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AllocationDescriptor {
+    /// Name of the `/dev/shm` segment the data was read from. Restore writes
+    /// the data back into (and if necessary recreates) this segment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shm_name: Option<String>,
+
+    /// Offset of the allocation within the shared memory segment
+    #[serde(default)]
+    pub shm_offset: u64,
+
+    /// Parts of the allocation that were copied when not all of it was,
+    /// i.e. only its resident or its mapped pages. The data section then
+    /// holds the segments back to back instead of the full
+    /// `AllocationHeader::size` bytes; everything else is a hole.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segments: Option<Vec<Segment>>,
+
+    /// Parts of the allocation that were never touched and are stored as
+    /// holes in the checkpoint file. Unlike the gaps between `segments` they
+    /// are part of the data section and restore writes them back as zeros.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub holes: Option<Vec<Segment>>,
+
+    /// File descriptor the allocation was mapped through in the original
+    /// process, so restore can translate it for the target process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fd: Option<RecordedFd>,
+
+    /// Architecture of the GPU the allocation belonged to, e.g. "Hopper"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<String>,
+
+    /// Hex-encoded CUDA IPC handle the allocation was exported with, for
+    /// re-import through `cudaIpcOpenMemHandle`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipc_handle: Option<String>,
+
+    /// Type the allocation was classified as during detection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alloc_type: Option<AllocationType>,
+
+    /// Permissions of the mapping at checkpoint time, as in
+    /// `/proc/PID/maps` (e.g. "r--s")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protection: Option<String>,
+
+    /// Windows written since the base checkpoint, for allocations of an
+    /// incremental checkpoint. The data section holds the changed windows
+    /// back to back; the others keep what the base checkpoint restored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed_windows: Option<ChangedWindows>,
+
+    /// Allocation of another process whose record holds this one's data,
+    /// for shared memory checkpointed once for a process group. The data
+    /// section is empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored_by: Option<AllocationRef>,
+
+    /// Content hash of every window of the allocation, for records of a
+    /// deduplicated checkpoint. The data section holds the windows not
+    /// stored earlier in the checkpoint back to back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup: Option<DedupWindows>,
+}
+
+/// Windows of an allocation in a deduplicated checkpoint
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DedupWindows {
+    pub window_size: u64,
+
+    /// One entry per window, in address order
+    pub windows: Vec<DedupWindow>,
+}
+
+/// One window of a deduplicated allocation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DedupWindow {
+    /// Hex-encoded SHA-256 of the window's contents
+    pub hash: String,
+
+    /// Index, counting the windows of all deduplicated records of the
+    /// checkpoint, of the earlier window with the same contents. The window
+    /// is not stored; restore copies that window's contents instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<u64>,
+}
+
+impl DedupWindows {
+    /// Each window of an allocation of `size` bytes with its byte range
+    pub fn layout(&self, size: u64) -> impl Iterator<Item = (Segment, &DedupWindow)> {
+        let window_size = self.window_size.max(1);
+        self.windows.iter().enumerate().map(move |(i, window)| {
+            let offset = (i as u64 * window_size).min(size);
+            let segment = Segment {
+                offset,
+                len: window_size.min(size - offset),
+            };
+            (segment, window)
+        })
     }
+}
 
-    pub fn checkpoint_process(
-        &self,
-        pid: u32,
-        detection: &DetectionResult,
-        output_path: &Path,
-    ) -> Result<CheckpointMetadata> {
-        info!("Starting BAR sliding checkpoint for PID {}", pid);
-        let start_time = Instant::now();
+/// Windows stored so far in a deduplicated checkpoint, by content
+#[derive(Debug, Default)]
+struct WindowIndex {
+    /// Number of windows seen and the index of the first window with each
+    /// hash
+    windows: Mutex<(u64, HashMap<[u8; 32], u64>)>,
+}
 
-        // Create checkpoint file
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(output_path)
-            .map_err(GpuCheckpointError::IoError)?;
+impl WindowIndex {
+    /// Record the next window with contents `hash`, returning the index of
+    /// an earlier window it repeats
+    fn add(&self, hash: [u8; 32]) -> Option<u64> {
+        let mut windows = self.windows.lock().expect("window index poisoned");
+        let (count, first) = &mut *windows;
+        let index = *count;
+        *count += 1;
+        match first.get(&hash) {
+            Some(&first) => Some(first),
+            None => {
+                first.insert(hash, index);
+                None
+            }
+        }
+    }
+}
 
-        // Write header
-        let header = CheckpointHeader {
-            magic: CHECKPOINT_MAGIC,
-            version: CHECKPOINT_VERSION,
-            pid,
-            num_allocations: detection.allocations.len() as u32,
-            total_size: detection.total_gpu_memory,
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-        };
+/// An allocation of a particular process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AllocationRef {
+    pub pid: u32,
+    pub vaddr_start: u64,
+}
 
-        self.write_header(&mut file, &header)?;
+/// Which windows of an allocation changed since the base checkpoint
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangedWindows {
+    pub window_size: u64,
 
-        // Set up progress bar
-        let progress = if self.show_progress {
-            let pb = ProgressBar::new(detection.total_gpu_memory);
-            pb.set_style(
-                ProgressStyle::default_bar()
-                    .template(
-                        "[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} ({eta})",
-                    )
-                    .unwrap()
-                    .progress_chars("=>-"),
-            );
-            Some(pb)
-        } else {
-            None
-        };
+    /// One flag per window of the allocation, in address order
+    pub changed: Vec<bool>,
+}
 
-        // Checkpoint each allocation
-        let mut total_written = 0u64;
-        for (idx, allocation) in detection.allocations.iter().enumerate() {
-            debug!(
-                "Checkpointing allocation {} of {}",
-                idx + 1,
-                detection.allocations.len()
-            );
+impl ChangedWindows {
+    /// Flag the windows of `allocation` overlapping any of the `dirty`
+    /// address ranges
+    pub fn from_dirty(allocation: &GpuAllocation, dirty: &[Range<u64>], window_size: u64) -> Self {
+        let window_size = window_size.max(1);
+        let mut changed = vec![false; allocation.size.div_ceil(window_size) as usize];
+        for range in dirty {
+            let start = range.start.max(allocation.vaddr_start);
+            let end = range.end.min(allocation.vaddr_end);
+            if start >= end {
+                continue;
+            }
 
-            let bytes_written =
-                self.checkpoint_allocation(pid, allocation, &mut file, &progress)?;
+            let first = (start - allocation.vaddr_start) / window_size;
+            let last = (end - 1 - allocation.vaddr_start) / window_size;
+            for window in &mut changed[first as usize..=last as usize] {
+                *window = true;
+            }
+        }
+        Self {
+            window_size,
+            changed,
+        }
+    }
 
-            total_written += bytes_written;
+    /// The changed windows of an allocation of `size` bytes, runs of
+    /// adjacent windows merged
+    pub fn segments(&self, size: u64) -> Vec<Segment> {
+        let mut segments: Vec<Segment> = Vec::new();
+        if self.window_size == 0 {
+            return segments;
         }
 
-        if let Some(pb) = progress {
-            pb.finish_with_message("Checkpoint complete");
+        for (idx, _) in self.changed.iter().enumerate().filter(|(_, c)| **c) {
+            let offset = idx as u64 * self.window_size;
+            if offset >= size {
+                break;
+            }
+            let len = self.window_size.min(size - offset);
+            match segments.last_mut() {
+                Some(last) if last.offset + last.len == offset => last.len += len,
+                _ => segments.push(Segment { offset, len }),
+            }
         }
+        segments
+    }
+}
 
-        let duration = start_time.elapsed();
-        info!(
-            "Checkpoint completed: {} bytes in {:.2}s ({:.2} MB/s)",
-            total_written,
-            duration.as_secs_f64(),
-            (total_written as f64 / (1024.0 * 1024.0)) / duration.as_secs_f64()
-        );
+/// The checkpoint an incremental checkpoint records the changes since
+struct IncrementalBase {
+    checkpoint_id: u64,
 
-        Ok(CheckpointMetadata {
-            pid,
-            path: output_path.to_path_buf(),
-            size_bytes: total_written,
-            duration_ms: duration.as_millis() as u64,
-            num_allocations: detection.allocations.len(),
+    /// Address ranges of the allocations it holds
+    allocations: HashSet<(u64, u64)>,
+}
+
+impl IncrementalBase {
+    fn open(path: &Path) -> Result<Self> {
+        let inspection = crate::restore::inspect_checkpoint(path)?;
+        let header = match &inspection.header {
+            Some(header) if inspection.complete => header,
+            _ => {
+                return Err(GpuCheckpointError::CheckpointError(format!(
+                    "base checkpoint {} is unreadable: {}",
+                    path.display(),
+                    inspection.error.as_deref().unwrap_or("incomplete")
+                )))
+            }
+        };
+        if header.checkpoint_id == 0 {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "base checkpoint {} (version {}) has no checkpoint id; take a new full checkpoint",
+                path.display(),
+                header.version
+            )));
+        }
+
+        Ok(Self {
+            checkpoint_id: header.checkpoint_id,
+            allocations: inspection
+                .allocations
+                .iter()
+                .map(|record| (record.header.vaddr_start, record.header.vaddr_end))
+                .collect(),
         })
     }
+}
 
-    fn checkpoint_allocation(
-        &self,
-        pid: u32,
-        allocation: &GpuAllocation,
-        output: &mut File,
-        progress: &Option<ProgressBar>,
-    ) -> Result<u64> {
-        // Write allocation header
-        let alloc_header = AllocationHeader {
-            vaddr_start: allocation.vaddr_start,
-            vaddr_end: allocation.vaddr_end,
-            size: allocation.size,
-            device_id: allocation.device_id.unwrap_or(0),
-            flags: 0, // Reserved for future use
-        };
+/// File descriptor of the checkpointed process and what it referred to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedFd {
+    pub fd: i32,
+    pub path: String,
+}
 
-        self.write_allocation_header(output, &alloc_header)?;
+/// Byte range of an allocation, relative to its start address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub offset: u64,
+    pub len: u64,
+}
 
-        // For real implementation, we would:
-        // 1. Pause the process using CRIU or ptrace
-        // 2. Map the GPU memory via BAR
-        // 3. Copy in sliding windows
-        // 4. Resume the process
+impl AllocationDescriptor {
+    /// Descriptor carrying the information recorded during detection
+    pub fn for_allocation(allocation: &GpuAllocation, detection: &DetectionResult) -> Self {
+        let fd = allocation
+            .fd
+            .zip(allocation.metadata.backing_file.clone())
+            .map(|(fd, path)| RecordedFd { fd, path });
 
-        // For now, simulate by reading from /proc/pid/mem
-        let mem_path = format!("/proc/{pid}/mem");
+        Self {
+            fd,
+            architecture: detection
+                .architecture_of(allocation.device_id)
+                .map(str::to_string),
+            ipc_handle: allocation.metadata.ipc_handle.clone(),
+            alloc_type: Some(allocation.alloc_type),
+            protection: Some(allocation.metadata.protection.clone())
+                .filter(|protection| !protection.is_empty()),
+            ..Default::default()
+        }
+    }
 
-        if Path::new(&mem_path).exists() {
-            match self.copy_memory_sliding(
-                &mem_path,
-                allocation.vaddr_start,
-                allocation.size,
-                output,
-                progress,
-            ) {
-                Ok(()) => {}
-                Err(e) => {
-                    // Handle permission errors gracefully
-                    warn!("Cannot read {}: {}, writing zeros", mem_path, e);
-                    self.write_zeros(allocation.size, output, progress)?;
-                }
-            }
-        } else {
-            // Fallback: write zeros for testing
-            warn!("Cannot access {}, writing zeros", mem_path);
-            self.write_zeros(allocation.size, output, progress)?;
+    /// `PROT_*` flags to re-apply after restore when the mapping was not
+    /// writable at checkpoint time
+    pub fn read_only_protection(&self) -> Option<i32> {
+        let perms = self.protection.as_deref()?.as_bytes();
+        if perms.len() < 3 || perms[1] == b'w' {
+            return None;
         }
 
-        Ok(allocation.size)
+        let mut prot = libc::PROT_NONE;
+        if perms[0] == b'r' {
+            prot |= libc::PROT_READ;
+        }
+        if perms[2] == b'x' {
+            prot |= libc::PROT_EXEC;
+        }
+        Some(prot)
     }
 
-    fn copy_memory_sliding(
-        &self,
-        mem_path: &str,
-        start_addr: u64,
-        size: u64,
-        output: &mut File,
-        progress: &Option<ProgressBar>,
-    ) -> Result<()> {
-        let mut mem_file = OpenOptions::new().read(true).open(mem_path).map_err(|e| {
-            if e.kind() == std::io::ErrorKind::PermissionDenied {
-                GpuCheckpointError::PermissionDenied
-            } else {
-                GpuCheckpointError::IoError(e)
+    /// Number of bytes stored as holes
+    pub fn hole_size(&self) -> u64 {
+        self.holes.iter().flatten().map(|hole| hole.len).sum()
+    }
+
+    /// The data section of an allocation of `size` bytes split into
+    /// consecutive parts, each flagged whether it is a hole
+    pub fn layout(&self, size: u64) -> Vec<(Segment, bool)> {
+        let mut layout = Vec::new();
+        let mut cursor = 0;
+        for hole in self.holes.iter().flatten() {
+            if hole.offset > cursor {
+                layout.push((
+                    Segment {
+                        offset: cursor,
+                        len: hole.offset - cursor,
+                    },
+                    false,
+                ));
             }
-        })?;
+            layout.push((*hole, true));
+            cursor = hole.offset + hole.len;
+        }
+        if cursor < size {
+            layout.push((
+                Segment {
+                    offset: cursor,
+                    len: size - cursor,
+                },
+                false,
+            ));
+        }
+        layout
+    }
+
+    /// Number of data bytes stored for an allocation of `size` bytes
+    pub fn stored_size(&self, size: u64) -> u64 {
+        if let Some(dedup) = &self.dedup {
+            return dedup
+                .layout(size)
+                .filter(|(_, window)| window.duplicate_of.is_none())
+                .map(|(segment, _)| segment.len)
+                .sum();
+        }
+        match (&self.segments, &self.changed_windows) {
+            (Some(segments), _) => segments.iter().map(|segment| segment.len).sum(),
+            (None, Some(_)) => self
+                .changed_segments(size)
+                .iter()
+                .map(|segment| segment.len)
+                .sum(),
+            (None, None) => size,
+        }
+    }
+
+    /// Changed windows of an allocation of `size` bytes in an incremental
+    /// checkpoint, as stored back to back in its data section
+    pub fn changed_segments(&self, size: u64) -> Vec<Segment> {
+        self.changed_windows
+            .as_ref()
+            .map_or_else(Vec::new, |changed| changed.segments(size))
+    }
+}
+
+impl Default for BarSlidingCheckpoint {
+    fn default() -> Self {
+        Self {
+            window_size: BAR_WINDOW_SIZE,
+            show_progress: true,
+            progress_callback: None,
+            events: None,
+            cow_snapshot: false,
+            shm_dir: PathBuf::from(DEFAULT_SHM_DIR),
+            limit_rss: false,
+            sparse: false,
+            freeze: true,
+            freeze_method: FreezeMethod::default(),
+            managed_prefetch: false,
+            mapped_reads: false,
+            abort_on_layout_change: false,
+            compression: Compression::None,
+            compression_threads: 1,
+            compression_stats: Arc::default(),
+            encryption_key: None,
+            parallelism: 1,
+            bandwidth_limit: 0,
+            incremental_base: None,
+            track_dirty: false,
+            process_vm: false,
+            stored_elsewhere: HashMap::new(),
+            dedup: false,
+        }
+    }
+}
+
+impl BarSlidingCheckpoint {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_window_size(mut self, size: usize) -> Self {
+        self.window_size = size;
+        self
+    }
+
+    /// Draw a progress bar on stderr while transferring (the default).
+    /// Embedders that own the terminal turn it off, and may report
+    /// progress through `with_progress_callback` instead.
+    pub fn with_progress(mut self, enabled: bool) -> Self {
+        self.show_progress = enabled;
+        self
+    }
+
+    /// Call `callback` with the bytes processed so far as the transfer
+    /// advances, whether or not the progress bar is drawn
+    pub fn with_progress_callback(mut self, callback: impl FnMut(u64) + Send + 'static) -> Self {
+        self.progress_callback = Some(ProgressCallback::new(callback));
+        self
+    }
+
+    /// Send checkpoint lifecycle events to `events`: started, each allocation
+    /// begun, bytes processed and finished. Events are dropped once the
+    /// receiver hangs up.
+    pub fn with_events(mut self, events: Sender<CheckpointEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Enable the experimental copy-on-write snapshot mode. The process is
+    /// only frozen while a snapshot of it is forked and runs on while the
+    /// checkpoint is copied from the snapshot; if the allocations cannot be
+    /// snapshotted the regular freeze-copy path is used instead.
+    pub fn with_cow_snapshot(mut self, enabled: bool) -> Self {
+        self.cow_snapshot = enabled;
+        self
+    }
+
+    /// Directory shared memory backed IPC allocations are read from
+    pub fn with_shm_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.shm_dir = dir.into();
+        self
+    }
+
+    /// Only copy the resident pages of each allocation, recording the
+    /// non-resident parts as holes that restore leaves untouched
+    pub fn with_limit_rss(mut self, enabled: bool) -> Self {
+        self.limit_rss = enabled;
+        self
+    }
+
+    /// Stop the target for as long as its memory is copied (the default),
+    /// so the checkpoint is not torn by writes during the copy. A target
+    /// that is already stopped is left alone.
+    pub fn with_freeze(mut self, enabled: bool) -> Self {
+        self.freeze = enabled;
+        self
+    }
+
+    /// Mechanism used to pause the target
+    pub fn with_freeze_method(mut self, method: FreezeMethod) -> Self {
+        self.freeze_method = method;
+        self
+    }
+
+    /// Seek over never-touched pages of anonymous allocations instead of
+    /// writing zeros, so the checkpoint file is sparse on disk
+    pub fn with_sparse(mut self, enabled: bool) -> Self {
+        self.sparse = enabled;
+        self
+    }
+
+    /// Touch every page of managed allocations before copying them, so
+    /// device-resident pages migrate to the host in one sweep instead of
+    /// faulting one by one in the middle of the copy
+    pub fn with_managed_prefetch(mut self, enabled: bool) -> Self {
+        self.managed_prefetch = enabled;
+        self
+    }
+
+    /// Copy allocations whose source is a regular file, such as shared
+    /// memory segments, straight from a memory map of the file instead of
+    /// reading them into a buffer first. Only applies where the data cannot
+    /// be copied file to file in the kernel, e.g. when it is compressed;
+    /// process memory cannot be mapped and is always read through a buffer.
+    ///
+    /// Off by default: a peer truncating the segment while it is mapped
+    /// kills the checkpoint with SIGBUS, where a buffered read only comes
+    /// up short and the rest of the record is zero-filled.
+    pub fn with_mapped_reads(mut self, enabled: bool) -> Self {
+        self.mapped_reads = enabled;
+        self
+    }
+
+    /// Fail with "memory layout changed during checkpoint" when the GPU
+    /// mappings differ before and after the copy, instead of only setting
+    /// `CheckpointMetadata::layout_changed`
+    pub fn with_abort_on_layout_change(mut self, enabled: bool) -> Self {
+        self.abort_on_layout_change = enabled;
+        self
+    }
+
+    /// Compress allocation data window by window. Never-touched pages are
+    /// compressed along with the rest instead of becoming file holes.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Compress up to `n` windows of an allocation at a time. The frames are
+    /// written in order, so the checkpoint is the same as with one thread.
+    pub fn with_compression_threads(mut self, n: usize) -> Self {
+        self.compression_threads = n.max(1);
+        self
+    }
+
+    /// Encrypt each window of allocation data with AES-256-GCM under `key`.
+    /// Like compression this replaces `--sparse` holes.
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption_key.is_some()
+    }
+
+    /// Progress of transferring `total_bytes`, if it is reported at all
+    fn progress(&self, total_bytes: u64) -> Option<TransferProgress> {
+        (self.show_progress || self.progress_callback.is_some() || self.events.is_some()).then(
+            || {
+                TransferProgress::with_reporting(
+                    total_bytes,
+                    self.show_progress,
+                    self.progress_callback.clone(),
+                )
+                .with_events(self.events.clone())
+            },
+        )
+    }
+
+    /// Checkpoint up to `n` allocations at a time. Each worker writes its
+    /// records to a segment file next to the checkpoint, and the segments
+    /// are appended to the checkpoint in allocation order.
+    pub fn with_parallelism(mut self, n: usize) -> Self {
+        self.parallelism = n.max(1);
+        self
+    }
+
+    /// Pace writes to the checkpoint to `mbps` MB/s, sleeping whenever the
+    /// copy gets ahead, so storage shared with other jobs is not saturated.
+    /// 0 (the default) writes as fast as storage allows.
+    pub fn with_bandwidth_limit(mut self, mbps: u64) -> Self {
+        self.bandwidth_limit = mbps;
+        self
+    }
+
+    /// Only capture the windows of each allocation written since the
+    /// checkpoint at `base`, which restore applies first. Allocations the
+    /// base does not hold, and those whose pages are not tracked, are
+    /// captured in full.
+    pub fn with_incremental_base(mut self, base: impl Into<PathBuf>) -> Self {
+        self.incremental_base = Some(base.into());
+        self
+    }
+
+    /// Clear the target's soft-dirty bits before copying, so a later
+    /// incremental checkpoint against this one only captures the windows
+    /// written after it. Incremental checkpoints always do.
+    pub fn with_dirty_tracking(mut self, enabled: bool) -> Self {
+        self.track_dirty = enabled;
+        self
+    }
+
+    /// Read the target's memory with `process_vm_readv`, which moves a
+    /// window per syscall without opening `/proc/PID/mem`. Windows it
+    /// cannot read are read through `/proc/PID/mem` instead.
+    pub fn with_process_vm(mut self, enabled: bool) -> Self {
+        self.process_vm = enabled;
+        self
+    }
+
+    /// Record the allocations starting at the given addresses without their
+    /// data, which the referenced allocation's record holds, e.g. shared
+    /// memory another member of a process group maps too
+    pub fn with_stored_elsewhere(mut self, allocations: HashMap<u64, AllocationRef>) -> Self {
+        self.stored_elsewhere = allocations;
+        self
+    }
+
+    /// Hash each window and store windows whose contents were stored
+    /// before, e.g. zero-filled regions, only once. Repeats are recorded by
+    /// reference and restore copies them from the first occurrence. Records
+    /// can only refer back, so allocations are checkpointed one at a time.
+    pub fn with_dedup(mut self, enabled: bool) -> Self {
+        self.dedup = enabled;
+        self
+    }
+
+    /// Whether the target is stopped for the whole copy, rather than not
+    /// at all or only while a snapshot is forked
+    pub(crate) fn freezes_for_copy(&self) -> bool {
+        self.freeze && !self.cow_snapshot
+    }
+
+    /// Controller pausing the process of `detection` the configured way
+    pub(crate) fn controller(&self, detection: &DetectionResult) -> ProcessController {
+        ProcessController::new(detection.pid)
+            .with_method(self.freeze_method)
+            .with_priority_threads(&detection.gpu_threads)
+    }
+
+    pub fn incremental_base(&self) -> Option<&Path> {
+        self.incremental_base.as_deref()
+    }
+
+    /// Whether writes to `allocation` show up in the target's soft-dirty
+    /// bits. Only writes through the target's own page tables set them, so
+    /// file-backed and shared mappings, whose contents the device or other
+    /// processes change directly, and pinned host memory the GPU writes by
+    /// DMA are always captured in full.
+    fn tracks_dirty_pages(allocation: &GpuAllocation) -> bool {
+        allocation.metadata.backing_file.is_none()
+            && !allocation.metadata.is_shared
+            && !matches!(
+                allocation.alloc_type,
+                AllocationType::Ipc | AllocationType::Distributed | AllocationType::HostPinned
+            )
+    }
+
+    /// Windows of each allocation written since `base` was taken, `None`
+    /// for allocations to capture in full
+    fn changed_windows(
+        &self,
+        pid: u32,
+        detection: &DetectionResult,
+        base: &IncrementalBase,
+    ) -> Vec<Option<ChangedWindows>> {
+        if !MemoryMapParser::soft_dirty_supported() {
+            warn!(
+                "The kernel does not track soft-dirty pages, so the incremental checkpoint \
+                 captures every allocation in full"
+            );
+            return vec![None; detection.allocations.len()];
+        }
+
+        detection
+            .allocations
+            .iter()
+            .map(|allocation| {
+                if !base
+                    .allocations
+                    .contains(&(allocation.vaddr_start, allocation.vaddr_end))
+                {
+                    debug!(
+                        "Allocation at 0x{:016x} is not in the base checkpoint, capturing it in full",
+                        allocation.vaddr_start
+                    );
+                    return None;
+                }
+                if !Self::tracks_dirty_pages(allocation) {
+                    return None;
+                }
+
+                match MemoryMapParser::soft_dirty_ranges(
+                    pid,
+                    allocation.vaddr_start,
+                    allocation.vaddr_end,
+                ) {
+                    Ok(dirty) => Some(ChangedWindows::from_dirty(
+                        allocation,
+                        &dirty,
+                        self.window_size as u64,
+                    )),
+                    Err(e) => {
+                        warn!(
+                            "Cannot read soft-dirty pages at 0x{:016x}: {}, capturing it in full",
+                            allocation.vaddr_start, e
+                        );
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Mappings of `pid` that overlap a detected allocation or are GPU
+    /// backed, so allocations made or freed during a copy show up as a
+    /// difference. `None` if the maps cannot be read.
+    fn gpu_layout(pid: u32, detection: &DetectionResult) -> Option<Vec<MemoryRegion>> {
+        let regions = match MemoryMapParser::parse_maps(pid) {
+            Ok(regions) => regions,
+            Err(e) => {
+                debug!("Not watching the memory layout of PID {}: {}", pid, e);
+                return None;
+            }
+        };
+
+        Some(
+            regions
+                .into_iter()
+                .filter(|region| {
+                    detection
+                        .allocations
+                        .iter()
+                        .any(|a| region.start < a.vaddr_end && a.vaddr_start < region.end)
+                        || MemoryMapParser::classify_region(region).is_some()
+                })
+                .collect(),
+        )
+    }
+
+    pub fn checkpoint_process(
+        &self,
+        pid: u32,
+        detection: &DetectionResult,
+        output_path: &Path,
+    ) -> Result<CheckpointMetadata> {
+        let base = self.open_incremental_base()?;
+
+        // Create checkpoint file, locked so concurrent restores of the same
+        // path fail instead of reading a partial checkpoint
+        let mut file = lock::create_locked(output_path)?;
+
+        let segment_dir = match output_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        self.checkpoint_into(
+            pid,
+            detection,
+            base,
+            &mut *file,
+            CheckpointTarget {
+                path: output_path,
+                segment_dir,
+                seekable: true,
+            },
+        )
+    }
+
+    /// Stream a checkpoint to `output`, e.g. stdout piped to remote storage,
+    /// without staging it in a local file. A stream cannot be rewritten, so
+    /// the header declares the detected size of the allocations even where
+    /// fewer bytes are captured (resident pages, changed windows), and
+    /// sparse holes are written out as zeros. The metadata's path is empty.
+    pub fn checkpoint_to_writer(
+        &self,
+        pid: u32,
+        detection: &DetectionResult,
+        output: impl Write,
+    ) -> Result<CheckpointMetadata> {
+        let base = self.open_incremental_base()?;
+        let segment_dir = std::env::temp_dir();
+        let mut stream = StreamOutput::new(output);
+        let metadata = self.checkpoint_into(
+            pid,
+            detection,
+            base,
+            &mut stream,
+            CheckpointTarget {
+                path: Path::new(""),
+                segment_dir: &segment_dir,
+                seekable: false,
+            },
+        )?;
+        stream.flush()?;
+        Ok(metadata)
+    }
+
+    /// Stream a checkpoint to `sink`, e.g. an object store, as it is
+    /// written, then finalize it. As with `checkpoint_to_writer`, the header
+    /// declares the detected size of the allocations.
+    pub fn checkpoint_to_sink(
+        &self,
+        pid: u32,
+        detection: &DetectionResult,
+        sink: impl CheckpointSink,
+    ) -> Result<CheckpointMetadata> {
+        let mut writer = SinkWriter::new(sink);
+        let metadata = self
+            .checkpoint_to_writer(pid, detection, &mut writer)
+            .map_err(|e| match e {
+                GpuCheckpointError::IoError(_) => writer.take_error(e),
+                other => other,
+            })?;
+        writer.into_inner().finalize()?;
+        Ok(metadata)
+    }
+
+    fn open_incremental_base(&self) -> Result<Option<IncrementalBase>> {
+        self.incremental_base
+            .as_deref()
+            .map(IncrementalBase::open)
+            .transpose()
+    }
+
+    /// Write a checkpoint of `pid` to `output`, front to back
+    fn checkpoint_into<W: Write + Seek + FileOutput>(
+        &self,
+        pid: u32,
+        detection: &DetectionResult,
+        base: Option<IncrementalBase>,
+        output: &mut W,
+        target: CheckpointTarget<'_>,
+    ) -> Result<CheckpointMetadata> {
+        info!("Starting BAR sliding checkpoint for PID {}", pid);
+        let start_time = Instant::now();
+        let output = &mut ThrottledWriter::new(output, self.bandwidth_limit);
+        self.compression_stats.reset();
+
+        // A snapshot stops the target only while it is forked. Dropping the
+        // controller resumes the target should the copy fail.
+        let mut controller = self.controller(detection);
+        if self.freezes_for_copy() {
+            controller.freeze_unless_stopped()?;
+        }
+
+        // Write header. Every allocation gets a record, with unreadable
+        // ranges stored as zeros, and a record that fails to write fails the
+        // checkpoint, so the count is final before any data is copied.
+        let header = CheckpointHeader {
+            magic: CHECKPOINT_MAGIC,
+            version: CHECKPOINT_VERSION,
+            pid,
+            num_allocations: detection.allocations.len() as u32,
+            total_size: detection.total_gpu_memory,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            compression: self.compression,
+            checkpoint_id: new_checkpoint_id(pid),
+            base_checkpoint_id: base.as_ref().map_or(0, |base| base.checkpoint_id),
+            encrypted: self.is_encrypted(),
+            deduplicated: self.dedup,
+            reserved: [0; HEADER_RESERVED_SIZE],
+        };
+
+        header.write_to(output)?;
+
+        let progress = self.progress(detection.total_gpu_memory);
+        if let Some(pb) = &progress {
+            pb.emit(CheckpointEvent::Started {
+                pid,
+                num_allocations: header.num_allocations,
+                total_bytes: detection.total_gpu_memory,
+            });
+        }
+
+        // Without a freeze the target can map or unmap GPU memory while it
+        // is copied; compare the layout around the copy to notice
+        let layout_before = Self::gpu_layout(pid, detection);
+
+        // Which windows changed is read before the bits are cleared for the
+        // next incremental checkpoint, and both before any data is copied
+        let changed_windows = match &base {
+            Some(base) => self.changed_windows(pid, detection, base),
+            None => vec![None; detection.allocations.len()],
+        };
+        if base.is_some() || self.track_dirty {
+            if let Err(e) = MemoryMapParser::clear_soft_dirty(pid) {
+                warn!(
+                    "Cannot clear the soft-dirty bits of PID {}: {}; an incremental checkpoint \
+                     against this one will capture more than the changed windows",
+                    pid, e
+                );
+            }
+        }
+
+        let snapshot = if self.cow_snapshot {
+            match CowSnapshot::capture(pid, detection, self.freeze_method) {
+                Ok(snapshot) => Some(snapshot),
+                Err(e) => {
+                    warn!(
+                        "Copy-on-write snapshot unavailable, falling back to freeze-copy: {}",
+                        e
+                    );
+                    if self.freeze {
+                        controller.freeze_unless_stopped()?;
+                    }
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // Memory is read from the snapshot, which holds still while the
+        // target runs on
+        let source_pid = snapshot.as_ref().map_or(pid, CowSnapshot::pid);
+
+        let managed = detection
+            .allocations
+            .iter()
+            .filter(|a| a.alloc_type == AllocationType::Managed)
+            .count();
+        if managed > 0 && !self.managed_prefetch {
+            warn!(
+                "Copying {} managed allocations may migrate device-resident pages to the host \
+                 while they are read; use --managed-prefetch to migrate them up front",
+                managed
+            );
+        }
+
+        // Reads of an exited process fall back to zeros, so track liveness to
+        // fail loudly instead of producing a silently empty checkpoint
+        let target_alive = ProcessScanner::is_alive(source_pid);
+
+        // Checkpoint each allocation
+        let window_index = self.dedup.then(WindowIndex::default);
+        if self.dedup && self.parallelism > 1 {
+            debug!("Deduplicating windows, checkpointing allocations one at a time");
+        }
+        let allocation_timings =
+            if self.parallelism > 1 && detection.allocations.len() > 1 && window_index.is_none() {
+                self.checkpoint_parallel(
+                    source_pid,
+                    header.checkpoint_id,
+                    detection,
+                    &changed_windows,
+                    target_alive,
+                    output,
+                    target.segment_dir,
+                    &progress,
+                )?
+            } else {
+                changed_windows
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, changed)| {
+                        self.checkpoint_record(
+                            source_pid,
+                            header.checkpoint_id,
+                            idx,
+                            detection,
+                            changed.as_ref(),
+                            window_index.as_ref(),
+                            target_alive,
+                            output,
+                            &progress,
+                        )
+                    })
+                    .collect::<Result<Vec<_>>>()?
+            };
+        controller.resume()?;
+        let total_written: u64 = allocation_timings.iter().map(|t| t.size).sum();
+
+        // The layout that counts is the one of the memory copied
+        let layout_changed = layout_before.is_some_and(|before| {
+            Self::gpu_layout(source_pid, detection).is_some_and(|after| after != before)
+        });
+        drop(snapshot);
+        if layout_changed {
+            if self.abort_on_layout_change {
+                return Err(GpuCheckpointError::CheckpointError(
+                    "memory layout changed during checkpoint".to_string(),
+                ));
+            }
+            warn!(
+                "GPU memory layout of PID {} changed during checkpoint; the checkpoint may be inconsistent",
+                pid
+            );
+        }
+
+        // Only resident pages may have been copied, so restore sizes its progress from what was actually captured
+        if total_written != header.total_size && target.seekable {
+            debug!(
+                "Captured {} of {} detected bytes, updating header",
+                total_written, header.total_size
+            );
+            Self::rewrite_total_size(output, total_written)?;
+        }
+
+        if let Some(pb) = progress {
+            pb.finish_with_message("Checkpoint complete");
+            pb.emit(CheckpointEvent::Finished {
+                bytes: total_written,
+            });
+        }
+
+        let duration = start_time.elapsed();
+        info!(
+            "Checkpoint completed: {} bytes in {:.2}s ({:.2} MB/s)",
+            total_written,
+            duration.as_secs_f64(),
+            (total_written as f64 / (1024.0 * 1024.0)) / duration.as_secs_f64()
+        );
+        let compression = self.compression.is_enabled().then(|| {
+            let throughput = self.compression_stats.throughput();
+            info!(
+                "Compressed {} bytes to {} in {:.2}s ({:.2} MB/s on {} threads)",
+                throughput.raw_bytes,
+                throughput.compressed_bytes,
+                throughput.duration_ms as f64 / 1000.0,
+                throughput.mb_per_sec,
+                self.compression_threads
+            );
+            throughput
+        });
+
+        Ok(CheckpointMetadata {
+            pid,
+            path: target.path.to_path_buf(),
+            size_bytes: total_written,
+            duration_ms: duration.as_millis() as u64,
+            num_allocations: allocation_timings.len(),
+            layout_changed,
+            allocation_timings,
+            compression,
+        })
+    }
+
+    /// Write the raw contents of a single allocation to `output_path`,
+    /// without any checkpoint framing, returning the number of bytes written
+    pub fn dump_allocation(
+        &self,
+        pid: u32,
+        allocation: &GpuAllocation,
+        output_path: &Path,
+    ) -> Result<u64> {
+        info!(
+            "Dumping allocation 0x{:016x}-0x{:016x} of PID {} to {:?}",
+            allocation.vaddr_start, allocation.vaddr_end, pid, output_path
+        );
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(output_path)
+            .map_err(GpuCheckpointError::IoError)?;
+
+        let progress = self.progress(allocation.size);
+
+        self.copy_memory_sliding(pid, allocation, &mut file, &progress)?;
+
+        if let Some(pb) = progress {
+            pb.finish_with_message("Dump complete");
+        }
+
+        Ok(file.stream_position()?)
+    }
+
+    /// Write the record of allocation `idx` of `detection` to `output`,
+    /// returning how many data bytes were written and how long it took
+    #[allow(clippy::too_many_arguments)]
+    fn checkpoint_record<W: Write + Seek + FileOutput>(
+        &self,
+        pid: u32,
+        checkpoint_id: u64,
+        idx: usize,
+        detection: &DetectionResult,
+        changed: Option<&ChangedWindows>,
+        window_index: Option<&WindowIndex>,
+        target_alive: bool,
+        output: &mut W,
+        progress: &Option<TransferProgress>,
+    ) -> Result<AllocationTiming> {
+        let allocation = &detection.allocations[idx];
+        debug!(
+            "Checkpointing allocation {} of {}",
+            idx + 1,
+            detection.allocations.len()
+        );
+
+        if !is_addressable(allocation.vaddr_start, allocation.vaddr_end) {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "allocation at 0x{:016x} ({} bytes) exceeds the address space of this host",
+                allocation.vaddr_start, allocation.size
+            )));
+        }
+
+        if let Some(pb) = progress {
+            pb.emit(CheckpointEvent::AllocationStarted {
+                index: idx as u32,
+                count: detection.allocations.len() as u32,
+                vaddr_start: allocation.vaddr_start,
+                size: allocation.size,
+            });
+        }
+
+        let start_time = Instant::now();
+        let record = RecordId {
+            checkpoint_id,
+            index: idx as u32,
+        };
+        let descriptor = AllocationDescriptor::for_allocation(allocation, detection);
+        let stored_by = self.stored_elsewhere.get(&allocation.vaddr_start);
+        let bytes_written = match (stored_by, changed) {
+            (Some(&stored_by), _) => self.checkpoint_stored_elsewhere(
+                allocation, record, descriptor, stored_by, output, progress,
+            )?,
+            (None, Some(changed)) => self.checkpoint_changed_windows(
+                pid, allocation, record, descriptor, changed, output, progress,
+            )?,
+            (None, None) => self.checkpoint_allocation(
+                pid,
+                allocation,
+                record,
+                descriptor,
+                window_index,
+                output,
+                progress,
+            )?,
+        };
+
+        if target_alive && !ProcessScanner::is_alive(pid) {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "target exited during checkpoint (PID {pid})"
+            )));
+        }
+
+        Ok(AllocationTiming::new(
+            allocation.vaddr_start,
+            bytes_written,
+            start_time.elapsed(),
+        ))
+    }
+
+    /// Checkpoint the allocations on `self.parallelism` threads into
+    /// unnamed segment files in `segment_dir`, then append the segments to
+    /// `output` in allocation order. Returns the timing of every record
+    /// written.
+    #[allow(clippy::too_many_arguments)]
+    fn checkpoint_parallel<W: Write + Seek + FileOutput>(
+        &self,
+        pid: u32,
+        checkpoint_id: u64,
+        detection: &DetectionResult,
+        changed_windows: &[Option<ChangedWindows>],
+        target_alive: bool,
+        output: &mut W,
+        segment_dir: &Path,
+        progress: &Option<TransferProgress>,
+    ) -> Result<Vec<AllocationTiming>> {
+        let num_allocations = detection.allocations.len();
+        let workers = self.parallelism.min(num_allocations);
+        debug!(
+            "Checkpointing {} allocations on {} threads",
+            num_allocations, workers
+        );
+
+        // Workers claim allocations in order and stop claiming once any
+        // record failed, so every record before a failure is present
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let mut records: Vec<(usize, Result<(File, AllocationTiming)>)> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut records = Vec::new();
+                        while !failed.load(Ordering::Relaxed) {
+                            let idx = next.fetch_add(1, Ordering::Relaxed);
+                            if idx >= num_allocations {
+                                break;
+                            }
+
+                            let record = tempfile::tempfile_in(segment_dir)
+                                .map_err(GpuCheckpointError::IoError)
+                                .and_then(|mut segment| {
+                                    let timing = self.checkpoint_record(
+                                        pid,
+                                        checkpoint_id,
+                                        idx,
+                                        detection,
+                                        changed_windows[idx].as_ref(),
+                                        None,
+                                        target_alive,
+                                        &mut segment,
+                                        progress,
+                                    )?;
+                                    Ok((segment, timing))
+                                });
+                            if record.is_err() {
+                                failed.store(true, Ordering::Relaxed);
+                            }
+                            records.push((idx, record));
+                        }
+                        records
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("checkpoint worker panicked"))
+                .collect()
+        });
+        records.sort_by_key(|(idx, _)| *idx);
+
+        let mut timings = Vec::with_capacity(records.len());
+        for (_, record) in records {
+            let (mut segment, timing) = record?;
+            segment.rewind()?;
+            std::io::copy(&mut segment, output)?;
+            timings.push(timing);
+        }
+        Ok(timings)
+    }
+
+    fn rewrite_total_size(file: &mut (impl Write + Seek), total_size: u64) -> Result<()> {
+        let end = file.stream_position()?;
+        file.seek(SeekFrom::Start(TOTAL_SIZE_OFFSET))?;
+        file.write_all(&total_size.to_le_bytes())?;
+        file.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn checkpoint_allocation<W: Write + Seek + FileOutput>(
+        &self,
+        pid: u32,
+        allocation: &GpuAllocation,
+        record: RecordId,
+        descriptor: AllocationDescriptor,
+        window_index: Option<&WindowIndex>,
+        output: &mut W,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        if self.managed_prefetch && allocation.alloc_type == AllocationType::Managed {
+            match Self::prefetch_to_host(pid, allocation) {
+                Ok(pages) => debug!(
+                    "Prefetched {} pages of managed allocation at 0x{:016x}",
+                    pages, allocation.vaddr_start
+                ),
+                Err(e) => warn!(
+                    "Cannot prefetch managed allocation at 0x{:016x}: {}",
+                    allocation.vaddr_start, e
+                ),
+            }
+        }
+
+        if let Some(segment) = self.shm_segment(allocation) {
+            match File::open(&segment) {
+                Ok(shm_file) => {
+                    return self.checkpoint_shm_allocation(
+                        allocation, record, descriptor, &segment, shm_file, output, progress,
+                    )
+                }
+                Err(e) => warn!(
+                    "Cannot open shared memory segment {}: {}, reading process memory instead",
+                    segment.display(),
+                    e
+                ),
+            }
+        }
+
+        if self.limit_rss {
+            match MemoryMapParser::resident_ranges(
+                pid,
+                allocation.vaddr_start,
+                allocation.vaddr_end,
+            ) {
+                Ok(ranges) => {
+                    return self.checkpoint_ranges(
+                        pid, allocation, record, descriptor, &ranges, output, progress,
+                    )
+                }
+                Err(e) => warn!(
+                    "Cannot determine resident pages at 0x{:016x}: {}, copying the whole allocation",
+                    allocation.vaddr_start, e
+                ),
+            }
+        }
+
+        // Detection can report allocations spanning unmapped gaps, and
+        // reading a gap fails, so only the mapped parts are copied
+        match MemoryMapParser::mapped_ranges(pid, allocation.vaddr_start, allocation.vaddr_end) {
+            Ok(mapped) if !Self::holes_between(allocation, &mapped).is_empty() => {
+                debug!(
+                    "Allocation at 0x{:016x} spans unmapped gaps, copying {} mapped range(s)",
+                    allocation.vaddr_start,
+                    mapped.len()
+                );
+                return self.checkpoint_ranges(
+                    pid, allocation, record, descriptor, &mapped, output, progress,
+                );
+            }
+            Ok(_) => {}
+            Err(e) => debug!(
+                "Cannot read the mappings of PID {}: {}, copying the whole allocation at 0x{:016x}",
+                pid, e, allocation.vaddr_start
+            ),
+        }
+
+        if let Some(window_index) = window_index {
+            return self.checkpoint_deduplicated(
+                pid,
+                allocation,
+                record,
+                descriptor,
+                window_index,
+                output,
+                progress,
+            );
+        }
+
+        // Non-resident pages of file-backed mappings may still hold data in
+        // the page cache, so only anonymous allocations get holes
+        if self.sparse
+            && !self.compression.is_enabled()
+            && !self.is_encrypted()
+            && allocation.metadata.backing_file.is_none()
+        {
+            match MemoryMapParser::resident_ranges(
+                pid,
+                allocation.vaddr_start,
+                allocation.vaddr_end,
+            ) {
+                Ok(ranges) => {
+                    let holes = Self::holes_between(allocation, &ranges);
+                    if !holes.is_empty() {
+                        return self.checkpoint_sparse_allocation(
+                            pid, allocation, record, descriptor, holes, output, progress,
+                        );
+                    }
+                }
+                Err(e) => warn!(
+                    "Cannot determine resident pages at 0x{:016x}: {}, writing the allocation densely",
+                    allocation.vaddr_start, e
+                ),
+            }
+        }
+
+        let context = self.write_allocation_record(output, record, allocation, &descriptor)?;
+        let mut data = self.data_writer(output, context);
+
+        // For real implementation, we would:
+        // 1. Map the GPU memory via BAR
+        // 2. Copy in sliding windows
+
+        // For now, simulate by reading from /proc/pid/mem
+        let mem_path = format!("/proc/{pid}/mem");
+
+        if Path::new(&mem_path).exists() {
+            let result = self.copy_memory_sliding(pid, allocation, &mut data, progress);
+
+            // The record must hold the declared number of bytes, so the
+            // rest of a failed or short read is zero-filled
+            let copied = data.bytes_written();
+            match result {
+                // Handle permission errors gracefully
+                Err(e) => warn!(
+                    "Cannot read allocation at 0x{:016x} ({} bytes) from {}: {}, writing zeros for \
+                     the {} bytes not read",
+                    allocation.vaddr_start,
+                    allocation.size,
+                    mem_path,
+                    e,
+                    allocation.size - copied
+                ),
+                Ok(()) if copied < allocation.size => warn!(
+                    "Read only {} of {} bytes at 0x{:016x}, writing zeros for the rest",
+                    copied, allocation.size, allocation.vaddr_start
+                ),
+                Ok(()) => {}
+            }
+            self.write_zeros(allocation.size - copied, &mut data, progress)?;
+        } else {
+            // Fallback: write zeros for testing
+            warn!("Cannot access {}, writing zeros", mem_path);
+            self.write_zeros(allocation.size, &mut data, progress)?;
+        }
+
+        Self::write_checksum(data)?;
+        Ok(allocation.size)
+    }
+
+    /// Read one byte of every page of `allocation`. For managed memory each
+    /// read of a device-resident page faults it over to the host, so the
+    /// following bulk copy runs at host memory speed. Returns the number of
+    /// pages touched.
+    fn prefetch_to_host(pid: u32, allocation: &GpuAllocation) -> Result<u64> {
+        let mem_file = File::open(format!("/proc/{pid}/mem"))?;
+        let page_size = crate::utils::page_size();
+
+        let mut byte = [0u8; 1];
+        let mut pages = 0;
+        let mut addr = allocation.vaddr_start;
+        while addr < allocation.vaddr_end {
+            mem_file.read_exact_at(&mut byte, addr)?;
+            pages += 1;
+            addr = (addr / page_size + 1) * page_size;
+        }
+        Ok(pages)
+    }
+
+    /// Shared memory segment backing an IPC allocation, if any. Reading the
+    /// segment directly does not depend on where the owning process mapped it.
+    fn shm_segment(&self, allocation: &GpuAllocation) -> Option<PathBuf> {
+        if !matches!(
+            allocation.alloc_type,
+            AllocationType::Ipc | AllocationType::Distributed
+        ) {
+            return None;
+        }
+
+        let backing = Path::new(allocation.metadata.backing_file.as_ref()?);
+        if backing.parent()? != self.shm_dir {
+            return None;
+        }
+
+        Some(backing.to_path_buf())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn checkpoint_shm_allocation<W: Write + Seek + FileOutput>(
+        &self,
+        allocation: &GpuAllocation,
+        record: RecordId,
+        descriptor: AllocationDescriptor,
+        segment: &Path,
+        mut shm_file: File,
+        output: &mut W,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        let descriptor = AllocationDescriptor {
+            shm_name: segment
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string()),
+            shm_offset: allocation.metadata.file_offset,
+            ..descriptor
+        };
+
+        let context = self.write_allocation_record(output, record, allocation, &descriptor)?;
+        let mut data = self.data_writer(output, context);
+
+        debug!(
+            "Reading IPC allocation at 0x{:016x} from {}",
+            allocation.vaddr_start,
+            segment.display()
+        );
+
+        let copied_in_kernel = self.copy_file_range(
+            &shm_file,
+            descriptor.shm_offset,
+            allocation.size,
+            &mut data,
+            progress,
+        )?;
+        let mapped = if copied_in_kernel.is_some() || !self.mapped_reads {
+            None
+        } else {
+            Self::map_file_range(&shm_file, descriptor.shm_offset, allocation.size)
+                .inspect_err(|e| {
+                    debug!(
+                        "Cannot map {}, reading it instead: {}",
+                        segment.display(),
+                        e
+                    )
+                })
+                .ok()
+        };
+        let copied = match (copied_in_kernel, mapped) {
+            (Some(copied), _) => copied,
+            (None, Some(map)) => {
+                self.copy_mapped(map.as_deref().unwrap_or_default(), &mut data, progress)?
+            }
+            (None, None) => {
+                shm_file.seek(SeekFrom::Start(descriptor.shm_offset))?;
+                self.copy_sliding(&mut shm_file, allocation.size, &mut data, progress)?
+            }
+        };
+        if copied < allocation.size {
+            // The segment shrank since it was mapped, keep the record size intact
+            warn!(
+                "Segment {} ended after {} of {} bytes, padding with zeros",
+                segment.display(),
+                copied,
+                allocation.size
+            );
+            self.write_zeros(allocation.size - copied, &mut data, progress)?;
+        }
+
+        Self::write_checksum(data)?;
+        Ok(allocation.size)
+    }
+
+    /// Copy up to `size` bytes of `file` from `offset` into `data` with
+    /// `copy_file_range`, so the kernel moves them file to file without a
+    /// buffer. Returns the number of bytes copied, short if `file` ends
+    /// early, or None to copy through a buffer instead: when the data is
+    /// compressed or encrypted, the checkpoint is not written straight to a
+    /// regular file, or the filesystems cannot copy between each other.
+    fn copy_file_range<W: Write + Seek + FileOutput>(
+        &self,
+        file: &File,
+        offset: u64,
+        size: u64,
+        data: &mut ChecksumWriter<DataWriter<'_, W>>,
+        progress: &Option<TransferProgress>,
+    ) -> Result<Option<u64>> {
+        let DataWriter::Raw(DataSink::Plain(output)) = data.get_mut() else {
+            return Ok(None);
+        };
+        let start = output.stream_position()?;
+        let Some(checkpoint) = output.as_file() else {
+            return Ok(None);
+        };
+
+        let mut copied = 0;
+        while copied < size {
+            let mut off_in = (offset + copied) as libc::loff_t;
+            let mut off_out = (start + copied) as libc::loff_t;
+            let len = usize::try_from(size - copied).unwrap_or(usize::MAX);
+            // SAFETY: both descriptors are open for the call, and the
+            // offsets are explicit so neither file position moves
+            let n = unsafe {
+                libc::copy_file_range(
+                    file.as_raw_fd(),
+                    &mut off_in,
+                    checkpoint.as_raw_fd(),
+                    &mut off_out,
+                    len,
+                    0,
+                )
+            };
+            if n < 0 {
+                let e = std::io::Error::last_os_error();
+                if copied == 0 {
+                    debug!("Cannot copy_file_range, copying through a buffer: {}", e);
+                    return Ok(None);
+                }
+                return Err(e.into());
+            }
+            if n == 0 {
+                break;
+            }
+            copied += n as u64;
+            if let Some(pb) = progress {
+                pb.inc(n as u64, n as u64);
+            }
+        }
+
+        // The data never passed through the checksum, so it is read back as
+        // it landed in the checkpoint
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buffer = vec![0u8; self.window_size.min(copied as usize)];
+        let mut checked = 0;
+        while checked < copied {
+            let len = (copied - checked).min(buffer.len() as u64) as usize;
+            checkpoint.read_exact_at(&mut buffer[..len], start + checked)?;
+            hasher.update(&buffer[..len]);
+            checked += len as u64;
+        }
+        output.seek(SeekFrom::Start(start + copied))?;
+        data.add_written(hasher.finalize(), copied);
+        Ok(Some(copied))
+    }
+
+    /// Read-only map of up to `size` bytes of `file` from `offset`, or None
+    /// if the file ends before `offset`
+    fn map_file_range(file: &File, offset: u64, size: u64) -> std::io::Result<Option<Mmap>> {
+        let len = file.metadata()?.len().saturating_sub(offset).min(size);
+        if len == 0 {
+            return Ok(None);
+        }
+        let len = usize::try_from(len).map_err(std::io::Error::other)?;
+        // SAFETY: the map is only read while it is alive. The process owning
+        // the segment is frozen for the copy; another process truncating it
+        // meanwhile would fault the read.
+        let map = unsafe { MmapOptions::new().offset(offset).len(len).map(file)? };
+        Ok(Some(map))
+    }
+
+    /// Write `data` to `output` a window at a time, returning its length
+    fn copy_mapped(
+        &self,
+        data: &[u8],
+        output: &mut impl Write,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        for window in data.chunks(self.window_size) {
+            output.write_all(window)?;
+            if let Some(pb) = progress {
+                pb.inc(window.len() as u64, window.len() as u64);
+            }
+        }
+        Ok(data.len() as u64)
+    }
+
+    /// Parts of `allocation` not covered by the `resident` address ranges
+    fn holes_between(allocation: &GpuAllocation, resident: &[Range<u64>]) -> Vec<Segment> {
+        let mut holes = Vec::new();
+        let mut cursor = allocation.vaddr_start;
+        for range in resident {
+            let start = range.start.clamp(cursor, allocation.vaddr_end);
+            if start > cursor {
+                holes.push(Segment {
+                    offset: cursor - allocation.vaddr_start,
+                    len: start - cursor,
+                });
+            }
+            cursor = cursor.max(range.end.min(allocation.vaddr_end));
+        }
+        if cursor < allocation.vaddr_end {
+            holes.push(Segment {
+                offset: cursor - allocation.vaddr_start,
+                len: allocation.vaddr_end - cursor,
+            });
+        }
+        holes
+    }
+
+    /// Copy an allocation, seeking over its `holes` so they take no space
+    /// on filesystems that support sparse files
+    #[allow(clippy::too_many_arguments)]
+    fn checkpoint_sparse_allocation<W: Write + Seek>(
+        &self,
+        pid: u32,
+        allocation: &GpuAllocation,
+        record: RecordId,
+        descriptor: AllocationDescriptor,
+        holes: Vec<Segment>,
+        output: &mut W,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        let descriptor = AllocationDescriptor {
+            holes: Some(holes),
+            ..descriptor
+        };
+
+        debug!(
+            "Allocation at 0x{:016x}: {} of {} bytes written as holes",
+            allocation.vaddr_start,
+            descriptor.hole_size(),
+            allocation.size
+        );
+
+        let context = self.write_allocation_record(output, record, allocation, &descriptor)?;
+        let mut data = self.data_writer(output, context);
+
+        let mem_path = format!("/proc/{pid}/mem");
+        let memory = self.open_memory(pid).ok();
+        for (segment, is_hole) in descriptor.layout(allocation.size) {
+            if is_hole {
+                data.skip_zeros(segment.len)?;
+                if let Some(pb) = progress {
+                    pb.inc(segment.len, 0);
+                }
+                continue;
+            }
+
+            let segment_start = data.bytes_written();
+            if let Some(memory) = &memory {
+                let addr = allocation.vaddr_start + segment.offset;
+                let mut input = memory.reader_at(addr);
+                if let Err(e) = self.copy_sliding(&mut input, segment.len, &mut data, progress) {
+                    warn!(
+                        "Cannot read 0x{:016x} ({} bytes) from {}: {}, writing zeros",
+                        addr, segment.len, mem_path, e
+                    );
+                }
+            }
+
+            let copied = data.bytes_written() - segment_start;
+            self.write_zeros(segment.len - copied, &mut data, progress)?;
+        }
+
+        // Writing the checksum extends the file past a trailing hole
+        Self::write_checksum(data)?;
+        Ok(allocation.size)
+    }
+
+    /// Copy only the given address `ranges` of an allocation, e.g. its
+    /// resident or mapped pages
+    #[allow(clippy::too_many_arguments)]
+    fn checkpoint_ranges<W: Write + Seek>(
+        &self,
+        pid: u32,
+        allocation: &GpuAllocation,
+        record: RecordId,
+        descriptor: AllocationDescriptor,
+        ranges: &[Range<u64>],
+        output: &mut W,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        let segments: Vec<Segment> = ranges
+            .iter()
+            .map(|range| Segment {
+                offset: range.start - allocation.vaddr_start,
+                len: range.end - range.start,
+            })
+            .collect();
+        let descriptor = AllocationDescriptor {
+            segments: Some(segments),
+            ..descriptor
+        };
+        let stored_size = descriptor.stored_size(allocation.size);
+
+        debug!(
+            "Allocation at 0x{:016x}: copying {} of {} bytes",
+            allocation.vaddr_start, stored_size, allocation.size
+        );
+
+        let context = self.write_allocation_record(output, record, allocation, &descriptor)?;
+        let mut data = self.data_writer(output, context);
+
+        let mem_path = format!("/proc/{pid}/mem");
+        let memory = self.open_memory(pid).ok();
+        for segment in descriptor.segments.iter().flatten() {
+            let segment_start = data.bytes_written();
+            if let Some(memory) = &memory {
+                let addr = allocation.vaddr_start + segment.offset;
+                let mut input = memory.reader_at(addr);
+                if let Err(e) = self.copy_sliding(&mut input, segment.len, &mut data, progress) {
+                    warn!(
+                        "Cannot read 0x{:016x} ({} bytes) from {}: {}, writing zeros",
+                        addr, segment.len, mem_path, e
+                    );
+                }
+            }
+
+            // Keep the segment length intact so the record stays parseable
+            let copied = data.bytes_written() - segment_start;
+            self.write_zeros(segment.len - copied, &mut data, progress)?;
+        }
+        Self::write_checksum(data)?;
+
+        // Holes are accounted for so the progress bar still reaches the total
+        if let Some(pb) = progress {
+            pb.inc(allocation.size - stored_size, 0);
+        }
+
+        Ok(stored_size)
+    }
+
+    /// Copy an allocation window by window, storing only windows whose
+    /// contents no earlier window of the checkpoint had. Windows are hashed
+    /// in a first pass so the descriptor can list them ahead of the data;
+    /// the windows to store are spooled as they are hashed and written from
+    /// the spool. Unreadable parts are stored as zeros.
+    #[allow(clippy::too_many_arguments)]
+    fn checkpoint_deduplicated<W: Write + Seek>(
+        &self,
+        pid: u32,
+        allocation: &GpuAllocation,
+        record: RecordId,
+        descriptor: AllocationDescriptor,
+        window_index: &WindowIndex,
+        output: &mut W,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        let memory = match self.open_memory(pid) {
+            Ok(memory) => Some(memory),
+            Err(e) => {
+                warn!("Cannot read memory of PID {}: {}, writing zeros", pid, e);
+                None
+            }
+        };
+        let mut buffer = vec![0u8; window_len(self.window_size, allocation.size)];
+
+        // The record's descriptor lists which windows are stored, so windows
+        // are hashed before any data is written. Unique windows are spooled
+        // as hashed: reading them again could store contents that changed
+        // since and no longer match their hash.
+        let mut spool = tempfile::tempfile()?;
+        let mut dedup = DedupWindows {
+            window_size: self.window_size as u64,
+            windows: Vec::new(),
+        };
+        let mut offset = 0;
+        while offset < allocation.size {
+            let window =
+                &mut buffer[..(allocation.size - offset).min(self.window_size as u64) as usize];
+            Self::read_window(memory.as_ref(), allocation.vaddr_start + offset, window);
+            let hash: [u8; 32] = Sha256::digest(&*window).into();
+            let duplicate_of = window_index.add(hash);
+            if duplicate_of.is_none() {
+                spool.write_all(window)?;
+            }
+            dedup.windows.push(DedupWindow {
+                hash: hash.iter().map(|byte| format!("{byte:02x}")).collect(),
+                duplicate_of,
+            });
+            offset += window.len() as u64;
+        }
+
+        let descriptor = AllocationDescriptor {
+            dedup: Some(dedup),
+            ..descriptor
+        };
+        let stored_size = descriptor.stored_size(allocation.size);
+        debug!(
+            "Allocation at 0x{:016x}: storing {} of {} bytes, the rest repeats earlier windows",
+            allocation.vaddr_start, stored_size, allocation.size
+        );
+
+        let context = self.write_allocation_record(output, record, allocation, &descriptor)?;
+        let mut data = self.data_writer(output, context);
+        spool.rewind()?;
+        for (segment, window) in descriptor
+            .dedup
+            .iter()
+            .flat_map(|d| d.layout(allocation.size))
+        {
+            let stored = window.duplicate_of.is_none();
+            if stored {
+                let window = &mut buffer[..segment.len as usize];
+                spool.read_exact(window)?;
+                data.write_all(window)?;
+            }
+            if let Some(pb) = progress {
+                pb.inc(segment.len, if stored { segment.len } else { 0 });
+            }
+        }
+        Self::write_checksum(data)?;
+
+        Ok(stored_size)
+    }
+
+    /// Fill `window` from `memory` at `addr`, zero-filling what cannot be
+    /// read
+    fn read_window(memory: Option<&ProcessMemory>, addr: u64, window: &mut [u8]) {
+        let mut filled = 0;
+        if let Some(memory) = memory {
+            let mut input = memory.reader_at(addr);
+            while filled < window.len() {
+                match input.read(&mut window[filled..]) {
+                    Ok(0) => break,
+                    Ok(len) => filled += len,
+                    Err(e) => {
+                        warn!("Cannot read 0x{:016x}: {}, writing zeros", addr, e);
+                        break;
+                    }
+                }
+            }
+        }
+        window[filled..].fill(0);
+    }
+
+    /// Copy only the `changed` windows of an allocation
+    #[allow(clippy::too_many_arguments)]
+    fn checkpoint_changed_windows<W: Write + Seek>(
+        &self,
+        pid: u32,
+        allocation: &GpuAllocation,
+        record: RecordId,
+        descriptor: AllocationDescriptor,
+        changed: &ChangedWindows,
+        output: &mut W,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        let descriptor = AllocationDescriptor {
+            changed_windows: Some(changed.clone()),
+            ..descriptor
+        };
+        let changed_size = descriptor.stored_size(allocation.size);
+
+        debug!(
+            "Allocation at 0x{:016x}: {} of {} bytes changed since the base checkpoint",
+            allocation.vaddr_start, changed_size, allocation.size
+        );
+
+        let context = self.write_allocation_record(output, record, allocation, &descriptor)?;
+        let mut data = self.data_writer(output, context);
+
+        let mem_path = format!("/proc/{pid}/mem");
+        let memory = self.open_memory(pid).ok();
+        for segment in changed.segments(allocation.size) {
+            let segment_start = data.bytes_written();
+            if let Some(memory) = &memory {
+                let addr = allocation.vaddr_start + segment.offset;
+                let mut input = memory.reader_at(addr);
+                if let Err(e) = self.copy_sliding(&mut input, segment.len, &mut data, progress) {
+                    warn!(
+                        "Cannot read 0x{:016x} ({} bytes) from {}: {}, writing zeros",
+                        addr, segment.len, mem_path, e
+                    );
+                }
+            }
+
+            let copied = data.bytes_written() - segment_start;
+            self.write_zeros(segment.len - copied, &mut data, progress)?;
+        }
+        Self::write_checksum(data)?;
+
+        // Unchanged windows are accounted for so the progress bar still
+        // reaches the total
+        if let Some(pb) = progress {
+            pb.inc(allocation.size - changed_size, 0);
+        }
+
+        Ok(changed_size)
+    }
+
+    /// Record an allocation whose data the record of `stored_by` holds,
+    /// with an empty data section
+    fn checkpoint_stored_elsewhere(
+        &self,
+        allocation: &GpuAllocation,
+        record: RecordId,
+        descriptor: AllocationDescriptor,
+        stored_by: AllocationRef,
+        output: &mut impl Write,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        debug!(
+            "Allocation at 0x{:016x} is stored with 0x{:016x} of PID {}",
+            allocation.vaddr_start, stored_by.vaddr_start, stored_by.pid
+        );
+        let descriptor = AllocationDescriptor {
+            stored_by: Some(stored_by),
+            segments: Some(Vec::new()),
+            ..descriptor
+        };
+        let context = self.write_allocation_record(output, record, allocation, &descriptor)?;
+        Self::write_checksum(self.data_writer(output, context))?;
+
+        if let Some(pb) = progress {
+            pb.inc(allocation.size, 0);
+        }
+        Ok(0)
+    }
+
+    /// Memory of `pid` for reading
+    fn open_memory(&self, pid: u32) -> std::io::Result<ProcessMemory> {
+        ProcessMemory::open(pid, false, self.process_vm)
+    }
+
+    /// Copy `allocation` from the memory of `pid`. Huge page backed
+    /// allocations are copied in windows of whole huge pages that end on
+    /// huge page boundaries, so no read splits a huge page.
+    fn copy_memory_sliding(
+        &self,
+        pid: u32,
+        allocation: &GpuAllocation,
+        output: &mut impl Write,
+        progress: &Option<TransferProgress>,
+    ) -> Result<()> {
+        let memory = self.open_memory(pid).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                GpuCheckpointError::PermissionDenied
+            } else {
+                GpuCheckpointError::IoError(e)
+            }
+        })?;
+
+        let mut input = memory.reader_at(allocation.vaddr_start);
+        if allocation.metadata.huge_page {
+            let window_size = huge_page_window(self.window_size);
+            let first_window = window_size - (allocation.vaddr_start % window_size as u64) as usize;
+            debug!(
+                "Copying huge page backed allocation at 0x{:016x} in {} byte windows",
+                allocation.vaddr_start, window_size
+            );
+            self.copy_windows(
+                &mut input,
+                allocation.size,
+                window_size,
+                first_window,
+                output,
+                progress,
+            )?;
+        } else {
+            self.copy_sliding(&mut input, allocation.size, output, progress)?;
+        }
+
+        Ok(())
+    }
 
-        mem_file.seek(SeekFrom::Start(start_addr))?;
+    /// Copy up to `size` bytes from `input` in window-sized chunks, returning
+    /// the number of bytes copied (less than `size` if `input` hit EOF)
+    fn copy_sliding(
+        &self,
+        input: &mut impl Read,
+        size: u64,
+        output: &mut impl Write,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        self.copy_windows(
+            input,
+            size,
+            self.window_size,
+            self.window_size,
+            output,
+            progress,
+        )
+    }
 
+    /// `copy_sliding` in windows of `window_size` bytes, the first of which
+    /// is `first_window` bytes
+    fn copy_windows(
+        &self,
+        input: &mut impl Read,
+        size: u64,
+        window_size: usize,
+        first_window: usize,
+        output: &mut impl Write,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
         let mut remaining = size;
-        let mut buffer = vec![0u8; self.window_size.min(size as usize)];
+        let mut buffer = vec![0u8; window_len(window_size, size)];
+        let mut window = first_window;
 
         while remaining > 0 {
-            let to_read = remaining.min(self.window_size as u64) as usize;
-            let bytes_read = mem_file.read(&mut buffer[..to_read])?;
+            let to_read = remaining.min(window as u64) as usize;
+            let bytes_read = input.read(&mut buffer[..to_read])?;
+            // After a short read, finish the window before starting the next
+            window = if bytes_read < to_read {
+                to_read - bytes_read
+            } else {
+                window_size
+            };
 
             if bytes_read == 0 {
                 break;
@@ -232,18 +2393,18 @@ impl BarSlidingCheckpoint {
             remaining -= bytes_read as u64;
 
             if let Some(pb) = progress {
-                pb.inc(bytes_read as u64);
+                pb.inc(bytes_read as u64, bytes_read as u64);
             }
         }
 
-        Ok(())
+        Ok(size - remaining)
     }
 
     fn write_zeros(
         &self,
         size: u64,
-        output: &mut File,
-        progress: &Option<ProgressBar>,
+        output: &mut impl Write,
+        progress: &Option<TransferProgress>,
     ) -> Result<()> {
         let zeros = vec![0u8; self.window_size];
         let mut remaining = size;
@@ -255,34 +2416,273 @@ impl BarSlidingCheckpoint {
             remaining -= to_write as u64;
 
             if let Some(pb) = progress {
-                pb.inc(to_write as u64);
+                pb.inc(to_write as u64, to_write as u64);
             }
         }
 
         Ok(())
     }
 
-    fn write_header(&self, file: &mut File, header: &CheckpointHeader) -> Result<()> {
-        // Write as binary for efficiency
-        file.write_all(&header.magic.to_le_bytes())?;
-        file.write_all(&header.version.to_le_bytes())?;
-        file.write_all(&header.pid.to_le_bytes())?;
-        file.write_all(&header.num_allocations.to_le_bytes())?;
-        file.write_all(&header.total_size.to_le_bytes())?;
-        file.write_all(&header.timestamp.to_le_bytes())?;
+    /// Writer for the data of one allocation, compressing and encrypting
+    /// it if enabled
+    fn data_writer<'a, W: Write>(
+        &self,
+        output: &'a mut W,
+        record: RecordContext,
+    ) -> ChecksumWriter<DataWriter<'a, W>> {
+        let sink = match &self.encryption_key {
+            Some(key) => DataSink::Encrypted(Box::new(EncryptWriter::new(
+                output,
+                key,
+                record,
+                self.window_size,
+            ))),
+            None => DataSink::Plain(output),
+        };
+        ChecksumWriter::new(match self.compression {
+            Compression::None => DataWriter::Raw(sink),
+            Compression::Deflate { level } => DataWriter::Compressed(
+                CompressWriter::new(sink, level, self.window_size)
+                    .with_threads(self.compression_threads)
+                    .with_stats(self.compression_stats.clone()),
+            ),
+        })
+    }
+
+    /// End an allocation's data with the CRC32 of everything written
+    /// through `data`
+    fn write_checksum<W: Write>(data: ChecksumWriter<DataWriter<'_, W>>) -> Result<()> {
+        let crc = data.finalize();
+        let mut sink = match data.into_inner() {
+            DataWriter::Raw(sink) => sink,
+            DataWriter::Compressed(writer) => writer.finish()?,
+        };
+        sink.write_all(&crc.to_le_bytes())?;
+        if let DataSink::Encrypted(writer) = sink {
+            writer.finish()?;
+        }
         Ok(())
     }
 
-    fn write_allocation_header(&self, file: &mut File, header: &AllocationHeader) -> Result<()> {
-        file.write_all(&header.vaddr_start.to_le_bytes())?;
-        file.write_all(&header.vaddr_end.to_le_bytes())?;
-        file.write_all(&header.size.to_le_bytes())?;
-        file.write_all(&header.device_id.to_le_bytes())?;
-        file.write_all(&header.flags.to_le_bytes())?;
+    /// Write the header of an allocation record, followed by `descriptor`
+    /// unless it carries no information. Returns the context the record's
+    /// data is encrypted in.
+    fn write_allocation_record(
+        &self,
+        file: &mut impl Write,
+        record: RecordId,
+        allocation: &GpuAllocation,
+        descriptor: &AllocationDescriptor,
+    ) -> Result<RecordContext> {
+        let has_descriptor = *descriptor != AllocationDescriptor::default();
+        let alloc_header = AllocationHeader {
+            vaddr_start: allocation.vaddr_start,
+            vaddr_end: allocation.vaddr_end,
+            size: allocation.size,
+            device_id: allocation.device_id.unwrap_or(0),
+            flags: if has_descriptor {
+                ALLOC_FLAG_DESCRIPTOR
+            } else {
+                0
+            },
+        };
+
+        alloc_header.write_to(file)?;
+        if has_descriptor {
+            self.write_allocation_descriptor(file, descriptor)?;
+        }
+        Ok(RecordContext::new(
+            record.checkpoint_id,
+            record.index,
+            &alloc_header,
+        ))
+    }
+
+    fn write_allocation_descriptor(
+        &self,
+        file: &mut impl Write,
+        descriptor: &AllocationDescriptor,
+    ) -> Result<()> {
+        let encoded = serde_json::to_vec(descriptor)
+            .map_err(|e| GpuCheckpointError::CheckpointError(e.to_string()))?;
+        file.write_all(&(encoded.len() as u32).to_le_bytes())?;
+        file.write_all(&encoded)?;
         Ok(())
     }
 }
 
+/// `window_size` rounded up to whole huge pages
+fn huge_page_window(window_size: usize) -> usize {
+    let huge_page = HUGE_PAGE_SIZE as usize;
+    window_size.max(1).div_ceil(huge_page) * huge_page
+}
+
+/// Position of a record in the checkpoint being written
+#[derive(Debug, Clone, Copy)]
+struct RecordId {
+    checkpoint_id: u64,
+    index: u32,
+}
+
+/// Identity of a new checkpoint of `pid`, never 0
+fn new_checkpoint_id(pid: u32) -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    (nanos ^ (u64::from(pid) << 32)).max(1)
+}
+
+/// Where `checkpoint_into` writes a checkpoint
+struct CheckpointTarget<'a> {
+    /// Checkpoint file, empty for a stream
+    path: &'a Path,
+
+    /// Directory records are staged in when checkpointing in parallel
+    segment_dir: &'a Path,
+
+    /// Whether the header can be rewritten once the data is written
+    seekable: bool,
+}
+
+/// Forward-only output. Seeking ahead writes zeros, so sparse records stay
+/// well-formed; seeking back fails.
+struct StreamOutput<W: Write> {
+    inner: W,
+    position: u64,
+}
+
+impl<W: Write> StreamOutput<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, position: 0 }
+    }
+}
+
+impl<W: Write> Write for StreamOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Seek for StreamOutput<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+        match target {
+            Some(target) if target >= self.position => {
+                std::io::copy(&mut std::io::repeat(0).take(target - self.position), self)?;
+                Ok(self.position)
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "cannot seek back in a checkpoint stream",
+            )),
+        }
+    }
+}
+
+/// Output the checkpoint is written to, which allocation data can be
+/// copied into by the kernel if it is a regular file
+trait FileOutput {
+    /// The file behind the output, if bytes written reach it unchanged
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl FileOutput for File {
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+impl<T: FileOutput + ?Sized> FileOutput for &mut T {
+    fn as_file(&self) -> Option<&File> {
+        (**self).as_file()
+    }
+}
+
+impl<W: Write> FileOutput for StreamOutput<W> {}
+
+/// A bandwidth limit paces writes, which a copy in the kernel would bypass
+impl<W: Write + FileOutput> FileOutput for ThrottledWriter<W> {
+    fn as_file(&self) -> Option<&File> {
+        if self.is_throttled() {
+            None
+        } else {
+            self.get_ref().as_file()
+        }
+    }
+}
+
+/// Destination of one allocation's data
+enum DataWriter<'a, W: Write> {
+    Raw(DataSink<'a, W>),
+    Compressed(CompressWriter<DataSink<'a, W>>),
+}
+
+impl<W: Write> Write for DataWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            DataWriter::Raw(sink) => sink.write(buf),
+            DataWriter::Compressed(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            DataWriter::Raw(sink) => sink.flush(),
+            DataWriter::Compressed(writer) => writer.flush(),
+        }
+    }
+}
+
+impl<W: Write + Seek> Seek for DataWriter<'_, W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            DataWriter::Raw(DataSink::Plain(output)) => output.seek(pos),
+            DataWriter::Raw(DataSink::Encrypted(_)) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "encrypted allocation data cannot be sparse",
+            )),
+            DataWriter::Compressed(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "compressed allocation data cannot be sparse",
+            )),
+        }
+    }
+}
+
+/// Output of one allocation's data once it is compressed
+enum DataSink<'a, W: Write> {
+    Plain(&'a mut W),
+    Encrypted(Box<EncryptWriter<&'a mut W>>),
+}
+
+impl<W: Write> Write for DataSink<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            DataSink::Plain(output) => output.write(buf),
+            DataSink::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            DataSink::Plain(output) => output.flush(),
+            DataSink::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CheckpointMetadata {
     pub pid: u32,
@@ -290,6 +2690,44 @@ pub struct CheckpointMetadata {
     pub size_bytes: u64,
     pub duration_ms: u64,
     pub num_allocations: usize,
+
+    /// The GPU memory layout changed while the checkpoint was copied
+    pub layout_changed: bool,
+
+    /// Time taken by each allocation, in checkpoint order
+    pub allocation_timings: Vec<AllocationTiming>,
+
+    /// Bytes compressed and compression throughput, apart from the I/O
+    /// throughput of `size_bytes` over `duration_ms`, if compression is on
+    pub compression: Option<CompressionThroughput>,
+}
+
+/// How long checkpointing one allocation took
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AllocationTiming {
+    pub vaddr_start: u64,
+
+    /// Data bytes written for the allocation
+    pub size: u64,
+
+    pub duration_ms: u64,
+    pub mb_per_sec: f64,
+}
+
+impl AllocationTiming {
+    pub fn new(vaddr_start: u64, size: u64, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64();
+        Self {
+            vaddr_start,
+            size,
+            duration_ms: elapsed.as_millis() as u64,
+            mb_per_sec: if secs > 0.0 {
+                (size as f64 / (1024.0 * 1024.0)) / secs
+            } else {
+                0.0
+            },
+        }
+    }
 }
 
 #[cfg(test)]
@@ -297,6 +2735,18 @@ mod tests {
     use super::*;
     use tempfile::tempdir;
 
+    #[test]
+    fn test_stream_output_only_seeks_forward() {
+        let mut output = StreamOutput::new(Vec::new());
+        output.write_all(&[1; 8]).unwrap();
+        assert_eq!(output.stream_position().unwrap(), 8);
+        // Seeking ahead writes the skipped bytes as zeros
+        assert_eq!(output.seek(SeekFrom::Current(8)).unwrap(), 16);
+        assert!(output.seek(SeekFrom::Start(0)).is_err());
+        assert!(output.seek(SeekFrom::End(0)).is_err());
+        assert_eq!(output.inner, [[1u8; 8], [0u8; 8]].concat());
+    }
+
     #[test]
     fn test_checkpoint_header_serialization() {
         let header = CheckpointHeader {
@@ -306,18 +2756,272 @@ mod tests {
             num_allocations: 2,
             total_size: 1024 * 1024,
             timestamp: 1234567890,
+            compression: Compression::None,
+            checkpoint_id: 1,
+            base_checkpoint_id: 0,
+            encrypted: false,
+            deduplicated: false,
+            reserved: [0; HEADER_RESERVED_SIZE],
         };
 
         let dir = tempdir().unwrap();
         let path = dir.path().join("test.ckpt");
         let mut file = File::create(&path).unwrap();
 
-        let checkpoint = BarSlidingCheckpoint::new();
-        checkpoint.write_header(&mut file, &header).unwrap();
+        header.write_to(&mut file).unwrap();
 
         // Verify file size
         let metadata = file.metadata().unwrap();
-        assert_eq!(metadata.len(), 32); // 6 fields * 4-8 bytes each
+        assert_eq!(metadata.len(), CHECKPOINT_HEADER_SIZE);
+        assert_eq!(header.size(), CHECKPOINT_HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = CheckpointHeader {
+            magic: CHECKPOINT_MAGIC,
+            version: CHECKPOINT_VERSION,
+            pid: 1234,
+            num_allocations: 2,
+            total_size: 1 << 40,
+            timestamp: 1234567890,
+            compression: Compression::Deflate { level: 6 },
+            checkpoint_id: 0x0123_4567_89ab_cdef,
+            base_checkpoint_id: 42,
+            encrypted: true,
+            deduplicated: true,
+            reserved: [1, 2, 3, 4, 5],
+        };
+
+        // Every version reads back what it wrote, in as many bytes as its
+        // layout has
+        for version in MIN_CHECKPOINT_VERSION..=CHECKPOINT_VERSION {
+            let header = CheckpointHeader {
+                version,
+                ..header.clone()
+            };
+            let mut bytes = Vec::new();
+            header.write_to(&mut bytes).unwrap();
+            assert_eq!(bytes.len() as u64, header.size());
+
+            let parsed = CheckpointHeader::read_from(&mut bytes.as_slice()).unwrap();
+            assert_eq!(parsed.version, version);
+            assert_eq!(
+                (
+                    parsed.pid,
+                    parsed.num_allocations,
+                    parsed.total_size,
+                    parsed.timestamp
+                ),
+                (1234, 2, 1 << 40, 1234567890)
+            );
+            assert_eq!(parsed.compression == header.compression, version >= 3);
+            assert_eq!(parsed.reserved == header.reserved, version >= 3);
+            assert_eq!(parsed.checkpoint_id == header.checkpoint_id, version >= 4);
+            assert_eq!(parsed.encrypted && parsed.deduplicated, version >= 5);
+        }
+
+        // Little-endian regardless of the host
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        assert_eq!(&bytes[..4], b"CUPG");
+        assert_eq!(&bytes[32..40], &[1, 6, 3, 1, 2, 3, 4, 5]);
+
+        let alloc_header = AllocationHeader {
+            vaddr_start: 0x7f00_0000_0000,
+            vaddr_end: 0x7f00_0010_0000,
+            size: 0x10_0000,
+            device_id: 3,
+            flags: ALLOC_FLAG_DESCRIPTOR,
+        };
+        let mut bytes = Vec::new();
+        alloc_header.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len() as u64, ALLOCATION_HEADER_SIZE);
+        assert_eq!(bytes[24..28], 3u32.to_le_bytes());
+        assert_eq!(
+            AllocationHeader::read_from(&mut bytes.as_slice()).unwrap(),
+            alloc_header
+        );
+        assert!(AllocationHeader::read_from(&mut &bytes[..16]).is_err());
+    }
+
+    #[test]
+    fn test_kernel_and_mapped_copies_match_buffered() {
+        let dir = tempdir().unwrap();
+        let shm_dir = dir.path().join("shm");
+        std::fs::create_dir_all(&shm_dir).unwrap();
+        let contents: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let segment = shm_dir.join("nccl-shm-0");
+        std::fs::write(&segment, &contents).unwrap();
+
+        // An unaligned offset, and a segment that ends before the allocation
+        let mut ipc = GpuAllocation::new(0x7f0000000000, 0x7f0000008000, AllocationType::Ipc);
+        ipc.metadata.backing_file = Some(segment.to_string_lossy().to_string());
+        ipc.metadata.file_offset = 100;
+        let mut detection = DetectionResult::new(1234, crate::detector::GpuVendor::Nvidia);
+        detection.add_allocation(ipc);
+
+        let checkpoint = |mapped_reads: bool| {
+            BarSlidingCheckpoint::new()
+                .with_progress(false)
+                .with_window_size(4096)
+                .with_shm_dir(&shm_dir)
+                .with_mapped_reads(mapped_reads)
+        };
+        // The header differs in its timestamp and checkpoint ID
+        let to_file = |checkpoint: BarSlidingCheckpoint| {
+            let path = dir.path().join("checkpoint.ckpt");
+            let metadata = checkpoint
+                .checkpoint_process(1234, &detection, &path)
+                .unwrap();
+            assert_eq!(metadata.size_bytes, 0x8000);
+            std::fs::read(&path).unwrap()[CHECKPOINT_HEADER_SIZE as usize..].to_vec()
+        };
+        // A stream is not a regular file to copy into in the kernel
+        let to_stream = |checkpoint: BarSlidingCheckpoint| {
+            let mut output = Vec::new();
+            checkpoint
+                .checkpoint_to_writer(1234, &detection, &mut output)
+                .unwrap();
+            output[CHECKPOINT_HEADER_SIZE as usize..].to_vec()
+        };
+        let copied_in_kernel = to_file(checkpoint(false));
+        assert_eq!(copied_in_kernel, to_stream(checkpoint(false)));
+        assert_eq!(copied_in_kernel, to_stream(checkpoint(true)));
+        // Mapped reads give way to the kernel copy
+        assert_eq!(copied_in_kernel, to_file(checkpoint(true)));
+
+        let mut input = &copied_in_kernel[ALLOCATION_HEADER_SIZE as usize..];
+        let descriptor_len = u32::from_le_bytes(input[..4].try_into().unwrap()) as usize;
+        input = &input[4 + descriptor_len..];
+        assert_eq!(&input[..contents.len() - 100], &contents[100..]);
+        assert!(input[contents.len() - 100..0x8000].iter().all(|&b| b == 0));
+
+        // The copy into a file happens in the kernel, checksummed as written
+        let segment = File::open(&segment).unwrap();
+        let mut output = tempfile::tempfile().unwrap();
+        output.write_all(&[1; 10]).unwrap();
+        let mut data = ChecksumWriter::new(DataWriter::Raw(DataSink::Plain(&mut output)));
+        let copied = checkpoint(false)
+            .copy_file_range(&segment, 100, 0x8000, &mut data, &None)
+            .unwrap();
+        assert_eq!(copied, Some(contents.len() as u64 - 100));
+        assert_eq!(
+            data.finalize(),
+            crate::utils::checksum::crc32(&contents[100..])
+        );
+        assert_eq!(
+            output.stream_position().unwrap(),
+            contents.len() as u64 - 90
+        );
+
+        let mut stream = StreamOutput::new(Vec::new());
+        let mut data = ChecksumWriter::new(DataWriter::Raw(DataSink::Plain(&mut stream)));
+        let copied = checkpoint(false)
+            .copy_file_range(&segment, 100, 0x8000, &mut data, &None)
+            .unwrap();
+        assert_eq!(copied, None);
+    }
+
+    #[test]
+    fn test_dedup_stores_windows_as_hashed() {
+        use std::sync::atomic::AtomicU64;
+
+        /// Output that rewrites the checkpointed memory on every write, as
+        /// a target running on through the checkpoint would
+        struct Rewrites<'a> {
+            memory: &'a [AtomicU64],
+            output: Vec<u8>,
+        }
+        impl Write for Rewrites<'_> {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                for word in self.memory {
+                    word.fetch_add(1 << 8, Ordering::Relaxed);
+                }
+                self.output.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // Every window differs from the others, so all are stored
+        const WINDOW: usize = 4096;
+        let memory: Vec<AtomicU64> = (0..16 * WINDOW / 8)
+            .map(|i| AtomicU64::new((i / (WINDOW / 8)) as u64))
+            .collect();
+        let start = memory.as_ptr() as u64;
+        let mut detection =
+            DetectionResult::new(std::process::id(), crate::detector::GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + (memory.len() * 8) as u64,
+            AllocationType::Standard,
+        ));
+
+        let mut rewrites = Rewrites {
+            memory: &memory,
+            output: Vec::new(),
+        };
+        BarSlidingCheckpoint::new()
+            .with_progress(false)
+            .with_freeze(false)
+            .with_window_size(WINDOW)
+            .with_dedup(true)
+            .checkpoint_to_writer(std::process::id(), &detection, &mut rewrites)
+            .unwrap();
+        let output = rewrites.output;
+
+        let mut input = &output[(CHECKPOINT_HEADER_SIZE + ALLOCATION_HEADER_SIZE) as usize..];
+        let descriptor_len = u32::from_le_bytes(input[..4].try_into().unwrap()) as usize;
+        let descriptor: AllocationDescriptor =
+            serde_json::from_slice(&input[4..4 + descriptor_len]).unwrap();
+        input = &input[4 + descriptor_len..];
+        let dedup = descriptor.dedup.unwrap();
+        assert_eq!(dedup.windows.len(), 16);
+        for window in &dedup.windows {
+            let stored: [u8; 32] = Sha256::digest(&input[..WINDOW]).into();
+            let stored: String = stored.iter().map(|byte| format!("{byte:02x}")).collect();
+            assert_eq!(stored, window.hash);
+            input = &input[WINDOW..];
+        }
+    }
+
+    #[test]
+    fn test_huge_page_windows() {
+        /// Records the length of every read
+        struct Reads<'a>(&'a mut Vec<usize>);
+        impl Read for Reads<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.0.push(buf.len());
+                buf.fill(7);
+                Ok(buf.len())
+            }
+        }
+
+        assert_eq!(huge_page_window(1), HUGE_PAGE_SIZE as usize);
+        assert_eq!(huge_page_window(3 << 20), 4 << 20);
+        assert_eq!(huge_page_window(8 << 20), 8 << 20);
+
+        // 1 MiB into a 4 MiB aligned block: the first window ends on the
+        // next boundary
+        let checkpoint = BarSlidingCheckpoint::new().with_progress(false);
+        let mut reads = Vec::new();
+        let mut output = Vec::new();
+        let copied = checkpoint
+            .copy_windows(
+                &mut Reads(&mut reads),
+                10 << 20,
+                4 << 20,
+                3 << 20,
+                &mut output,
+                &None,
+            )
+            .unwrap();
+        assert_eq!(copied, 10 << 20);
+        assert_eq!(reads, [3 << 20, 4 << 20, 3 << 20]);
+        assert_eq!(output.len(), 10 << 20);
     }
 
     #[test]
@@ -334,4 +3038,301 @@ mod tests {
         let metadata = file.metadata().unwrap();
         assert_eq!(metadata.len(), 1024 * 1024);
     }
+
+    #[test]
+    fn test_prefetch_to_host() {
+        let buffer = vec![1u8; 5 * 4096];
+        let start = buffer.as_ptr() as u64;
+        let allocation = GpuAllocation::new(start, start + 3 * 4096, AllocationType::Managed);
+
+        let page_size = crate::utils::page_size();
+        let pages =
+            BarSlidingCheckpoint::prefetch_to_host(std::process::id(), &allocation).unwrap();
+        assert_eq!(pages, allocation.page_count(page_size));
+
+        // Unmapped memory cannot be prefetched
+        let unmapped = GpuAllocation::new(0, 4096, AllocationType::Managed);
+        assert!(BarSlidingCheckpoint::prefetch_to_host(std::process::id(), &unmapped).is_err());
+    }
+
+    #[test]
+    fn test_gpu_layout_tracks_mapping_changes() {
+        let page_size = crate::utils::page_size() as usize;
+        let buffer = memmap2::MmapMut::map_anon(4 * page_size).unwrap();
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, crate::detector::GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + buffer.len() as u64,
+            AllocationType::Standard,
+        ));
+
+        let before = BarSlidingCheckpoint::gpu_layout(pid, &detection).unwrap();
+        assert!(before.iter().any(|region| region.start <= start));
+        assert_eq!(
+            BarSlidingCheckpoint::gpu_layout(pid, &detection).unwrap(),
+            before
+        );
+
+        // Protecting part of the allocation differently splits its mapping
+        // SAFETY: the last page belongs to `buffer` and is not written again
+        let ret = unsafe {
+            libc::mprotect(
+                buffer.as_ptr().add(3 * page_size) as *mut libc::c_void,
+                page_size,
+                libc::PROT_READ,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_ne!(
+            BarSlidingCheckpoint::gpu_layout(pid, &detection).unwrap(),
+            before
+        );
+
+        // Processes whose maps cannot be read are not watched
+        assert!(BarSlidingCheckpoint::gpu_layout(u32::MAX, &detection).is_none());
+    }
+
+    #[test]
+    fn test_dump_allocation() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("dump.bin");
+
+        let buffer: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let start = buffer.as_ptr() as u64;
+        let allocation =
+            GpuAllocation::new(start, start + buffer.len() as u64, AllocationType::Standard);
+
+        let written = BarSlidingCheckpoint::new()
+            .dump_allocation(std::process::id(), &allocation, &path)
+            .unwrap();
+
+        assert_eq!(written, buffer.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), buffer);
+    }
+
+    #[test]
+    fn test_header_records_captured_size() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("captured.ckpt");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(4 * page_size).unwrap();
+        // Only one page is resident, so only one is captured
+        buffer[page_size..2 * page_size].fill(0x11);
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, crate::detector::GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + buffer.len() as u64,
+            AllocationType::Managed,
+        ));
+
+        let metadata = BarSlidingCheckpoint::new()
+            .with_limit_rss(true)
+            .checkpoint_process(pid, &detection, &path)
+            .unwrap();
+        assert_eq!(metadata.size_bytes, page_size as u64);
+
+        let header = CheckpointHeader::read_from(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!(detection.total_gpu_memory, 4 * page_size as u64);
+        assert_eq!(header.total_size, page_size as u64);
+    }
+
+    #[test]
+    fn test_header_records_short_read_size() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("short.ckpt");
+
+        // The mapping ends two pages before the allocation detection reported
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(4 * page_size).unwrap();
+        buffer.fill(0x22);
+        let start = buffer.as_ptr() as u64;
+        // SAFETY: the pages unmapped are not accessed again; dropping the
+        // map unmaps the whole range, which tolerates the gap
+        assert_eq!(
+            unsafe { libc::munmap(buffer.as_mut_ptr().add(2 * page_size).cast(), 2 * page_size,) },
+            0
+        );
+
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, crate::detector::GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + 4 * page_size as u64,
+            AllocationType::Standard,
+        ));
+
+        let metadata = BarSlidingCheckpoint::new()
+            .with_progress(false)
+            .checkpoint_process(pid, &detection, &path)
+            .unwrap();
+        assert_eq!(metadata.size_bytes, 2 * page_size as u64);
+
+        let header = CheckpointHeader::read_from(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!(detection.total_gpu_memory, 4 * page_size as u64);
+        assert_eq!(header.total_size, 2 * page_size as u64);
+    }
+
+    #[test]
+    fn test_allocation_timings() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("timed.ckpt");
+
+        let buffers: Vec<Vec<u8>> = (1..=3u8).map(|i| vec![i; (i as usize) << 22]).collect();
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, crate::detector::GpuVendor::Nvidia);
+        for buffer in &buffers {
+            let start = buffer.as_ptr() as u64;
+            detection.add_allocation(GpuAllocation::new(
+                start,
+                start + buffer.len() as u64,
+                AllocationType::Standard,
+            ));
+        }
+
+        let metadata = BarSlidingCheckpoint::new()
+            .checkpoint_process(pid, &detection, &path)
+            .unwrap();
+        let timings = &metadata.allocation_timings;
+        assert_eq!(timings.len(), 3);
+        for (timing, buffer) in timings.iter().zip(&buffers) {
+            assert_eq!(timing.vaddr_start, buffer.as_ptr() as u64);
+            assert_eq!(timing.size, buffer.len() as u64);
+        }
+        assert_eq!(
+            timings.iter().map(|t| t.size).sum::<u64>(),
+            metadata.size_bytes
+        );
+
+        // Records are written one after another, so their durations add up
+        // to the checkpoint's, less the header and rounding to milliseconds
+        let summed: u64 = timings.iter().map(|t| t.duration_ms).sum();
+        assert!(summed <= metadata.duration_ms);
+        assert!(
+            metadata.duration_ms - summed <= 5 + metadata.duration_ms / 4,
+            "allocations took {summed}ms of {}ms",
+            metadata.duration_ms
+        );
+    }
+
+    /// Output recording whether the target was stopped at every write
+    struct StateProbe {
+        pid: u32,
+        stopped: Vec<bool>,
+    }
+
+    impl Write for StateProbe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let state = ProcessScanner::process_state(self.pid).unwrap();
+            self.stopped.push(state.is_stopped());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_target_stopped_during_checkpoint() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        let region = MemoryMapParser::parse_maps(pid)
+            .unwrap()
+            .into_iter()
+            .find(|r| r.perms == "rw-p" && r.pathname.is_none())
+            .expect("sleep has an anonymous writable mapping");
+        let mut detection = DetectionResult::new(pid, crate::detector::GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            region.start,
+            region.end,
+            AllocationType::Standard,
+        ));
+
+        let checkpoint = |checkpoint: BarSlidingCheckpoint| {
+            let mut probe = StateProbe {
+                pid,
+                stopped: Vec::new(),
+            };
+            checkpoint
+                .checkpoint_to_writer(pid, &detection, &mut probe)
+                .unwrap();
+            let stopped_after = ProcessScanner::process_state(pid).unwrap().is_stopped();
+            (probe.stopped, stopped_after)
+        };
+
+        let (stopped, stopped_after) = checkpoint(BarSlidingCheckpoint::new().with_freeze(true));
+        let (running, running_after) = checkpoint(BarSlidingCheckpoint::new().with_freeze(false));
+        // With a snapshot the target is only frozen for the fork
+        let (snapshot, snapshot_after) = checkpoint(
+            BarSlidingCheckpoint::new()
+                .with_freeze(true)
+                .with_cow_snapshot(true),
+        );
+        // A target stopped beforehand stays stopped
+        nix::sys::signal::kill(
+            nix::unistd::Pid::from_raw(pid as i32),
+            nix::sys::signal::SIGSTOP,
+        )
+        .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let (_, left_stopped) = checkpoint(BarSlidingCheckpoint::new().with_freeze(true));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert!(!stopped.is_empty() && stopped.iter().all(|&s| s));
+        assert!(!stopped_after);
+        assert!(!running.is_empty() && running.iter().all(|&s| !s));
+        assert!(!running_after);
+        assert!(!snapshot.is_empty() && snapshot.iter().all(|&s| !s));
+        assert!(!snapshot_after);
+        assert!(left_stopped);
+    }
+
+    #[test]
+    fn test_changed_windows() {
+        let allocation =
+            GpuAllocation::new(0x10000, 0x10000 + 10 * 0x1000, AllocationType::Managed);
+        let dirty = [
+            0x8000..0x11000,  // starts before the allocation
+            0x13800..0x14800, // straddles windows 1 and 2
+            0x19000..0x20000, // runs past the end
+        ];
+
+        let changed = ChangedWindows::from_dirty(&allocation, &dirty, 0x2000);
+        assert_eq!(changed.changed, vec![true, true, true, false, true]);
+
+        // Adjacent windows merge, the last one is cut at the allocation end
+        let size = allocation.size - 0x800;
+        assert_eq!(
+            changed.segments(size),
+            vec![
+                Segment {
+                    offset: 0,
+                    len: 0x6000
+                },
+                Segment {
+                    offset: 0x8000,
+                    len: 0x1800
+                },
+            ]
+        );
+
+        let descriptor = AllocationDescriptor {
+            changed_windows: Some(changed),
+            ..Default::default()
+        };
+        assert_eq!(descriptor.stored_size(size), 0x7800);
+    }
 }
@@ -0,0 +1,293 @@
+//! CUDA checkpoint strategy
+//!
+//! The driver can suspend a process's CUDA work and move its device memory
+//! into host memory, and reverse that later. `nvidia-cuda-checkpoint`
+//! toggles a process between the two; once toggled the process holds no
+//! device state and its memory can be captured by CPU checkpoint tools.
+
+use crate::{GpuCheckpointError, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use tracing::{debug, info};
+
+/// Name of the toggle tool looked up on `PATH`
+pub const CUDA_CHECKPOINT_TOOL: &str = "nvidia-cuda-checkpoint";
+
+/// CUDA state of a process as reported by `--get-state`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CudaProcessState {
+    /// CUDA calls run normally
+    Running,
+
+    /// CUDA calls are blocked, device memory is still on the GPU
+    Locked,
+
+    /// Device memory was moved to the host and the GPU released
+    Checkpointed,
+
+    /// A previous checkpoint or restore of the process failed
+    Failed,
+}
+
+impl FromStr for CudaProcessState {
+    type Err = GpuCheckpointError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "running" => Ok(CudaProcessState::Running),
+            "locked" => Ok(CudaProcessState::Locked),
+            "checkpointed" => Ok(CudaProcessState::Checkpointed),
+            "failed" => Ok(CudaProcessState::Failed),
+            other => Err(GpuCheckpointError::CheckpointError(format!(
+                "unknown CUDA process state {other:?}"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for CudaProcessState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CudaProcessState::Running => write!(f, "running"),
+            CudaProcessState::Locked => write!(f, "locked"),
+            CudaProcessState::Checkpointed => write!(f, "checkpointed"),
+            CudaProcessState::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// What a CUDA checkpoint toggled, so restore can toggle it back
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CudaToggle {
+    /// Tool that performed the toggle
+    pub tool: PathBuf,
+
+    /// State of the process before the checkpoint
+    pub state_before: CudaProcessState,
+
+    /// State the checkpoint left the process in
+    pub state_after: CudaProcessState,
+}
+
+/// The `nvidia-cuda-checkpoint` tool
+#[derive(Debug, Clone)]
+pub struct CudaCheckpointTool {
+    path: PathBuf,
+}
+
+impl CudaCheckpointTool {
+    /// Use the tool at `path`
+    pub fn at(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Look the tool up on `PATH`
+    pub fn find() -> Option<Self> {
+        Self::find_in(&env::var_os("PATH")?)
+    }
+
+    /// Look the tool up in the directories of a `PATH`-style list
+    pub fn find_in(search_path: &OsStr) -> Option<Self> {
+        env::split_paths(search_path)
+            .map(|dir| dir.join(CUDA_CHECKPOINT_TOOL))
+            .find(|candidate| is_executable(candidate))
+            .map(Self::at)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Current CUDA state of `pid`
+    pub fn state(&self, pid: u32) -> Result<CudaProcessState> {
+        self.run(&["--get-state", "--pid", &pid.to_string()])?
+            .parse()
+    }
+
+    /// Toggle `pid` between running and checkpointed
+    pub fn toggle(&self, pid: u32) -> Result<()> {
+        self.run(&["--toggle", "--pid", &pid.to_string()])
+            .map(|_| ())
+    }
+
+    /// Move the device state of a running `pid` to the host
+    pub fn checkpoint(&self, pid: u32) -> Result<CudaToggle> {
+        let state_before = self.state(pid)?;
+        if state_before != CudaProcessState::Running {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "CUDA state of PID {pid} is {state_before}, expected running"
+            )));
+        }
+
+        self.toggle(pid)?;
+        let state_after = self.state(pid)?;
+        if state_after != CudaProcessState::Checkpointed {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "CUDA state of PID {pid} is {state_after} after toggling, expected checkpointed"
+            )));
+        }
+
+        info!("Toggled PID {} to {}", pid, state_after);
+        Ok(CudaToggle {
+            tool: self.path.clone(),
+            state_before,
+            state_after,
+        })
+    }
+
+    /// Reverse `toggle` on `pid`, moving its device state back to the GPU.
+    /// A process that is already running is left alone.
+    pub fn restore(&self, pid: u32, toggle: &CudaToggle) -> Result<()> {
+        let state = self.state(pid).map_err(restore_error)?;
+        if state == toggle.state_before {
+            debug!("PID {} is already {}", pid, state);
+            return Ok(());
+        }
+        if state != toggle.state_after {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "CUDA state of PID {pid} is {state}, expected {}",
+                toggle.state_after
+            )));
+        }
+
+        self.toggle(pid).map_err(restore_error)?;
+        let state = self.state(pid).map_err(restore_error)?;
+        if state != toggle.state_before {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "CUDA state of PID {pid} is {state} after toggling, expected {}",
+                toggle.state_before
+            )));
+        }
+
+        info!("Toggled PID {} back to {}", pid, state);
+        Ok(())
+    }
+
+    /// Run the tool with `args`, returning its standard output
+    fn run(&self, args: &[&str]) -> Result<String> {
+        debug!("Running {} {}", self.path.display(), args.join(" "));
+        let output = Command::new(&self.path).args(args).output().map_err(|e| {
+            GpuCheckpointError::CheckpointError(format!("cannot run {}: {e}", self.path.display()))
+        })?;
+
+        if !output.status.success() {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "{} {} failed ({}): {}",
+                self.path.display(),
+                args.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+fn restore_error(e: GpuCheckpointError) -> GpuCheckpointError {
+    match e {
+        GpuCheckpointError::CheckpointError(msg) => GpuCheckpointError::RestoreError(msg),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    /// Stand-in for the tool keeping the state of every PID in a file next
+    /// to it
+    const FAKE_TOOL: &str = r#"#!/bin/sh
+state="$(dirname "$0")/state-$3"
+[ -f "$state" ] || echo running > "$state"
+case "$1" in
+    --get-state) cat "$state" ;;
+    --toggle)
+        if [ "$(cat "$state")" = running ]; then
+            echo checkpointed > "$state"
+        else
+            echo running > "$state"
+        fi ;;
+    *) echo "unknown option $1" >&2; exit 2 ;;
+esac
+"#;
+
+    fn install_fake_tool(dir: &Path) -> PathBuf {
+        let path = dir.join(CUDA_CHECKPOINT_TOOL);
+        fs::write(&path, FAKE_TOOL).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_find_in_search_path() {
+        let dir = tempdir().unwrap();
+        let empty = tempdir().unwrap();
+        let search_path = env::join_paths([empty.path(), dir.path()]).unwrap();
+        assert!(CudaCheckpointTool::find_in(&search_path).is_none());
+
+        let path = install_fake_tool(dir.path());
+        let tool = CudaCheckpointTool::find_in(&search_path).unwrap();
+        assert_eq!(tool.path(), path);
+
+        // Not executable
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(CudaCheckpointTool::find_in(&search_path).is_none());
+    }
+
+    #[test]
+    fn test_checkpoint_and_restore_toggle() {
+        let dir = tempdir().unwrap();
+        let tool = CudaCheckpointTool::at(install_fake_tool(dir.path()));
+
+        let toggle = tool.checkpoint(1234).unwrap();
+        assert_eq!(toggle.state_before, CudaProcessState::Running);
+        assert_eq!(toggle.state_after, CudaProcessState::Checkpointed);
+        assert_eq!(tool.state(1234).unwrap(), CudaProcessState::Checkpointed);
+
+        // Already checkpointed
+        let err = tool.checkpoint(1234).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("is checkpointed, expected running"));
+
+        tool.restore(1234, &toggle).unwrap();
+        assert_eq!(tool.state(1234).unwrap(), CudaProcessState::Running);
+        // Restoring twice leaves the running process alone
+        tool.restore(1234, &toggle).unwrap();
+        assert_eq!(tool.state(1234).unwrap(), CudaProcessState::Running);
+    }
+
+    #[test]
+    fn test_tool_failures() {
+        let dir = tempdir().unwrap();
+        let tool = CudaCheckpointTool::at(dir.path().join("missing"));
+        assert!(matches!(
+            tool.state(1234),
+            Err(GpuCheckpointError::CheckpointError(msg)) if msg.contains("cannot run")
+        ));
+
+        let tool = CudaCheckpointTool::at(install_fake_tool(dir.path()));
+        let err = tool.run(&["--bogus", "--pid", "1"]).unwrap_err();
+        assert!(err.to_string().contains("unknown option --bogus"), "{err}");
+
+        assert_eq!(
+            "Checkpointed\n".parse::<CudaProcessState>().unwrap(),
+            CudaProcessState::Checkpointed
+        );
+        assert!("suspended".parse::<CudaProcessState>().is_err());
+    }
+}
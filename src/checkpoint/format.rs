@@ -0,0 +1,486 @@
+use crate::checkpoint::bar_sliding::{
+    AllocationDescriptor, AllocationHeader, BarSlidingCheckpoint, CheckpointHeader,
+    CheckpointMetadata, ALLOC_FLAG_DESCRIPTOR, CHECKPOINT_MAGIC, HEADER_RESERVED_SIZE,
+};
+use crate::detector::DetectionResult;
+use crate::restore::{BarRestore, RestoreMetadata};
+use crate::utils::compression::{self, Compression, DecompressReader};
+use crate::utils::lock;
+use crate::{GpuCheckpointError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Instant;
+use tracing::{debug, info};
+
+/// Name of the archive entry describing the checkpoint
+pub const ARCHIVE_METADATA_ENTRY: &str = "metadata.json";
+
+/// On-disk layout of a checkpoint
+pub trait CheckpointFormat {
+    /// File extension (without the dot) of checkpoints in this format
+    fn extension(&self) -> &'static str;
+
+    /// Checkpoint `detection` of `pid` into `output_path`
+    fn write(
+        &self,
+        checkpoint: &BarSlidingCheckpoint,
+        pid: u32,
+        detection: &DetectionResult,
+        output_path: &Path,
+    ) -> Result<CheckpointMetadata>;
+
+    /// Restore the checkpoint at `path` into `target_pid` (or the
+    /// checkpointed PID)
+    fn restore(
+        &self,
+        restore: &BarRestore,
+        path: &Path,
+        target_pid: Option<u32>,
+    ) -> Result<RestoreMetadata>;
+}
+
+/// Built-in checkpoint formats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CheckpointFileFormat {
+    /// Single `.bin` file of framed allocation records
+    #[default]
+    Binary,
+
+    /// Tar archive with a `metadata.json` entry and one entry per allocation
+    Tar,
+}
+
+impl CheckpointFileFormat {
+    pub fn implementation(&self) -> &'static dyn CheckpointFormat {
+        match self {
+            CheckpointFileFormat::Binary => &BinaryFormat,
+            CheckpointFileFormat::Tar => &TarFormat,
+        }
+    }
+
+    /// Identify the format of an existing checkpoint from its contents
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        File::open(path)?.read_exact(&mut magic)?;
+        if u32::from_le_bytes(magic) == CHECKPOINT_MAGIC {
+            Ok(CheckpointFileFormat::Binary)
+        } else {
+            Ok(CheckpointFileFormat::Tar)
+        }
+    }
+}
+
+impl FromStr for CheckpointFileFormat {
+    type Err = GpuCheckpointError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bin" | "binary" => Ok(CheckpointFileFormat::Binary),
+            "tar" => Ok(CheckpointFileFormat::Tar),
+            _ => Err(GpuCheckpointError::CheckpointError(format!(
+                "Unknown checkpoint format: {s}"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for CheckpointFileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointFileFormat::Binary => write!(f, "bin"),
+            CheckpointFileFormat::Tar => write!(f, "tar"),
+        }
+    }
+}
+
+/// The native single-file format
+pub struct BinaryFormat;
+
+impl CheckpointFormat for BinaryFormat {
+    fn extension(&self) -> &'static str {
+        "bin"
+    }
+
+    fn write(
+        &self,
+        checkpoint: &BarSlidingCheckpoint,
+        pid: u32,
+        detection: &DetectionResult,
+        output_path: &Path,
+    ) -> Result<CheckpointMetadata> {
+        checkpoint.checkpoint_process(pid, detection, output_path)
+    }
+
+    fn restore(
+        &self,
+        restore: &BarRestore,
+        path: &Path,
+        target_pid: Option<u32>,
+    ) -> Result<RestoreMetadata> {
+        restore.restore_from_checkpoint(path, target_pid)
+    }
+}
+
+/// Contents of the `metadata.json` archive entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveMetadata {
+    pub version: u32,
+    pub pid: u32,
+    pub total_size: u64,
+    pub timestamp: u64,
+    pub allocations: Vec<ArchiveEntry>,
+}
+
+/// An allocation stored as its own archive entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    /// Path of the entry holding the allocation's data
+    pub name: String,
+    pub vaddr_start: u64,
+    pub vaddr_end: u64,
+    pub size: u64,
+    pub device_id: u32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub descriptor: Option<AllocationDescriptor>,
+}
+
+impl ArchiveEntry {
+    fn allocation_header(&self) -> AllocationHeader {
+        AllocationHeader {
+            vaddr_start: self.vaddr_start,
+            vaddr_end: self.vaddr_end,
+            size: self.size,
+            device_id: self.device_id,
+            flags: if self.descriptor.is_some() {
+                ALLOC_FLAG_DESCRIPTOR
+            } else {
+                0
+            },
+        }
+    }
+}
+
+/// Tar archive with a leading `metadata.json` entry followed by one entry per
+/// allocation, named `allocations/<index>-<address>.bin`. Allocations can be
+/// inspected and extracted with standard tools.
+pub struct TarFormat;
+
+impl TarFormat {
+    /// Re-encode a binary checkpoint as a tar archive. Allocation data is
+    /// streamed entry by entry, so memory use does not depend on its size.
+    /// Compressed data is inflated so entries can be read with standard
+    /// tools.
+    pub fn encode(bin_path: &Path, output_path: &Path) -> Result<()> {
+        let reader = BarRestore::new();
+        let mut input = File::open(bin_path)?;
+        let header = CheckpointHeader::read_from(&mut input)?;
+        header.validate()?;
+        if header.encrypted {
+            return Err(GpuCheckpointError::CheckpointError(
+                "encrypted checkpoints cannot be re-encoded as tar archives".to_string(),
+            ));
+        }
+        // Repeated windows refer to data in other entries
+        if header.deduplicated {
+            return Err(GpuCheckpointError::CheckpointError(
+                "deduplicated checkpoints cannot be re-encoded as tar archives".to_string(),
+            ));
+        }
+
+        // Collect the layout first so metadata.json can lead the archive
+        let mut allocations = Vec::new();
+        let mut data = Vec::new();
+        for idx in 0..header.num_allocations {
+            let alloc_header = AllocationHeader::read_from(&mut input)?;
+            let descriptor = reader.read_allocation_descriptor(&mut input, &alloc_header)?;
+            let stored_size = descriptor
+                .as_ref()
+                .map_or(alloc_header.size, |d| d.stored_size(alloc_header.size));
+
+            let offset = input.stream_position()?;
+            if header.compression.is_enabled() {
+                compression::skip_frames(&mut input, stored_size)?;
+            } else {
+                input.seek(SeekFrom::Current(stored_size as i64))?;
+            }
+            // The entry keeps the checksum so restore can verify it
+            let mut checksum = vec![0u8; header.checksum_size() as usize];
+            input.read_exact(&mut checksum)?;
+            data.push((offset, stored_size, checksum));
+
+            allocations.push(ArchiveEntry {
+                name: format!(
+                    "allocations/{:04}-{:016x}.bin",
+                    idx, alloc_header.vaddr_start
+                ),
+                vaddr_start: alloc_header.vaddr_start,
+                vaddr_end: alloc_header.vaddr_end,
+                size: alloc_header.size,
+                device_id: alloc_header.device_id,
+                descriptor,
+            });
+        }
+
+        let metadata = ArchiveMetadata {
+            version: header.version,
+            pid: header.pid,
+            total_size: header.total_size,
+            timestamp: header.timestamp,
+            allocations,
+        };
+        let encoded = serde_json::to_vec_pretty(&metadata)
+            .map_err(|e| GpuCheckpointError::CheckpointError(e.to_string()))?;
+
+        let mut output = lock::create_locked(output_path)?;
+        let mut builder = tar::Builder::new(&mut *output);
+        let entry_header = |size: u64| {
+            let mut entry_header = tar::Header::new_gnu();
+            entry_header.set_size(size);
+            entry_header.set_mode(0o600);
+            entry_header.set_mtime(header.timestamp);
+            entry_header
+        };
+
+        builder.append_data(
+            &mut entry_header(encoded.len() as u64),
+            ARCHIVE_METADATA_ENTRY,
+            encoded.as_slice(),
+        )?;
+
+        for (entry, (offset, stored_size, checksum)) in metadata.allocations.iter().zip(data) {
+            input.seek(SeekFrom::Start(offset))?;
+            let entry_data: Box<dyn Read> = if header.compression.is_enabled() {
+                Box::new(DecompressReader::new(&mut input, stored_size))
+            } else {
+                Box::new((&mut input).take(stored_size))
+            };
+            builder.append_data(
+                &mut entry_header(stored_size + checksum.len() as u64),
+                &entry.name,
+                entry_data.chain(checksum.as_slice()),
+            )?;
+        }
+
+        builder.into_inner()?.sync_all()?;
+        debug!(
+            "Encoded {} allocations from {:?} into {:?}",
+            metadata.allocations.len(),
+            bin_path,
+            output_path
+        );
+        Ok(())
+    }
+}
+
+impl CheckpointFormat for TarFormat {
+    fn extension(&self) -> &'static str {
+        "tar"
+    }
+
+    fn write(
+        &self,
+        checkpoint: &BarSlidingCheckpoint,
+        pid: u32,
+        detection: &DetectionResult,
+        output_path: &Path,
+    ) -> Result<CheckpointMetadata> {
+        // Archive entries have no room for the base checkpoint's identity
+        if checkpoint.incremental_base().is_some() {
+            return Err(GpuCheckpointError::CheckpointError(
+                "incremental checkpoints are only supported in the bin format".to_string(),
+            ));
+        }
+        // Entries hold the plain data so standard tools can read them
+        if checkpoint.is_encrypted() {
+            return Err(GpuCheckpointError::CheckpointError(
+                "encrypted checkpoints are only supported in the bin format".to_string(),
+            ));
+        }
+
+        // Capture into the native format next to the archive, then re-encode
+        let staging_path = output_path.with_extension("bin.partial");
+        let mut metadata = checkpoint.checkpoint_process(pid, detection, &staging_path)?;
+
+        let encoded = Self::encode(&staging_path, output_path);
+        fs::remove_file(&staging_path)?;
+        encoded?;
+
+        info!("Wrote tar checkpoint {:?}", output_path);
+        metadata.path = output_path.to_path_buf();
+        Ok(metadata)
+    }
+
+    fn restore(
+        &self,
+        restore: &BarRestore,
+        path: &Path,
+        target_pid: Option<u32>,
+    ) -> Result<RestoreMetadata> {
+        info!("Starting tar restore from {:?}", path);
+        let start_time = Instant::now();
+
+        let mut file = lock::open_shared(path)?;
+        restore.verify_signature(&mut file)?;
+
+        let mut archive = tar::Archive::new(&mut *file);
+        let mut entries = archive.entries()?;
+
+        let mut metadata_entry = entries.next().ok_or_else(|| {
+            GpuCheckpointError::RestoreError("Checkpoint archive is empty".to_string())
+        })??;
+        if metadata_entry.path()?.as_ref() != Path::new(ARCHIVE_METADATA_ENTRY) {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "Checkpoint archive does not start with {ARCHIVE_METADATA_ENTRY}"
+            )));
+        }
+        let metadata: ArchiveMetadata =
+            serde_json::from_reader(&mut metadata_entry).map_err(|e| {
+                GpuCheckpointError::RestoreError(format!("Invalid {ARCHIVE_METADATA_ENTRY}: {e}"))
+            })?;
+
+        let header = CheckpointHeader {
+            magic: CHECKPOINT_MAGIC,
+            version: metadata.version,
+            pid: metadata.pid,
+            num_allocations: metadata.allocations.len() as u32,
+            total_size: metadata.total_size,
+            timestamp: metadata.timestamp,
+            // Entries always hold the uncompressed data
+            compression: Compression::None,
+            checkpoint_id: 0,
+            base_checkpoint_id: 0,
+            encrypted: false,
+            deduplicated: false,
+            reserved: [0; HEADER_RESERVED_SIZE],
+        };
+        header.validate()?;
+
+        restore.restore_records(&header, target_pid, start_time, |records| {
+            let mut total_restored = 0u64;
+            for (index, allocation) in metadata.allocations.iter().enumerate() {
+                let mut entry = entries.next().ok_or_else(|| {
+                    GpuCheckpointError::RestoreError(format!(
+                        "Checkpoint archive is missing {}",
+                        allocation.name
+                    ))
+                })??;
+                if entry.path()?.as_ref() != Path::new(&allocation.name) {
+                    return Err(GpuCheckpointError::RestoreError(format!(
+                        "Expected archive entry {} but found {}",
+                        allocation.name,
+                        entry.path()?.display()
+                    )));
+                }
+
+                total_restored += records.restore(
+                    index as u32,
+                    &allocation.allocation_header(),
+                    &allocation.descriptor.clone().unwrap_or_default(),
+                    &mut entry,
+                )?;
+            }
+            Ok(total_restored)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::{AllocationType, GpuAllocation, GpuVendor};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_tar_roundtrip() {
+        let dir = tempdir().unwrap();
+        let archive_path = dir.path().join("checkpoint.tar");
+
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            0x100000,
+            0x101000,
+            AllocationType::Standard,
+        ));
+        detection.add_allocation(GpuAllocation::new(0x200000, 0x202000, AllocationType::Uvm));
+
+        let format = CheckpointFileFormat::Tar.implementation();
+        let ckpt_metadata = format
+            .write(
+                &BarSlidingCheckpoint::new(),
+                1234,
+                &detection,
+                &archive_path,
+            )
+            .unwrap();
+        assert_eq!(ckpt_metadata.path, archive_path);
+        assert!(!dir.path().join("checkpoint.bin.partial").exists());
+        assert_eq!(
+            CheckpointFileFormat::detect(&archive_path).unwrap(),
+            CheckpointFileFormat::Tar
+        );
+
+        let mut archive = tar::Archive::new(File::open(&archive_path).unwrap());
+        let names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "metadata.json",
+                "allocations/0000-0000000000100000.bin",
+                "allocations/0001-0000000000200000.bin",
+            ]
+        );
+
+        let restore_metadata = format
+            .restore(&BarRestore::new(), &archive_path, Some(5678))
+            .unwrap();
+        assert_eq!(restore_metadata.num_allocations, 2);
+        assert_eq!(restore_metadata.total_size, 0x3000);
+    }
+
+    #[test]
+    fn test_tar_entries_of_compressed_checkpoint_are_inflated() {
+        let dir = tempdir().unwrap();
+        let archive_path = dir.path().join("compressed.tar");
+
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            0x100000,
+            0x104000,
+            AllocationType::Standard,
+        ));
+
+        let format = CheckpointFileFormat::Tar.implementation();
+        format
+            .write(
+                &BarSlidingCheckpoint::new().with_compression(Compression::deflate(9).unwrap()),
+                1234,
+                &detection,
+                &archive_path,
+            )
+            .unwrap();
+
+        let mut archive = tar::Archive::new(File::open(&archive_path).unwrap());
+        let sizes: Vec<u64> = archive
+            .entries()
+            .unwrap()
+            .skip(1)
+            .map(|entry| entry.unwrap().size())
+            .collect();
+        // The data plus its checksum
+        assert_eq!(sizes, vec![0x4000 + 4]);
+
+        let restore_metadata = format
+            .restore(&BarRestore::new(), &archive_path, Some(5678))
+            .unwrap();
+        assert_eq!(restore_metadata.applied, vec![0x100000]);
+    }
+}
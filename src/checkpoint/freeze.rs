@@ -0,0 +1,997 @@
+use crate::detector::ProcessScanner;
+use crate::{GpuCheckpointError, Result};
+use nix::sys::ptrace;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// How long to wait for the target to report a stopped state after SIGSTOP
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Mount point of the cgroup hierarchies
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Mechanism used to pause a target process
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FreezeMethod {
+    /// SIGSTOP/SIGCONT. Needs no privileges, but the target can observe the
+    /// signals and a concurrent SIGCONT from elsewhere resumes it.
+    #[default]
+    Signal,
+
+    /// Attach to every thread with ptrace. Needs `CAP_SYS_PTRACE` (or a
+    /// permissive Yama policy) and excludes other tracers such as debuggers.
+    Ptrace,
+
+    /// Freeze the target's cgroup through `cgroup.freeze` (v2) or
+    /// `freezer.state` (v1). Freezes every process in that cgroup, which
+    /// suits one-process-per-container deployments.
+    CgroupFreezer,
+}
+
+impl FromStr for FreezeMethod {
+    type Err = GpuCheckpointError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "signal" => Ok(FreezeMethod::Signal),
+            "ptrace" => Ok(FreezeMethod::Ptrace),
+            "cgroup" | "cgroup-freezer" => Ok(FreezeMethod::CgroupFreezer),
+            _ => Err(GpuCheckpointError::CheckpointError(format!(
+                "Unknown freeze method: {s}"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for FreezeMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FreezeMethod::Signal => write!(f, "signal"),
+            FreezeMethod::Ptrace => write!(f, "ptrace"),
+            FreezeMethod::CgroupFreezer => write!(f, "cgroup-freezer"),
+        }
+    }
+}
+
+/// Control file of the cgroup freezer a process belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CgroupFreezer {
+    /// cgroup v2 directory holding `cgroup.freeze` and `cgroup.events`
+    V2(PathBuf),
+
+    /// cgroup v1 `freezer.state` file
+    V1(PathBuf),
+}
+
+impl CgroupFreezer {
+    /// Freezer of the cgroup `pid` belongs to
+    fn for_process(pid: u32) -> Result<Self> {
+        let contents = fs::read_to_string(format!("/proc/{pid}/cgroup")).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                GpuCheckpointError::ProcessNotFound(pid)
+            } else {
+                GpuCheckpointError::IoError(e)
+            }
+        })?;
+
+        Self::parse(&contents, Path::new(CGROUP_ROOT)).ok_or_else(|| {
+            GpuCheckpointError::CheckpointError(format!("PID {pid} is not in a freezable cgroup"))
+        })
+    }
+
+    /// Resolve the freezer from the contents of `/proc/PID/cgroup`,
+    /// preferring a v1 freezer hierarchy when one is mounted
+    fn parse(contents: &str, root: &Path) -> Option<Self> {
+        let mut v2 = None;
+        for line in contents.lines() {
+            let mut fields = line.splitn(3, ':');
+            let (Some(id), Some(controllers), Some(path)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let relative = path.trim_start_matches('/');
+
+            if controllers.split(',').any(|c| c == "freezer") {
+                let hierarchy = root.join("freezer");
+                if hierarchy.is_dir() {
+                    return Some(CgroupFreezer::V1(
+                        hierarchy.join(relative).join("freezer.state"),
+                    ));
+                }
+            } else if id == "0" && controllers.is_empty() && !relative.is_empty() {
+                // The root cgroup cannot be frozen
+                let unified = root.join("unified");
+                let base = if unified.is_dir() {
+                    unified
+                } else {
+                    root.into()
+                };
+                v2 = Some(CgroupFreezer::V2(base.join(relative)));
+            }
+        }
+        v2
+    }
+
+    fn set_frozen(&self, frozen: bool) -> Result<()> {
+        let (path, value) = match self {
+            CgroupFreezer::V2(dir) => (dir.join("cgroup.freeze"), if frozen { "1" } else { "0" }),
+            CgroupFreezer::V1(state) => (state.clone(), if frozen { "FROZEN" } else { "THAWED" }),
+        };
+        fs::write(&path, value).map_err(|e| {
+            GpuCheckpointError::CheckpointError(format!("failed to write {}: {e}", path.display()))
+        })
+    }
+
+    fn is_frozen(&self) -> bool {
+        match self {
+            CgroupFreezer::V2(dir) => fs::read_to_string(dir.join("cgroup.events"))
+                .is_ok_and(|events| events.lines().any(|line| line == "frozen 1")),
+            CgroupFreezer::V1(state) => {
+                fs::read_to_string(state).is_ok_and(|state| state.trim() == "FROZEN")
+            }
+        }
+    }
+
+    /// Whether freezing this cgroup would also freeze the current process
+    fn contains_current_process(&self) -> bool {
+        let Ok(own) = Self::for_process(std::process::id()) else {
+            return false;
+        };
+        match (self, &own) {
+            (CgroupFreezer::V2(target), CgroupFreezer::V2(own)) => own.starts_with(target),
+            (CgroupFreezer::V1(target), CgroupFreezer::V1(own)) => own
+                .parent()
+                .zip(target.parent())
+                .is_some_and(|(own, target)| own.starts_with(target)),
+            _ => false,
+        }
+    }
+}
+
+/// Stops and resumes a target process around checkpoint and restore
+#[derive(Debug)]
+pub struct ProcessController {
+    pid: u32,
+    method: FreezeMethod,
+
+    /// Whether this controller stopped the process and has not resumed it
+    frozen: bool,
+
+    /// Threads attached with ptrace
+    traced: Vec<Pid>,
+
+    /// Cgroup frozen with the cgroup freezer
+    cgroup: Option<CgroupFreezer>,
+
+    /// Threads attached before all others when freezing with ptrace
+    priority_threads: Vec<u32>,
+}
+
+impl ProcessController {
+    pub fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            method: FreezeMethod::default(),
+            frozen: false,
+            traced: Vec::new(),
+            cgroup: None,
+            priority_threads: Vec::new(),
+        }
+    }
+
+    /// Pause the target with `method` instead of SIGSTOP
+    pub fn with_method(mut self, method: FreezeMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// Stop `threads` (e.g. `DetectionResult::gpu_threads`) before the rest
+    /// of the target, so GPU work is not submitted by threads that are still
+    /// running. Only the ptrace method stops threads individually.
+    pub fn with_priority_threads(mut self, threads: &[u32]) -> Self {
+        self.priority_threads = threads.to_vec();
+        self
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn method(&self) -> FreezeMethod {
+        self.method
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Stop the target and wait until it is stopped. Returns `false` when
+    /// freezing was skipped because the target is the current process.
+    pub fn freeze(&mut self) -> Result<bool> {
+        if self.pid == std::process::id() {
+            debug!(
+                "Not freezing PID {} because it is the current process",
+                self.pid
+            );
+            return Ok(false);
+        }
+
+        match self.method {
+            FreezeMethod::Signal => self.freeze_signal()?,
+            FreezeMethod::Ptrace => self.freeze_ptrace()?,
+            FreezeMethod::CgroupFreezer => self.freeze_cgroup()?,
+        }
+        Ok(true)
+    }
+
+    /// Like `freeze`, but leave a target that is stopped already or has
+    /// exited as it is. Returns whether this controller stopped it.
+    pub fn freeze_unless_stopped(&mut self) -> Result<bool> {
+        match ProcessScanner::process_state(self.pid) {
+            Ok(state) if !state.is_stopped() && !state.has_exited() => self.freeze(),
+            Ok(_) | Err(GpuCheckpointError::ProcessNotFound(_)) => {
+                debug!("Not freezing PID {}: stopped or gone already", self.pid);
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Resume the target if this controller stopped it
+    pub fn resume(&mut self) -> Result<()> {
+        if !self.frozen {
+            return Ok(());
+        }
+
+        match self.method {
+            FreezeMethod::Signal => self.send(Signal::SIGCONT, "resume")?,
+            FreezeMethod::Ptrace => self.detach(None)?,
+            FreezeMethod::CgroupFreezer => {
+                if let Some(cgroup) = self.cgroup.take() {
+                    cgroup.set_frozen(false)?;
+                }
+            }
+        }
+        self.frozen = false;
+        Ok(())
+    }
+
+    /// Give up control of the target but keep it stopped, e.g. so another
+    /// tool can attach to it
+    pub fn leave_stopped(&mut self) -> Result<()> {
+        if self.frozen && self.method == FreezeMethod::Ptrace {
+            // Detaching with SIGSTOP turns the ptrace stop into a group stop
+            self.detach(Some(Signal::SIGSTOP))?;
+        }
+        self.frozen = false;
+        Ok(())
+    }
+
+    fn send(&self, signal: Signal, action: &str) -> Result<()> {
+        kill(Pid::from_raw(self.pid as i32), signal).map_err(|e| {
+            GpuCheckpointError::CheckpointError(format!("failed to {action} PID {}: {e}", self.pid))
+        })
+    }
+
+    fn freeze_signal(&mut self) -> Result<()> {
+        self.send(Signal::SIGSTOP, "stop")?;
+        self.frozen = true;
+        self.wait_until_stopped();
+        Ok(())
+    }
+
+    fn wait_until_stopped(&self) {
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while Instant::now() < deadline {
+            if matches!(ProcessScanner::process_state(self.pid), Ok(state) if state.is_stopped()) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        warn!("PID {} did not report a stopped state in time", self.pid);
+    }
+
+    /// Attach to every thread of the target. Threads created while attaching
+    /// are picked up by rescanning until no new ones appear.
+    fn freeze_ptrace(&mut self) -> Result<()> {
+        loop {
+            let mut tasks = Self::tasks(self.pid)?;
+            tasks.sort_by_key(|tid| !self.priority_threads.contains(&(tid.as_raw() as u32)));
+            let new: Vec<Pid> = tasks
+                .into_iter()
+                .filter(|tid| !self.traced.contains(tid))
+                .collect();
+            if new.is_empty() {
+                break;
+            }
+
+            for tid in new {
+                match ptrace::attach(tid) {
+                    Ok(()) => {}
+                    // The thread exited in the meantime
+                    Err(nix::errno::Errno::ESRCH) => continue,
+                    Err(e) => {
+                        let _ = self.detach(None);
+                        return Err(if e == nix::errno::Errno::EPERM {
+                            GpuCheckpointError::PermissionDenied
+                        } else {
+                            GpuCheckpointError::CheckpointError(format!(
+                                "failed to attach to thread {tid} of PID {}: {e}",
+                                self.pid
+                            ))
+                        });
+                    }
+                }
+                self.traced.push(tid);
+                self.frozen = true;
+                if let Err(e) = waitpid(tid, Some(WaitPidFlag::__WALL)) {
+                    warn!("Waiting for thread {} to stop failed: {}", tid, e);
+                }
+            }
+        }
+
+        debug!(
+            "Attached to {} thread(s) of PID {}",
+            self.traced.len(),
+            self.pid
+        );
+        Ok(())
+    }
+
+    fn detach(&mut self, signal: Option<Signal>) -> Result<()> {
+        let mut result = Ok(());
+        for tid in self.traced.drain(..) {
+            match ptrace::detach(tid, signal) {
+                Ok(()) | Err(nix::errno::Errno::ESRCH) => {}
+                Err(e) => {
+                    result = Err(GpuCheckpointError::CheckpointError(format!(
+                        "failed to detach from thread {tid}: {e}"
+                    )))
+                }
+            }
+        }
+        result
+    }
+
+    fn tasks(pid: u32) -> Result<Vec<Pid>> {
+        let entries = fs::read_dir(format!("/proc/{pid}/task")).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                GpuCheckpointError::ProcessNotFound(pid)
+            } else {
+                GpuCheckpointError::IoError(e)
+            }
+        })?;
+
+        Ok(entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+            .map(Pid::from_raw)
+            .collect())
+    }
+
+    fn freeze_cgroup(&mut self) -> Result<()> {
+        let cgroup = CgroupFreezer::for_process(self.pid)?;
+        if cgroup.contains_current_process() {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "PID {} shares a cgroup with this process, freezing it would freeze us too",
+                self.pid
+            )));
+        }
+
+        cgroup.set_frozen(true)?;
+        self.frozen = true;
+
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while Instant::now() < deadline && !cgroup.is_frozen() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        if !cgroup.is_frozen() {
+            warn!("Cgroup of PID {} did not report frozen in time", self.pid);
+        }
+
+        self.cgroup = Some(cgroup);
+        Ok(())
+    }
+
+    /// Change the protection of `len` bytes at `addr` in the target to
+    /// `prot` (`PROT_*` flags)
+    pub fn mprotect(&mut self, addr: u64, len: u64, prot: i32) -> Result<()> {
+        self.syscall(libc::SYS_mprotect, [addr, len, prot as u64, 0, 0, 0])
+            .map(|_| ())
+    }
+
+    /// Map `len` bytes of anonymous private memory with protection `prot`
+    /// at exactly `addr` in the target. Fails with `EEXIST` if anything is
+    /// mapped in that range already.
+    pub fn map_anonymous(&mut self, addr: u64, len: u64, prot: i32) -> Result<()> {
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE;
+        // fd -1
+        let args = [addr, len, prot as u64, flags as u64, u64::MAX, 0];
+        let mapped = self.syscall(libc::SYS_mmap, args)? as u64;
+        if mapped != addr {
+            // Kernels before 4.17 take the address as a mere hint
+            let _ = self.syscall(libc::SYS_munmap, [mapped, len, 0, 0, 0, 0]);
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "PID {} mapped 0x{mapped:x} instead of 0x{addr:x}",
+                self.pid
+            )));
+        }
+        Ok(())
+    }
+
+    /// Give `len` bytes at `addr` in the target the `MADV_*` `advice`
+    pub fn madvise(&mut self, addr: u64, len: u64, advice: i32) -> Result<()> {
+        self.syscall(libc::SYS_madvise, [addr, len, advice as u64, 0, 0, 0])
+            .map(|_| ())
+    }
+
+    /// Fork the target, returning the PID of a child whose memory is that
+    /// of the target at the fork, shared copy-on-write. The child is traced
+    /// by this process and never runs: it stays stopped until `kill_fork`.
+    /// Mappings marked `MADV_DONTFORK` are left out of the child.
+    pub fn fork_stopped(&mut self) -> Result<u32> {
+        if self.pid == std::process::id() {
+            return Err(GpuCheckpointError::CheckpointError(
+                "cannot fork the current process as a snapshot".to_string(),
+            ));
+        }
+
+        // clone without CLONE_VM copies the address space, and an exit
+        // signal of 0 keeps the target from getting SIGCHLD for the child
+        let child = self.syscall_traced(
+            libc::SYS_clone,
+            [0; 6],
+            ptrace::Options::PTRACE_O_TRACECLONE,
+        )?;
+        let child = Pid::from_raw(child as i32);
+
+        // The child is attached to us and starts in a ptrace stop
+        match waitpid(child, Some(WaitPidFlag::__WALL)) {
+            Ok(WaitStatus::Stopped(..) | WaitStatus::PtraceEvent(..)) => {
+                debug!("Forked PID {} as stopped child {}", self.pid, child);
+                Ok(child.as_raw() as u32)
+            }
+            status => {
+                let _ = kill(child, Signal::SIGKILL);
+                Err(GpuCheckpointError::CheckpointError(format!(
+                    "fork {child} of PID {} did not stop: {status:?}",
+                    self.pid
+                )))
+            }
+        }
+    }
+
+    /// Kill a child of `fork_stopped` and reap it in the target, which
+    /// would otherwise keep it as a zombie
+    pub fn kill_fork(&mut self, child: u32) -> Result<()> {
+        let child = Pid::from_raw(child as i32);
+        kill(child, Signal::SIGKILL).map_err(|e| {
+            GpuCheckpointError::CheckpointError(format!("failed to kill fork {child}: {e}"))
+        })?;
+        // Its exit is reported to us as its tracer first, then to the target
+        let _ = waitpid(child, Some(WaitPidFlag::__WALL));
+
+        let options = (libc::WNOHANG | libc::__WALL) as u64;
+        let reaped = self.syscall(
+            libc::SYS_wait4,
+            [child.as_raw() as u64, 0, options, 0, 0, 0],
+        )?;
+        if reaped != child.as_raw() as i64 {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "PID {} could not reap its fork {child}",
+                self.pid
+            )));
+        }
+        Ok(())
+    }
+
+    /// Run syscall `nr` with `args` in the target, returning its result.
+    /// Another process's mappings can only be changed from inside it, so
+    /// the syscall is injected into its main thread, whose code and
+    /// registers are restored afterwards.
+    fn syscall(&mut self, nr: libc::c_long, args: [u64; 6]) -> Result<i64> {
+        self.syscall_traced(nr, args, ptrace::Options::empty())
+    }
+
+    /// Like `syscall`, with the ptrace `options` set on the main thread
+    /// while the syscall runs
+    fn syscall_traced(
+        &mut self,
+        nr: libc::c_long,
+        args: [u64; 6],
+        options: ptrace::Options,
+    ) -> Result<i64> {
+        if self.pid == std::process::id() {
+            // SAFETY: only memory management syscalls on ranges the caller
+            // owns are issued through here
+            let ret =
+                unsafe { libc::syscall(nr, args[0], args[1], args[2], args[3], args[4], args[5]) };
+            return if ret < 0 {
+                Err(GpuCheckpointError::IoError(std::io::Error::last_os_error()))
+            } else {
+                Ok(ret)
+            };
+        }
+
+        if self.frozen && self.method == FreezeMethod::CgroupFreezer {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "cannot inject into PID {} while its cgroup is frozen",
+                self.pid
+            )));
+        }
+
+        let main = Pid::from_raw(self.pid as i32);
+        let attach = !self.traced.contains(&main);
+        // Signals the target receives while it is stepped through the
+        // syscall, which are held back until its registers are restored
+        let mut signals = Vec::new();
+        // Whether the SIGSTOP attaching sends has yet to be reported
+        let mut attach_stop = false;
+        if attach {
+            ptrace::attach(main).map_err(|e| match e {
+                nix::errno::Errno::EPERM => GpuCheckpointError::PermissionDenied,
+                e => GpuCheckpointError::RestoreError(format!(
+                    "failed to attach to PID {}: {e}",
+                    self.pid
+                )),
+            })?;
+            match waitpid(main, Some(WaitPidFlag::__WALL)) {
+                Ok(WaitStatus::Stopped(_, Signal::SIGSTOP)) => {}
+                Ok(WaitStatus::Stopped(_, signal)) => {
+                    attach_stop = true;
+                    signals.push(signal);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Waiting for PID {} to stop failed: {}", self.pid, e),
+            }
+        }
+
+        let ret = if options.is_empty() {
+            inject_syscall(main, nr, args, &mut signals)
+        } else {
+            ptrace::setoptions(main, options)
+                .map_err(|e| {
+                    GpuCheckpointError::CheckpointError(format!(
+                        "failed to set ptrace options on PID {}: {e}",
+                        self.pid
+                    ))
+                })
+                .and_then(|()| inject_syscall(main, nr, args, &mut signals))
+        };
+        if !options.is_empty() {
+            if let Err(e) = ptrace::setoptions(main, ptrace::Options::empty()) {
+                warn!("Cannot clear the ptrace options of PID {}: {}", self.pid, e);
+            }
+        }
+
+        // Queue the held back signals again, except for the SIGSTOP of
+        // attaching, so they are delivered once the target runs
+        for signal in signals {
+            if attach_stop && signal == Signal::SIGSTOP {
+                attach_stop = false;
+                continue;
+            }
+            debug!(
+                "Delivering {} to PID {} after syscall injection",
+                signal, self.pid
+            );
+            if let Err(e) = requeue_signal(self.pid, main, signal) {
+                warn!(
+                    "Cannot deliver {} to PID {} after syscall injection: {}",
+                    signal, self.pid, e
+                );
+            }
+        }
+
+        if attach {
+            // A target stopped with SIGSTOP has to stay stopped after we leave
+            let stay_stopped = self.frozen && self.method == FreezeMethod::Signal;
+            match ptrace::detach(main, stay_stopped.then_some(Signal::SIGSTOP)) {
+                Ok(()) if stay_stopped => self.wait_until_stopped(),
+                Ok(()) => {}
+                Err(e) => warn!("Detaching from PID {} failed: {}", self.pid, e),
+            }
+        }
+
+        match ret? {
+            ret if (-4095..0).contains(&ret) => Err(GpuCheckpointError::IoError(
+                std::io::Error::from_raw_os_error(-ret as i32),
+            )),
+            ret => Ok(ret),
+        }
+    }
+}
+
+/// Resumes a target that is still frozen, e.g. because the checkpoint or
+/// restore it was frozen for failed or panicked
+impl Drop for ProcessController {
+    fn drop(&mut self) {
+        if let Err(e) = self.resume() {
+            warn!("Cannot resume PID {}: {}", self.pid, e);
+        }
+    }
+}
+
+/// Queue `signal` for thread `tid` of `pid`. Its siginfo is not preserved,
+/// the signal arrives as if sent by this process.
+fn requeue_signal(pid: u32, tid: Pid, signal: Signal) -> std::io::Result<()> {
+    // SAFETY: tgkill has no memory safety preconditions
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_tgkill,
+            pid as libc::pid_t,
+            tid.as_raw(),
+            signal as libc::c_int,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Execute syscall `nr` with `args` in the stopped tracee `tid`, returning
+/// the raw return value (negative errno on failure). Signals reported
+/// while the syscall is stepped through are suppressed and appended to
+/// `signals`, for the caller to deliver once the thread is restored.
+#[cfg(target_arch = "x86_64")]
+fn inject_syscall(
+    tid: Pid,
+    nr: libc::c_long,
+    args: [u64; 6],
+    signals: &mut Vec<Signal>,
+) -> Result<i64> {
+    let injection_error =
+        |e: nix::errno::Errno| GpuCheckpointError::RestoreError(format!("syscall injection: {e}"));
+
+    let saved = ptrace::getregs(tid).map_err(injection_error)?;
+    let ip = saved.rip as ptrace::AddressType;
+    let saved_code = ptrace::read(tid, ip).map_err(injection_error)?;
+
+    // Overwrite the next instruction with `syscall` (0f 05)
+    let code = (saved_code & !0xffff) | 0x050f;
+    ptrace::write(tid, ip, code).map_err(injection_error)?;
+
+    let mut regs = saved;
+    regs.rax = nr as u64;
+    // Keep the kernel from restarting a syscall the thread was blocked in
+    regs.orig_rax = u64::MAX;
+    regs.rdi = args[0];
+    regs.rsi = args[1];
+    regs.rdx = args[2];
+    regs.r10 = args[3];
+    regs.r8 = args[4];
+    regs.r9 = args[5];
+
+    let result = ptrace::setregs(tid, regs)
+        .and_then(|()| {
+            // Pending signals are reported before the step completes. A
+            // handler must not run on the injected registers, so they are
+            // suppressed by stepping again and kept for the caller.
+            for _ in 0..8 {
+                ptrace::step(tid, None)?;
+                match waitpid(tid, Some(WaitPidFlag::__WALL))? {
+                    WaitStatus::Stopped(_, Signal::SIGTRAP) => {
+                        return ptrace::getregs(tid).map(|regs| regs.rax as i64);
+                    }
+                    WaitStatus::Stopped(_, signal) => signals.push(signal),
+                    _ => {}
+                }
+            }
+            Err(nix::errno::Errno::EINTR)
+        })
+        .map_err(injection_error);
+
+    let restored = ptrace::write(tid, ip, saved_code).and_then(|()| ptrace::setregs(tid, saved));
+    if let Err(e) = restored {
+        return Err(GpuCheckpointError::RestoreError(format!(
+            "failed to restore thread {tid} after syscall injection: {e}"
+        )));
+    }
+    result
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn inject_syscall(
+    _tid: Pid,
+    _nr: libc::c_long,
+    _args: [u64; 6],
+    _signals: &mut Vec<Signal>,
+) -> Result<i64> {
+    Err(GpuCheckpointError::RestoreError(
+        "syscall injection is only supported on x86_64".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::ProcessState;
+
+    #[test]
+    fn test_freeze_and_resume_child() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+
+        let mut controller = ProcessController::new(child.id());
+        assert!(controller.freeze().unwrap());
+        assert!(controller.is_frozen());
+        assert_eq!(
+            ProcessScanner::process_state(child.id()).unwrap(),
+            ProcessState::Stopped
+        );
+
+        controller.resume().unwrap();
+        assert!(!controller.is_frozen());
+        assert!(!ProcessScanner::process_state(child.id())
+            .unwrap()
+            .is_stopped());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_ptrace_freeze_and_resume_child() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+
+        let mut controller = ProcessController::new(child.id()).with_method(FreezeMethod::Ptrace);
+        match controller.freeze() {
+            Ok(frozen) => assert!(frozen),
+            // ptrace may be disallowed in the test environment
+            Err(GpuCheckpointError::PermissionDenied) => {
+                child.kill().unwrap();
+                child.wait().unwrap();
+                return;
+            }
+            Err(e) => panic!("ptrace freeze failed: {e}"),
+        }
+        assert_eq!(
+            ProcessScanner::process_state(child.id()).unwrap(),
+            ProcessState::TracingStop
+        );
+
+        controller.resume().unwrap();
+        assert!(!ProcessScanner::process_state(child.id())
+            .unwrap()
+            .is_stopped());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_mprotect_in_stopped_child() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        // Wait for exec so the maps below belong to sleep
+        std::thread::sleep(Duration::from_millis(50));
+
+        let region = crate::detector::MemoryMapParser::parse_maps(pid)
+            .unwrap()
+            .into_iter()
+            .find(|region| region.perms == "rw-p" && region.pathname.is_none())
+            .expect("sleep has an anonymous writable mapping");
+
+        let mut controller = ProcessController::new(pid);
+        controller.freeze().unwrap();
+        let result = controller.mprotect(region.start, 4096, libc::PROT_READ);
+        let perms = crate::detector::MemoryMapParser::parse_maps(pid)
+            .unwrap()
+            .into_iter()
+            .find(|r| r.start == region.start)
+            .unwrap()
+            .perms;
+        let stopped = ProcessScanner::process_state(pid).unwrap().is_stopped();
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        match result {
+            Ok(()) => {
+                assert_eq!(perms, "r--p");
+                assert!(stopped);
+            }
+            // ptrace may be disallowed in the test environment
+            Err(GpuCheckpointError::PermissionDenied) => {}
+            Err(e) => panic!("mprotect injection failed: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_signals_during_injection_are_delivered() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("usr1");
+        let mut child = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "trap 'touch {}' USR1; while :; do sleep 0.05; done",
+                marker.display()
+            ))
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        let target = Pid::from_raw(pid as i32);
+        std::thread::sleep(Duration::from_millis(100));
+
+        // A signal pending while the target is stopped is reported during
+        // the injection, which must not swallow it
+        kill(target, Signal::SIGSTOP).unwrap();
+        while !ProcessScanner::process_state(pid).unwrap().is_stopped() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        kill(target, Signal::SIGUSR1).unwrap();
+
+        let region = crate::detector::MemoryMapParser::parse_maps(pid)
+            .unwrap()
+            .into_iter()
+            .find(|region| region.perms == "rw-p" && region.pathname.is_none())
+            .expect("sh has an anonymous writable mapping");
+        let result = ProcessController::new(pid).mprotect(
+            region.start,
+            4096,
+            libc::PROT_READ | libc::PROT_WRITE,
+        );
+
+        kill(target, Signal::SIGCONT).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !marker.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        match result {
+            Ok(()) => assert!(marker.exists(), "SIGUSR1 was lost"),
+            // ptrace may be disallowed in the test environment
+            Err(GpuCheckpointError::PermissionDenied) => {}
+            Err(e) => panic!("mprotect injection failed: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_mprotect_current_process() {
+        let page_size = crate::utils::page_size() as usize;
+        // SAFETY: anonymous private mapping owned by this test
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+
+        let mut controller = ProcessController::new(std::process::id());
+        controller
+            .mprotect(addr as u64, page_size as u64, libc::PROT_READ)
+            .unwrap();
+        let perms = crate::detector::MemoryMapParser::parse_maps(std::process::id())
+            .unwrap()
+            .into_iter()
+            .find(|r| r.start == addr as u64)
+            .unwrap()
+            .perms;
+        assert_eq!(perms, "r--p");
+
+        // SAFETY: unmapping the mapping created above
+        unsafe { libc::munmap(addr, page_size) };
+    }
+
+    #[test]
+    fn test_map_anonymous_in_stopped_child() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let addr = 0x1000_0000_0000u64;
+        let len = 2 * crate::utils::page_size();
+        let mut controller = ProcessController::new(pid);
+        controller.freeze().unwrap();
+        let result = controller.map_anonymous(addr, len, libc::PROT_READ | libc::PROT_WRITE);
+        // Mapping over the fresh range again must not replace it
+        let again = controller.map_anonymous(addr, len, libc::PROT_READ);
+        let region = crate::detector::MemoryMapParser::parse_maps(pid)
+            .unwrap()
+            .into_iter()
+            .find(|r| r.start == addr);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        match result {
+            Ok(()) => {
+                let region = region.expect("fresh mapping is listed");
+                assert_eq!(region.end, addr + len);
+                assert_eq!(region.perms, "rw-p");
+                assert!(again.is_err());
+            }
+            // ptrace may be disallowed in the test environment
+            Err(GpuCheckpointError::PermissionDenied) => {}
+            Err(e) => panic!("mmap injection failed: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_cgroup_freezer() {
+        let root = tempfile::tempdir().unwrap();
+
+        // Pure v2
+        let v2 = "0::/kubepods/pod1/container\n";
+        assert_eq!(
+            CgroupFreezer::parse(v2, root.path()),
+            Some(CgroupFreezer::V2(
+                root.path().join("kubepods/pod1/container")
+            ))
+        );
+        assert_eq!(CgroupFreezer::parse("0::/\n", root.path()), None);
+
+        // v1 freezer hierarchy takes precedence once it is mounted
+        let hybrid = "6:freezer:/docker/abc\n1:cpu,cpuacct:/docker/abc\n0::/docker/abc\n";
+        assert_eq!(
+            CgroupFreezer::parse(hybrid, root.path()),
+            Some(CgroupFreezer::V2(root.path().join("docker/abc")))
+        );
+        fs::create_dir(root.path().join("freezer")).unwrap();
+        assert_eq!(
+            CgroupFreezer::parse(hybrid, root.path()),
+            Some(CgroupFreezer::V1(
+                root.path().join("freezer/docker/abc/freezer.state")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_freeze_method_from_str() {
+        assert_eq!(
+            "signal".parse::<FreezeMethod>().unwrap(),
+            FreezeMethod::Signal
+        );
+        assert_eq!(
+            "ptrace".parse::<FreezeMethod>().unwrap(),
+            FreezeMethod::Ptrace
+        );
+        assert_eq!(
+            "cgroup-freezer".parse::<FreezeMethod>().unwrap(),
+            FreezeMethod::CgroupFreezer
+        );
+        assert!("sigstop".parse::<FreezeMethod>().is_err());
+    }
+
+    #[test]
+    fn test_never_freezes_self() {
+        for method in [
+            FreezeMethod::Signal,
+            FreezeMethod::Ptrace,
+            FreezeMethod::CgroupFreezer,
+        ] {
+            let mut controller = ProcessController::new(std::process::id()).with_method(method);
+            assert!(!controller.freeze().unwrap());
+            assert!(!controller.is_frozen());
+            controller.resume().unwrap();
+        }
+    }
+}
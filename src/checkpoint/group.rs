@@ -0,0 +1,337 @@
+//! Checkpoints of process groups
+//!
+//! Every member of the group is checkpointed in the bin format into one tar
+//! archive, behind a `group.json` entry listing the members. Shared memory
+//! that several members map is stored once, in the record of the first
+//! member mapping it; the records of the others only refer to it.
+
+use crate::checkpoint::bar_sliding::{AllocationRef, BarSlidingCheckpoint};
+use crate::detector::ProcessGroup;
+use crate::restore::{BarRestore, RestoreMetadata};
+use crate::utils::lock;
+use crate::{GpuCheckpointError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Seek;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Name of the archive entry describing the group
+pub const GROUP_METADATA_ENTRY: &str = "group.json";
+
+/// Contents of the `group.json` archive entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMetadata {
+    pub members: Vec<GroupMember>,
+
+    /// Allocations whose data is stored in the record of another member
+    pub shared: Vec<SharedAllocation>,
+
+    /// Data bytes stored for all members
+    pub size_bytes: u64,
+    pub duration_ms: u64,
+}
+
+/// A process of the group and the archive entry holding its checkpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupMember {
+    pub pid: u32,
+    pub entry: String,
+    pub size_bytes: u64,
+    pub num_allocations: usize,
+
+    /// The member's GPU memory layout changed while it was copied
+    #[serde(default)]
+    pub layout_changed: bool,
+}
+
+/// Allocation of a member that maps memory stored with another member
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedAllocation {
+    pub pid: u32,
+    pub vaddr_start: u64,
+    pub stored_by: AllocationRef,
+}
+
+/// Checkpoint every member of `group` into the archive at `output_path`.
+/// All members are stopped before the first is copied, so the checkpoints
+/// are consistent with each other.
+pub fn checkpoint_group(
+    checkpoint: &BarSlidingCheckpoint,
+    group: &ProcessGroup,
+    output_path: &Path,
+) -> Result<GroupMetadata> {
+    if checkpoint.incremental_base().is_some() {
+        return Err(GpuCheckpointError::CheckpointError(
+            "process groups cannot be checkpointed incrementally".to_string(),
+        ));
+    }
+
+    info!(
+        "Checkpointing process group {:?} to {:?}",
+        group.pids(),
+        output_path
+    );
+    let start_time = Instant::now();
+
+    // Dropping the controllers resumes the members should a checkpoint fail
+    let mut controllers = Vec::new();
+    if checkpoint.freezes_for_copy() {
+        for member in &group.members {
+            let mut controller = checkpoint.controller(member);
+            controller.freeze_unless_stopped()?;
+            controllers.push(controller);
+        }
+    }
+
+    let shared_allocations = group.shared_allocations();
+    let mut shared: Vec<SharedAllocation> = shared_allocations
+        .iter()
+        .map(
+            |(&(pid, vaddr_start), &(owner, owner_start))| SharedAllocation {
+                pid,
+                vaddr_start,
+                stored_by: AllocationRef {
+                    pid: owner,
+                    vaddr_start: owner_start,
+                },
+            },
+        )
+        .collect();
+    shared.sort_by_key(|allocation| (allocation.pid, allocation.vaddr_start));
+
+    // Members are captured next to the archive, then appended to it
+    let staging_dir = output_path.parent().unwrap_or(Path::new("."));
+    let mut members = Vec::new();
+    let mut staged = Vec::new();
+    for member in &group.members {
+        let stored_elsewhere = shared
+            .iter()
+            .filter(|allocation| allocation.pid == member.pid)
+            .map(|allocation| (allocation.vaddr_start, allocation.stored_by))
+            .collect();
+        let staging = tempfile::NamedTempFile::new_in(staging_dir)?;
+        let metadata = checkpoint
+            .clone()
+            .with_stored_elsewhere(stored_elsewhere)
+            .checkpoint_process(member.pid, member, staging.path())?;
+
+        members.push(GroupMember {
+            pid: member.pid,
+            entry: format!("processes/{}.bin", member.pid),
+            size_bytes: metadata.size_bytes,
+            num_allocations: metadata.num_allocations,
+            layout_changed: metadata.layout_changed,
+        });
+        staged.push(staging);
+    }
+
+    for mut controller in controllers {
+        controller.resume()?;
+    }
+
+    let metadata = GroupMetadata {
+        size_bytes: members.iter().map(|member| member.size_bytes).sum(),
+        duration_ms: start_time.elapsed().as_millis() as u64,
+        members,
+        shared,
+    };
+    let encoded = serde_json::to_vec_pretty(&metadata)
+        .map_err(|e| GpuCheckpointError::CheckpointError(e.to_string()))?;
+
+    let mut output = lock::create_locked(output_path)?;
+    let mut builder = tar::Builder::new(&mut *output);
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let entry_header = |size: u64| {
+        let mut entry_header = tar::Header::new_gnu();
+        entry_header.set_size(size);
+        entry_header.set_mode(0o600);
+        entry_header.set_mtime(mtime);
+        entry_header
+    };
+
+    builder.append_data(
+        &mut entry_header(encoded.len() as u64),
+        GROUP_METADATA_ENTRY,
+        encoded.as_slice(),
+    )?;
+    for (member, staging) in metadata.members.iter().zip(&staged) {
+        let mut file = staging.reopen()?;
+        let size = file.seek(std::io::SeekFrom::End(0))?;
+        file.rewind()?;
+        builder.append_data(&mut entry_header(size), &member.entry, file)?;
+    }
+    builder.into_inner()?.sync_all()?;
+
+    info!(
+        "Checkpointed {} processes ({} shared allocations stored once) in {}ms",
+        metadata.members.len(),
+        metadata.shared.len(),
+        metadata.duration_ms
+    );
+    Ok(metadata)
+}
+
+/// Whether the archive at `path` holds a process group checkpoint
+pub fn is_group_checkpoint(path: &Path) -> Result<bool> {
+    // Other formats do not parse as tar entries at all
+    let mut archive = tar::Archive::new(File::open(path)?);
+    let Some(Ok(first)) = archive.entries()?.next() else {
+        return Ok(false);
+    };
+    Ok(first
+        .path()
+        .is_ok_and(|entry| entry.as_ref() == Path::new(GROUP_METADATA_ENTRY)))
+}
+
+/// Restore every member of the group checkpoint at `path`, into the
+/// process `pids` maps its original PID to or else the original PID
+pub fn restore_group(
+    restore: &BarRestore,
+    path: &Path,
+    pids: &HashMap<u32, u32>,
+) -> Result<Vec<RestoreMetadata>> {
+    info!("Restoring process group from {:?}", path);
+    let mut file = lock::open_shared(path)?;
+    let mut archive = tar::Archive::new(&mut *file);
+    let mut entries = archive.entries()?;
+
+    let mut metadata_entry = entries.next().ok_or_else(|| {
+        GpuCheckpointError::RestoreError("Group checkpoint archive is empty".to_string())
+    })??;
+    if metadata_entry.path()?.as_ref() != Path::new(GROUP_METADATA_ENTRY) {
+        return Err(GpuCheckpointError::RestoreError(format!(
+            "Group checkpoint archive does not start with {GROUP_METADATA_ENTRY}"
+        )));
+    }
+    let metadata: GroupMetadata = serde_json::from_reader(&mut metadata_entry).map_err(|e| {
+        GpuCheckpointError::RestoreError(format!("Invalid {GROUP_METADATA_ENTRY}: {e}"))
+    })?;
+
+    for original in pids.keys() {
+        if !metadata
+            .members
+            .iter()
+            .any(|member| member.pid == *original)
+        {
+            warn!("PID {} is not a member of the process group", original);
+        }
+    }
+
+    let mut restored = Vec::new();
+    for member in &metadata.members {
+        let mut entry = entries.next().ok_or_else(|| {
+            GpuCheckpointError::RestoreError(format!(
+                "Group checkpoint archive is missing {}",
+                member.entry
+            ))
+        })??;
+        if entry.path()?.as_ref() != Path::new(&member.entry) {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "Expected archive entry {} but found {}",
+                member.entry,
+                entry.path()?.display()
+            )));
+        }
+
+        let target = pids.get(&member.pid).copied().unwrap_or(member.pid);
+        restored.push(restore.restore_from_stream(&mut entry, Some(target))?);
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::{AllocationType, DetectionResult, GpuAllocation, GpuVendor};
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_shared_segment_stored_once() {
+        let dir = tempdir().unwrap();
+        let shm_dir = dir.path().join("shm");
+        std::fs::create_dir(&shm_dir).unwrap();
+        let segment = shm_dir.join("nccl-shm-test");
+        let contents: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&segment, &contents).unwrap();
+        let inode = std::fs::metadata(&segment).unwrap().ino();
+
+        // Two ranks mapping the same segment at different addresses; the
+        // PIDs are not running, the data comes from the segment itself
+        let members = [
+            (4_000_001, 0x7f00_0000_0000u64),
+            (4_000_002, 0x7e00_0000_0000),
+        ]
+        .into_iter()
+        .map(|(pid, start)| {
+            let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+            let mut alloc = GpuAllocation::new(
+                start,
+                start + contents.len() as u64,
+                AllocationType::Distributed,
+            );
+            alloc.metadata.backing_file = Some(segment.to_string_lossy().into_owned());
+            alloc.metadata.is_shared = true;
+            alloc.metadata.inode = Some(inode);
+            detection.add_allocation(alloc);
+            detection
+        })
+        .collect();
+        let group = ProcessGroup::new(members);
+        assert_eq!(group.total_gpu_memory(), contents.len() as u64);
+
+        let archive = dir.path().join("group.tar");
+        let checkpoint = BarSlidingCheckpoint::new().with_shm_dir(&shm_dir);
+        let metadata = checkpoint_group(&checkpoint, &group, &archive).unwrap();
+        assert!(is_group_checkpoint(&archive).unwrap());
+
+        // One stored copy, referenced by the second rank
+        assert_eq!(metadata.size_bytes, contents.len() as u64);
+        assert_eq!(metadata.members[0].size_bytes, contents.len() as u64);
+        assert_eq!(metadata.members[1].size_bytes, 0);
+        assert_eq!(
+            metadata.shared,
+            vec![SharedAllocation {
+                pid: 4_000_002,
+                vaddr_start: 0x7e00_0000_0000,
+                stored_by: AllocationRef {
+                    pid: 4_000_001,
+                    vaddr_start: 0x7f00_0000_0000,
+                },
+            }]
+        );
+
+        let mut tar = tar::Archive::new(File::open(&archive).unwrap());
+        let sizes: Vec<(String, u64)> = tar
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                (path, entry.size())
+            })
+            .collect();
+        assert_eq!(sizes[0].0, GROUP_METADATA_ENTRY);
+        assert_eq!(sizes[1].0, "processes/4000001.bin");
+        assert!(sizes[1].1 > contents.len() as u64);
+        assert!(sizes[2].1 < contents.len() as u64);
+
+        // Restoring the group writes the segment back once
+        std::fs::write(&segment, vec![0u8; contents.len()]).unwrap();
+        let restored = restore_group(
+            &BarRestore::new().with_shm_dir(&shm_dir),
+            &archive,
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[0].pid, 4_000_001);
+        assert_eq!(restored[1].pid, 4_000_002);
+        assert_eq!(std::fs::read(&segment).unwrap(), contents);
+    }
+}
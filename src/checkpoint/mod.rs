@@ -1,15 +1,50 @@
 pub mod bar_sliding;
+pub mod cuda;
+pub mod format;
+pub mod freeze;
+pub mod group;
+pub mod naming;
+pub mod retention;
+pub mod signing;
+pub mod snapshot;
+pub mod tuning;
 
-pub use bar_sliding::{BarSlidingCheckpoint, CheckpointMetadata as BarCheckpointMetadata};
+pub use bar_sliding::{
+    AllocationTiming, BarSlidingCheckpoint, CheckpointMetadata as BarCheckpointMetadata,
+};
+pub use cuda::{CudaCheckpointTool, CudaProcessState, CudaToggle};
+pub use format::{CheckpointFileFormat, CheckpointFormat};
+pub use freeze::{FreezeMethod, ProcessController};
+pub use group::GroupMetadata;
+pub use naming::{NameContext, NameTemplate};
+pub use retention::{prune_checkpoints, PruneReport};
 
-use crate::detector::DetectionResult;
-use crate::Result;
+use crate::detector::{
+    AllocationType, DetectionResult, GpuDeviceInfo, GpuVendor, ProcessGroup, ProcessScanner,
+};
+use crate::storage::{self, CheckpointSink, S3Config, S3Location, S3Sink};
+use crate::utils::compression::{Compression, CompressionThroughput, DEFAULT_COMPRESSION_LEVEL};
+use crate::utils::encryption::EncryptionKey;
+use crate::{GpuCheckpointError, Result};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use std::time::{Duration, SystemTime};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// `storage_path` that streams the checkpoint to stdout instead of a file
+pub const STDOUT_STORAGE: &str = "-";
+
+/// Directory checkpoints are stored in unless another is given
+pub const DEFAULT_STORAGE_PATH: &str = "/tmp/gpu-checkpoint";
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CheckpointStrategy {
+    /// Let the engine select a strategy from the detection at checkpoint time
+    Auto,
+
     /// Use CUDA checkpoint API (fastest, but limited)
     CudaCheckpoint,
 
@@ -23,13 +58,174 @@ pub enum CheckpointStrategy {
     SkipGpu,
 }
 
+impl FromStr for CheckpointStrategy {
+    type Err = GpuCheckpointError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(CheckpointStrategy::Auto),
+            "cuda" => Ok(CheckpointStrategy::CudaCheckpoint),
+            "bar-sliding" => Ok(CheckpointStrategy::BarSliding),
+            "hybrid" => Ok(CheckpointStrategy::Hybrid),
+            "skip-gpu" => Ok(CheckpointStrategy::SkipGpu),
+            _ => Err(GpuCheckpointError::StrategyError(format!(
+                "Unknown strategy: {s}"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for CheckpointStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointStrategy::Auto => write!(f, "auto"),
+            CheckpointStrategy::CudaCheckpoint => write!(f, "cuda"),
+            CheckpointStrategy::BarSliding => write!(f, "bar-sliding"),
+            CheckpointStrategy::Hybrid => write!(f, "hybrid"),
+            CheckpointStrategy::SkipGpu => write!(f, "skip-gpu"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CheckpointConfig {
     pub strategy: CheckpointStrategy,
+
+    /// Directory the checkpoint is written to, `STDOUT_STORAGE`, or an
+    /// `s3://bucket/prefix` to upload it under
     pub storage_path: String,
+
+    /// Storage bandwidth in MB/s, which estimates are made at
     pub bandwidth_mbps: u64,
+
+    /// Pace checkpoint writes to `bandwidth_mbps` instead of writing as
+    /// fast as storage allows. A bandwidth of 0 leaves writes unthrottled.
+    pub throttle: bool,
     pub timeout: Duration,
+
+    /// Compress allocation data with deflate at `compression_level`
     pub compression: bool,
+
+    /// Deflate level from 0 (store) to 9 (smallest)
+    pub compression_level: u32,
+
+    /// Windows of an allocation compressed concurrently
+    pub compression_threads: usize,
+
+    /// Experimental: only freeze while forking a copy-on-write snapshot,
+    /// then copy from the snapshot
+    pub cow_snapshot: bool,
+
+    /// Only copy resident pages, leaving the rest of each allocation as holes
+    pub limit_rss: bool,
+
+    /// Write never-touched pages as holes so the checkpoint file is sparse
+    pub sparse: bool,
+
+    /// Stop the target while its memory is copied
+    pub freeze: bool,
+
+    /// How the target is paused while its memory is copied
+    pub freeze_method: FreezeMethod,
+
+    /// On-disk layout of the checkpoint
+    pub format: CheckpointFileFormat,
+
+    /// File name of the checkpoint instead of `checkpoint_<pid>.<ext>`
+    pub name_template: Option<NameTemplate>,
+
+    /// Checkpoint file to write, relative to `storage_path`, instead of a
+    /// name derived from the PID or `name_template`. An existing file is
+    /// only overwritten with `force`.
+    pub output_file: Option<PathBuf>,
+
+    /// Overwrite an existing `output_file`
+    pub force: bool,
+
+    /// Ed25519 secret key used to sign the finished checkpoint
+    pub sign_key: Option<PathBuf>,
+
+    /// Record the target's GPU-related environment variables in the metadata
+    pub capture_env: bool,
+
+    /// Bytes copied per window instead of the default
+    pub window_size: Option<usize>,
+
+    /// Benchmark the storage path and use the fastest window size
+    pub auto_window: bool,
+
+    /// Migrate managed allocations to the host before copying them
+    pub managed_prefetch: bool,
+
+    /// Fail when the memory layout changes during the copy instead of only
+    /// flagging the checkpoint
+    pub abort_on_change: bool,
+
+    /// Strategy to use instead of the built-in heuristics, per GPU vendor
+    pub vendor_strategies: HashMap<GpuVendor, CheckpointStrategy>,
+
+    /// Number of allocations checkpointed concurrently
+    pub parallelism: usize,
+
+    /// Store windows repeated within the checkpoint only once
+    pub dedup: bool,
+
+    /// Only capture what changed since this checkpoint file
+    pub incremental_base: Option<PathBuf>,
+
+    /// Clear the target's soft-dirty bits so later incremental checkpoints
+    /// only capture what changed after this one
+    pub track_dirty: bool,
+
+    /// Read process memory with `process_vm_readv` where possible
+    pub process_vm: bool,
+
+    /// `nvidia-cuda-checkpoint` to run instead of the one on `PATH`
+    pub cuda_tool: Option<PathBuf>,
+
+    /// Encrypt allocation data with this key. Never serialized.
+    #[serde(skip)]
+    pub encryption_key: Option<EncryptionKey>,
+}
+
+impl CheckpointConfig {
+    /// Configuration checkpointing to `storage_path` with the bar-sliding
+    /// strategy and every option off
+    pub fn new(storage_path: String) -> Self {
+        Self {
+            strategy: CheckpointStrategy::BarSliding,
+            storage_path,
+            bandwidth_mbps: 1000,
+            throttle: false,
+            timeout: Duration::from_secs(300),
+            compression: false,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            compression_threads: 1,
+            parallelism: 1,
+            dedup: false,
+            incremental_base: None,
+            track_dirty: false,
+            process_vm: false,
+            cuda_tool: None,
+            encryption_key: None,
+            cow_snapshot: false,
+            limit_rss: false,
+            sparse: false,
+            freeze: true,
+            freeze_method: FreezeMethod::default(),
+            format: CheckpointFileFormat::Binary,
+            name_template: None,
+            output_file: None,
+            force: false,
+            sign_key: None,
+            capture_env: false,
+            window_size: None,
+            auto_window: false,
+            managed_prefetch: false,
+            abort_on_change: false,
+            vendor_strategies: HashMap::new(),
+        }
+    }
 }
 
 pub struct CheckpointEngine {
@@ -42,18 +238,472 @@ impl CheckpointEngine {
     }
 
     pub fn select_strategy(detection: &DetectionResult) -> CheckpointStrategy {
+        Self::select_strategy_with_overrides(detection, &HashMap::new())
+    }
+
+    /// Like `select_strategy`, along with a human readable reason for the
+    /// choice
+    pub fn select_strategy_explained(detection: &DetectionResult) -> (CheckpointStrategy, String) {
+        Self::select_strategy_explained_with_overrides(detection, &HashMap::new())
+    }
+
+    /// Strategy this engine uses for `detection`, resolving `Auto` with the
+    /// configured per-vendor overrides
+    pub fn resolve_strategy(&self, detection: &DetectionResult) -> CheckpointStrategy {
+        self.resolve_strategy_explained(detection).0
+    }
+
+    /// Like `resolve_strategy`, along with a human readable reason for the
+    /// choice
+    pub fn resolve_strategy_explained(
+        &self,
+        detection: &DetectionResult,
+    ) -> (CheckpointStrategy, String) {
+        match self._config.strategy {
+            CheckpointStrategy::Auto => {
+                let (strategy, reason) = Self::select_strategy_explained_with_overrides(
+                    detection,
+                    &self._config.vendor_strategies,
+                );
+                if strategy == CheckpointStrategy::CudaCheckpoint && self.cuda_tool().is_none() {
+                    return (
+                        CheckpointStrategy::BarSliding,
+                        format!(
+                            "{reason}, but {} is not on PATH, so BAR sliding is used instead",
+                            cuda::CUDA_CHECKPOINT_TOOL
+                        ),
+                    );
+                }
+                (strategy, reason)
+            }
+            strategy => (
+                strategy,
+                format!("strategy {strategy} was requested explicitly"),
+            ),
+        }
+    }
+
+    /// Predict the size and duration of checkpointing `detection` at the
+    /// configured bandwidth, without touching the process or storage
+    pub fn estimate(&self, pid: u32, detection: &DetectionResult) -> Result<CheckpointEstimate> {
+        if self._config.bandwidth_mbps == 0 {
+            return Err(GpuCheckpointError::CheckpointError(
+                "cannot estimate a checkpoint at a bandwidth of 0 MB/s".to_string(),
+            ));
+        }
+
+        let (strategy, reason) = self.resolve_strategy_explained(detection);
+        let size_bytes: u64 = detection.allocations.iter().map(|a| a.size).sum();
+        let secs = (size_bytes as f64 / (1024.0 * 1024.0)) / self._config.bandwidth_mbps as f64;
+
+        Ok(CheckpointEstimate {
+            pid,
+            strategy,
+            reason,
+            num_allocations: detection.allocations.len(),
+            size_bytes,
+            bandwidth_mbps: self._config.bandwidth_mbps,
+            duration_ms: (secs * 1000.0).ceil() as u64,
+        })
+    }
+
+    /// Checkpoint every member of `group` into one archive, stopping them
+    /// all for the copy and storing memory they share once. Returns the
+    /// group metadata and the archive's path.
+    pub fn checkpoint_group(&self, group: &ProcessGroup) -> Result<(GroupMetadata, PathBuf)> {
+        let Some(first) = group.members.first() else {
+            return Err(GpuCheckpointError::CheckpointError(
+                "the process group has no members".to_string(),
+            ));
+        };
+        if self._config.storage_path == STDOUT_STORAGE {
+            return Err(GpuCheckpointError::CheckpointError(
+                "a process group cannot be streamed to stdout".to_string(),
+            ));
+        }
+        if storage::is_object_url(&self._config.storage_path) {
+            return Err(GpuCheckpointError::CheckpointError(
+                "a process group cannot be uploaded to an object store".to_string(),
+            ));
+        }
+        if self._config.sign_key.is_some() {
+            return Err(GpuCheckpointError::CheckpointError(
+                "process group checkpoints cannot be signed".to_string(),
+            ));
+        }
+
+        let (bar_checkpoint, _) = self.bar_sliding_checkpoint()?;
+        let file_name =
+            if self._config.name_template.is_some() || self._config.output_file.is_some() {
+                self.checkpoint_file_name(
+                    first.pid,
+                    CheckpointStrategy::BarSliding,
+                    CheckpointFileFormat::Tar.implementation(),
+                )
+            } else {
+                format!("group_{}.tar", first.pid)
+            };
+        let output_path = PathBuf::from(&self._config.storage_path).join(file_name);
+        self.check_overwrite(&output_path)?;
+        let metadata = group::checkpoint_group(&bar_checkpoint, group, &output_path)?;
+        Ok((metadata, output_path))
+    }
+
+    /// BAR sliding checkpoint set up from the configuration, along with the
+    /// window size `auto_window` calibration picked
+    fn bar_sliding_checkpoint(&self) -> Result<(BarSlidingCheckpoint, Option<usize>)> {
+        let mut bar_checkpoint = BarSlidingCheckpoint::new();
+        let mut tuned_window_size = None;
+        if let Some(window_size) = self._config.window_size {
+            let window_size = tuning::clamp_to_bar(window_size, tuning::gpu_bar_size());
+            bar_checkpoint = bar_checkpoint.with_window_size(window_size);
+        } else if self._config.auto_window && self._config.storage_path == STDOUT_STORAGE {
+            warn!("No storage to calibrate the window size on when streaming to stdout");
+        } else if self._config.auto_window {
+            match tuning::calibrate_window_size(Path::new(&self._config.storage_path)) {
+                Ok(calibration) => {
+                    bar_checkpoint = bar_checkpoint.with_window_size(calibration.window_size);
+                    tuned_window_size = Some(calibration.window_size);
+                }
+                Err(e) => warn!("Window size calibration failed, using the default: {}", e),
+            }
+        }
+        let bar_checkpoint = bar_checkpoint
+            .with_cow_snapshot(self._config.cow_snapshot)
+            .with_limit_rss(self._config.limit_rss)
+            .with_sparse(self._config.sparse)
+            .with_managed_prefetch(self._config.managed_prefetch)
+            .with_abort_on_layout_change(self._config.abort_on_change)
+            .with_compression(if self._config.compression {
+                Compression::deflate(self._config.compression_level)?
+            } else {
+                Compression::None
+            })
+            .with_compression_threads(self._config.compression_threads)
+            .with_parallelism(self._config.parallelism)
+            .with_bandwidth_limit(if self._config.throttle {
+                self._config.bandwidth_mbps
+            } else {
+                0
+            })
+            .with_dedup(self._config.dedup)
+            .with_dirty_tracking(self._config.track_dirty)
+            .with_process_vm(self._config.process_vm)
+            .with_freeze(self._config.freeze)
+            .with_freeze_method(self._config.freeze_method);
+        let bar_checkpoint = match &self._config.incremental_base {
+            Some(base) => bar_checkpoint.with_incremental_base(base),
+            None => bar_checkpoint,
+        };
+        let bar_checkpoint = match &self._config.encryption_key {
+            Some(key) => bar_checkpoint.with_encryption_key(key.clone()),
+            None => bar_checkpoint,
+        };
+        Ok((bar_checkpoint, tuned_window_size))
+    }
+
+    /// Copy the allocations of `detection` with BAR sliding into a checkpoint
+    /// named for `strategy`, without writing its manifest
+    async fn checkpoint_bar_sliding(
+        &self,
+        pid: u32,
+        detection: &DetectionResult,
+        strategy: CheckpointStrategy,
+        environment: BTreeMap<String, String>,
+    ) -> Result<CheckpointMetadata> {
+        let storage = &self._config.storage_path;
+        let uploading = storage::is_object_url(storage);
+        let streaming = storage == STDOUT_STORAGE || uploading;
+        let destination = if uploading {
+            "an object store"
+        } else {
+            "stdout"
+        };
+        let (bar_checkpoint, tuned_window_size) = self.bar_sliding_checkpoint()?;
+        let (bar_metadata, output_path, url) = if streaming {
+            // The signature trailer covers the finished file and a tar archive
+            // is re-encoded from one
+            if self._config.sign_key.is_some() {
+                return Err(GpuCheckpointError::CheckpointError(format!(
+                    "a checkpoint streamed to {destination} cannot be signed"
+                )));
+            }
+            if self._config.format != CheckpointFileFormat::Binary {
+                return Err(GpuCheckpointError::CheckpointError(format!(
+                    "only the bin format can be streamed to {destination}"
+                )));
+            }
+            if uploading {
+                let name =
+                    self.checkpoint_file_name(pid, strategy, self._config.format.implementation());
+                let location = S3Location::parse(storage)?.join(&name);
+                let sink = S3Sink::new(S3Config::from_env(), location)?;
+                let url = sink.location();
+                // The sink waits on the upload, so the copy runs where it
+                // may block
+                let detection = detection.clone();
+                let bar_metadata = tokio::task::spawn_blocking(move || {
+                    bar_checkpoint.checkpoint_to_sink(pid, &detection, sink)
+                })
+                .await
+                .map_err(|e| {
+                    GpuCheckpointError::CheckpointError(format!("upload to {url} failed: {e}"))
+                })??;
+                (bar_metadata, None, Some(url))
+            } else {
+                let bar_metadata = bar_checkpoint.checkpoint_to_writer(
+                    pid,
+                    detection,
+                    std::io::stdout().lock(),
+                )?;
+                (bar_metadata, None, None)
+            }
+        } else {
+            let format = self._config.format.implementation();
+            let output_path = PathBuf::from(&self._config.storage_path)
+                .join(self.checkpoint_file_name(pid, strategy, format));
+            self.check_overwrite(&output_path)?;
+
+            let bar_metadata = format.write(&bar_checkpoint, pid, detection, &output_path)?;
+
+            if let Some(key_path) = &self._config.sign_key {
+                let key = signing::load_signing_key(key_path)?;
+                signing::sign_checkpoint(&output_path, &key)?;
+            }
+            (bar_metadata, Some(output_path), None)
+        };
+
+        Ok(CheckpointMetadata {
+            pid,
+            strategy_used: strategy,
+            timestamp: SystemTime::now(),
+            size_bytes: bar_metadata.size_bytes,
+            duration_ms: bar_metadata.duration_ms,
+            signed: self._config.sign_key.is_some(),
+            gpus: detection.gpus.clone(),
+            environment,
+            tuned_window_size,
+            layout_changed: bar_metadata.layout_changed,
+            allocation_timings: bar_metadata.allocation_timings,
+            compression: bar_metadata.compression,
+            cuda_toggle: None,
+            path: output_path,
+            manifest: None,
+            base_checkpoint: self._config.incremental_base.clone(),
+            command: None,
+            url,
+        })
+    }
+
+    /// The CUDA checkpoint tool, if it is installed
+    fn cuda_tool(&self) -> Option<CudaCheckpointTool> {
+        match &self._config.cuda_tool {
+            Some(path) => Some(CudaCheckpointTool::at(path)),
+            None => CudaCheckpointTool::find(),
+        }
+    }
+
+    /// Like `cuda_tool`, but failing when it is not installed
+    fn require_cuda_tool(&self, strategy: CheckpointStrategy) -> Result<CudaCheckpointTool> {
+        self.cuda_tool().ok_or_else(|| {
+            GpuCheckpointError::CheckpointError(format!(
+                "{} not found on PATH; it is needed for the {strategy} strategy",
+                cuda::CUDA_CHECKPOINT_TOOL
+            ))
+        })
+    }
+
+    /// Write the manifest of `metadata` next to its checkpoint file. A
+    /// checkpoint that only toggled CUDA state has no file, so its manifest
+    /// goes in the storage directory under the checkpoint's name, where
+    /// restore finds the toggle to reverse.
+    fn write_manifest(&self, metadata: &mut CheckpointMetadata) -> Result<()> {
+        let manifest = match (&metadata.path, &metadata.cuda_toggle) {
+            (Some(path), _) => CheckpointMetadata::manifest_path(path),
+            (None, None) => return Ok(()),
+            (None, Some(toggle)) => {
+                let storage = &self._config.storage_path;
+                if metadata.url.is_some()
+                    || storage == STDOUT_STORAGE
+                    || storage::is_object_url(storage)
+                {
+                    warn!(
+                        "No manifest is kept outside a storage directory; reverse the CUDA \
+                         toggle of PID {} with {} --toggle --pid {}",
+                        metadata.pid,
+                        toggle.tool.display(),
+                        metadata.pid
+                    );
+                    return Ok(());
+                }
+                let name = self.checkpoint_file_name(
+                    metadata.pid,
+                    metadata.strategy_used,
+                    CheckpointFileFormat::Binary.implementation(),
+                );
+                CheckpointMetadata::manifest_path(&Path::new(storage).join(name))
+            }
+        };
+        metadata.manifest = Some(manifest.clone());
+        metadata.write_manifest(&manifest)
+    }
+
+    /// Refuse to replace an existing file named by `output_file` unless
+    /// `force` is set. Derived names are overwritten as before.
+    fn check_overwrite(&self, output_path: &Path) -> Result<()> {
+        if self._config.output_file.is_some() && !self._config.force && output_path.exists() {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "{} already exists, refusing to overwrite it without force",
+                output_path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Name of the checkpoint file for `pid`: the configured output file,
+    /// else rendered from the configured template if any
+    fn checkpoint_file_name(
+        &self,
+        pid: u32,
+        strategy: CheckpointStrategy,
+        format: &dyn CheckpointFormat,
+    ) -> String {
+        if let Some(output_file) = &self._config.output_file {
+            return output_file.to_string_lossy().into_owned();
+        }
+        match &self._config.name_template {
+            Some(template) => template.render(&NameContext {
+                pid,
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+                hostname: &crate::utils::hostname(),
+                strategy,
+            }),
+            None => format!("checkpoint_{pid}.{}", format.extension()),
+        }
+    }
+
+    /// Select a strategy, preferring a configured per-vendor override over
+    /// the built-in heuristics. Overrides never apply to a process without
+    /// GPU allocations, and an `Auto` override falls back to the heuristics.
+    pub fn select_strategy_with_overrides(
+        detection: &DetectionResult,
+        overrides: &HashMap<GpuVendor, CheckpointStrategy>,
+    ) -> CheckpointStrategy {
+        Self::select_strategy_explained_with_overrides(detection, overrides).0
+    }
+
+    /// `select_strategy_with_overrides` along with a human readable reason
+    /// for the choice
+    pub fn select_strategy_explained_with_overrides(
+        detection: &DetectionResult,
+        overrides: &HashMap<GpuVendor, CheckpointStrategy>,
+    ) -> (CheckpointStrategy, String) {
         // If no allocations, we can skip GPU
         if detection.allocations.is_empty() {
-            return CheckpointStrategy::SkipGpu;
+            return (
+                CheckpointStrategy::SkipGpu,
+                "no GPU allocations were detected, so there is no GPU state to checkpoint"
+                    .to_string(),
+            );
+        }
+
+        if let Some(strategy) = overrides
+            .get(&detection.vendor)
+            .filter(|strategy| **strategy != CheckpointStrategy::Auto)
+        {
+            return (
+                *strategy,
+                format!(
+                    "strategy {strategy} is configured for {} GPUs",
+                    detection.vendor
+                ),
+            );
+        }
+
+        match detection.vendor {
+            GpuVendor::Nvidia => {
+                // If we have problematic allocations, must use BAR sliding
+                if detection.has_problematic_allocations() {
+                    return (
+                        CheckpointStrategy::BarSliding,
+                        format!(
+                            "{} not CUDA-checkpointable, so BAR sliding is required",
+                            Self::describe_problematic(detection)
+                        ),
+                    );
+                }
+
+                // Otherwise, CUDA checkpoint should work. Pinned host memory
+                // is ordinary process memory and is captured along with it.
+                let (pinned, device): (Vec<_>, Vec<_>) = detection
+                    .allocations
+                    .iter()
+                    .partition(|a| a.alloc_type == AllocationType::HostPinned);
+                let mut reason = format!(
+                    "all {} device allocations ({}) are standard device memory, which the CUDA \
+                     checkpoint API can capture",
+                    device.len(),
+                    crate::utils::format_memory(device.iter().map(|a| a.size).sum())
+                );
+                if !pinned.is_empty() {
+                    reason.push_str(&format!(
+                        "; {} pinned host allocation{} ({}) {} captured with process memory",
+                        pinned.len(),
+                        if pinned.len() == 1 { "" } else { "s" },
+                        crate::utils::format_memory(pinned.iter().map(|a| a.size).sum()),
+                        if pinned.len() == 1 { "is" } else { "are" }
+                    ));
+                }
+                (CheckpointStrategy::CudaCheckpoint, reason)
+            }
+            // The CUDA checkpoint API is NVIDIA only, BAR sliding works everywhere
+            GpuVendor::Amd | GpuVendor::Intel | GpuVendor::Unknown => (
+                CheckpointStrategy::BarSliding,
+                format!(
+                    "the CUDA checkpoint API only supports NVIDIA GPUs, so {} GPUs use BAR sliding",
+                    detection.vendor
+                ),
+            ),
         }
+    }
+
+    /// "12 UVM allocations (3.20 GiB) and 1 IPC allocation (64.00 MiB) are"
+    fn describe_problematic(detection: &DetectionResult) -> String {
+        let mut parts = Vec::new();
+        let mut count = 0;
+        for alloc_type in [
+            AllocationType::Uvm,
+            AllocationType::Managed,
+            AllocationType::Ipc,
+            AllocationType::Distributed,
+        ] {
+            let allocations: Vec<_> = detection
+                .allocations
+                .iter()
+                .filter(|a| a.alloc_type == alloc_type)
+                .collect();
+            if allocations.is_empty() {
+                continue;
+            }
 
-        // If we have problematic allocations, must use BAR sliding
-        if detection.has_problematic_allocations() {
-            return CheckpointStrategy::BarSliding;
+            count += allocations.len();
+            parts.push(format!(
+                "{} {} allocation{} ({})",
+                allocations.len(),
+                alloc_type,
+                if allocations.len() == 1 { "" } else { "s" },
+                crate::utils::format_memory(allocations.iter().map(|a| a.size).sum())
+            ));
         }
 
-        // Otherwise, CUDA checkpoint should work
-        CheckpointStrategy::CudaCheckpoint
+        let listed = match parts.split_last() {
+            Some((last, [])) => last.clone(),
+            Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+            None => String::new(),
+        };
+        format!("{listed} {}", if count == 1 { "is" } else { "are" })
     }
 
     pub async fn checkpoint(
@@ -64,31 +714,136 @@ impl CheckpointEngine {
         use std::time::Instant;
         let start = Instant::now();
 
-        match self._config.strategy {
+        if detection.is_mps {
+            warn!(
+                "PID {} uses MPS; state held by the MPS server is not captured and the checkpoint may be inconsistent",
+                pid
+            );
+        }
+
+        let environment = if self._config.capture_env {
+            ProcessScanner::gpu_environment(pid).unwrap_or_else(|e| {
+                warn!("Cannot read the environment of PID {}: {}", pid, e);
+                BTreeMap::new()
+            })
+        } else {
+            BTreeMap::new()
+        };
+        let command = LaunchCommand::capture(pid).unwrap_or_else(|e| {
+            warn!("Cannot read the command line of PID {}: {}", pid, e);
+            None
+        });
+
+        match self.resolve_strategy(detection) {
+            CheckpointStrategy::Auto => {
+                unreachable!("Auto is resolved before dispatching on the strategy")
+            }
             CheckpointStrategy::BarSliding => {
                 // Use BAR sliding for problematic allocations
-                let bar_checkpoint = BarSlidingCheckpoint::new();
-                let output_path =
-                    PathBuf::from(&self._config.storage_path).join(format!("checkpoint_{pid}.bin"));
-
-                let bar_metadata =
-                    bar_checkpoint.checkpoint_process(pid, detection, &output_path)?;
+                let mut metadata = self
+                    .checkpoint_bar_sliding(
+                        pid,
+                        detection,
+                        CheckpointStrategy::BarSliding,
+                        environment,
+                    )
+                    .await?;
+                metadata.command = command;
+                self.write_manifest(&mut metadata)?;
+                Ok(metadata)
+            }
+            CheckpointStrategy::CudaCheckpoint => {
+                let tool = self.require_cuda_tool(CheckpointStrategy::CudaCheckpoint)?;
+                let toggle = tool.checkpoint(pid)?;
 
-                Ok(CheckpointMetadata {
+                let mut metadata = CheckpointMetadata {
                     pid,
-                    strategy_used: CheckpointStrategy::BarSliding,
+                    strategy_used: CheckpointStrategy::CudaCheckpoint,
                     timestamp: SystemTime::now(),
-                    size_bytes: bar_metadata.size_bytes,
-                    duration_ms: bar_metadata.duration_ms,
-                })
-            }
-            CheckpointStrategy::CudaCheckpoint => {
-                // TODO: Implement CUDA checkpoint
-                todo!("CUDA checkpoint not yet implemented")
+                    // Device memory now lives in the process's host memory,
+                    // nothing is written by this strategy
+                    size_bytes: 0,
+                    duration_ms: start.elapsed().as_millis() as u64,
+                    signed: false,
+                    gpus: detection.gpus.clone(),
+                    environment,
+                    tuned_window_size: None,
+                    layout_changed: false,
+                    allocation_timings: Vec::new(),
+                    compression: None,
+                    cuda_toggle: Some(toggle),
+                    path: None,
+                    manifest: None,
+                    base_checkpoint: None,
+                    command,
+                    url: None,
+                };
+                self.write_manifest(&mut metadata)?;
+                Ok(metadata)
             }
             CheckpointStrategy::Hybrid => {
-                // TODO: Implement hybrid approach
-                todo!("Hybrid checkpoint not yet implemented")
+                // UVM, managed, IPC and distributed memory is copied with
+                // BAR sliding, the rest is moved to the host by the CUDA
+                // checkpoint API
+                let problematic = detection.filtered(|a| a.is_problematic());
+                let clean = detection.filtered(|a| !a.is_problematic());
+                let tool = if clean.allocations.is_empty() {
+                    None
+                } else {
+                    Some(self.require_cuda_tool(CheckpointStrategy::Hybrid)?)
+                };
+                info!(
+                    "Hybrid checkpoint of PID {}: {} allocations with BAR sliding, {} with CUDA \
+                     checkpoint",
+                    pid,
+                    problematic.allocations.len(),
+                    clean.allocations.len()
+                );
+
+                // The BAR copy runs first, while device memory is still on
+                // the GPU
+                let mut metadata = if problematic.allocations.is_empty() {
+                    CheckpointMetadata {
+                        pid,
+                        strategy_used: CheckpointStrategy::Hybrid,
+                        timestamp: SystemTime::now(),
+                        size_bytes: 0,
+                        duration_ms: 0,
+                        signed: false,
+                        gpus: Vec::new(),
+                        environment,
+                        tuned_window_size: None,
+                        layout_changed: false,
+                        allocation_timings: Vec::new(),
+                        compression: None,
+                        cuda_toggle: None,
+                        path: None,
+                        manifest: None,
+                        base_checkpoint: None,
+                        command: None,
+                        url: None,
+                    }
+                } else {
+                    self.checkpoint_bar_sliding(
+                        pid,
+                        &problematic,
+                        CheckpointStrategy::Hybrid,
+                        environment,
+                    )
+                    .await?
+                };
+                if let Some(tool) = tool {
+                    metadata.cuda_toggle = Some(tool.checkpoint(pid)?);
+                }
+
+                metadata.strategy_used = CheckpointStrategy::Hybrid;
+                metadata.gpus = detection.gpus.clone();
+                metadata.command = command;
+                metadata.duration_ms = start.elapsed().as_millis() as u64;
+                // Without problematic allocations there is no checkpoint
+                // file, only the manifest recording the toggle
+                self.write_manifest(&mut metadata)?;
+                Ok(metadata)
             }
             CheckpointStrategy::SkipGpu => {
                 // No GPU state to checkpoint
@@ -98,12 +853,40 @@ impl CheckpointEngine {
                     timestamp: SystemTime::now(),
                     size_bytes: 0,
                     duration_ms: start.elapsed().as_millis() as u64,
+                    signed: false,
+                    gpus: detection.gpus.clone(),
+                    environment,
+                    tuned_window_size: None,
+                    layout_changed: false,
+                    allocation_timings: Vec::new(),
+                    compression: None,
+                    cuda_toggle: None,
+                    path: None,
+                    manifest: None,
+                    base_checkpoint: None,
+                    command,
+                    url: None,
                 })
             }
         }
     }
 }
 
+/// What a checkpoint would write and how long it would take, from
+/// `CheckpointEngine::estimate`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckpointEstimate {
+    pub pid: u32,
+    pub strategy: CheckpointStrategy,
+
+    /// Why `strategy` would be used
+    pub reason: String,
+    pub num_allocations: usize,
+    pub size_bytes: u64,
+    pub bandwidth_mbps: u64,
+    pub duration_ms: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CheckpointMetadata {
     pub pid: u32,
@@ -111,4 +894,238 @@ pub struct CheckpointMetadata {
     pub timestamp: SystemTime,
     pub size_bytes: u64,
     pub duration_ms: u64,
+
+    /// Whether a signature trailer was appended to the checkpoint
+    #[serde(default)]
+    pub signed: bool,
+
+    /// GPUs the checkpointed process used
+    #[serde(default)]
+    pub gpus: Vec<GpuDeviceInfo>,
+
+    /// GPU-related environment variables of the process, with `--capture-env`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environment: BTreeMap<String, String>,
+
+    /// Window size picked by `--auto-window` calibration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tuned_window_size: Option<usize>,
+
+    /// GPU mappings changed while memory was copied, so the checkpoint may
+    /// be inconsistent
+    #[serde(default)]
+    pub layout_changed: bool,
+
+    /// Time taken by each allocation the bar-sliding strategy copied
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allocation_timings: Vec<AllocationTiming>,
+
+    /// Bytes the bar-sliding strategy compressed and how fast, apart from
+    /// the throughput of the checkpoint as a whole
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionThroughput>,
+
+    /// CUDA state toggled by the cuda strategy, reversed on restore
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cuda_toggle: Option<CudaToggle>,
+
+    /// Checkpoint file written by the strategy, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+
+    /// Manifest the metadata was written to. A strategy that only toggled
+    /// CUDA state writes no checkpoint file, only this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<PathBuf>,
+
+    /// Checkpoint an incremental checkpoint only holds the changes since
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_checkpoint: Option<PathBuf>,
+
+    /// How the process was started, for `restore --spawn`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<LaunchCommand>,
+
+    /// Object store location the checkpoint was uploaded to, in place of
+    /// `path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// JSON Schema (draft 2020-12) of `CheckpointMetadata`, the manifest written
+/// next to bar-sliding checkpoints and the result `--append-log` records for
+/// a checkpoint. Hand-written like `detection_schema`, whose `GpuDeviceInfo`
+/// it shares.
+pub fn checkpoint_schema() -> serde_json::Value {
+    use serde_json::json;
+
+    let unsigned = json!({ "type": "integer", "minimum": 0 });
+    let path = json!({ "type": "string" });
+    let cuda_state = json!({ "enum": ["running", "locked", "checkpointed", "failed"] });
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "CheckpointMetadata",
+        "description": "Checkpoint manifest of gpu-checkpoint checkpoint",
+        "type": "object",
+        "required": ["pid", "strategy_used", "timestamp", "size_bytes", "duration_ms"],
+        "properties": {
+            "pid": unsigned,
+            "strategy_used": { "$ref": "#/$defs/CheckpointStrategy" },
+            "timestamp": {
+                "type": "object",
+                "required": ["secs_since_epoch", "nanos_since_epoch"],
+                "properties": {
+                    "secs_since_epoch": unsigned,
+                    "nanos_since_epoch": unsigned
+                }
+            },
+            "size_bytes": unsigned,
+            "duration_ms": unsigned,
+            "signed": { "type": "boolean" },
+            "gpus": { "type": "array", "items": { "$ref": "#/$defs/GpuDeviceInfo" } },
+            "environment": { "type": "object", "additionalProperties": { "type": "string" } },
+            "tuned_window_size": unsigned,
+            "layout_changed": { "type": "boolean" },
+            "allocation_timings": {
+                "type": "array",
+                "items": { "$ref": "#/$defs/AllocationTiming" }
+            },
+            "compression": { "$ref": "#/$defs/CompressionThroughput" },
+            "cuda_toggle": { "$ref": "#/$defs/CudaToggle" },
+            "path": path,
+            "manifest": path,
+            "base_checkpoint": path,
+            "command": { "$ref": "#/$defs/LaunchCommand" },
+            "url": { "type": "string" }
+        },
+        "$defs": {
+            "CheckpointStrategy": {
+                "enum": ["Auto", "CudaCheckpoint", "BarSliding", "Hybrid", "SkipGpu"]
+            },
+            "GpuDeviceInfo": crate::detector::detection_schema()["$defs"]["GpuDeviceInfo"].clone(),
+            "AllocationTiming": {
+                "type": "object",
+                "required": ["vaddr_start", "size", "duration_ms", "mb_per_sec"],
+                "properties": {
+                    "vaddr_start": unsigned,
+                    "size": unsigned,
+                    "duration_ms": unsigned,
+                    "mb_per_sec": { "type": "number", "minimum": 0 }
+                }
+            },
+            "CompressionThroughput": {
+                "type": "object",
+                "required": ["raw_bytes", "compressed_bytes", "duration_ms", "mb_per_sec"],
+                "properties": {
+                    "raw_bytes": unsigned,
+                    "compressed_bytes": unsigned,
+                    "duration_ms": unsigned,
+                    "mb_per_sec": { "type": "number", "minimum": 0 }
+                }
+            },
+            "CudaToggle": {
+                "type": "object",
+                "required": ["tool", "state_before", "state_after"],
+                "properties": {
+                    "tool": path,
+                    "state_before": cuda_state,
+                    "state_after": cuda_state
+                }
+            },
+            "LaunchCommand": {
+                "type": "object",
+                "required": ["argv"],
+                "properties": {
+                    "argv": { "type": "array", "items": { "type": "string" } },
+                    "cwd": path
+                }
+            }
+        }
+    })
+}
+
+/// Command line and working directory a process was started with. Its
+/// environment is not recorded beyond the GPU variables of `--capture-env`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LaunchCommand {
+    /// Program and arguments
+    pub argv: Vec<String>,
+
+    /// Working directory, if it could be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+}
+
+impl LaunchCommand {
+    /// How `pid` was started. `None` for kernel threads, which have no
+    /// command line.
+    pub fn capture(pid: u32) -> Result<Option<Self>> {
+        let argv = ProcessScanner::process_argv(pid)?;
+        if argv.first().is_none_or(|program| program.is_empty()) {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            argv,
+            cwd: ProcessScanner::process_cwd(pid).ok(),
+        }))
+    }
+}
+
+impl CheckpointMetadata {
+    /// Allocation that took longest to checkpoint
+    pub fn slowest_allocation(&self) -> Option<&AllocationTiming> {
+        self.allocation_timings
+            .iter()
+            .max_by_key(|timing| timing.duration_ms)
+    }
+
+    /// Manifest written next to `checkpoint`: the same name with a `.json`
+    /// extension, e.g. `checkpoint_<pid>.json` for `checkpoint_<pid>.bin`
+    pub fn manifest_path(checkpoint: &Path) -> PathBuf {
+        let manifest = checkpoint.with_extension("json");
+        if manifest == checkpoint {
+            // Never overwrite a checkpoint that was itself named .json
+            let mut name = checkpoint.as_os_str().to_owned();
+            name.push(".manifest.json");
+            PathBuf::from(name)
+        } else {
+            manifest
+        }
+    }
+
+    /// Write the metadata as a JSON manifest
+    pub fn write_manifest(&self, manifest: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(|e| {
+            GpuCheckpointError::CheckpointError(format!("Cannot serialize metadata: {e}"))
+        })?;
+        std::fs::write(manifest, json)?;
+        Ok(())
+    }
+
+    /// Read a manifest written by `write_manifest`
+    pub fn read_manifest(manifest: &Path) -> Result<Self> {
+        let json = std::fs::read(manifest)?;
+        serde_json::from_slice(&json).map_err(|e| {
+            GpuCheckpointError::RestoreError(format!(
+                "Invalid checkpoint manifest {}: {e}",
+                manifest.display()
+            ))
+        })
+    }
+
+    /// Checkpoint file the manifest at `manifest` describes. The manifest
+    /// lives next to the checkpoint, so a file of the recorded name in its
+    /// directory is preferred; that keeps working when both were moved.
+    pub fn checkpoint_file(&self, manifest: &Path) -> Option<PathBuf> {
+        let path = self.path.as_ref()?;
+        let sibling = manifest
+            .parent()
+            .zip(path.file_name())
+            .map(|(dir, name)| dir.join(name));
+        match sibling {
+            Some(sibling) if sibling.exists() => Some(sibling),
+            _ => Some(path.clone()),
+        }
+    }
 }
@@ -0,0 +1,152 @@
+use crate::checkpoint::CheckpointStrategy;
+use crate::{GpuCheckpointError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Placeholders a name template may contain
+const PLACEHOLDERS: &[&str] = &["pid", "timestamp", "hostname", "strategy"];
+
+/// Values substituted into a `NameTemplate`
+#[derive(Debug, Clone)]
+pub struct NameContext<'a> {
+    pub pid: u32,
+    pub timestamp: u64,
+    pub hostname: &'a str,
+    pub strategy: CheckpointStrategy,
+}
+
+/// Checkpoint file name with `{pid}`, `{timestamp}`, `{hostname}` and
+/// `{strategy}` placeholders, e.g. `{hostname}-{pid}-{timestamp}.bin`.
+/// Placeholders are validated when the template is parsed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NameTemplate {
+    template: String,
+}
+
+impl NameTemplate {
+    /// File name for a checkpoint described by `context`
+    pub fn render(&self, context: &NameContext) -> String {
+        let mut name = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        while let Some(open) = rest.find('{') {
+            name.push_str(&rest[..open]);
+            // Balanced braces were checked when parsing
+            let close = open + rest[open..].find('}').unwrap();
+            match &rest[open + 1..close] {
+                "pid" => name.push_str(&context.pid.to_string()),
+                "timestamp" => name.push_str(&context.timestamp.to_string()),
+                "hostname" => name.push_str(context.hostname),
+                _ => name.push_str(&context.strategy.to_string()),
+            }
+            rest = &rest[close + 1..];
+        }
+        name.push_str(rest);
+        name
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = GpuCheckpointError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: String| {
+            GpuCheckpointError::CheckpointError(format!("Invalid name template {s:?}: {reason}"))
+        };
+
+        if s.is_empty() || s.contains('/') {
+            return Err(invalid("must be a non-empty file name".to_string()));
+        }
+
+        let mut rest = s;
+        while let Some(open) = rest.find(['{', '}']) {
+            if rest[open..].starts_with('}') {
+                return Err(invalid("unmatched '}'".to_string()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .map(|offset| open + offset)
+                .ok_or_else(|| invalid("unmatched '{'".to_string()))?;
+
+            let placeholder = &rest[open + 1..close];
+            if !PLACEHOLDERS.contains(&placeholder) {
+                return Err(invalid(format!(
+                    "unknown placeholder {{{placeholder}}}, expected one of {}",
+                    PLACEHOLDERS
+                        .iter()
+                        .map(|p| format!("{{{p}}}"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+            rest = &rest[close + 1..];
+        }
+
+        Ok(Self {
+            template: s.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for NameTemplate {
+    type Error = GpuCheckpointError;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<NameTemplate> for String {
+    fn from(template: NameTemplate) -> Self {
+        template.template
+    }
+}
+
+impl fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let template: NameTemplate = "{hostname}-{pid}-{timestamp}-{strategy}.ckpt"
+            .parse()
+            .unwrap();
+        let context = NameContext {
+            pid: 1234,
+            timestamp: 1700000000,
+            hostname: "node7",
+            strategy: CheckpointStrategy::BarSliding,
+        };
+        assert_eq!(
+            template.render(&context),
+            "node7-1234-1700000000-bar-sliding.ckpt"
+        );
+
+        let plain: NameTemplate = "latest.bin".parse().unwrap();
+        assert_eq!(plain.render(&context), "latest.bin");
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        for template in [
+            "{user}.bin",
+            "{pid.bin",
+            "pid}.bin",
+            "{}.bin",
+            "",
+            "dir/{pid}.bin",
+        ] {
+            assert!(
+                template.parse::<NameTemplate>().is_err(),
+                "{template:?} should be rejected"
+            );
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Retention of the checkpoints in a storage directory
+//!
+//! Checkpoints are aged by the timestamp in their own header rather than the
+//! file's mtime, which copies and restores from backup reset. Files that do
+//! not parse as checkpoints are never deleted.
+
+use crate::checkpoint::bar_sliding::CheckpointHeader;
+use crate::checkpoint::format::{ArchiveMetadata, ARCHIVE_METADATA_ENTRY};
+use crate::checkpoint::group::GROUP_METADATA_ENTRY;
+use crate::checkpoint::{CheckpointFileFormat, CheckpointMetadata};
+use crate::restore::journal::{RestoreJournal, JOURNAL_SUFFIX};
+use crate::{GpuCheckpointError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// What `prune_checkpoints` did to a storage directory
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PruneReport {
+    /// Checkpoints deleted, along with their sidecars
+    pub removed: Vec<PathBuf>,
+
+    /// Checkpoints retained, newest first
+    pub kept: Vec<PathBuf>,
+
+    /// Files left alone because they do not parse as checkpoints
+    pub unrecognized: Vec<PathBuf>,
+}
+
+/// Delete the checkpoints in `dir` beyond the `keep_last` most recent, and
+/// those taken more than `older_than` ago, together with their manifests and
+/// restore journals. Pass `usize::MAX` or `Duration::MAX` to prune by only
+/// one of the two.
+pub fn prune_checkpoints(
+    dir: &Path,
+    keep_last: usize,
+    older_than: Duration,
+) -> Result<PruneReport> {
+    let mut report = PruneReport::default();
+    let mut checkpoints = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || is_sidecar(&path) {
+            continue;
+        }
+        match checkpoint_timestamp(&path) {
+            Ok(timestamp) => checkpoints.push((timestamp, path)),
+            Err(e) => {
+                debug!("Not pruning {}: {}", path.display(), e);
+                report.unrecognized.push(path);
+            }
+        }
+    }
+
+    // Newest first, ties broken by name so the result is deterministic
+    checkpoints.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let cutoff = now.checked_sub(older_than.as_secs());
+
+    for (rank, (timestamp, path)) in checkpoints.into_iter().enumerate() {
+        let expired = cutoff.is_some_and(|cutoff| timestamp < cutoff);
+        if rank < keep_last && !expired {
+            report.kept.push(path);
+            continue;
+        }
+
+        info!(
+            "Removing checkpoint {} (taken at {})",
+            path.display(),
+            timestamp
+        );
+        std::fs::remove_file(&path)?;
+        remove_sidecars(&path)?;
+        report.removed.push(path);
+    }
+
+    report.unrecognized.sort();
+    Ok(report)
+}
+
+/// Seconds since the epoch at which the checkpoint at `path` was taken, read
+/// from its header. Fails for anything that is not a checkpoint.
+pub fn checkpoint_timestamp(path: &Path) -> Result<u64> {
+    if CheckpointFileFormat::detect(path)? == CheckpointFileFormat::Binary {
+        let header = CheckpointHeader::read_from(&mut File::open(path)?)?;
+        header.validate()?;
+        return Ok(header.timestamp);
+    }
+
+    let not_a_checkpoint =
+        || GpuCheckpointError::CheckpointError(format!("{} is not a checkpoint", path.display()));
+    let mut archive = tar::Archive::new(File::open(path)?);
+    let mut entries = archive.entries()?;
+    let mut first = entries.next().ok_or_else(not_a_checkpoint)??;
+    let name = first.path()?.into_owned();
+    if name == Path::new(ARCHIVE_METADATA_ENTRY) {
+        let metadata: ArchiveMetadata =
+            serde_json::from_reader(&mut first).map_err(|_| not_a_checkpoint())?;
+        Ok(metadata.timestamp)
+    } else if name == Path::new(GROUP_METADATA_ENTRY) {
+        // Members are checkpointed one after another; the group is as old
+        // as its first
+        let mut member = entries.next().ok_or_else(not_a_checkpoint)??;
+        let header = CheckpointHeader::read_from(&mut member)?;
+        header.validate()?;
+        Ok(header.timestamp)
+    } else {
+        Err(not_a_checkpoint())
+    }
+}
+
+/// Files written next to checkpoints, pruned along with them
+fn is_sidecar(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+        || path.to_string_lossy().ends_with(JOURNAL_SUFFIX)
+}
+
+/// Remove the manifest and restore journal of the checkpoint at `path`, if
+/// they exist. A `.json` file that is not a manifest of it is left alone.
+fn remove_sidecars(path: &Path) -> Result<()> {
+    let manifest = CheckpointMetadata::manifest_path(path);
+    if manifest.is_file() {
+        match CheckpointMetadata::read_manifest(&manifest) {
+            Ok(_) => std::fs::remove_file(&manifest)?,
+            Err(e) => warn!("Keeping {}: {}", manifest.display(), e),
+        }
+    }
+    RestoreJournal::remove(&RestoreJournal::path_for(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::bar_sliding::BarSlidingCheckpoint;
+    use crate::checkpoint::format::TarFormat;
+    use crate::detector::{DetectionResult, GpuVendor};
+    use std::io::{Seek, SeekFrom, Write};
+    use tempfile::tempdir;
+
+    /// Offset of the timestamp in the checkpoint header
+    const TIMESTAMP_OFFSET: u64 = 24;
+
+    /// Write an empty checkpoint named `name` taken `age` seconds ago
+    fn checkpoint_aged(dir: &Path, name: &str, age: u64) -> PathBuf {
+        let path = dir.join(name);
+        BarSlidingCheckpoint::new()
+            .with_progress(false)
+            .checkpoint_process(1234, &DetectionResult::new(1234, GpuVendor::Nvidia), &path)
+            .unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(TIMESTAMP_OFFSET)).unwrap();
+        file.write_all(&(now - age).to_le_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_prune_keeps_most_recent() {
+        let dir = tempdir().unwrap();
+        let oldest = checkpoint_aged(dir.path(), "a.bin", 3000);
+        let middle = checkpoint_aged(dir.path(), "b.bin", 2000);
+        let newest = checkpoint_aged(dir.path(), "c.bin", 1000);
+        let manifest = CheckpointMetadata::manifest_path(&oldest);
+        std::fs::write(
+            &manifest,
+            r#"{"pid":1234,"strategy_used":"BarSliding",
+                "timestamp":{"secs_since_epoch":0,"nanos_since_epoch":0},
+                "size_bytes":0,"duration_ms":0,"path":"a.bin"}"#,
+        )
+        .unwrap();
+        let journal = RestoreJournal::path_for(&oldest);
+        std::fs::write(&journal, "{}").unwrap();
+        let notes = dir.path().join("notes.txt");
+        std::fs::write(&notes, "not a checkpoint").unwrap();
+
+        let report = prune_checkpoints(dir.path(), 2, Duration::MAX).unwrap();
+        assert_eq!(report.kept, vec![newest.clone(), middle.clone()]);
+        assert_eq!(report.removed, vec![oldest.clone()]);
+        assert_eq!(report.unrecognized, vec![notes.clone()]);
+        assert!(!oldest.exists());
+        assert!(!manifest.exists());
+        assert!(!journal.exists());
+        assert!(notes.exists());
+
+        // Pruning again changes nothing
+        let report = prune_checkpoints(dir.path(), 2, Duration::MAX).unwrap();
+        assert!(report.removed.is_empty());
+        assert_eq!(report.kept, vec![newest, middle]);
+    }
+
+    #[test]
+    fn test_prune_older_than() {
+        let dir = tempdir().unwrap();
+        let old = checkpoint_aged(dir.path(), "old.bin", 10 * 86400);
+        let recent = checkpoint_aged(dir.path(), "recent.bin", 3600);
+        let archive = dir.path().join("old.tar");
+        TarFormat::encode(&old, &archive).unwrap();
+
+        // Age comes from the header, not the freshly written file
+        assert!(checkpoint_timestamp(&archive).unwrap() < checkpoint_timestamp(&recent).unwrap());
+
+        let report = prune_checkpoints(dir.path(), usize::MAX, Duration::from_secs(86400)).unwrap();
+        assert_eq!(report.kept, vec![recent.clone()]);
+        assert_eq!(report.removed, vec![old.clone(), archive.clone()]);
+        assert!(!old.exists() && !archive.exists() && recent.exists());
+
+        // Either condition removes a checkpoint
+        let report = prune_checkpoints(dir.path(), 0, Duration::MAX).unwrap();
+        assert_eq!(report.removed, vec![recent]);
+    }
+
+    #[test]
+    fn test_prune_refuses_unparseable_files() {
+        let dir = tempdir().unwrap();
+        let checkpoint = checkpoint_aged(dir.path(), "ckpt.bin", 10);
+        let truncated = dir.path().join("truncated.bin");
+        std::fs::write(&truncated, &std::fs::read(&checkpoint).unwrap()[..16]).unwrap();
+        let tiny = dir.path().join("tiny");
+        std::fs::write(&tiny, b"x").unwrap();
+
+        let report = prune_checkpoints(dir.path(), 0, Duration::ZERO).unwrap();
+        assert_eq!(report.removed, vec![checkpoint]);
+        assert_eq!(report.unrecognized, vec![tiny.clone(), truncated.clone()]);
+        assert!(truncated.exists() && tiny.exists());
+    }
+}
@@ -0,0 +1,204 @@
+use crate::utils::lock;
+use crate::{GpuCheckpointError, Result};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey, SIGNATURE_LENGTH};
+use sha2::{Digest, Sha512};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tracing::{debug, info};
+
+/// Magic number closing a signature trailer
+pub const SIGNATURE_MAGIC: u32 = 0x53475043; // "CPGS"
+
+/// Size of the trailer appended to signed checkpoints: signature + magic
+pub const SIGNATURE_TRAILER_LEN: u64 = SIGNATURE_LENGTH as u64 + 4;
+
+/// Domain separation context for Ed25519ph signatures
+const SIGNATURE_CONTEXT: &[u8] = b"gpu-checkpoint";
+
+/// Sign a finished checkpoint file and append the signature trailer.
+///
+/// The signature covers every byte written before the trailer (header,
+/// allocation headers and data), so any modification is detected. This is
+/// independent of per-allocation integrity checks, which only detect
+/// accidental corruption.
+pub fn sign_checkpoint(path: &Path, key: &SigningKey) -> Result<()> {
+    let mut file =
+        lock::lock_exclusive(OpenOptions::new().read(true).append(true).open(path)?, path)?;
+    let len = file.metadata()?.len();
+    let digest = digest_prefix(&mut file, len)?;
+
+    let signature = key
+        .sign_prehashed(digest, Some(SIGNATURE_CONTEXT))
+        .map_err(|e| GpuCheckpointError::CheckpointError(format!("Signing failed: {e}")))?;
+
+    file.write_all(&signature.to_bytes())?;
+    file.write_all(&SIGNATURE_MAGIC.to_le_bytes())?;
+
+    info!("Signed checkpoint {:?}", path);
+    Ok(())
+}
+
+/// Verify the signature trailer of a checkpoint file, rejecting unsigned files
+///
+/// Verification reads through `file`, the descriptor the restore goes on to
+/// read and holds locked, so the verified bytes are the restored ones. The
+/// file is left rewound to its start.
+pub fn verify_checkpoint(file: &mut File, key: &VerifyingKey) -> Result<()> {
+    let len = file.metadata()?.len();
+    if len < SIGNATURE_TRAILER_LEN {
+        return Err(GpuCheckpointError::RestoreError(
+            "Checkpoint is not signed".to_string(),
+        ));
+    }
+
+    let signed_len = len - SIGNATURE_TRAILER_LEN;
+    file.seek(SeekFrom::Start(signed_len))?;
+    let mut signature_bytes = [0u8; SIGNATURE_LENGTH];
+    file.read_exact(&mut signature_bytes)?;
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+
+    if u32::from_le_bytes(magic) != SIGNATURE_MAGIC {
+        return Err(GpuCheckpointError::RestoreError(
+            "Checkpoint is not signed".to_string(),
+        ));
+    }
+
+    let digest = digest_prefix(file, signed_len)?;
+    key.verify_prehashed(
+        digest,
+        Some(SIGNATURE_CONTEXT),
+        &Signature::from_bytes(&signature_bytes),
+    )
+    .map_err(|_| {
+        GpuCheckpointError::RestoreError("Checkpoint signature verification failed".to_string())
+    })?;
+
+    file.rewind()?;
+    debug!("Checkpoint signature verified");
+    Ok(())
+}
+
+/// Load an Ed25519 secret key (32 raw bytes or 64 hex characters)
+pub fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    Ok(SigningKey::from_bytes(&read_key_file(path)?))
+}
+
+/// Load an Ed25519 public key (32 raw bytes or 64 hex characters)
+pub fn load_verifying_key(path: &Path) -> Result<VerifyingKey> {
+    VerifyingKey::from_bytes(&read_key_file(path)?)
+        .map_err(|e| GpuCheckpointError::RestoreError(format!("Invalid public key {path:?}: {e}")))
+}
+
+/// Read a 32 byte key file holding the key raw or as 64 hex characters
+pub(crate) fn read_key_file(path: &Path) -> Result<[u8; 32]> {
+    let contents = std::fs::read(path)?;
+    let invalid = || {
+        GpuCheckpointError::IoError(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{path:?} is not a 32 byte key in raw or hex form"),
+        ))
+    };
+
+    if let Ok(key) = <[u8; 32]>::try_from(contents.as_slice()) {
+        return Ok(key);
+    }
+
+    let hex = std::str::from_utf8(&contents).map_err(|_| invalid())?;
+    parse_hex_key(hex).ok_or_else(invalid)
+}
+
+/// Parse a 32 byte key written as 64 hex characters
+pub(crate) fn parse_hex_key(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+fn digest_prefix(file: &mut File, len: u64) -> Result<Sha512> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = Sha512::new();
+    let copied = std::io::copy(&mut file.take(len), &mut hasher)?;
+    if copied != len {
+        return Err(GpuCheckpointError::IoError(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "checkpoint shrank while hashing",
+        )));
+    }
+    Ok(hasher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn write_key(path: &Path, bytes: &[u8; 32]) {
+        let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        std::fs::write(path, hex + "\n").unwrap();
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let dir = tempdir().unwrap();
+        let checkpoint = dir.path().join("test.ckpt");
+        std::fs::write(&checkpoint, b"checkpoint contents").unwrap();
+
+        let secret_path = dir.path().join("key");
+        let public_path = dir.path().join("key.pub");
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        write_key(&secret_path, &signing_key.to_bytes());
+        std::fs::write(&public_path, signing_key.verifying_key().to_bytes()).unwrap();
+
+        sign_checkpoint(&checkpoint, &load_signing_key(&secret_path).unwrap()).unwrap();
+        let public = load_verifying_key(&public_path).unwrap();
+        verify_checkpoint(&mut File::open(&checkpoint).unwrap(), &public).unwrap();
+
+        // Tamper with the signed contents
+        let mut bytes = std::fs::read(&checkpoint).unwrap();
+        bytes[0] ^= 0xff;
+        std::fs::write(&checkpoint, &bytes).unwrap();
+        let err = verify_checkpoint(&mut File::open(&checkpoint).unwrap(), &public).unwrap_err();
+        assert!(err.to_string().contains("signature verification failed"));
+    }
+
+    #[test]
+    fn test_verify_reads_the_open_file() {
+        let dir = tempdir().unwrap();
+        let checkpoint = dir.path().join("test.ckpt");
+        std::fs::write(&checkpoint, b"checkpoint contents").unwrap();
+        let signing_key = SigningKey::from_bytes(&[9u8; 32]);
+        sign_checkpoint(&checkpoint, &signing_key).unwrap();
+
+        // Swapping another file in at the path does not affect the one
+        // already open, and verification leaves it ready to be read
+        let mut file = File::open(&checkpoint).unwrap();
+        let swapped = dir.path().join("swapped.ckpt");
+        std::fs::write(&swapped, b"unsigned contents").unwrap();
+        std::fs::rename(&swapped, &checkpoint).unwrap();
+
+        verify_checkpoint(&mut file, &signing_key.verifying_key()).unwrap();
+        let mut contents = [0u8; 19];
+        file.read_exact(&mut contents).unwrap();
+        assert_eq!(&contents, b"checkpoint contents");
+    }
+
+    #[test]
+    fn test_verify_rejects_unsigned() {
+        let dir = tempdir().unwrap();
+        let checkpoint = dir.path().join("unsigned.ckpt");
+        std::fs::write(&checkpoint, vec![0u8; 256]).unwrap();
+
+        let key = SigningKey::from_bytes(&[1u8; 32]).verifying_key();
+        let err = verify_checkpoint(&mut File::open(&checkpoint).unwrap(), &key).unwrap_err();
+        assert!(err.to_string().contains("not signed"));
+    }
+}
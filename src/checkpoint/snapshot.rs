@@ -0,0 +1,235 @@
+use crate::checkpoint::freeze::{FreezeMethod, ProcessController};
+use crate::detector::{DetectionResult, MemoryMapParser, SmapsRegion};
+use crate::{GpuCheckpointError, Result};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Experimental copy-on-write snapshot of a process's GPU-adjacent memory.
+///
+/// The target is frozen only while a child is forked off it (by injecting
+/// `clone` with ptrace, x86_64 only). The child shares the target's pages
+/// copy-on-write and is kept stopped, so its memory stays as it was at the
+/// fork while the target runs on, and the checkpoint is copied from it.
+/// Mappings marked `MADV_DONTFORK`, as CUDA marks its host allocations, are
+/// made inheritable for the fork and marked again right after it.
+///
+/// Only private mappings are copied on write. Shared mappings (IPC
+/// segments, UVM) and device memory would still change under the child,
+/// so allocations in them cannot be snapshotted and the caller falls back
+/// to freeze-copy.
+pub struct CowSnapshot {
+    /// Target the snapshot was forked off
+    target: u32,
+
+    /// Stopped child holding the snapshot
+    child: u32,
+
+    /// How long the target was frozen for the fork
+    pub freeze_duration: Duration,
+}
+
+impl CowSnapshot {
+    /// Freeze `pid`, fork the snapshot off it and resume it.
+    ///
+    /// Fails (so the caller can fall back to the freeze-copy path) when an
+    /// allocation lies in a mapping that is not copied on write, or the
+    /// process cannot be frozen or forked.
+    pub fn capture(pid: u32, detection: &DetectionResult, method: FreezeMethod) -> Result<Self> {
+        let smaps = MemoryMapParser::parse_smaps(pid)?;
+        let dont_fork = Self::dont_fork_mappings(&smaps, detection)?;
+
+        let freeze_start = Instant::now();
+        let mut controller = ProcessController::new(pid)
+            .with_method(method)
+            .with_priority_threads(&detection.gpu_threads);
+        controller.freeze()?;
+
+        let mut inheritable = Vec::new();
+        let mut prepared = Ok(());
+        for mapping in &dont_fork {
+            prepared = controller.madvise(mapping.start, mapping.len, libc::MADV_DOFORK);
+            if prepared.is_err() {
+                break;
+            }
+            inheritable.push(mapping);
+        }
+        let forked = prepared.and_then(|()| controller.fork_stopped());
+        for mapping in inheritable {
+            if let Err(e) = controller.madvise(mapping.start, mapping.len, libc::MADV_DONTFORK) {
+                warn!(
+                    "Cannot mark 0x{:016x} of PID {} MADV_DONTFORK again: {}",
+                    mapping.start, pid, e
+                );
+            }
+        }
+
+        controller.resume()?;
+        let child = forked?;
+        let freeze_duration = freeze_start.elapsed();
+
+        info!(
+            "Copy-on-write snapshot of PID {} forked as {} in {:.2}ms",
+            pid,
+            child,
+            freeze_duration.as_secs_f64() * 1000.0
+        );
+
+        Ok(Self {
+            target: pid,
+            child,
+            freeze_duration,
+        })
+    }
+
+    /// Process whose memory is the snapshot, to read the checkpoint from
+    pub fn pid(&self) -> u32 {
+        self.child
+    }
+
+    /// Mappings holding allocations of `detection` that are marked
+    /// `MADV_DONTFORK`, failing on any that is not copied on write
+    fn dont_fork_mappings(
+        smaps: &[SmapsRegion],
+        detection: &DetectionResult,
+    ) -> Result<Vec<Mapping>> {
+        let mut dont_fork = Vec::new();
+        for entry in smaps {
+            let region = &entry.region;
+            let Some(allocation) = detection
+                .allocations
+                .iter()
+                .find(|a| region.start < a.vaddr_end && a.vaddr_start < region.end)
+            else {
+                continue;
+            };
+
+            // VM_IO and VM_PFNMAP memory is shared with the device
+            if region.perms.ends_with('s') || entry.has_vm_flag("io") || entry.has_vm_flag("pf") {
+                return Err(GpuCheckpointError::CheckpointError(format!(
+                    "allocation at 0x{:016x} is in a shared or device mapping ({} {}), which \
+                     is not copied on write",
+                    allocation.vaddr_start,
+                    region.perms,
+                    region.pathname.as_deref().unwrap_or("[anon]")
+                )));
+            }
+            if entry.has_vm_flag("dc") {
+                dont_fork.push(Mapping {
+                    start: region.start,
+                    len: region.end - region.start,
+                });
+            }
+        }
+        Ok(dont_fork)
+    }
+}
+
+/// Kills the child holding the snapshot once the checkpoint is copied
+impl Drop for CowSnapshot {
+    fn drop(&mut self) {
+        if let Err(e) = ProcessController::new(self.target).kill_fork(self.child) {
+            warn!(
+                "Cannot clean up snapshot {} of PID {}: {}",
+                self.child, self.target, e
+            );
+        }
+    }
+}
+
+/// Address range of a mapping
+struct Mapping {
+    start: u64,
+    len: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detector::{AllocationType, GpuAllocation, GpuVendor, ProcessScanner};
+
+    #[test]
+    fn test_refuses_shared_mappings() {
+        let smaps = MemoryMapParser::parse_smaps_content(
+            "\
+7f0000000000-7f0000200000 rw-p 00000000 00:00 0
+VmFlags: rd wr mr mw me dc ac
+7f0000200000-7f0000400000 rw-s 00000000 00:05 412 /dev/nvidia-uvm
+VmFlags: rd wr sh mr mw me ms dc
+",
+        );
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            0x7f0000000000,
+            0x7f0000100000,
+            AllocationType::HostPinned,
+        ));
+        let dont_fork = CowSnapshot::dont_fork_mappings(&smaps, &detection).unwrap();
+        assert_eq!(dont_fork.len(), 1);
+        assert_eq!(dont_fork[0].start, 0x7f0000000000);
+        assert_eq!(dont_fork[0].len, 0x200000);
+
+        detection.add_allocation(GpuAllocation::new(
+            0x7f0000200000,
+            0x7f0000400000,
+            AllocationType::Uvm,
+        ));
+        let err = CowSnapshot::dont_fork_mappings(&smaps, &detection)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("0x00007f0000200000"), "{err}");
+    }
+
+    #[test]
+    fn test_snapshot_of_child() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        // Wait for exec so the maps below belong to sleep
+        std::thread::sleep(Duration::from_millis(50));
+
+        let region = MemoryMapParser::parse_maps(pid)
+            .unwrap()
+            .into_iter()
+            .find(|region| region.perms == "rw-p" && region.pathname.is_none())
+            .expect("sleep has an anonymous writable mapping");
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            region.start,
+            region.end,
+            AllocationType::Standard,
+        ));
+
+        let snapshot = match CowSnapshot::capture(pid, &detection, FreezeMethod::Signal) {
+            Ok(snapshot) => snapshot,
+            // ptrace may be disallowed in the test environment
+            Err(GpuCheckpointError::PermissionDenied) => {
+                child.kill().unwrap();
+                child.wait().unwrap();
+                return;
+            }
+            Err(e) => panic!("snapshot failed: {e}"),
+        };
+        let fork = snapshot.pid();
+        assert_ne!(fork, pid);
+
+        // The target runs on, the fork holds its memory
+        assert!(!ProcessScanner::process_state(pid).unwrap().is_stopped());
+        let read = |pid: u32| {
+            let mut data = vec![0u8; (region.end - region.start) as usize];
+            let mem = std::fs::File::open(format!("/proc/{pid}/mem")).unwrap();
+            std::os::unix::fs::FileExt::read_exact_at(&mem, &mut data, region.start).unwrap();
+            data
+        };
+        assert_eq!(read(fork), read(pid));
+
+        // Dropping the snapshot kills the fork and reaps it in the target
+        drop(snapshot);
+        assert!(!std::path::Path::new(&format!("/proc/{fork}")).exists());
+        assert!(!ProcessScanner::process_state(pid).unwrap().is_stopped());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
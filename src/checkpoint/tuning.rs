@@ -0,0 +1,267 @@
+//! Window size calibration against the checkpoint storage
+//!
+//! The best window size depends on the storage: local NVMe favours large
+//! sequential writes while network file systems often peak lower. A short
+//! benchmark in the storage directory picks the fastest candidate.
+//!
+//! The window also should not exceed the GPU's BAR aperture, which ranges
+//! from 256MB to all of VRAM with resizable BAR. Its size is read from the
+//! PCI functions' sysfs `resource` files.
+
+use crate::Result;
+use serde::Serialize;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+use tracing::{debug, info, warn};
+
+/// Window sizes tried by `calibrate_window_size`
+pub const CALIBRATION_WINDOWS: &[usize] =
+    &[256 * 1024, 1024 * 1024, 4 * 1024 * 1024, 16 * 1024 * 1024];
+
+/// Bytes written for each candidate window size
+pub const CALIBRATION_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Name of the scratch file written during calibration
+const CALIBRATION_FILE: &str = ".gpu-checkpoint-calibration";
+
+/// Sysfs directory with one entry per PCI function, named by its address
+const PCI_DEVICES_DIR: &str = "/sys/bus/pci/devices";
+
+/// PCI vendor IDs of the GPUs whose BARs are checked (NVIDIA, AMD)
+const GPU_PCI_VENDORS: &[u16] = &[0x10de, 0x1002];
+
+/// Number of standard BARs at the start of a `resource` file; the lines
+/// after them describe the expansion ROM and bridge windows
+const PCI_STD_NUM_BARS: usize = 6;
+
+/// Outcome of a window size calibration
+#[derive(Debug, Clone, Serialize)]
+pub struct WindowCalibration {
+    /// Fastest window size
+    pub window_size: usize,
+
+    /// Measured write throughput in MB/s per candidate window size
+    pub throughput: Vec<(usize, f64)>,
+}
+
+/// Pick the fastest of `CALIBRATION_WINDOWS` for writing into `dir`
+pub fn calibrate_window_size(dir: &Path) -> Result<WindowCalibration> {
+    calibrate_window_size_with(dir, CALIBRATION_WINDOWS, CALIBRATION_BYTES)
+}
+
+/// Time writing (and syncing) `bytes` bytes into `dir` with each of
+/// `windows` and pick the fastest
+pub fn calibrate_window_size_with(
+    dir: &Path,
+    windows: &[usize],
+    bytes: u64,
+) -> Result<WindowCalibration> {
+    let path = dir.join(CALIBRATION_FILE);
+    let result = measure(&path, windows, bytes);
+    let _ = fs::remove_file(&path);
+    let throughput = result?;
+
+    let window_size = throughput
+        .iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(windows[0], |&(window, _)| window);
+    info!(
+        "Calibrated window size for {}: {} bytes",
+        dir.display(),
+        window_size
+    );
+
+    Ok(WindowCalibration {
+        window_size,
+        throughput,
+    })
+}
+
+/// Sizes of the regions listed in a PCI function's sysfs `resource` file,
+/// one line of "start end flags" per region, 0 for unused regions
+pub fn parse_bar_sizes(resource: &str) -> Vec<u64> {
+    let hex = |field: &str| u64::from_str_radix(field.trim_start_matches("0x"), 16).ok();
+    resource
+        .lines()
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next().and_then(hex), fields.next().and_then(hex)) {
+                (Some(start), Some(end)) if end > start => end - start + 1,
+                _ => 0,
+            }
+        })
+        .collect()
+}
+
+/// Size of the BAR aperture of the GPUs on this host: the largest BAR of
+/// each GPU, and of those the smallest so a window fits every GPU. None if
+/// no GPU is found.
+pub fn gpu_bar_size() -> Option<u64> {
+    gpu_bar_size_in(Path::new(PCI_DEVICES_DIR))
+}
+
+/// `gpu_bar_size` for the PCI functions listed in `devices_dir`
+pub fn gpu_bar_size_in(devices_dir: &Path) -> Option<u64> {
+    let read_hex = |device: &Path, file: &str| {
+        let value = fs::read_to_string(device.join(file)).ok()?;
+        u32::from_str_radix(value.trim().trim_start_matches("0x"), 16).ok()
+    };
+
+    let mut bar_size: Option<u64> = None;
+    for entry in fs::read_dir(devices_dir).ok()?.flatten() {
+        let device = entry.path();
+        // Display controllers only, not the GPU's audio or USB functions
+        let is_gpu = read_hex(&device, "vendor")
+            .is_some_and(|vendor| GPU_PCI_VENDORS.iter().any(|&v| u32::from(v) == vendor))
+            && read_hex(&device, "class").is_some_and(|class| class >> 16 == 0x03);
+        if !is_gpu {
+            continue;
+        }
+
+        let Ok(resource) = fs::read_to_string(device.join("resource")) else {
+            continue;
+        };
+        let largest = parse_bar_sizes(&resource)
+            .into_iter()
+            .take(PCI_STD_NUM_BARS)
+            .max()
+            .unwrap_or(0);
+        if largest > 0 {
+            debug!("GPU {} has a {} byte BAR", device.display(), largest);
+            bar_size = Some(bar_size.map_or(largest, |size| size.min(largest)));
+        }
+    }
+    bar_size
+}
+
+/// `window_size`, reduced to `bar_size` with a warning if it is larger
+pub fn clamp_to_bar(window_size: usize, bar_size: Option<u64>) -> usize {
+    match bar_size {
+        Some(bar_size) if window_size as u64 > bar_size => {
+            warn!(
+                "Window size of {} exceeds the GPU BAR of {}, using {}",
+                crate::utils::format_memory(window_size as u64),
+                crate::utils::format_memory(bar_size),
+                crate::utils::format_memory(bar_size)
+            );
+            bar_size as usize
+        }
+        _ => window_size,
+    }
+}
+
+fn measure(path: &Path, windows: &[usize], bytes: u64) -> Result<Vec<(usize, f64)>> {
+    let mut throughput = Vec::with_capacity(windows.len());
+    for &window in windows {
+        let buffer = vec![0xa5u8; window];
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let start = Instant::now();
+        let mut remaining = bytes;
+        while remaining > 0 {
+            let len = remaining.min(window as u64) as usize;
+            file.write_all(&buffer[..len])?;
+            remaining -= len as u64;
+        }
+        // Without syncing this would only measure the page cache
+        file.sync_all()?;
+
+        let mb_per_sec =
+            bytes as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64().max(f64::EPSILON);
+        debug!("Window size {}: {:.2} MB/s", window, mb_per_sec);
+        throughput.push((window, mb_per_sec));
+    }
+    Ok(throughput)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_calibration_picks_a_candidate() {
+        let dir = tempdir().unwrap();
+        let windows = [4096, 64 * 1024];
+
+        let calibration = calibrate_window_size_with(dir.path(), &windows, 256 * 1024).unwrap();
+        assert!(windows.contains(&calibration.window_size));
+        assert_eq!(
+            calibration
+                .throughput
+                .iter()
+                .map(|&(window, _)| window)
+                .collect::<Vec<_>>(),
+            windows
+        );
+        assert!(!dir.path().join(CALIBRATION_FILE).exists());
+    }
+
+    #[test]
+    fn test_parse_bar_sizes() {
+        // BAR0 16MiB registers, BAR1 32GiB resizable VRAM aperture (64-bit,
+        // so BAR2 is unused), BAR3 32MiB, BAR5 I/O ports, expansion ROM
+        let resource = "\
+0x00000000fa000000 0x00000000faffffff 0x0000000000040200
+0x0000038000000000 0x00000387ffffffff 0x000000000014220c
+0x0000000000000000 0x0000000000000000 0x0000000000000000
+0x0000038800000000 0x0000038801ffffff 0x000000000014220c
+0x0000000000000000 0x0000000000000000 0x0000000000000000
+0x000000000000e000 0x000000000000e07f 0x0000000000040101
+0x00000000fb000000 0x00000000fb07ffff 0x0000000000046200
+";
+        assert_eq!(
+            parse_bar_sizes(resource),
+            vec![16 << 20, 32 << 30, 0, 32 << 20, 0, 128, 512 << 10]
+        );
+        assert!(parse_bar_sizes("").is_empty());
+        assert_eq!(parse_bar_sizes("garbage\n"), vec![0]);
+    }
+
+    #[test]
+    fn test_gpu_bar_size() {
+        let dir = tempdir().unwrap();
+        let device = |address: &str, vendor: &str, class: &str, bar1_end: &str| {
+            let path = dir.path().join(address);
+            fs::create_dir(&path).unwrap();
+            fs::write(path.join("vendor"), format!("{vendor}\n")).unwrap();
+            fs::write(path.join("class"), format!("{class}\n")).unwrap();
+            fs::write(
+                path.join("resource"),
+                format!(
+                    "0x00000000fa000000 0x00000000faffffff 0x0000000000040200\n\
+                     0x0000038000000000 {bar1_end} 0x000000000014220c\n"
+                ),
+            )
+            .unwrap();
+        };
+        assert_eq!(gpu_bar_size_in(dir.path()), None);
+
+        // A GPU with 32GiB resizable BAR and one with the legacy 256MiB
+        device("0000:01:00.0", "0x10de", "0x030200", "0x00000387ffffffff");
+        assert_eq!(gpu_bar_size_in(dir.path()), Some(32 << 30));
+        device("0000:02:00.0", "0x1002", "0x030000", "0x000003800fffffff");
+        assert_eq!(gpu_bar_size_in(dir.path()), Some(256 << 20));
+
+        // Neither a NIC nor the GPU's audio function count
+        device("0000:03:00.0", "0x15b3", "0x020000", "0x00000380000fffff");
+        device("0000:01:00.1", "0x10de", "0x040300", "0x0000038000003fff");
+        assert_eq!(gpu_bar_size_in(dir.path()), Some(256 << 20));
+
+        assert_eq!(clamp_to_bar(512 << 20, Some(256 << 20)), 256 << 20);
+        assert_eq!(clamp_to_bar(64 << 20, Some(256 << 20)), 64 << 20);
+        assert_eq!(clamp_to_bar(512 << 20, None), 512 << 20);
+    }
+
+    #[test]
+    fn test_calibration_fails_for_missing_dir() {
+        let dir = tempdir().unwrap();
+        assert!(calibrate_window_size_with(&dir.path().join("missing"), &[4096], 4096).is_err());
+    }
+}
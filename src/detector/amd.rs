@@ -0,0 +1,405 @@
+use crate::detector::memory::{MemoryMapParser, MemoryRegion};
+use crate::detector::process::{FileDescriptor, GpuDeviceType, ProcessScanner};
+use crate::detector::{
+    AllocationType, DetectionResult, GpuAllocation, GpuDetector, GpuDeviceInfo, GpuVendor,
+};
+use crate::Result;
+use std::fs;
+use std::path::Path;
+use tracing::{debug, info, trace};
+
+/// Device node of the AMD kernel fusion driver (KFD), opened by every ROCm
+/// process
+pub const KFD_DEVICE: &str = "/dev/kfd";
+
+/// Directory the KFD publishes the HSA topology in, one entry per CPU or
+/// GPU node
+const KFD_TOPOLOGY_NODES_DIR: &str = "/sys/class/kfd/kfd/topology/nodes";
+
+/// Prefix of DRM render nodes, followed by the minor number
+pub(crate) const DRM_RENDER_NODE_PREFIX: &str = "/dev/dri/renderD";
+
+/// Minor number of the first render node; device IDs count from it
+pub(crate) const DRM_RENDER_MINOR_BASE: u32 = 128;
+
+/// GFX target prefixes and the architecture they imply. More specific
+/// prefixes must come before prefixes they contain.
+const AMD_ARCHITECTURES: &[(&str, &str)] = &[
+    ("gfx950", "CDNA4"),
+    ("gfx94", "CDNA3"),
+    ("gfx90a", "CDNA2"),
+    ("gfx908", "CDNA"),
+    ("gfx906", "GCN5"),
+    ("gfx12", "RDNA4"),
+    ("gfx11", "RDNA3"),
+    ("gfx103", "RDNA2"),
+    ("gfx101", "RDNA"),
+];
+
+pub struct AmdDetector;
+
+impl Default for AmdDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AmdDetector {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Allocations backed by the KFD, AMD render nodes, HIP managed memory
+    /// or ROCm IPC segments. Render nodes are only considered if their
+    /// device ID is in `device_ids`, unless it is empty (topology unknown).
+    fn classify_regions(regions: &[MemoryRegion], device_ids: &[u32]) -> Vec<GpuAllocation> {
+        let mut allocations = Vec::new();
+
+        for region in regions {
+            let Some(pathname) = &region.pathname else {
+                continue;
+            };
+
+            let mut alloc = if pathname == KFD_DEVICE {
+                // Doorbells, events and remapped MMIO of the KFD are device
+                // registers, not memory
+                GpuAllocation::new(region.start, region.end, AllocationType::BarMapped)
+            } else if let Some(device_id) = Self::render_device_id(pathname) {
+                if !device_ids.is_empty() && !device_ids.contains(&device_id) {
+                    trace!("Ignoring mapping of non-AMD render node {}", pathname);
+                    continue;
+                }
+                // HIP device memory mapped to the host through the render node
+                let mut alloc =
+                    GpuAllocation::new(region.start, region.end, AllocationType::Standard);
+                alloc.device_id = Some(device_id);
+                alloc
+            } else if pathname.starts_with("[anon:")
+                && (pathname.contains("hip") || pathname.contains("hsa"))
+            {
+                let mut alloc =
+                    GpuAllocation::new(region.start, region.end, AllocationType::Managed);
+                alloc.metadata.protection = region.perms.clone();
+                debug!(
+                    "Found HIP managed memory allocation: {:x}-{:x} ({} bytes)",
+                    region.start, region.end, alloc.size
+                );
+                allocations.push(alloc);
+                continue;
+            } else if pathname.starts_with("/dev/shm/")
+                && (pathname.contains("hip") || pathname.contains("rccl"))
+            {
+                let mut alloc = GpuAllocation::new(region.start, region.end, AllocationType::Ipc);
+                alloc.metadata.is_shared = true;
+                alloc.metadata.file_offset = region.offset;
+                alloc.metadata.inode = Some(region.inode).filter(|&inode| inode != 0);
+                if pathname.contains("rccl") {
+                    alloc.alloc_type = AllocationType::Distributed;
+                    alloc.metadata.is_distributed = true;
+                }
+                alloc
+            } else {
+                continue;
+            };
+
+            alloc.metadata.backing_file = Some(pathname.clone());
+            alloc.metadata.protection = region.perms.clone();
+            alloc.metadata.is_shared |= region.perms.contains('s');
+            debug!(
+                "Found {} allocation backed by {}: {:x}-{:x} ({} bytes)",
+                alloc.alloc_type, pathname, region.start, region.end, alloc.size
+            );
+            allocations.push(alloc);
+        }
+
+        allocations
+    }
+
+    /// Device ID of a render node path, counting from `renderD128`
+    pub(crate) fn render_device_id(path: &str) -> Option<u32> {
+        path.strip_prefix(DRM_RENDER_NODE_PREFIX)?
+            .parse::<u32>()
+            .ok()?
+            .checked_sub(DRM_RENDER_MINOR_BASE)
+    }
+
+    /// Whether any mapping is backed by the KFD or a render node
+    fn has_gpu_mappings(regions: &[MemoryRegion]) -> bool {
+        regions.iter().any(|region| {
+            region
+                .pathname
+                .as_deref()
+                .is_some_and(|path| path == KFD_DEVICE || path.starts_with(DRM_RENDER_NODE_PREFIX))
+        })
+    }
+
+    /// Record the descriptor each file-backed allocation was mapped through
+    fn attach_fds(allocations: &mut [GpuAllocation], fds: &[FileDescriptor]) {
+        for alloc in allocations {
+            let Some(backing_file) = &alloc.metadata.backing_file else {
+                continue;
+            };
+
+            alloc.fd = fds
+                .iter()
+                .find(|fd| &fd.target == backing_file)
+                .map(|fd| fd.fd);
+        }
+    }
+
+    /// GPUs in the KFD topology
+    pub fn gpu_devices() -> Vec<GpuDeviceInfo> {
+        Self::read_gpu_devices(Path::new(KFD_TOPOLOGY_NODES_DIR))
+    }
+
+    fn read_gpu_devices(nodes_dir: &Path) -> Vec<GpuDeviceInfo> {
+        let Ok(entries) = fs::read_dir(nodes_dir) else {
+            return Vec::new();
+        };
+
+        let mut gpus: Vec<GpuDeviceInfo> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let properties = fs::read_to_string(entry.path().join("properties")).ok()?;
+                let name = fs::read_to_string(entry.path().join("name")).unwrap_or_default();
+                Self::parse_node_properties(&properties, name.trim())
+            })
+            .collect();
+        gpus.sort_by_key(|gpu| gpu.device_id);
+        gpus
+    }
+
+    /// Parse the `properties` file of a topology node. CPU nodes, which
+    /// have no SIMDs, yield `None`.
+    fn parse_node_properties(properties: &str, name: &str) -> Option<GpuDeviceInfo> {
+        let property = |key: &str| {
+            properties.lines().find_map(|line| {
+                let (k, value) = line.split_once(' ')?;
+                (k == key).then(|| value.trim().parse::<u64>().ok())?
+            })
+        };
+
+        if property("simd_count")? == 0 {
+            return None;
+        }
+
+        let gfx_target = property("gfx_target_version").map(Self::gfx_target);
+        let model = match (name, &gfx_target) {
+            ("", Some(target)) => format!("AMD GPU {target}"),
+            ("", None) => "AMD GPU".to_string(),
+            (name, _) => name.to_string(),
+        };
+
+        Some(GpuDeviceInfo {
+            device_id: property("drm_render_minor")
+                .and_then(|minor| u32::try_from(minor).ok())
+                .and_then(|minor| minor.checked_sub(DRM_RENDER_MINOR_BASE)),
+            model,
+            architecture: gfx_target
+                .as_deref()
+                .and_then(Self::architecture)
+                .map(str::to_string),
+            compute_capability: gfx_target,
+        })
+    }
+
+    /// GFX target name of a `gfx_target_version` (e.g. 90010 is "gfx90a")
+    fn gfx_target(version: u64) -> String {
+        let major = version / 10000;
+        let minor = version / 100 % 100;
+        let stepping = version % 100;
+        format!("gfx{major}{minor:x}{stepping:x}")
+    }
+
+    fn architecture(gfx_target: &str) -> Option<&'static str> {
+        AMD_ARCHITECTURES
+            .iter()
+            .find(|(prefix, _)| gfx_target.starts_with(prefix))
+            .map(|(_, arch)| *arch)
+    }
+}
+
+impl GpuDetector for AmdDetector {
+    fn detect_allocations(&self, pid: u32) -> Result<DetectionResult> {
+        info!("Starting AMD GPU detection for PID {}", pid);
+
+        let mut result = DetectionResult::new(pid, GpuVendor::Amd);
+
+        let regions = MemoryMapParser::parse_maps(pid)?;
+        let fds = ProcessScanner::scan_file_descriptors(pid)?;
+        let gpu_fds: Vec<_> = fds
+            .iter()
+            .filter_map(ProcessScanner::classify_fd)
+            .filter(|info| info.device_type == GpuDeviceType::AmdGpu)
+            .collect();
+
+        if gpu_fds.is_empty()
+            && !Self::has_gpu_mappings(&regions)
+            && !ProcessScanner::has_gpu_environment(pid)?
+        {
+            debug!("No AMD GPU usage detected for PID {}", pid);
+            return Ok(result);
+        }
+
+        let gpus = Self::gpu_devices();
+        let amd_device_ids: Vec<u32> = gpus.iter().filter_map(|gpu| gpu.device_id).collect();
+        for alloc in Self::classify_regions(&regions, &amd_device_ids) {
+            result.add_allocation(alloc);
+        }
+        Self::attach_fds(&mut result.allocations, &fds);
+        MemoryMapParser::attach_physical_frames(pid, &mut result.allocations);
+
+        let gpu_fd_numbers: Vec<i32> = gpu_fds.iter().map(|info| info.fd).collect();
+        result.gpu_threads =
+            ProcessScanner::gpu_threads(pid, &gpu_fd_numbers).unwrap_or_else(|e| {
+                debug!("Cannot scan threads of PID {}: {}", pid, e);
+                Vec::new()
+            });
+
+        // Render nodes the process has open identify the GPUs it uses
+        let mut device_ids: Vec<u32> = gpu_fds
+            .iter()
+            .filter_map(|info| Self::render_device_id(&info.path))
+            .filter(|id| amd_device_ids.is_empty() || amd_device_ids.contains(id))
+            .collect();
+        device_ids.sort_unstable();
+        device_ids.dedup();
+        result.group_contexts(&device_ids);
+        result.gpus = gpus
+            .into_iter()
+            .filter(|gpu| {
+                device_ids.is_empty() || gpu.device_id.is_some_and(|id| device_ids.contains(&id))
+            })
+            .collect();
+
+        info!(
+            "AMD detection complete for PID {}: found {} allocations, {} problematic",
+            pid,
+            result.allocations.len(),
+            result
+                .allocations
+                .iter()
+                .filter(|a| a.is_problematic())
+                .count()
+        );
+
+        Ok(result)
+    }
+
+    fn is_gpu_process(&self, pid: u32) -> Result<bool> {
+        let fds = ProcessScanner::scan_file_descriptors(pid)?;
+        if fds.iter().any(|fd| {
+            ProcessScanner::classify_fd(fd)
+                .is_some_and(|info| info.device_type == GpuDeviceType::AmdGpu)
+        }) {
+            return Ok(true);
+        }
+
+        ProcessScanner::has_gpu_environment(pid)
+    }
+
+    fn get_vendor(&self) -> GpuVendor {
+        GpuVendor::Amd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amd_detector_creation() {
+        assert_eq!(AmdDetector::new().get_vendor(), GpuVendor::Amd);
+    }
+
+    #[test]
+    fn test_classify_rocm_mappings() {
+        let maps = "\
+7f1000000000-7f1000200000 rw-s 1a2b3c000 00:05 1043 /dev/dri/renderD128
+7f2000000000-7f2000400000 rw-s 2a2b3c000 00:05 1044 /dev/dri/renderD129
+7f3000000000-7f3000002000 rw-s 00000000 00:05 1040 /dev/kfd
+7f4000000000-7f4000100000 rw-p 00000000 00:00 0 [anon:hip managed]
+7f5000000000-7f5000100000 rw-s 00001000 00:1a 2001 /dev/shm/rccl-buffers
+7f6000000000-7f6000100000 rw-s 00000000 00:1a 2002 /dev/shm/hip_ipc_mem
+7f7000000000-7f7000100000 rw-s 00000000 00:05 1045 /dev/dri/renderD130
+7f8000000000-7f8000100000 r-xp 00000000 08:01 3001 /opt/rocm/lib/libamdhip64.so.6";
+        let regions: Vec<_> = maps
+            .lines()
+            .filter_map(MemoryMapParser::parse_line)
+            .collect();
+
+        // renderD130 belongs to another vendor's GPU
+        let allocations = AmdDetector::classify_regions(&regions, &[0, 1]);
+        let found: Vec<_> = allocations
+            .iter()
+            .map(|a| (a.vaddr_start, a.alloc_type, a.device_id))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (0x7f1000000000, AllocationType::Standard, Some(0)),
+                (0x7f2000000000, AllocationType::Standard, Some(1)),
+                (0x7f3000000000, AllocationType::BarMapped, None),
+                (0x7f4000000000, AllocationType::Managed, None),
+                (0x7f5000000000, AllocationType::Distributed, None),
+                (0x7f6000000000, AllocationType::Ipc, None),
+            ]
+        );
+        assert_eq!(
+            allocations[0].metadata.backing_file.as_deref(),
+            Some("/dev/dri/renderD128")
+        );
+        assert_eq!(allocations[4].metadata.file_offset, 0x1000);
+        assert!(allocations[4].metadata.is_distributed);
+
+        // Without a topology every render node is taken
+        assert_eq!(AmdDetector::classify_regions(&regions, &[]).len(), 7);
+        assert!(AmdDetector::has_gpu_mappings(&regions));
+        assert!(!AmdDetector::has_gpu_mappings(&regions[7..]));
+    }
+
+    #[test]
+    fn test_read_kfd_topology() {
+        let dir = tempfile::tempdir().unwrap();
+        for (node, name, properties) in [
+            (
+                "0",
+                "",
+                "cpu_cores_count 64\nsimd_count 0\ngfx_target_version 0\n",
+            ),
+            (
+                "1",
+                "",
+                "simd_count 440\ngfx_target_version 90010\ndrm_render_minor 129\n",
+            ),
+            (
+                "2",
+                "AMD Instinct MI300X",
+                "simd_count 1216\ngfx_target_version 90402\ndrm_render_minor 128\n",
+            ),
+        ] {
+            let node_dir = dir.path().join(node);
+            fs::create_dir_all(&node_dir).unwrap();
+            fs::write(node_dir.join("name"), format!("{name}\n")).unwrap();
+            fs::write(node_dir.join("properties"), properties).unwrap();
+        }
+
+        let gpus = AmdDetector::read_gpu_devices(dir.path());
+        assert_eq!(gpus.len(), 2);
+        assert_eq!(gpus[0].device_id, Some(0));
+        assert_eq!(gpus[0].model, "AMD Instinct MI300X");
+        assert_eq!(gpus[0].architecture.as_deref(), Some("CDNA3"));
+        assert_eq!(gpus[0].compute_capability.as_deref(), Some("gfx942"));
+        assert_eq!(gpus[1].device_id, Some(1));
+        assert_eq!(gpus[1].model, "AMD GPU gfx90a");
+        assert_eq!(gpus[1].architecture.as_deref(), Some("CDNA2"));
+
+        assert!(AmdDetector::read_gpu_devices(&dir.path().join("missing")).is_empty());
+        assert_eq!(AmdDetector::gfx_target(110000), "gfx1100");
+        assert_eq!(
+            AmdDetector::render_device_id("/dev/dri/renderD131"),
+            Some(3)
+        );
+        assert_eq!(AmdDetector::render_device_id("/dev/dri/card0"), None);
+    }
+}
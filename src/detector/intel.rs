@@ -0,0 +1,387 @@
+use crate::detector::amd::{AmdDetector, DRM_RENDER_MINOR_BASE, DRM_RENDER_NODE_PREFIX};
+use crate::detector::memory::{MemoryMapParser, MemoryRegion};
+use crate::detector::process::{FileDescriptor, ProcessScanner};
+use crate::detector::{
+    AllocationType, DetectionResult, GpuAllocation, GpuDetector, GpuDeviceInfo, GpuVendor,
+};
+use crate::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, trace};
+
+/// Directory the DRM subsystem lists its cards and render nodes in
+const DRM_CLASS_DIR: &str = "/sys/class/drm";
+
+/// PCI vendor ID of Intel
+pub const INTEL_PCI_VENDOR: u32 = 0x8086;
+
+/// Intel GPUs driven by i915 or xe. Render nodes under `/dev/dri` are
+/// shared with other vendors, so only those whose card reports Intel's PCI
+/// vendor ID are taken.
+pub struct IntelDetector;
+
+impl Default for IntelDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntelDetector {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Allocations backed by Intel render nodes, Level Zero shared memory or
+    /// Level Zero and oneCCL IPC segments. Render nodes are only considered
+    /// if their device ID is in `device_ids`, unless it is empty (no DRM
+    /// sysfs to tell vendors apart).
+    fn classify_regions(regions: &[MemoryRegion], device_ids: &[u32]) -> Vec<GpuAllocation> {
+        let mut allocations = Vec::new();
+
+        for region in regions {
+            let Some(pathname) = &region.pathname else {
+                continue;
+            };
+
+            let mut alloc = if let Some(device_id) = AmdDetector::render_device_id(pathname) {
+                if !device_ids.is_empty() && !device_ids.contains(&device_id) {
+                    trace!("Ignoring mapping of non-Intel render node {}", pathname);
+                    continue;
+                }
+                // Device or host memory mapped through the render node
+                let mut alloc =
+                    GpuAllocation::new(region.start, region.end, AllocationType::Standard);
+                alloc.device_id = Some(device_id);
+                alloc
+            } else if pathname.starts_with("[anon:") && Self::is_level_zero_name(pathname) {
+                // Shared USM migrates between host and device like managed
+                // memory
+                let mut alloc =
+                    GpuAllocation::new(region.start, region.end, AllocationType::Managed);
+                alloc.metadata.protection = region.perms.clone();
+                debug!(
+                    "Found Level Zero shared allocation: {:x}-{:x} ({} bytes)",
+                    region.start, region.end, alloc.size
+                );
+                allocations.push(alloc);
+                continue;
+            } else if pathname.starts_with("/dev/shm/")
+                && (Self::is_level_zero_name(pathname) || pathname.contains("ccl"))
+            {
+                let mut alloc = GpuAllocation::new(region.start, region.end, AllocationType::Ipc);
+                alloc.metadata.is_shared = true;
+                alloc.metadata.file_offset = region.offset;
+                alloc.metadata.inode = Some(region.inode).filter(|&inode| inode != 0);
+                if pathname.contains("ccl") {
+                    alloc.alloc_type = AllocationType::Distributed;
+                    alloc.metadata.is_distributed = true;
+                }
+                alloc
+            } else {
+                continue;
+            };
+
+            alloc.metadata.backing_file = Some(pathname.clone());
+            alloc.metadata.protection = region.perms.clone();
+            alloc.metadata.is_shared |= region.perms.contains('s');
+            debug!(
+                "Found {} allocation backed by {}: {:x}-{:x} ({} bytes)",
+                alloc.alloc_type, pathname, region.start, region.end, alloc.size
+            );
+            allocations.push(alloc);
+        }
+
+        allocations
+    }
+
+    /// Whether a mapping name refers to Level Zero, e.g. `[anon:ze_usm]`
+    /// or `level_zero`
+    fn is_level_zero_name(name: &str) -> bool {
+        name.contains("level_zero")
+            || name
+                .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .any(|word| word == "ze" || word.starts_with("ze_"))
+    }
+
+    /// Device IDs of the render nodes of Intel GPUs
+    pub fn render_device_ids() -> Vec<u32> {
+        Self::render_device_ids_in(Path::new(DRM_CLASS_DIR))
+    }
+
+    /// Like `render_device_ids`, with `drm_dir` in place of `/sys/class/drm`
+    fn render_device_ids_in(drm_dir: &Path) -> Vec<u32> {
+        let mut device_ids: Vec<u32> = Self::intel_cards(drm_dir)
+            .iter()
+            .flat_map(|card| Self::card_render_nodes(&card.join("device")))
+            .collect();
+        device_ids.sort_unstable();
+        device_ids.dedup();
+        device_ids
+    }
+
+    /// `card*` entries of `drm_dir` whose PCI vendor is Intel
+    fn intel_cards(drm_dir: &Path) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(drm_dir) else {
+            return Vec::new();
+        };
+
+        let mut cards: Vec<_> = entries
+            .flatten()
+            .filter(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                // card0-DP-1 and the like are connectors of a card
+                name.strip_prefix("card")
+                    .is_some_and(|n| n.chars().all(|c| c.is_ascii_digit()))
+            })
+            .map(|entry| entry.path())
+            .filter(|card| {
+                fs::read_to_string(card.join("device/vendor"))
+                    .ok()
+                    .and_then(|vendor| Self::parse_pci_id(&vendor))
+                    == Some(INTEL_PCI_VENDOR)
+            })
+            .collect();
+        cards.sort();
+        cards
+    }
+
+    /// Device IDs of the render nodes the DRM device at `device` exposes
+    fn card_render_nodes(device: &Path) -> Vec<u32> {
+        let Ok(entries) = fs::read_dir(device.join("drm")) else {
+            return Vec::new();
+        };
+
+        entries
+            .flatten()
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()?
+                    .strip_prefix("renderD")?
+                    .parse::<u32>()
+                    .ok()?
+                    .checked_sub(DRM_RENDER_MINOR_BASE)
+            })
+            .collect()
+    }
+
+    /// Parse a PCI ID as sysfs shows it, e.g. "0x8086"
+    fn parse_pci_id(value: &str) -> Option<u32> {
+        u32::from_str_radix(value.trim().strip_prefix("0x")?, 16).ok()
+    }
+
+    /// Intel GPUs with a render node
+    pub fn gpu_devices() -> Vec<GpuDeviceInfo> {
+        Self::read_gpu_devices(Path::new(DRM_CLASS_DIR))
+    }
+
+    fn read_gpu_devices(drm_dir: &Path) -> Vec<GpuDeviceInfo> {
+        let mut gpus = Vec::new();
+        for card in Self::intel_cards(drm_dir) {
+            let device = card.join("device");
+            let pci_device = fs::read_to_string(device.join("device")).unwrap_or_default();
+            let driver = fs::read_link(device.join("driver"))
+                .ok()
+                .and_then(|driver| Some(driver.file_name()?.to_string_lossy().into_owned()));
+            let model = match (pci_device.trim(), driver) {
+                ("", None) => "Intel GPU".to_string(),
+                ("", Some(driver)) => format!("Intel GPU ({driver})"),
+                (id, None) => format!("Intel GPU {id}"),
+                (id, Some(driver)) => format!("Intel GPU {id} ({driver})"),
+            };
+
+            for device_id in Self::card_render_nodes(&device) {
+                gpus.push(GpuDeviceInfo {
+                    device_id: Some(device_id),
+                    model: model.clone(),
+                    architecture: None,
+                    compute_capability: None,
+                });
+            }
+        }
+        gpus.sort_by_key(|gpu| gpu.device_id);
+        gpus
+    }
+
+    /// Whether any mapping is backed by one of the render nodes `device_ids`
+    fn has_gpu_mappings(regions: &[MemoryRegion], device_ids: &[u32]) -> bool {
+        regions.iter().any(|region| {
+            region
+                .pathname
+                .as_deref()
+                .and_then(AmdDetector::render_device_id)
+                .is_some_and(|id| device_ids.contains(&id))
+        })
+    }
+
+    /// Descriptors of the render nodes `device_ids`
+    fn gpu_fds<'a>(
+        fds: &'a [FileDescriptor],
+        device_ids: &'a [u32],
+    ) -> impl Iterator<Item = &'a FileDescriptor> {
+        fds.iter().filter(|fd| {
+            fd.target.starts_with(DRM_RENDER_NODE_PREFIX)
+                && AmdDetector::render_device_id(&fd.target)
+                    .is_some_and(|id| device_ids.contains(&id))
+        })
+    }
+
+    /// Record the descriptor each file-backed allocation was mapped through
+    fn attach_fds(allocations: &mut [GpuAllocation], fds: &[FileDescriptor]) {
+        for alloc in allocations {
+            let Some(backing_file) = &alloc.metadata.backing_file else {
+                continue;
+            };
+
+            alloc.fd = fds
+                .iter()
+                .find(|fd| &fd.target == backing_file)
+                .map(|fd| fd.fd);
+        }
+    }
+}
+
+impl GpuDetector for IntelDetector {
+    fn detect_allocations(&self, pid: u32) -> Result<DetectionResult> {
+        info!("Starting Intel GPU detection for PID {}", pid);
+
+        let mut result = DetectionResult::new(pid, GpuVendor::Intel);
+
+        let intel_device_ids = Self::render_device_ids();
+        let regions = MemoryMapParser::parse_maps(pid)?;
+        let fds = ProcessScanner::scan_file_descriptors(pid)?;
+        let gpu_fds: Vec<_> = Self::gpu_fds(&fds, &intel_device_ids).collect();
+
+        if gpu_fds.is_empty() && !Self::has_gpu_mappings(&regions, &intel_device_ids) {
+            debug!("No Intel GPU usage detected for PID {}", pid);
+            return Ok(result);
+        }
+
+        for alloc in Self::classify_regions(&regions, &intel_device_ids) {
+            result.add_allocation(alloc);
+        }
+        Self::attach_fds(&mut result.allocations, &fds);
+
+        let gpu_fd_numbers: Vec<i32> = gpu_fds.iter().map(|fd| fd.fd).collect();
+        result.gpu_threads =
+            ProcessScanner::gpu_threads(pid, &gpu_fd_numbers).unwrap_or_else(|e| {
+                debug!("Cannot scan threads of PID {}: {}", pid, e);
+                Vec::new()
+            });
+
+        // Render nodes the process has open identify the GPUs it uses
+        let mut device_ids: Vec<u32> = gpu_fds
+            .iter()
+            .filter_map(|fd| AmdDetector::render_device_id(&fd.target))
+            .collect();
+        device_ids.sort_unstable();
+        device_ids.dedup();
+        result.group_contexts(&device_ids);
+        result.gpus = Self::gpu_devices()
+            .into_iter()
+            .filter(|gpu| gpu.device_id.is_some_and(|id| device_ids.contains(&id)))
+            .collect();
+
+        info!(
+            "Intel detection complete for PID {}: found {} allocations, {} problematic",
+            pid,
+            result.allocations.len(),
+            result
+                .allocations
+                .iter()
+                .filter(|a| a.is_problematic())
+                .count()
+        );
+
+        Ok(result)
+    }
+
+    fn is_gpu_process(&self, pid: u32) -> Result<bool> {
+        let fds = ProcessScanner::scan_file_descriptors(pid)?;
+        let device_ids = Self::render_device_ids();
+        let found = Self::gpu_fds(&fds, &device_ids).next().is_some();
+        Ok(found)
+    }
+
+    fn get_vendor(&self) -> GpuVendor {
+        GpuVendor::Intel
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intel_detector_creation() {
+        assert_eq!(IntelDetector::new().get_vendor(), GpuVendor::Intel);
+    }
+
+    /// A DRM class directory with an Intel card and an AMD card, each with
+    /// its render node and a connector
+    fn fake_drm_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (card, vendor, pci_device, render) in [
+            ("card0", "0x1002", "0x74a1", "renderD128"),
+            ("card1", "0x8086", "0x56a0", "renderD129"),
+        ] {
+            let device = dir.path().join(card).join("device");
+            fs::create_dir_all(device.join("drm").join(card)).unwrap();
+            fs::create_dir_all(device.join("drm").join(render)).unwrap();
+            fs::write(device.join("vendor"), format!("{vendor}\n")).unwrap();
+            fs::write(device.join("device"), format!("{pci_device}\n")).unwrap();
+        }
+        fs::create_dir_all(dir.path().join("card1-DP-1")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_render_nodes_by_vendor() {
+        let dir = fake_drm_dir();
+
+        // renderD128 belongs to the AMD card
+        assert_eq!(IntelDetector::render_device_ids_in(dir.path()), vec![1]);
+        let gpus = IntelDetector::read_gpu_devices(dir.path());
+        assert_eq!(gpus.len(), 1);
+        assert_eq!(gpus[0].device_id, Some(1));
+        assert_eq!(gpus[0].model, "Intel GPU 0x56a0");
+
+        assert!(IntelDetector::render_device_ids_in(&dir.path().join("missing")).is_empty());
+        assert_eq!(IntelDetector::parse_pci_id("0x8086\n"), Some(0x8086));
+        assert_eq!(IntelDetector::parse_pci_id("8086"), None);
+    }
+
+    #[test]
+    fn test_classify_level_zero_mappings() {
+        let maps = "\
+7f1000000000-7f1000200000 rw-s 1a2b3c000 00:05 1043 /dev/dri/renderD128
+7f2000000000-7f2000400000 rw-s 2a2b3c000 00:05 1044 /dev/dri/renderD129
+7f4000000000-7f4000100000 rw-p 00000000 00:00 0 [anon:ze_usm_shared]
+7f5000000000-7f5000100000 rw-s 00001000 00:1a 2001 /dev/shm/ccl-shm-1234
+7f6000000000-7f6000100000 rw-s 00000000 00:1a 2002 /dev/shm/level_zero_ipc
+7f7000000000-7f7000100000 rw-p 00000000 00:00 0 [anon:sized buffer]
+7f8000000000-7f8000100000 r-xp 00000000 08:01 3001 /usr/lib/libze_loader.so.1";
+        let regions: Vec<_> = maps
+            .lines()
+            .filter_map(MemoryMapParser::parse_line)
+            .collect();
+
+        let allocations = IntelDetector::classify_regions(&regions, &[1]);
+        let found: Vec<_> = allocations
+            .iter()
+            .map(|a| (a.vaddr_start, a.alloc_type, a.device_id))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (0x7f2000000000, AllocationType::Standard, Some(1)),
+                (0x7f4000000000, AllocationType::Managed, None),
+                (0x7f5000000000, AllocationType::Distributed, None),
+                (0x7f6000000000, AllocationType::Ipc, None),
+            ]
+        );
+        assert!(IntelDetector::has_gpu_mappings(&regions, &[1]));
+        assert!(!IntelDetector::has_gpu_mappings(&regions, &[2]));
+    }
+}
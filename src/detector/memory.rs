@@ -1,18 +1,87 @@
-use crate::detector::types::{AllocationMetadata, AllocationType, GpuAllocation};
+use crate::detector::types::{AllocationMetadata, AllocationType, GpuAllocation, GpuLibrary};
 #[cfg(target_os = "linux")]
 use crate::GpuCheckpointError;
 use crate::Result;
+use regex::Regex;
+use std::collections::HashMap;
 #[cfg(target_os = "linux")]
 use std::fs::File;
 #[cfg(target_os = "linux")]
-use std::io::{BufRead, BufReader};
-#[allow(unused_imports)]
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
 use std::path::Path;
+use std::sync::LazyLock;
 use tracing::debug;
 #[cfg(target_os = "linux")]
 use tracing::trace;
 
-#[derive(Debug)]
+/// Size of one `/proc/PID/pagemap` entry
+const PAGEMAP_ENTRY_SIZE: u64 = 8;
+
+/// Size of the huge pages checkpoints align windows of huge page backed
+/// allocations to, the x86-64 and arm64 (4K granule) default
+pub const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Mount point of hugetlbfs, whose files are always huge page backed
+const HUGETLBFS_MOUNT: &str = "/dev/hugepages/";
+
+/// Name the kernel gives anonymous `MAP_HUGETLB` mappings
+const ANON_HUGEPAGE: &str = "/anon_hugepage";
+
+/// Pagemap bit 63: page present in RAM
+const PAGEMAP_PRESENT: u64 = 1 << 63;
+
+/// Pagemap bit 62: page swapped out
+const PAGEMAP_SWAPPED: u64 = 1 << 62;
+
+/// Pagemap bit 55: page written since the soft-dirty bits were last cleared
+const PAGEMAP_SOFT_DIRTY: u64 = 1 << 55;
+
+/// Pagemap bits 0-54: page frame number of a present page. The kernel
+/// reports 0 unless the reader has `CAP_SYS_ADMIN`.
+const PAGEMAP_PFN_MASK: u64 = (1 << 55) - 1;
+
+/// Capability the kernel requires to report PFNs in pagemap entries
+#[cfg(target_os = "linux")]
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// Value written to `/proc/PID/clear_refs` to clear the soft-dirty bits
+#[cfg(target_os = "linux")]
+const CLEAR_SOFT_DIRTY: &[u8] = b"4";
+
+/// Matches sysfs PCI resource files (`.../<domain:bus:dev.fn>/resource<N>`,
+/// optionally write-combined) and captures the address and BAR index
+static PCI_RESOURCE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^/sys/.*/([0-9a-fA-F]{4}:[0-9a-fA-F]{2}:[0-9a-fA-F]{2}\.[0-7])/resource(\d+)(?:_wc)?$",
+    )
+    .expect("valid PCI resource regex")
+});
+
+/// Shared libraries of the CUDA, ROCm and driver stacks, by name without
+/// the `.so` suffix
+pub const GPU_LIBRARIES: &[&str] = &[
+    "libcuda",
+    "libcudart",
+    "libcudnn",
+    "libnccl",
+    "libcublas",
+    "libcublasLt",
+    "libcufft",
+    "libcurand",
+    "libcusparse",
+    "libcusolver",
+    "libnvrtc",
+    "libnvinfer",
+    "libnvidia-ml",
+    "libnvidia-ptxjitcompiler",
+    "libamdhip64",
+    "libhsa-runtime64",
+    "librccl",
+    "libMIOpen",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[allow(dead_code)]
 pub struct MemoryRegion {
     pub start: u64,
@@ -24,6 +93,52 @@ pub struct MemoryRegion {
     pub pathname: Option<String>,
 }
 
+/// A mapping from `/proc/PID/smaps` along with the fields of its detail
+/// lines that detection uses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmapsRegion {
+    pub region: MemoryRegion,
+
+    /// Bytes of the mapping resident in RAM (`Rss:`)
+    pub rss: u64,
+
+    /// Proportional share of the resident bytes, shared pages divided
+    /// among the processes mapping them (`Pss:`)
+    pub pss: u64,
+
+    /// Bytes of the mapping swapped out (`Swap:`)
+    pub swap: u64,
+
+    /// Bytes of the mapping locked in memory (`Locked:`)
+    pub locked: u64,
+
+    /// Size of the pages backing the mapping (`KernelPageSize:`), 0 if not
+    /// listed
+    pub kernel_page_size: u64,
+
+    /// Bytes of the mapping backed by transparent huge pages
+    /// (`AnonHugePages:`)
+    pub anon_huge_pages: u64,
+
+    /// Two-letter flags of the mapping (`VmFlags:`), e.g. `dc` for memory
+    /// marked `MADV_DONTFORK` or `io` for device memory
+    pub vm_flags: Vec<String>,
+}
+
+impl SmapsRegion {
+    /// Whether the mapping is backed by huge pages: hugetlbfs pages larger
+    /// than the base page size, or transparent huge pages
+    pub fn is_huge_page(&self) -> bool {
+        self.kernel_page_size > crate::utils::page_size()
+            || self.anon_huge_pages > 0
+            || MemoryMapParser::is_hugetlb_mapping(&self.region)
+    }
+
+    pub fn has_vm_flag(&self, flag: &str) -> bool {
+        self.vm_flags.iter().any(|f| f == flag)
+    }
+}
+
 pub struct MemoryMapParser;
 
 #[allow(dead_code)]
@@ -65,6 +180,99 @@ impl MemoryMapParser {
         }
     }
 
+    /// Mappings of `pid` with their detail fields, from `/proc/PID/smaps`
+    pub fn parse_smaps(pid: u32) -> Result<Vec<SmapsRegion>> {
+        #[cfg(target_os = "linux")]
+        {
+            let contents = std::fs::read_to_string(format!("/proc/{pid}/smaps")).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    GpuCheckpointError::ProcessNotFound(pid)
+                } else if e.kind() == std::io::ErrorKind::PermissionDenied {
+                    GpuCheckpointError::PermissionDenied
+                } else {
+                    GpuCheckpointError::IoError(e)
+                }
+            })?;
+
+            let regions = Self::parse_smaps_content(&contents);
+            debug!("Parsed {} smaps regions for PID {}", regions.len(), pid);
+            Ok(regions)
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = pid;
+            debug!("smaps parsing not supported on this platform");
+            Ok(Vec::new())
+        }
+    }
+
+    /// Parse the contents of an smaps file: each mapping line as in `maps`,
+    /// followed by `Name: value [kB]` detail lines
+    pub fn parse_smaps_content(contents: &str) -> Vec<SmapsRegion> {
+        let mut regions: Vec<SmapsRegion> = Vec::new();
+
+        for line in contents.lines() {
+            if let Some((key, value)) = line.split_once(':') {
+                // Detail names never contain '-', mapping addresses always do
+                if !key.contains('-') {
+                    if let Some(current) = regions.last_mut() {
+                        if key == "VmFlags" {
+                            current.vm_flags =
+                                value.split_whitespace().map(str::to_string).collect();
+                            continue;
+                        }
+                        let field = match key {
+                            "Rss" => &mut current.rss,
+                            "Pss" => &mut current.pss,
+                            "Swap" => &mut current.swap,
+                            "Locked" => &mut current.locked,
+                            "KernelPageSize" => &mut current.kernel_page_size,
+                            "AnonHugePages" => &mut current.anon_huge_pages,
+                            _ => continue,
+                        };
+                        *field = Self::parse_smaps_size(value).unwrap_or(0);
+                    }
+                    continue;
+                }
+            }
+
+            if let Some(region) = Self::parse_line(line) {
+                regions.push(SmapsRegion {
+                    region,
+                    rss: 0,
+                    pss: 0,
+                    swap: 0,
+                    locked: 0,
+                    kernel_page_size: 0,
+                    anon_huge_pages: 0,
+                    vm_flags: Vec::new(),
+                });
+            }
+        }
+
+        regions
+    }
+
+    /// smaps regions keyed by their start address
+    pub fn smaps_by_start(regions: &[SmapsRegion]) -> HashMap<u64, &SmapsRegion> {
+        regions
+            .iter()
+            .map(|entry| (entry.region.start, entry))
+            .collect()
+    }
+
+    /// Bytes of an smaps size value such as "  2048 kB"
+    fn parse_smaps_size(value: &str) -> Option<u64> {
+        let mut parts = value.split_whitespace();
+        let amount = parts.next()?.parse::<u64>().ok()?;
+        match parts.next() {
+            Some("kB") => Some(amount * 1024),
+            None => Some(amount),
+            Some(_) => None,
+        }
+    }
+
     pub fn parse_line(line: &str) -> Option<MemoryRegion> {
         let parts: Vec<&str> = line.split_whitespace().collect();
         if parts.len() < 5 {
@@ -110,6 +318,285 @@ impl MemoryMapParser {
         })
     }
 
+    /// Address ranges within `[start, end)` mapped in `pid`, adjacent
+    /// mappings merged
+    pub fn mapped_ranges(pid: u32, start: u64, end: u64) -> Result<Vec<Range<u64>>> {
+        Ok(Self::ranges_within(&Self::parse_maps(pid)?, start, end))
+    }
+
+    /// Parts of `[start, end)` covered by `regions`, which are in address
+    /// order as in `/proc/PID/maps`
+    pub fn ranges_within(regions: &[MemoryRegion], start: u64, end: u64) -> Vec<Range<u64>> {
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for region in regions {
+            let range = region.start.max(start)..region.end.min(end);
+            if range.is_empty() {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => ranges.push(range),
+            }
+        }
+        ranges
+    }
+
+    /// Address ranges within `[start, end)` whose pages are resident (in
+    /// RAM or swapped), according to `/proc/PID/pagemap`
+    pub fn resident_ranges(pid: u32, start: u64, end: u64) -> Result<Vec<Range<u64>>> {
+        Self::pagemap_ranges(pid, start, end, PAGEMAP_PRESENT | PAGEMAP_SWAPPED)
+    }
+
+    /// Address ranges within `[start, end)` written since the soft-dirty
+    /// bits of `pid` were last cleared. Without kernel support for
+    /// soft-dirty tracking no page is ever reported, see
+    /// `soft_dirty_supported`.
+    pub fn soft_dirty_ranges(pid: u32, start: u64, end: u64) -> Result<Vec<Range<u64>>> {
+        Self::pagemap_ranges(pid, start, end, PAGEMAP_SOFT_DIRTY)
+    }
+
+    /// Clear the soft-dirty bits of every page of `pid`, so
+    /// `soft_dirty_ranges` only reports pages written from now on
+    pub fn clear_soft_dirty(pid: u32) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            std::fs::write(format!("/proc/{pid}/clear_refs"), CLEAR_SOFT_DIRTY).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    GpuCheckpointError::ProcessNotFound(pid)
+                } else if e.kind() == std::io::ErrorKind::PermissionDenied {
+                    GpuCheckpointError::PermissionDenied
+                } else {
+                    GpuCheckpointError::IoError(e)
+                }
+            })?;
+            debug!("Cleared soft-dirty bits of PID {}", pid);
+            Ok(())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = pid;
+            Ok(())
+        }
+    }
+
+    /// Whether the kernel tracks soft-dirty pages. Kernels built without
+    /// `CONFIG_MEM_SOFT_DIRTY` never set the bit, so a page this process
+    /// just wrote is checked for it.
+    pub fn soft_dirty_supported() -> bool {
+        static SUPPORTED: LazyLock<bool> = LazyLock::new(|| {
+            let Ok(mut page) = memmap2::MmapMut::map_anon(1) else {
+                return false;
+            };
+            page[0] = 1;
+            let start = page.as_ptr() as u64;
+            MemoryMapParser::soft_dirty_ranges(std::process::id(), start, start + 1)
+                .is_ok_and(|ranges| !ranges.is_empty())
+        });
+        *SUPPORTED
+    }
+
+    /// Physical frame number of every page in `[start, end)`, 0 for pages
+    /// that are not present. `None` when the frames cannot be read: PFNs are
+    /// only reported to readers with `CAP_SYS_ADMIN`.
+    pub fn physical_frames(pid: u32, start: u64, end: u64) -> Result<Option<Vec<u64>>> {
+        #[cfg(target_os = "linux")]
+        {
+            if !Self::can_read_frames() {
+                trace!("Not privileged to read the physical frames of PID {}", pid);
+                return Ok(None);
+            }
+
+            let (entries, _) = Self::read_pagemap(pid, start, end)?;
+            let frames = Self::parse_pagemap_frames(&entries);
+            // Without the privilege the kernel zeroes the PFNs instead of
+            // failing the read, e.g. when it is dropped by a user namespace
+            if frames.iter().all(|&frame| frame == 0) {
+                return Ok(None);
+            }
+            Ok(Some(frames))
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (pid, start, end);
+            Ok(None)
+        }
+    }
+
+    /// Whether a mapping is of a hugetlbfs file or anonymous `MAP_HUGETLB`
+    /// memory, which is all `maps` shows of huge pages
+    pub fn is_hugetlb_mapping(region: &MemoryRegion) -> bool {
+        region.pathname.as_deref().is_some_and(|path| {
+            path.starts_with(HUGETLBFS_MOUNT) || path.starts_with(ANON_HUGEPAGE)
+        })
+    }
+
+    /// Flag the allocations that start at a huge page backed mapping,
+    /// judged from smaps when it could be read and from the pathnames in
+    /// `regions` otherwise
+    pub fn attach_huge_pages(
+        allocations: &mut [GpuAllocation],
+        regions: &[MemoryRegion],
+        smaps: &[SmapsRegion],
+    ) {
+        let by_start = Self::smaps_by_start(smaps);
+        for alloc in allocations {
+            alloc.metadata.huge_page = match by_start.get(&alloc.vaddr_start) {
+                Some(entry) => entry.is_huge_page(),
+                None => regions
+                    .iter()
+                    .find(|region| region.start == alloc.vaddr_start)
+                    .is_some_and(Self::is_hugetlb_mapping),
+            };
+        }
+    }
+
+    /// Record the physical frames backing each `BarMapped` allocation of
+    /// `pid`. Allocations whose frames cannot be read are left without.
+    pub fn attach_physical_frames(pid: u32, allocations: &mut [GpuAllocation]) {
+        for alloc in allocations
+            .iter_mut()
+            .filter(|alloc| alloc.alloc_type == AllocationType::BarMapped)
+        {
+            alloc.metadata.physical_frames =
+                match Self::physical_frames(pid, alloc.vaddr_start, alloc.vaddr_end) {
+                    Ok(frames) => frames,
+                    Err(e) => {
+                        debug!(
+                            "Cannot resolve physical frames of {:x}-{:x}: {}",
+                            alloc.vaddr_start, alloc.vaddr_end, e
+                        );
+                        None
+                    }
+                };
+        }
+    }
+
+    /// Whether this process has `CAP_SYS_ADMIN`, without which pagemap
+    /// entries carry no PFNs
+    #[cfg(target_os = "linux")]
+    fn can_read_frames() -> bool {
+        std::fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                crate::detector::ProcessScanner::parse_effective_capabilities(&status)
+            })
+            .is_some_and(|caps| caps & (1 << CAP_SYS_ADMIN) != 0)
+    }
+
+    /// Address ranges within `[start, end)` whose pagemap entries have any
+    /// of the `mask` bits set
+    fn pagemap_ranges(pid: u32, start: u64, end: u64, mask: u64) -> Result<Vec<Range<u64>>> {
+        #[cfg(target_os = "linux")]
+        {
+            let page_size = crate::utils::page_size();
+            let (entries, base) = Self::read_pagemap(pid, start, end)?;
+            let ranges = Self::parse_pagemap(&entries, base, page_size, mask)
+                .into_iter()
+                .map(|range| range.start.max(start)..range.end.min(end))
+                .collect::<Vec<_>>();
+
+            trace!(
+                "PID {} has {} range(s) matching {:x} in {:x}-{:x}",
+                pid,
+                ranges.len(),
+                mask,
+                start,
+                end
+            );
+            Ok(ranges)
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (pid, mask);
+            Ok(vec![start..end])
+        }
+    }
+
+    /// Raw pagemap entries of the pages covering `[start, end)`, along with
+    /// the address of the first page
+    #[cfg(target_os = "linux")]
+    fn read_pagemap(pid: u32, start: u64, end: u64) -> Result<(Vec<u8>, u64)> {
+        let page_size = crate::utils::page_size();
+        let first_page = start / page_size;
+        let last_page = end.div_ceil(page_size);
+
+        let mut pagemap = File::open(format!("/proc/{pid}/pagemap")).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                GpuCheckpointError::ProcessNotFound(pid)
+            } else if e.kind() == std::io::ErrorKind::PermissionDenied {
+                GpuCheckpointError::PermissionDenied
+            } else {
+                GpuCheckpointError::IoError(e)
+            }
+        })?;
+
+        pagemap.seek(SeekFrom::Start(first_page * PAGEMAP_ENTRY_SIZE))?;
+        let entries_len =
+            usize::try_from((last_page - first_page) * PAGEMAP_ENTRY_SIZE).map_err(|_| {
+                GpuCheckpointError::DetectionError(format!(
+                    "range 0x{start:016x}-0x{end:016x} exceeds the address space of this host"
+                ))
+            })?;
+        let mut entries = vec![0u8; entries_len];
+        pagemap.read_exact(&mut entries)?;
+        Ok((entries, first_page * page_size))
+    }
+
+    /// Coalesce runs of resident pages from raw pagemap entries starting at
+    /// address `base`
+    pub fn parse_pagemap_resident(entries: &[u8], base: u64, page_size: u64) -> Vec<Range<u64>> {
+        Self::parse_pagemap(entries, base, page_size, PAGEMAP_PRESENT | PAGEMAP_SWAPPED)
+    }
+
+    /// Coalesce runs of soft-dirty pages from raw pagemap entries starting
+    /// at address `base`
+    pub fn parse_pagemap_soft_dirty(entries: &[u8], base: u64, page_size: u64) -> Vec<Range<u64>> {
+        Self::parse_pagemap(entries, base, page_size, PAGEMAP_SOFT_DIRTY)
+    }
+
+    /// Physical frame number of each raw pagemap entry, 0 for pages that
+    /// are not present
+    pub fn parse_pagemap_frames(entries: &[u8]) -> Vec<u64> {
+        entries
+            .chunks_exact(PAGEMAP_ENTRY_SIZE as usize)
+            .map(|entry| {
+                Self::pagemap_frame(u64::from_le_bytes(entry.try_into().expect("8 byte chunk")))
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+
+    /// Physical frame number of a pagemap entry of a present page. The low
+    /// bits of a swapped page hold its swap location, not a frame.
+    pub fn pagemap_frame(entry: u64) -> Option<u64> {
+        (entry & PAGEMAP_PRESENT != 0).then_some(entry & PAGEMAP_PFN_MASK)
+    }
+
+    fn parse_pagemap(entries: &[u8], base: u64, page_size: u64, mask: u64) -> Vec<Range<u64>> {
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+
+        for (i, entry) in entries
+            .chunks_exact(PAGEMAP_ENTRY_SIZE as usize)
+            .enumerate()
+        {
+            let entry = u64::from_le_bytes(entry.try_into().expect("8 byte chunk"));
+            if entry & mask == 0 {
+                continue;
+            }
+
+            let page_start = base + i as u64 * page_size;
+            match ranges.last_mut() {
+                Some(last) if last.end == page_start => last.end += page_size,
+                _ => ranges.push(page_start..page_start + page_size),
+            }
+        }
+
+        ranges
+    }
+
     pub fn classify_region(region: &MemoryRegion) -> Option<GpuAllocation> {
         let pathname = region.pathname.as_ref()?;
 
@@ -146,16 +633,69 @@ impl MemoryMapParser {
         }
 
         // Check for GPU BAR mappings (PCIe memory-mapped regions)
-        if pathname.contains("/sys/bus/pci/devices/") && pathname.contains("resource") {
+        if let Some((_, bar_index)) = Self::parse_pci_resource(pathname) {
             let mut allocation =
                 GpuAllocation::new(region.start, region.end, AllocationType::BarMapped);
             allocation.metadata.backing_file = Some(pathname.clone());
             allocation.metadata.protection = region.perms.clone();
+            allocation.metadata.bar_index = Some(bar_index);
             return Some(allocation);
         }
 
         None
     }
+
+    /// PCI address (e.g. "0000:01:00.1") and BAR index of a mapped sysfs
+    /// resource file, under `/sys/bus/pci/devices` or `/sys/devices`
+    pub fn parse_pci_resource(pathname: &str) -> Option<(String, u32)> {
+        let captures = PCI_RESOURCE_RE.captures(pathname)?;
+        Some((captures[1].to_ascii_lowercase(), captures[2].parse().ok()?))
+    }
+
+    /// GPU libraries among the file-backed `regions`, one entry per mapped
+    /// file, sorted by name
+    pub fn gpu_libraries(regions: &[MemoryRegion]) -> Vec<GpuLibrary> {
+        let mut libraries: Vec<GpuLibrary> = Vec::new();
+        for pathname in regions.iter().filter_map(|r| r.pathname.as_deref()) {
+            if libraries.iter().any(|lib| lib.path == pathname) {
+                continue;
+            }
+            if let Some(library) = Self::parse_library(pathname) {
+                libraries.push(library);
+            }
+        }
+
+        libraries.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.path.cmp(&b.path)));
+        libraries
+    }
+
+    /// Identify a GPU library from its path. The version is taken from the
+    /// file name, since the loader maps the file the soname symlink resolves
+    /// to (e.g. `libcudart.so.12.2.140`).
+    pub fn parse_library(pathname: &str) -> Option<GpuLibrary> {
+        let file_name = Path::new(pathname).file_name()?.to_str()?;
+        let (base, suffix) = file_name.split_once(".so")?;
+        if !suffix.is_empty() && !suffix.starts_with('.') {
+            return None;
+        }
+
+        // Wheels vendor libraries with a hash, e.g. libcudart-9335f6a2.so.12
+        let name = GPU_LIBRARIES.iter().find(|name| {
+            base.strip_prefix(**name).is_some_and(|rest| {
+                rest.is_empty()
+                    || rest
+                        .strip_prefix('-')
+                        .is_some_and(|hash| hash.chars().all(|c| c.is_ascii_hexdigit()))
+            })
+        })?;
+
+        let version = suffix.trim_start_matches('.');
+        Some(GpuLibrary {
+            name: name.to_string(),
+            path: pathname.to_string(),
+            version: (!version.is_empty()).then(|| version.to_string()),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -183,6 +723,109 @@ mod tests {
         assert_eq!(region.pathname, None);
     }
 
+    #[test]
+    fn test_parse_smaps() {
+        let smaps = "\
+7f5a00000000-7f5b00000000 rw-s 00000000 00:05 412 /dev/nvidia-uvm
+Size:            4194304 kB
+KernelPageSize:        4 kB
+MMUPageSize:           4 kB
+Rss:              524288 kB
+Pss:              262144 kB
+Pss_Anon:              0 kB
+Shared_Clean:          0 kB
+Shared_Dirty:     524288 kB
+Private_Clean:         0 kB
+Private_Dirty:         0 kB
+Referenced:       524288 kB
+Anonymous:             0 kB
+Swap:               2048 kB
+SwapPss:            1024 kB
+Locked:                0 kB
+THPeligible:    0
+VmFlags: rd wr sh mr mw me ms dc de
+7f5b00000000-7f5b00001000 r--p 00000000 08:01 1234 /usr/lib/libcuda.so.1
+Size:                  4 kB
+Rss:                   4 kB
+Pss:                   4 kB
+Swap:                  0 kB
+Locked:                0 kB
+";
+        let regions = MemoryMapParser::parse_smaps_content(smaps);
+        assert_eq!(regions.len(), 2);
+
+        let uvm = &regions[0];
+        assert_eq!(uvm.region.start, 0x7f5a00000000);
+        assert_eq!(uvm.region.pathname.as_deref(), Some("/dev/nvidia-uvm"));
+        assert_eq!(uvm.rss, 512 * 1024 * 1024);
+        assert_eq!(uvm.pss, 256 * 1024 * 1024);
+        // SwapPss does not overwrite Swap
+        assert_eq!(uvm.swap, 2 * 1024 * 1024);
+        assert_eq!(uvm.locked, 0);
+        assert!(uvm.has_vm_flag("dc"));
+        assert!(!uvm.has_vm_flag("io"));
+        assert_eq!(regions[1].rss, 4096);
+        assert!(regions[1].vm_flags.is_empty());
+
+        let by_start = MemoryMapParser::smaps_by_start(&regions);
+        assert_eq!(by_start[&0x7f5b00000000].pss, 4096);
+        assert!(!by_start.contains_key(&0x7f5a00001000));
+    }
+
+    #[test]
+    fn test_huge_page_mappings() {
+        let smaps = "\
+7f4000000000-7f4040000000 rw-s 00000000 00:2f 1001 /dev/hugepages/cuda-pinned
+Size:            1048576 kB
+KernelPageSize:     2048 kB
+MMUPageSize:        2048 kB
+Rss:              524288 kB
+AnonHugePages:         0 kB
+Locked:                0 kB
+7f4040000000-7f4080000000 rw-p 00000000 00:00 0
+Size:            1048576 kB
+KernelPageSize:        4 kB
+AnonHugePages:    262144 kB
+7f4080000000-7f40c0000000 rw-p 00000000 00:00 0
+Size:            1048576 kB
+KernelPageSize:        4 kB
+AnonHugePages:         0 kB
+";
+        let regions = MemoryMapParser::parse_smaps_content(smaps);
+        assert_eq!(regions[0].kernel_page_size, HUGE_PAGE_SIZE);
+        assert_eq!(regions[1].anon_huge_pages, 256 * 1024 * 1024);
+        assert!(regions[0].is_huge_page());
+        assert!(regions[1].is_huge_page());
+        assert!(!regions[2].is_huge_page());
+
+        let mut allocations: Vec<GpuAllocation> = regions
+            .iter()
+            .map(|entry| {
+                GpuAllocation::new(
+                    entry.region.start,
+                    entry.region.end,
+                    AllocationType::HostPinned,
+                )
+            })
+            .collect();
+        MemoryMapParser::attach_huge_pages(&mut allocations, &[], &regions);
+        let flags: Vec<bool> = allocations.iter().map(|a| a.metadata.huge_page).collect();
+        assert_eq!(flags, [true, true, false]);
+
+        // Without smaps, only hugetlb pathnames in maps tell
+        let maps: Vec<MemoryRegion> = [
+            "7f4000000000-7f4040000000 rw-s 00000000 00:2f 1001 /dev/hugepages/cuda-pinned",
+            "7f4040000000-7f4080000000 rw-p 00000000 00:0f 2002 /anon_hugepage (deleted)",
+            "7f4080000000-7f40c0000000 rw-p 00000000 00:00 0",
+        ]
+        .iter()
+        .map(|line| MemoryMapParser::parse_line(line).unwrap())
+        .collect();
+        MemoryMapParser::attach_huge_pages(&mut allocations, &maps, &[]);
+        let flags: Vec<bool> = allocations.iter().map(|a| a.metadata.huge_page).collect();
+        assert_eq!(flags, [true, true, false]);
+    }
+
     #[test]
     fn test_parse_with_spaces_in_path() {
         let line = "7f0000000000-7f0001000000 r-xp 00000000 08:01 123456 /path/with spaces/file";
@@ -191,6 +834,85 @@ mod tests {
         assert_eq!(region.pathname, Some("/path/with spaces/file".to_string()));
     }
 
+    #[test]
+    fn test_ranges_within() {
+        let regions: Vec<MemoryRegion> = [
+            "1000-3000 rw-p 00000000 00:00 0",
+            "3000-4000 r--p 00000000 00:00 0",
+            "6000-8000 rw-p 00000000 00:00 0",
+            "9000-a000 rw-p 00000000 00:00 0",
+        ]
+        .iter()
+        .map(|line| MemoryMapParser::parse_line(line).unwrap())
+        .collect();
+
+        // Adjacent mappings merge, the gaps between them are left out
+        assert_eq!(
+            MemoryMapParser::ranges_within(&regions, 0x2000, 0x9800),
+            vec![0x2000..0x4000, 0x6000..0x8000, 0x9000..0x9800]
+        );
+        assert_eq!(
+            MemoryMapParser::ranges_within(&regions, 0x1000, 0x3000),
+            vec![0x1000..0x3000]
+        );
+        assert!(MemoryMapParser::ranges_within(&regions, 0x4000, 0x6000).is_empty());
+    }
+
+    #[test]
+    fn test_parse_pagemap_resident() {
+        let page_size = 4096;
+        let mut entries = Vec::new();
+        for entry in [
+            PAGEMAP_PRESENT | 0x1234, // resident
+            PAGEMAP_PRESENT,          // resident, PFN hidden without CAP_SYS_ADMIN
+            0,                        // never touched
+            PAGEMAP_SWAPPED,          // swapped out still has contents
+            0,
+        ] {
+            entries.extend_from_slice(&u64::to_le_bytes(entry));
+        }
+
+        let ranges = MemoryMapParser::parse_pagemap_resident(&entries, 0x10000, page_size);
+        assert_eq!(ranges, vec![0x10000..0x12000, 0x13000..0x14000]);
+
+        // Only pages written since the last clear are soft-dirty
+        let mut entries = Vec::new();
+        for entry in [
+            PAGEMAP_PRESENT | PAGEMAP_SOFT_DIRTY,
+            PAGEMAP_PRESENT,
+            PAGEMAP_SWAPPED | PAGEMAP_SOFT_DIRTY,
+        ] {
+            entries.extend_from_slice(&u64::to_le_bytes(entry));
+        }
+        let ranges = MemoryMapParser::parse_pagemap_soft_dirty(&entries, 0x10000, page_size);
+        assert_eq!(ranges, vec![0x10000..0x11000, 0x12000..0x13000]);
+    }
+
+    #[test]
+    fn test_parse_pagemap_frames() {
+        let mut entries = Vec::new();
+        for entry in [
+            PAGEMAP_PRESENT | 0x1234,                      // present
+            PAGEMAP_PRESENT | PAGEMAP_SOFT_DIRTY | 0xabcd, // flags above the PFN
+            PAGEMAP_SWAPPED | 0x42,                        // swap location, not a frame
+            0,                                             // never touched
+            PAGEMAP_PRESENT | PAGEMAP_PFN_MASK,            // widest PFN
+        ] {
+            entries.extend_from_slice(&u64::to_le_bytes(entry));
+        }
+
+        assert_eq!(
+            MemoryMapParser::parse_pagemap_frames(&entries),
+            vec![0x1234, 0xabcd, 0, 0, (1 << 55) - 1]
+        );
+        assert_eq!(MemoryMapParser::pagemap_frame(PAGEMAP_PRESENT), Some(0));
+        assert_eq!(MemoryMapParser::pagemap_frame(PAGEMAP_SWAPPED | 7), None);
+
+        // A trailing partial entry is ignored
+        entries.extend_from_slice(&[0xff; 4]);
+        assert_eq!(MemoryMapParser::parse_pagemap_frames(&entries).len(), 5);
+    }
+
     #[test]
     fn test_classify_nvidia_uvm() {
         let region = MemoryRegion {
@@ -227,6 +949,44 @@ mod tests {
         assert!(!allocation.metadata.is_shared);
     }
 
+    #[test]
+    fn test_gpu_libraries() {
+        let maps = "\
+7f0000000000-7f0000100000 r--p 00000000 08:01 11 /usr/lib/x86_64-linux-gnu/libcuda.so.535.104.05
+7f0000100000-7f0000200000 r-xp 00100000 08:01 11 /usr/lib/x86_64-linux-gnu/libcuda.so.535.104.05
+7f0000200000-7f0000300000 r-xp 00000000 08:01 12 /opt/venv/torch/lib/libcudart-9335f6a2.so.12
+7f0000300000-7f0000400000 r-xp 00000000 08:01 13 /opt/venv/nvidia/cublas/lib/libcublasLt.so.12
+7f0000400000-7f0000500000 r-xp 00000000 08:01 14 /usr/lib/libcudann.so.1
+7f0000500000-7f0000600000 r-xp 00000000 08:01 15 /usr/lib/libnccl.so
+7f0000600000-7f0000700000 rw-p 00000000 00:00 0";
+        let regions: Vec<_> = maps
+            .lines()
+            .filter_map(MemoryMapParser::parse_line)
+            .collect();
+
+        let libraries = MemoryMapParser::gpu_libraries(&regions);
+        let summary: Vec<_> = libraries
+            .iter()
+            .map(|lib| (lib.name.as_str(), lib.version.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("libcublasLt", Some("12")),
+                ("libcuda", Some("535.104.05")),
+                ("libcudart", Some("12")),
+                ("libnccl", None),
+            ]
+        );
+        assert_eq!(
+            libraries[1].path,
+            "/usr/lib/x86_64-linux-gnu/libcuda.so.535.104.05"
+        );
+
+        assert!(MemoryMapParser::parse_library("/usr/lib/libcudart_static.a").is_none());
+        assert!(MemoryMapParser::parse_library("/usr/lib/libcuda.sock").is_none());
+    }
+
     #[test]
     fn test_classify_bar_mapping() {
         let region = MemoryRegion {
@@ -241,5 +1001,28 @@ mod tests {
 
         let allocation = MemoryMapParser::classify_region(&region).unwrap();
         assert_eq!(allocation.alloc_type, AllocationType::BarMapped);
+        assert_eq!(allocation.metadata.bar_index, Some(0));
+    }
+
+    #[test]
+    fn test_parse_pci_resource() {
+        let parse = MemoryMapParser::parse_pci_resource;
+        assert_eq!(
+            parse("/sys/bus/pci/devices/0000:01:00.0/resource1"),
+            Some(("0000:01:00.0".to_string(), 1))
+        );
+        // Non-zero functions, write-combined resources and the canonical path
+        assert_eq!(
+            parse("/sys/bus/pci/devices/0000:3b:00.1/resource3_wc"),
+            Some(("0000:3b:00.1".to_string(), 3))
+        );
+        assert_eq!(
+            parse("/sys/devices/pci0000:00/0000:00:01.0/0000:01:00.0/resource0"),
+            Some(("0000:01:00.0".to_string(), 0))
+        );
+
+        assert_eq!(parse("/sys/bus/pci/devices/0000:01:00.0/resource"), None);
+        assert_eq!(parse("/sys/bus/pci/devices/0000:01:00.0/config"), None);
+        assert_eq!(parse("/tmp/0000:01:00.0/resource0"), None);
     }
 }
@@ -1,13 +1,25 @@
+mod amd;
+mod intel;
 mod memory;
 mod nvidia;
+#[cfg(feature = "nvml")]
+mod nvml;
 mod process;
 mod types;
 
+pub use amd::AmdDetector;
+pub use intel::IntelDetector;
+pub use memory::{MemoryMapParser, MemoryRegion, SmapsRegion, HUGE_PAGE_SIZE};
 pub use nvidia::NvidiaDetector;
-pub use process::ProcessScanner;
-pub use types::{AllocationType, DetectionResult, GpuAllocation, GpuVendor};
+pub use process::{FileDescriptor, GpuDeviceType, GpuFdInfo, ProcessScanner, ProcessState};
+pub use types::detection_schema;
+pub use types::{
+    AllocationFilter, AllocationType, ContextAllocations, DetectionDiff, DetectionReport,
+    DetectionResult, GpuAllocation, GpuDeviceInfo, GpuLibrary, GpuVendor, IpcHandle, ProcessGroup,
+    CUDA_IPC_HANDLE_SIZE, DETECTION_SCHEMA_VERSION,
+};
 
-use crate::Result;
+use crate::{GpuCheckpointError, Result};
 use std::path::Path;
 use tracing::{debug, info, warn};
 
@@ -19,8 +31,25 @@ pub trait GpuDetector: Send + Sync {
     fn get_vendor(&self) -> GpuVendor;
 }
 
+/// What `CompositeDetector::detect_all` found, and which detectors failed
+#[derive(Debug, Default)]
+pub struct Detections {
+    pub results: Vec<DetectionResult>,
+    pub errors: Vec<DetectorFailure>,
+}
+
+/// Error of one detector of a `CompositeDetector`
+#[derive(Debug)]
+pub struct DetectorFailure {
+    pub vendor: GpuVendor,
+    pub error: GpuCheckpointError,
+}
+
 pub struct CompositeDetector {
     detectors: Vec<Box<dyn GpuDetector>>,
+
+    /// Fail on the first detector error instead of skipping the detector
+    strict: bool,
 }
 
 impl Default for CompositeDetector {
@@ -39,20 +68,53 @@ impl CompositeDetector {
             detectors.push(Box::new(NvidiaDetector::new()));
         }
 
-        // Future: Add AMD, Intel detectors here
+        if Path::new(amd::KFD_DEVICE).exists() {
+            info!("AMD GPU detected, adding AMD detector");
+            detectors.push(Box::new(AmdDetector::new()));
+        }
+
+        // Render nodes exist for every vendor, only an Intel card counts
+        if !IntelDetector::render_device_ids().is_empty() {
+            info!("Intel GPU detected, adding Intel detector");
+            detectors.push(Box::new(IntelDetector::new()));
+        }
 
         if detectors.is_empty() {
             warn!("No GPU detectors available on this system");
         }
 
-        Self { detectors }
+        Self::from_detectors(detectors)
+    }
+
+    /// Composite of the given detectors rather than those for the GPUs
+    /// present on this system
+    pub fn from_detectors(detectors: Vec<Box<dyn GpuDetector>>) -> Self {
+        Self {
+            detectors,
+            strict: false,
+        }
+    }
+
+    /// Propagate the first detector error from `detect_all` instead of
+    /// logging it and continuing with the remaining detectors
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
     }
 
-    pub fn detect_all(&self, pid: u32) -> Result<Vec<DetectionResult>> {
-        let mut results = Vec::new();
+    /// Run every detector on `pid`. Failed detectors are reported in
+    /// `Detections::errors` rather than failing the detection, unless
+    /// strict.
+    pub fn detect_all(&self, pid: u32) -> Result<Detections> {
+        let mut detections = Detections::default();
 
         for detector in &self.detectors {
-            match detector.detect_allocations(pid) {
+            // A detector that produces inconsistent or overlapping ranges is
+            // treated as failed rather than trusted with a checkpoint
+            let detected = detector
+                .detect_allocations(pid)
+                .and_then(|result| result.validate().map(|()| result));
+            match detected {
                 Ok(result) => {
                     debug!(
                         "Detector {:?} found {} allocations for PID {}",
@@ -60,7 +122,15 @@ impl CompositeDetector {
                         result.allocations.len(),
                         pid
                     );
-                    results.push(result);
+                    detections.results.push(result);
+                }
+                Err(e) if self.strict => {
+                    return Err(GpuCheckpointError::DetectionError(format!(
+                        "{:?} detector failed for PID {}: {}",
+                        detector.get_vendor(),
+                        pid,
+                        e
+                    )));
                 }
                 Err(e) => {
                     warn!(
@@ -69,10 +139,119 @@ impl CompositeDetector {
                         pid,
                         e
                     );
+                    detections.errors.push(DetectorFailure {
+                        vendor: detector.get_vendor(),
+                        error: e,
+                    });
                 }
             }
         }
 
-        Ok(results)
+        Ok(detections)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FailingDetector;
+
+    impl GpuDetector for FailingDetector {
+        fn detect_allocations(&self, _pid: u32) -> Result<DetectionResult> {
+            Err(GpuCheckpointError::PermissionDenied)
+        }
+
+        fn is_gpu_process(&self, _pid: u32) -> Result<bool> {
+            Ok(true)
+        }
+
+        fn get_vendor(&self) -> GpuVendor {
+            GpuVendor::Amd
+        }
+    }
+
+    struct EmptyDetector;
+
+    impl GpuDetector for EmptyDetector {
+        fn detect_allocations(&self, pid: u32) -> Result<DetectionResult> {
+            Ok(DetectionResult::new(pid, GpuVendor::Nvidia))
+        }
+
+        fn is_gpu_process(&self, _pid: u32) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn get_vendor(&self) -> GpuVendor {
+            GpuVendor::Nvidia
+        }
+    }
+
+    struct OverlappingDetector;
+
+    impl GpuDetector for OverlappingDetector {
+        fn detect_allocations(&self, pid: u32) -> Result<DetectionResult> {
+            let mut result = DetectionResult::new(pid, GpuVendor::Intel);
+            result.add_allocation(GpuAllocation::new(0x1000, 0x3000, AllocationType::Standard));
+            result.add_allocation(GpuAllocation::new(0x2000, 0x4000, AllocationType::Standard));
+            Ok(result)
+        }
+
+        fn is_gpu_process(&self, _pid: u32) -> Result<bool> {
+            Ok(true)
+        }
+
+        fn get_vendor(&self) -> GpuVendor {
+            GpuVendor::Intel
+        }
+    }
+
+    #[test]
+    fn test_invalid_detection_fails() {
+        let detections = CompositeDetector::from_detectors(vec![
+            Box::new(OverlappingDetector),
+            Box::new(EmptyDetector),
+        ])
+        .detect_all(1234)
+        .unwrap();
+        assert_eq!(detections.results.len(), 1);
+        assert_eq!(detections.errors.len(), 1);
+        assert_eq!(detections.errors[0].vendor, GpuVendor::Intel);
+        assert!(detections.errors[0].error.to_string().contains("overlap"));
+    }
+
+    #[test]
+    fn test_strict_detection() {
+        let detectors = || -> Vec<Box<dyn GpuDetector>> {
+            vec![Box::new(FailingDetector), Box::new(EmptyDetector)]
+        };
+
+        // Lenient by default: the failing detector is reported next to
+        // the results of the others
+        let detections = CompositeDetector::from_detectors(detectors())
+            .detect_all(1234)
+            .unwrap();
+        assert_eq!(detections.results.len(), 1);
+        assert_eq!(detections.results[0].vendor, GpuVendor::Nvidia);
+        assert_eq!(detections.errors.len(), 1);
+        assert_eq!(detections.errors[0].vendor, GpuVendor::Amd);
+        assert!(matches!(
+            detections.errors[0].error,
+            GpuCheckpointError::PermissionDenied
+        ));
+
+        // A failure is not mistaken for a process without GPU state
+        let detections = CompositeDetector::from_detectors(vec![Box::new(FailingDetector)])
+            .detect_all(1234)
+            .unwrap();
+        assert!(detections.results.is_empty());
+        assert_eq!(detections.errors.len(), 1);
+
+        let err = CompositeDetector::from_detectors(detectors())
+            .with_strict(true)
+            .detect_all(1234)
+            .unwrap_err();
+        assert!(matches!(err, GpuCheckpointError::DetectionError(_)));
+        assert!(err.to_string().contains("Amd detector failed for PID 1234"));
     }
 }
@@ -1,11 +1,51 @@
-use crate::detector::memory::MemoryMapParser;
-use crate::detector::process::{GpuDeviceType, ProcessScanner};
-use crate::detector::{AllocationType, DetectionResult, GpuAllocation, GpuDetector, GpuVendor};
-use crate::Result;
-#[allow(unused_imports)]
+use crate::detector::memory::{MemoryMapParser, SmapsRegion};
+use crate::detector::process::{FileDescriptor, GpuDeviceType, ProcessScanner};
+use crate::detector::{
+    AllocationType, DetectionResult, GpuAllocation, GpuDetector, GpuDeviceInfo, GpuVendor,
+    IpcHandle, CUDA_IPC_HANDLE_SIZE,
+};
+use crate::{GpuCheckpointError, Result};
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
-use tracing::{debug, info};
+use tracing::{debug, info, trace, warn};
+
+/// Directory the NVIDIA driver publishes per-GPU information in
+const NVIDIA_GPUS_DIR: &str = "/proc/driver/nvidia/gpus";
+
+/// Sysfs directory with one entry per PCI function, named by its address
+const PCI_DEVICES_DIR: &str = "/sys/bus/pci/devices";
+
+/// PCI vendor ID of NVIDIA
+const NVIDIA_PCI_VENDOR: u16 = 0x10de;
+
+/// Smallest locked mapping treated as pinned host memory; the CUDA runtime
+/// pins host buffers in chunks of at least this size
+const MIN_PINNED_SIZE: u64 = 1024 * 1024;
+
+/// Model name fragments and the architecture / compute capability they imply.
+/// More specific fragments must come before fragments they contain.
+const NVIDIA_ARCHITECTURES: &[(&str, &str, &str)] = &[
+    ("GB200", "Blackwell", "10.0"),
+    ("B200", "Blackwell", "10.0"),
+    ("B100", "Blackwell", "10.0"),
+    ("GH200", "Hopper", "9.0"),
+    ("H200", "Hopper", "9.0"),
+    ("H100", "Hopper", "9.0"),
+    ("H800", "Hopper", "9.0"),
+    ("L40", "Ada Lovelace", "8.9"),
+    ("L4", "Ada Lovelace", "8.9"),
+    ("A100", "Ampere", "8.0"),
+    ("A800", "Ampere", "8.0"),
+    ("A30", "Ampere", "8.0"),
+    ("A40", "Ampere", "8.6"),
+    ("A10", "Ampere", "8.6"),
+    ("A16", "Ampere", "8.6"),
+    ("T4", "Turing", "7.5"),
+    ("V100", "Volta", "7.0"),
+    ("P100", "Pascal", "6.0"),
+    ("P40", "Pascal", "6.1"),
+];
 
 pub struct NvidiaDetector;
 
@@ -28,8 +68,9 @@ impl NvidiaDetector {
 
         for region in regions {
             if let Some(pathname) = &region.pathname {
-                // Direct UVM device mapping
-                if pathname.contains("/dev/nvidia-uvm") {
+                // Direct UVM device mapping (the tools node only carries
+                // profiler event queues, not allocations)
+                if pathname.contains("/dev/nvidia-uvm") && !pathname.contains("nvidia-uvm-tools") {
                     let mut alloc =
                         GpuAllocation::new(region.start, region.end, AllocationType::Uvm);
                     alloc.metadata.backing_file = Some(pathname.clone());
@@ -78,6 +119,8 @@ impl NvidiaDetector {
                     alloc.metadata.backing_file = Some(pathname.clone());
                     alloc.metadata.protection = region.perms.clone();
                     alloc.metadata.is_shared = true;
+                    alloc.metadata.file_offset = region.offset;
+                    alloc.metadata.inode = Some(region.inode).filter(|&inode| inode != 0);
 
                     // Check if this is a distributed training allocation
                     if pathname.contains("nccl") || pathname.contains("horovod") {
@@ -97,47 +140,413 @@ impl NvidiaDetector {
         allocations
     }
 
+    /// Whether a descriptor is a memfd or anonymous inode named for CUDA or
+    /// NCCL, as exported by `cuMemExportToShareableHandle` for POSIX fd and
+    /// fabric handles
+    fn is_shareable_handle(target: &str) -> bool {
+        ["/memfd:", "memfd:", "anon_inode:"]
+            .iter()
+            .find_map(|prefix| target.strip_prefix(prefix))
+            .is_some_and(|name| {
+                let name = name.to_ascii_lowercase();
+                name.contains("cuda") || name.contains("nccl") || name.contains("nvidia")
+            })
+    }
+
+    /// IPC allocations mapped through shareable handle descriptors. Their
+    /// sizes come from the mappings of the same file: a memfd is matched by
+    /// inode, an anonymous inode (which all share one inode) by name.
+    fn detect_shareable_handle_allocations(
+        &self,
+        regions: &[crate::detector::memory::MemoryRegion],
+        fds: &[FileDescriptor],
+    ) -> Vec<GpuAllocation> {
+        let mut allocations: Vec<GpuAllocation> = Vec::new();
+
+        for fd in fds
+            .iter()
+            .filter(|fd| Self::is_shareable_handle(&fd.target))
+        {
+            let inode = fd
+                .metadata
+                .as_ref()
+                .filter(|_| !fd.target.starts_with("anon_inode:"))
+                .map(std::os::unix::fs::MetadataExt::ino)
+                .filter(|&inode| inode != 0);
+
+            for region in regions {
+                let same_file = match inode {
+                    Some(inode) => region.inode == inode,
+                    None => region.pathname.as_deref() == Some(fd.target.as_str()),
+                };
+                if !same_file || allocations.iter().any(|a| a.vaddr_start == region.start) {
+                    continue;
+                }
+
+                let mut alloc = GpuAllocation::new(region.start, region.end, AllocationType::Ipc);
+                alloc.fd = Some(fd.fd);
+                alloc.metadata.backing_file = Some(fd.target.clone());
+                alloc.metadata.protection = region.perms.clone();
+                alloc.metadata.is_shared = region.perms.ends_with('s');
+                alloc.metadata.file_offset = region.offset;
+                alloc.metadata.inode = inode;
+                if fd.target.to_ascii_lowercase().contains("nccl") {
+                    alloc.alloc_type = AllocationType::Distributed;
+                    alloc.metadata.is_distributed = true;
+                }
+
+                debug!(
+                    "Found shareable handle allocation through fd {} ({}): {:x}-{:x} ({} bytes)",
+                    fd.fd, fd.target, region.start, region.end, alloc.size
+                );
+                allocations.push(alloc);
+            }
+        }
+
+        allocations
+    }
+
     fn detect_bar_mappings(
         &self,
         regions: &[crate::detector::memory::MemoryRegion],
     ) -> Vec<GpuAllocation> {
+        Self::read_bar_mappings(regions, Path::new(PCI_DEVICES_DIR))
+    }
+
+    /// Mappings of PCI resource files of NVIDIA functions, on any function
+    /// and BAR. The vendor is looked up under `devices_dir` so resources of
+    /// other devices (NICs, NVMe) are not mistaken for GPU memory.
+    fn read_bar_mappings(
+        regions: &[crate::detector::memory::MemoryRegion],
+        devices_dir: &Path,
+    ) -> Vec<GpuAllocation> {
+        let mut vendors: HashMap<String, Option<u16>> = HashMap::new();
         let mut allocations = Vec::new();
 
         for region in regions {
-            if let Some(pathname) = &region.pathname {
-                // PCIe BAR resource mappings
-                if pathname.contains("/sys/bus/pci/devices/") && pathname.contains(":00.0/resource")
-                {
-                    // Extract device info from path
-                    let mut alloc =
-                        GpuAllocation::new(region.start, region.end, AllocationType::BarMapped);
-                    alloc.metadata.backing_file = Some(pathname.clone());
-                    alloc.metadata.protection = region.perms.clone();
+            let Some(pathname) = &region.pathname else {
+                continue;
+            };
+            let Some((address, bar_index)) = MemoryMapParser::parse_pci_resource(pathname) else {
+                continue;
+            };
 
-                    debug!(
-                        "Found BAR mapping: {:x}-{:x} ({} bytes)",
-                        region.start, region.end, alloc.size
-                    );
-                    allocations.push(alloc);
-                }
+            let vendor = *vendors
+                .entry(address.clone())
+                .or_insert_with(|| Self::pci_vendor(devices_dir, &address));
+            if vendor != Some(NVIDIA_PCI_VENDOR) {
+                trace!("Ignoring resource mapping of non-NVIDIA device {}", address);
+                continue;
             }
+
+            let mut alloc = GpuAllocation::new(region.start, region.end, AllocationType::BarMapped);
+            alloc.metadata.backing_file = Some(pathname.clone());
+            alloc.metadata.protection = region.perms.clone();
+            alloc.metadata.bar_index = Some(bar_index);
+
+            debug!(
+                "Found BAR{} mapping of {}: {:x}-{:x} ({} bytes)",
+                bar_index, address, region.start, region.end, alloc.size
+            );
+            allocations.push(alloc);
         }
 
         allocations
     }
 
-    fn check_nvidia_ml(&self, pid: u32) -> Result<Option<NvmlInfo>> {
-        // In a real implementation, we would use nvidia-ml bindings
-        // For now, we'll check for nvidia-smi output or /proc/driver/nvidia
+    /// PCI vendor ID of the function at `address`, from its sysfs `vendor`
+    /// file (e.g. "0x10de")
+    fn pci_vendor(devices_dir: &Path, address: &str) -> Option<u16> {
+        let vendor = fs::read_to_string(devices_dir.join(address).join("vendor")).ok()?;
+        u16::from_str_radix(vendor.trim().trim_start_matches("0x"), 16).ok()
+    }
+
+    /// Host memory pinned for DMA by `cudaHostAlloc` / `cudaHostRegister`:
+    /// large anonymous mappings with locked pages, next to a mapping of an
+    /// NVIDIA device node in the address space
+    fn detect_pinned_allocations(&self, smaps: &[SmapsRegion]) -> Vec<GpuAllocation> {
+        let is_nvidia = |entry: &SmapsRegion| {
+            entry
+                .region
+                .pathname
+                .as_deref()
+                .is_some_and(|path| path.starts_with("/dev/nvidia"))
+        };
+
+        let mut allocations = Vec::new();
+        for (i, entry) in smaps.iter().enumerate() {
+            let region = &entry.region;
+            let anonymous = match &region.pathname {
+                None => true,
+                // Named managed memory is already reported as such
+                Some(path) => path.starts_with("[anon:") && !path.contains("cuda"),
+            };
+            if !anonymous || entry.locked < MIN_PINNED_SIZE {
+                continue;
+            }
+
+            let adjacent =
+                (i > 0 && is_nvidia(&smaps[i - 1])) || smaps.get(i + 1).is_some_and(is_nvidia);
+            if !adjacent {
+                trace!(
+                    "Locked region {:x}-{:x} is not next to an NVIDIA mapping",
+                    region.start,
+                    region.end
+                );
+                continue;
+            }
+
+            let mut alloc =
+                GpuAllocation::new(region.start, region.end, AllocationType::HostPinned);
+            alloc.metadata.protection = region.perms.clone();
+            alloc.metadata.is_shared = region.perms.contains('s');
 
-        // Check if process has NVIDIA GPU context via /proc/driver/nvidia/gpus
-        let nvidia_dir = "/proc/driver/nvidia/gpus";
-        if Path::new(nvidia_dir).exists() {
-            // This would parse actual NVML data
-            debug!("NVIDIA driver detected, would query NVML for PID {}", pid);
+            debug!(
+                "Found pinned host allocation: {:x}-{:x} ({} bytes, {} locked)",
+                region.start, region.end, alloc.size, entry.locked
+            );
+            allocations.push(alloc);
+        }
+
+        allocations
+    }
+
+    /// Whether any mapping is backed by an NVIDIA device node
+    fn has_gpu_mappings(regions: &[crate::detector::memory::MemoryRegion]) -> bool {
+        regions.iter().any(|region| {
+            region
+                .pathname
+                .as_deref()
+                .is_some_and(|path| path.starts_with("/dev/nvidia"))
+        })
+    }
+
+    /// Record the descriptor each file-backed allocation was mapped through,
+    /// so restore can translate it to the new process's descriptor
+    fn attach_fds(allocations: &mut [GpuAllocation], fds: &[FileDescriptor]) {
+        for alloc in allocations {
+            let Some(backing_file) = &alloc.metadata.backing_file else {
+                continue;
+            };
+
+            alloc.fd = fds
+                .iter()
+                .find(|fd| &fd.target == backing_file)
+                .map(|fd| fd.fd);
+        }
+    }
+
+    /// Record how much of each allocation is resident, for the allocations
+    /// that start at a mapping listed in smaps
+    fn attach_rss(allocations: &mut [GpuAllocation], smaps: &[SmapsRegion]) {
+        let by_start = MemoryMapParser::smaps_by_start(smaps);
+        for alloc in allocations {
+            alloc.metadata.rss_bytes = by_start.get(&alloc.vaddr_start).map(|entry| entry.rss);
         }
+    }
+
+    /// CUDA IPC handles held in shared memory files the process has open.
+    /// Files are read through `/proc/PID/fd` so unlinked ones are found too.
+    fn ipc_handles(pid: u32, fds: &[FileDescriptor]) -> Vec<IpcHandle> {
+        fds.iter()
+            .filter(|fd| {
+                ProcessScanner::classify_fd(fd)
+                    .is_some_and(|info| info.device_type == GpuDeviceType::SharedMemory)
+            })
+            .filter_map(|fd| {
+                let contents = fs::read(format!("/proc/{pid}/fd/{}", fd.fd)).ok()?;
+                let handle = Self::parse_ipc_handle(&contents)?;
+                debug!("Found CUDA IPC handle in {}", fd.target);
+                Some(IpcHandle {
+                    path: fd.target.clone(),
+                    handle,
+                })
+            })
+            .collect()
+    }
+
+    /// Hex encoding of `contents` if it is exactly one IPC handle
+    fn parse_ipc_handle(contents: &[u8]) -> Option<String> {
+        if contents.len() != CUDA_IPC_HANDLE_SIZE {
+            return None;
+        }
+        Some(contents.iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    /// Record the IPC handle of each IPC allocation backed by a handle file
+    fn attach_ipc_handles(allocations: &mut [GpuAllocation], handles: &[IpcHandle]) {
+        for alloc in allocations.iter_mut().filter(|a| {
+            matches!(
+                a.alloc_type,
+                AllocationType::Ipc | AllocationType::Distributed
+            )
+        }) {
+            alloc.metadata.ipc_handle = handles
+                .iter()
+                .find(|h| alloc.metadata.backing_file.as_ref() == Some(&h.path))
+                .map(|h| h.handle.clone());
+        }
+    }
 
-        Ok(None)
+    /// Model and architecture of every GPU the driver reports
+    pub fn gpu_devices() -> Vec<GpuDeviceInfo> {
+        Self::read_gpu_devices(Path::new(NVIDIA_GPUS_DIR))
+    }
+
+    fn read_gpu_devices(gpus_dir: &Path) -> Vec<GpuDeviceInfo> {
+        let Ok(entries) = fs::read_dir(gpus_dir) else {
+            return Vec::new();
+        };
+
+        let mut gpus: Vec<GpuDeviceInfo> = entries
+            .flatten()
+            .filter_map(|entry| fs::read_to_string(entry.path().join("information")).ok())
+            .filter_map(|information| Self::parse_gpu_information(&information))
+            .collect();
+        gpus.sort_by_key(|gpu| gpu.device_id);
+        gpus
+    }
+
+    /// Parse a `/proc/driver/nvidia/gpus/*/information` file
+    fn parse_gpu_information(information: &str) -> Option<GpuDeviceInfo> {
+        let field = |name: &str| {
+            information.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                (key.trim() == name).then(|| value.trim().to_string())
+            })
+        };
+
+        let model = field("Model")?;
+        let (architecture, compute_capability) = Self::architecture(&model)
+            .map(|(arch, cc)| (Some(arch.to_string()), Some(cc.to_string())))
+            .unwrap_or_default();
+
+        Some(GpuDeviceInfo {
+            device_id: field("Device Minor").and_then(|minor| minor.parse().ok()),
+            model,
+            architecture,
+            compute_capability,
+        })
+    }
+
+    /// Architecture and compute capability implied by a model name
+    fn architecture(model: &str) -> Option<(&'static str, &'static str)> {
+        let model = model.to_ascii_uppercase();
+        let tokens: Vec<&str> = model
+            .split(|c: char| c.is_whitespace() || c == '-')
+            .collect();
+
+        // GeForce / workstation cards: the series follows "RTX" or "GTX"
+        if let Some(series) = tokens
+            .windows(2)
+            .find(|pair| pair[0] == "RTX" || pair[0] == "GTX")
+            .map(|pair| pair[1])
+        {
+            let arch = match series.get(..2) {
+                Some("50") => Some(("Blackwell", "12.0")),
+                Some("40") => Some(("Ada Lovelace", "8.9")),
+                Some("30") => Some(("Ampere", "8.6")),
+                Some("20") | Some("16") => Some(("Turing", "7.5")),
+                Some("10") => Some(("Pascal", "6.1")),
+                _ => None,
+            };
+            if arch.is_some() {
+                return arch;
+            }
+        }
+
+        NVIDIA_ARCHITECTURES
+            .iter()
+            .find(|(fragment, _, _)| tokens.iter().any(|token| token.starts_with(fragment)))
+            .map(|(_, arch, cc)| (*arch, *cc))
+    }
+
+    /// GPU memory nvidia-smi attributes to `pid`, summed over all GPUs.
+    /// `Ok(None)` if nvidia-smi does not list the process.
+    pub fn nvidia_smi_memory(pid: u32) -> Result<Option<u64>> {
+        let output = std::process::Command::new("nvidia-smi")
+            .args([
+                "--query-compute-apps=pid,used_memory",
+                "--format=csv,noheader",
+            ])
+            .output()
+            .map_err(|e| {
+                GpuCheckpointError::GpuDeviceError(format!("cannot run nvidia-smi: {e}"))
+            })?;
+
+        if !output.status.success() {
+            return Err(GpuCheckpointError::GpuDeviceError(format!(
+                "nvidia-smi failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(Self::parse_compute_apps(
+            &String::from_utf8_lossy(&output.stdout),
+            pid,
+        ))
+    }
+
+    /// Sum the `used_memory` of all rows for `pid` in
+    /// `nvidia-smi --query-compute-apps=pid,used_memory --format=csv,noheader`
+    /// output
+    fn parse_compute_apps(output: &str, pid: u32) -> Option<u64> {
+        let mut total = None;
+        for line in output.lines() {
+            let Some((row_pid, used)) = line.split_once(',') else {
+                continue;
+            };
+            if row_pid.trim().parse::<u32>().ok() != Some(pid) {
+                continue;
+            }
+
+            let used = used.trim();
+            let (value, unit) = used.split_once(' ').unwrap_or((used, "MiB"));
+            let Ok(value) = value.parse::<u64>() else {
+                continue;
+            };
+            let bytes = match unit.trim() {
+                "KiB" => value * 1024,
+                "GiB" => value * 1024 * 1024 * 1024,
+                _ => value * 1024 * 1024,
+            };
+            *total.get_or_insert(0) += bytes;
+        }
+        total
+    }
+
+    /// GPU memory NVML attributes to `pid`, summed over its devices.
+    /// `Ok(None)` without the `nvml` feature, if NVML cannot be loaded or
+    /// does not list the process.
+    fn check_nvidia_ml(&self, pid: u32) -> Result<Option<NvmlInfo>> {
+        #[cfg(feature = "nvml")]
+        {
+            let nvml = match crate::detector::nvml::Nvml::load() {
+                Ok(nvml) => nvml,
+                Err(e) => {
+                    debug!("NVML unavailable: {}", e);
+                    return Ok(None);
+                }
+            };
+            let usage = nvml.process_usage(pid)?;
+            let Some(first) = usage.first() else {
+                debug!("NVML does not list PID {}", pid);
+                return Ok(None);
+            };
+            Ok(Some(NvmlInfo {
+                gpu_memory_used: usage.iter().filter_map(|u| u.used_gpu_memory).sum(),
+                device_id: first.device_index,
+            }))
+        }
+
+        #[cfg(not(feature = "nvml"))]
+        {
+            debug!(
+                "Built without NVML support, not querying it for PID {}",
+                pid
+            );
+            Ok(None)
+        }
     }
 }
 
@@ -165,7 +574,12 @@ impl GpuDetector for NvidiaDetector {
             })
             .collect();
 
-        if gpu_fds.is_empty() && !ProcessScanner::has_gpu_environment(pid)? {
+        // The runtime may close device fds while memory stays mapped, so
+        // GPU-backed mappings alone are enough evidence to keep going
+        if gpu_fds.is_empty()
+            && !Self::has_gpu_mappings(&regions)
+            && !ProcessScanner::has_gpu_environment(pid)?
+        {
             debug!("No NVIDIA GPU usage detected for PID {}", pid);
             return Ok(result);
         }
@@ -173,7 +587,13 @@ impl GpuDetector for NvidiaDetector {
         // Detect different allocation types
         let uvm_allocs = self.detect_uvm_allocations(&regions);
         let ipc_allocs = self.detect_ipc_allocations(&regions);
+        let handle_allocs = self.detect_shareable_handle_allocations(&regions, &fds);
         let bar_allocs = self.detect_bar_mappings(&regions);
+        let smaps = MemoryMapParser::parse_smaps(pid).unwrap_or_else(|e| {
+            debug!("Cannot read smaps of PID {}: {}", pid, e);
+            Vec::new()
+        });
+        let pinned_allocs = self.detect_pinned_allocations(&smaps);
 
         // Add device IDs from file descriptors
         for alloc in uvm_allocs {
@@ -182,18 +602,88 @@ impl GpuDetector for NvidiaDetector {
         for alloc in ipc_allocs {
             result.add_allocation(alloc);
         }
+        for alloc in handle_allocs {
+            result.add_allocation(alloc);
+        }
         for alloc in bar_allocs {
             result.add_allocation(alloc);
         }
+        for alloc in pinned_allocs {
+            result.add_allocation(alloc);
+        }
+        Self::attach_fds(&mut result.allocations, &fds);
+        Self::attach_rss(&mut result.allocations, &smaps);
+        MemoryMapParser::attach_huge_pages(&mut result.allocations, &regions, &smaps);
+        MemoryMapParser::attach_physical_frames(pid, &mut result.allocations);
 
-        // Try to get additional info from NVML
-        if let Ok(Some(nvml_info)) = self.check_nvidia_ml(pid) {
-            debug!(
-                "NVML reports {} bytes GPU memory for PID {}",
-                nvml_info.gpu_memory_used, pid
+        result.ipc_handles = Self::ipc_handles(pid, &fds);
+        Self::attach_ipc_handles(&mut result.allocations, &result.ipc_handles);
+
+        result.uvm_tools_attached = fds.iter().any(|fd| {
+            ProcessScanner::classify_fd(fd)
+                .is_some_and(|info| info.device_type == GpuDeviceType::NvidiaUvmTools)
+        });
+        if result.uvm_tools_attached {
+            warn!(
+                "PID {} has /dev/nvidia-uvm-tools open; a profiler or debugger may change UVM state during checkpoint",
+                pid
             );
         }
 
+        result.is_mps = ProcessScanner::uses_mps(pid, &fds);
+        if result.is_mps {
+            warn!(
+                "PID {} is an MPS client; its GPU context is shared with the MPS server, \
+                 so checkpointing it alone may be inconsistent",
+                pid
+            );
+        }
+
+        let gpu_fd_numbers: Vec<i32> = gpu_fds.iter().map(|info| info.fd).collect();
+        result.gpu_threads =
+            ProcessScanner::gpu_threads(pid, &gpu_fd_numbers).unwrap_or_else(|e| {
+                debug!("Cannot scan threads of PID {}: {}", pid, e);
+                Vec::new()
+            });
+
+        // Group allocations per CUDA context so multi-GPU and MPS processes
+        // can be checkpointed context by context
+        let mut device_ids: Vec<u32> = gpu_fds.iter().filter_map(|info| info.device_id).collect();
+        device_ids.sort_unstable();
+        device_ids.dedup();
+        result.group_contexts(&device_ids);
+        result.gpus = Self::gpu_devices()
+            .into_iter()
+            .filter(|gpu| {
+                device_ids.is_empty() || gpu.device_id.is_some_and(|id| device_ids.contains(&id))
+            })
+            .collect();
+        debug!(
+            "Grouped allocations for PID {} into {} context(s)",
+            pid,
+            result.contexts.len()
+        );
+
+        // Device memory that is not mapped to the host is invisible in the
+        // maps, but NVML accounts for it
+        match self.check_nvidia_ml(pid) {
+            Ok(Some(nvml_info)) => {
+                debug!(
+                    "NVML reports {} bytes GPU memory for PID {} (device {})",
+                    nvml_info.gpu_memory_used, pid, nvml_info.device_id
+                );
+                if let Some(undetected) = nvml_info.undetected(result.total_gpu_memory) {
+                    warn!(
+                        "NVML reports {} bytes of GPU memory for PID {} but only {} were \
+                         detected from its mappings; {} bytes of allocations were not detected",
+                        nvml_info.gpu_memory_used, pid, result.total_gpu_memory, undetected
+                    );
+                }
+            }
+            Ok(None) => {}
+            Err(e) => debug!("Cannot query NVML for PID {}: {}", pid, e),
+        }
+
         info!(
             "NVIDIA detection complete for PID {}: found {} allocations, {} problematic",
             pid,
@@ -235,19 +725,390 @@ impl GpuDetector for NvidiaDetector {
 }
 
 #[derive(Debug)]
-#[allow(dead_code)]
 struct NvmlInfo {
+    /// Bytes of GPU memory used, summed over the process's devices
     gpu_memory_used: u64,
+
+    /// NVML index of the first device the process uses
     device_id: u32,
 }
 
+impl NvmlInfo {
+    /// Bytes NVML accounts for beyond the `detected` total, if any
+    fn undetected(&self, detected: u64) -> Option<u64> {
+        self.gpu_memory_used
+            .checked_sub(detected)
+            .filter(|&undetected| undetected > 0)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::detector::memory::MemoryRegion;
 
     #[test]
     fn test_nvidia_detector_creation() {
         let detector = NvidiaDetector::new();
         assert_eq!(detector.get_vendor(), GpuVendor::Nvidia);
     }
+
+    #[test]
+    #[cfg(not(feature = "nvml"))]
+    fn test_nvml_disabled() {
+        let detector = NvidiaDetector::new();
+        assert!(detector
+            .check_nvidia_ml(std::process::id())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_nvml_undetected_memory() {
+        let info = NvmlInfo {
+            gpu_memory_used: 3 * 1024 * 1024,
+            device_id: 0,
+        };
+        assert_eq!(info.undetected(1024 * 1024), Some(2 * 1024 * 1024));
+        assert_eq!(info.undetected(3 * 1024 * 1024), None);
+        assert_eq!(info.undetected(4 * 1024 * 1024), None);
+    }
+
+    #[test]
+    fn test_parse_gpu_information() {
+        let information = "Model: \t\t NVIDIA H100 80GB HBM3\n\
+                           IRQ:   \t\t 176\n\
+                           GPU UUID: \t GPU-5e1c0a5e-0000-0000-0000-000000000000\n\
+                           Bus Location: \t 0000:18:00.0\n\
+                           Device Minor: \t 3\n";
+
+        let gpu = NvidiaDetector::parse_gpu_information(information).unwrap();
+        assert_eq!(gpu.model, "NVIDIA H100 80GB HBM3");
+        assert_eq!(gpu.device_id, Some(3));
+        assert_eq!(gpu.architecture.as_deref(), Some("Hopper"));
+        assert_eq!(gpu.compute_capability.as_deref(), Some("9.0"));
+
+        assert!(NvidiaDetector::parse_gpu_information("IRQ: 1\n").is_none());
+    }
+
+    #[test]
+    fn test_architecture_from_model() {
+        let arch = |model| NvidiaDetector::architecture(model).map(|(arch, _)| arch);
+        assert_eq!(arch("NVIDIA A100-SXM4-80GB"), Some("Ampere"));
+        assert_eq!(arch("NVIDIA A10G"), Some("Ampere"));
+        assert_eq!(arch("NVIDIA L40S"), Some("Ada Lovelace"));
+        assert_eq!(arch("Tesla V100-PCIE-32GB"), Some("Volta"));
+        assert_eq!(arch("NVIDIA GeForce RTX 4090"), Some("Ada Lovelace"));
+        assert_eq!(arch("NVIDIA GeForce RTX 3080 Ti"), Some("Ampere"));
+        assert_eq!(arch("Some Future GPU"), None);
+    }
+
+    #[test]
+    fn test_read_gpu_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        for (bus, model, minor) in [
+            ("0000:3b:00.0", "NVIDIA A100-SXM4-40GB", 1),
+            ("0000:18:00.0", "NVIDIA A100-SXM4-40GB", 0),
+        ] {
+            let gpu_dir = dir.path().join(bus);
+            fs::create_dir_all(&gpu_dir).unwrap();
+            fs::write(
+                gpu_dir.join("information"),
+                format!("Model: \t\t {model}\nDevice Minor: \t {minor}\n"),
+            )
+            .unwrap();
+        }
+
+        let gpus = NvidiaDetector::read_gpu_devices(dir.path());
+        assert_eq!(gpus.len(), 2);
+        assert_eq!(gpus[0].device_id, Some(0));
+        assert_eq!(gpus[1].device_id, Some(1));
+        assert!(NvidiaDetector::read_gpu_devices(&dir.path().join("missing")).is_empty());
+    }
+
+    #[test]
+    fn test_bar_mappings_of_multi_function_gpus() {
+        let devices = tempfile::tempdir().unwrap();
+        for (address, vendor) in [
+            ("0000:3b:00.0", "0x10de"),
+            ("0000:3b:00.1", "0x10de"),
+            ("0000:5e:00.1", "0x10de"),
+            ("0000:af:00.0", "0x15b3"),
+        ] {
+            fs::create_dir_all(devices.path().join(address)).unwrap();
+            fs::write(
+                devices.path().join(address).join("vendor"),
+                format!("{vendor}\n"),
+            )
+            .unwrap();
+        }
+
+        // A GPU on function 0 with BAR0 and BAR1 mapped, a second GPU only
+        // present as function 1, a Mellanox NIC and a device without sysfs
+        let maps = "\
+7f1000000000-7f1001000000 rw-s 00000000 00:15 101 /sys/bus/pci/devices/0000:3b:00.0/resource0
+7f2000000000-7f2010000000 rw-s 00000000 00:15 102 /sys/devices/pci0000:3a/0000:3a:00.0/0000:3b:00.0/resource1_wc
+7f3000000000-7f3000100000 rw-s 00000000 00:15 103 /sys/bus/pci/devices/0000:3b:00.1/resource3
+7f4000000000-7f4002000000 rw-s 00000000 00:15 104 /sys/bus/pci/devices/0000:5e:00.1/resource1
+7f5000000000-7f5000100000 rw-s 00000000 00:15 105 /sys/bus/pci/devices/0000:af:00.0/resource0
+7f6000000000-7f6000100000 rw-s 00000000 00:15 106 /sys/bus/pci/devices/0000:d8:00.0/resource0
+7f7000000000-7f7000100000 rw-p 00000000 00:00 0";
+        let regions: Vec<_> = maps
+            .lines()
+            .filter_map(MemoryMapParser::parse_line)
+            .collect();
+
+        let allocations = NvidiaDetector::read_bar_mappings(&regions, devices.path());
+        let found: Vec<_> = allocations
+            .iter()
+            .map(|a| (a.vaddr_start, a.metadata.bar_index))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (0x7f1000000000, Some(0)),
+                (0x7f2000000000, Some(1)),
+                (0x7f3000000000, Some(3)),
+                (0x7f4000000000, Some(1)),
+            ]
+        );
+        assert!(allocations
+            .iter()
+            .all(|a| a.alloc_type == AllocationType::BarMapped));
+    }
+
+    #[test]
+    fn test_detect_pinned_allocations() {
+        // A pinned buffer next to a device mapping, the same buffer size
+        // locked away from any NVIDIA mapping, a small locked region and an
+        // unlocked one
+        let smaps = "\
+7f1000000000-7f1000400000 rw-s 00000000 00:05 501 /dev/nvidiactl
+Size:               4096 kB
+Locked:                0 kB
+7f1000400000-7f1000800000 rw-p 00000000 00:00 0
+Size:               4096 kB
+Rss:                4096 kB
+Locked:             4096 kB
+VmFlags: rd wr mr mw me lo ac sd
+7f1000800000-7f1000801000 rw-p 00000000 00:00 0
+Size:                  4 kB
+Locked:                4 kB
+7f1000801000-7f1000802000 rw-s 00000000 00:05 502 /dev/nvidia0
+Size:                  4 kB
+Locked:                0 kB
+7f1fff000000-7f1fff200000 r-xp 00000000 08:01 77 /usr/lib/libc.so.6
+Size:               2048 kB
+Locked:                0 kB
+7f2000000000-7f2000400000 rw-p 00000000 00:00 0
+Size:               4096 kB
+Locked:             4096 kB
+7f3000000000-7f3000400000 rw-p 00000000 00:00 0 [anon:pinned]
+Size:               4096 kB
+Locked:                0 kB
+7f3000400000-7f3000401000 rw-s 00000000 00:05 501 /dev/nvidiactl
+Size:                  4 kB
+Locked:                0 kB
+";
+        let regions = MemoryMapParser::parse_smaps_content(smaps);
+        assert_eq!(regions.len(), 8);
+        assert_eq!(regions[1].locked, 4 * 1024 * 1024);
+        assert_eq!(regions[1].region.pathname, None);
+
+        let allocations = NvidiaDetector::new().detect_pinned_allocations(&regions);
+        assert_eq!(allocations.len(), 1);
+        let alloc = &allocations[0];
+        assert_eq!(alloc.alloc_type, AllocationType::HostPinned);
+        assert_eq!(alloc.vaddr_start, 0x7f1000400000);
+        assert_eq!(alloc.size, 4 * 1024 * 1024);
+        assert_eq!(alloc.metadata.protection, "rw-p");
+        assert!(!alloc.is_problematic());
+
+        let mut allocations = allocations;
+        allocations.push(GpuAllocation::new(
+            0x7f5000000000,
+            0x7f5000001000,
+            AllocationType::Uvm,
+        ));
+        NvidiaDetector::attach_rss(&mut allocations, &regions);
+        assert_eq!(allocations[0].metadata.rss_bytes, Some(4 * 1024 * 1024));
+        assert_eq!(allocations[1].metadata.rss_bytes, None);
+    }
+
+    #[test]
+    fn test_has_gpu_mappings() {
+        let region = |pathname: Option<&str>| crate::detector::memory::MemoryRegion {
+            start: 0x1000,
+            end: 0x2000,
+            perms: "rw-s".to_string(),
+            offset: 0,
+            dev: "00:05".to_string(),
+            inode: 0,
+            pathname: pathname.map(str::to_string),
+        };
+
+        let plain = [region(None), region(Some("/usr/lib/libc.so.6"))];
+        assert!(!NvidiaDetector::has_gpu_mappings(&plain));
+
+        let uvm = [region(Some("[heap]")), region(Some("/dev/nvidia-uvm"))];
+        assert!(NvidiaDetector::has_gpu_mappings(&uvm));
+        assert!(NvidiaDetector::has_gpu_mappings(&[region(Some(
+            "/dev/nvidia0"
+        ))]));
+    }
+
+    #[test]
+    fn test_ipc_handles() {
+        assert_eq!(NvidiaDetector::parse_ipc_handle(&[0xab; 63]), None);
+        assert_eq!(
+            NvidiaDetector::parse_ipc_handle(&[0xab; CUDA_IPC_HANDLE_SIZE]),
+            Some("ab".repeat(CUDA_IPC_HANDLE_SIZE))
+        );
+
+        // Handle files this process holds open are found through /proc
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cuda_ipc_handle");
+        std::fs::write(&path, [0x01; CUDA_IPC_HANDLE_SIZE]).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let fd = FileDescriptor {
+            fd: std::os::fd::AsRawFd::as_raw_fd(&file),
+            target: "/dev/shm/cuda_ipc_handle".to_string(),
+            metadata: None,
+        };
+        let handles = NvidiaDetector::ipc_handles(std::process::id(), &[fd]);
+        assert_eq!(handles.len(), 1);
+        assert_eq!(handles[0].handle, "01".repeat(CUDA_IPC_HANDLE_SIZE));
+
+        let mut ipc = GpuAllocation::new(0x1000, 0x2000, AllocationType::Ipc);
+        ipc.metadata.backing_file = Some("/dev/shm/cuda_ipc_handle".to_string());
+        let mut uvm = GpuAllocation::new(0x3000, 0x4000, AllocationType::Uvm);
+        uvm.metadata.backing_file = Some("/dev/shm/cuda_ipc_handle".to_string());
+        let mut allocations = vec![ipc, uvm];
+        NvidiaDetector::attach_ipc_handles(&mut allocations, &handles);
+        assert_eq!(
+            allocations[0].metadata.ipc_handle.as_deref(),
+            Some(handles[0].handle.as_str())
+        );
+        assert_eq!(allocations[1].metadata.ipc_handle, None);
+    }
+
+    #[test]
+    fn test_parse_compute_apps() {
+        let output = "1234, 1024 MiB\n5678, 300 MiB\n1234, 512 MiB\n";
+        assert_eq!(
+            NvidiaDetector::parse_compute_apps(output, 1234),
+            Some(1536 * 1024 * 1024)
+        );
+        assert_eq!(
+            NvidiaDetector::parse_compute_apps(output, 5678),
+            Some(300 * 1024 * 1024)
+        );
+        assert_eq!(NvidiaDetector::parse_compute_apps(output, 42), None);
+        assert_eq!(NvidiaDetector::parse_compute_apps("", 1234), None);
+        // Unsupported rows (e.g. "[N/A]" on some drivers) are skipped
+        assert_eq!(
+            NvidiaDetector::parse_compute_apps("1234, [N/A]\n", 1234),
+            None
+        );
+    }
+
+    #[test]
+    fn test_shareable_handle_allocations() {
+        let region = |start, end, inode, pathname: &str| MemoryRegion {
+            start,
+            end,
+            perms: "rw-s".to_string(),
+            offset: 0,
+            dev: "00:01".to_string(),
+            inode,
+            pathname: Some(pathname.to_string()),
+        };
+        let regions = [
+            region(0x10000, 0x30000, 2048, "/memfd:nccl-ipc (deleted)"),
+            region(0x40000, 0x41000, 7, "anon_inode:[cuda-fabric]"),
+            region(0x50000, 0x51000, 7, "anon_inode:[eventfd]"),
+            region(0x60000, 0x61000, 4096, "/memfd:wayland (deleted)"),
+        ];
+        let fds = [
+            FileDescriptor {
+                fd: 9,
+                target: "/memfd:nccl-ipc (deleted)".to_string(),
+                metadata: None,
+            },
+            FileDescriptor {
+                fd: 10,
+                target: "anon_inode:[cuda-fabric]".to_string(),
+                metadata: None,
+            },
+            FileDescriptor {
+                fd: 11,
+                target: "anon_inode:[eventfd]".to_string(),
+                metadata: None,
+            },
+            FileDescriptor {
+                fd: 12,
+                target: "/memfd:wayland (deleted)".to_string(),
+                metadata: None,
+            },
+        ];
+
+        let allocations = NvidiaDetector::new().detect_shareable_handle_allocations(&regions, &fds);
+        assert_eq!(allocations.len(), 2);
+
+        let nccl = &allocations[0];
+        assert_eq!((nccl.vaddr_start, nccl.size), (0x10000, 0x20000));
+        assert_eq!(nccl.alloc_type, AllocationType::Distributed);
+        assert!(nccl.is_problematic());
+        assert_eq!(nccl.fd, Some(9));
+        assert!(nccl.metadata.is_shared);
+
+        let fabric = &allocations[1];
+        assert_eq!(fabric.vaddr_start, 0x40000);
+        assert_eq!(fabric.alloc_type, AllocationType::Ipc);
+        assert_eq!(fabric.fd, Some(10));
+
+        // A memfd is matched by inode even when the mapping is named otherwise
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let metadata = file.as_file().metadata().unwrap();
+        let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+        let fds = [FileDescriptor {
+            fd: 4,
+            target: "/memfd:cuda-shareable".to_string(),
+            metadata: Some(metadata),
+        }];
+        let regions = [
+            region(0x10000, 0x12000, inode, "/memfd:cuda-shareable (deleted)"),
+            region(0x20000, 0x21000, inode + 1, "/memfd:cuda-shareable"),
+        ];
+        let allocations = NvidiaDetector::new().detect_shareable_handle_allocations(&regions, &fds);
+        assert_eq!(allocations.len(), 1);
+        assert_eq!(allocations[0].vaddr_start, 0x10000);
+        assert_eq!(allocations[0].alloc_type, AllocationType::Ipc);
+        assert_eq!(allocations[0].metadata.inode, Some(inode));
+    }
+
+    #[test]
+    fn test_attach_fds() {
+        let mut uvm = GpuAllocation::new(0x1000, 0x2000, AllocationType::Uvm);
+        uvm.metadata.backing_file = Some("/dev/nvidia-uvm".to_string());
+        let managed = GpuAllocation::new(0x3000, 0x4000, AllocationType::Managed);
+        let mut allocations = vec![uvm, managed];
+
+        let fds = [
+            FileDescriptor {
+                fd: 3,
+                target: "/dev/nvidiactl".to_string(),
+                metadata: None,
+            },
+            FileDescriptor {
+                fd: 5,
+                target: "/dev/nvidia-uvm".to_string(),
+                metadata: None,
+            },
+        ];
+
+        NvidiaDetector::attach_fds(&mut allocations, &fds);
+        assert_eq!(allocations[0].fd, Some(5));
+        assert_eq!(allocations[1].fd, None);
+    }
 }
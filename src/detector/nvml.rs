@@ -0,0 +1,200 @@
+//! Minimal NVML bindings, loaded from `libnvidia-ml.so.1` at runtime
+//!
+//! Only built with the `nvml` feature. The library ships with the NVIDIA
+//! driver rather than the toolkit, so it is opened with `dlopen` instead of
+//! linked: hosts without the driver fail `Nvml::load` and detection carries
+//! on with what `/proc` shows.
+
+use crate::{GpuCheckpointError, Result};
+use std::ffi::{c_char, c_int, c_uint, c_ulonglong, c_void, CStr};
+use tracing::debug;
+
+/// Soname of the NVML library installed by the driver
+const NVML_LIBRARY: &CStr = c"libnvidia-ml.so.1";
+
+/// `NVML_SUCCESS`
+const NVML_SUCCESS: c_int = 0;
+
+/// `NVML_ERROR_INSUFFICIENT_SIZE`: the process list grew between calls
+const NVML_ERROR_INSUFFICIENT_SIZE: c_int = 7;
+
+/// `NVML_VALUE_NOT_AVAILABLE`, reported as the memory of processes NVML
+/// cannot account for, e.g. without access to their PID namespace
+const NVML_VALUE_NOT_AVAILABLE: c_ulonglong = c_ulonglong::MAX;
+
+/// `nvmlDevice_t`
+type NvmlDevice = *mut c_void;
+
+/// `nvmlProcessInfo_t` as filled by `nvmlDeviceGetComputeRunningProcesses_v3`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct NvmlProcessInfo {
+    pid: c_uint,
+    used_gpu_memory: c_ulonglong,
+    gpu_instance_id: c_uint,
+    compute_instance_id: c_uint,
+}
+
+type InitFn = unsafe extern "C" fn() -> c_int;
+type ShutdownFn = unsafe extern "C" fn() -> c_int;
+type ErrorStringFn = unsafe extern "C" fn(c_int) -> *const c_char;
+type DeviceGetCountFn = unsafe extern "C" fn(*mut c_uint) -> c_int;
+type DeviceGetHandleByIndexFn = unsafe extern "C" fn(c_uint, *mut NvmlDevice) -> c_int;
+type DeviceGetComputeRunningProcessesFn =
+    unsafe extern "C" fn(NvmlDevice, *mut c_uint, *mut NvmlProcessInfo) -> c_int;
+
+/// GPU memory NVML attributes to a process on one device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessUsage {
+    /// NVML index of the device
+    pub device_index: u32,
+
+    /// Bytes used, `None` if NVML cannot account for them
+    pub used_gpu_memory: Option<u64>,
+}
+
+/// An initialized NVML library, shut down and closed on drop
+pub struct Nvml {
+    library: *mut c_void,
+    shutdown: ShutdownFn,
+    error_string: ErrorStringFn,
+    device_get_count: DeviceGetCountFn,
+    device_get_handle_by_index: DeviceGetHandleByIndexFn,
+    device_get_compute_running_processes: DeviceGetComputeRunningProcessesFn,
+}
+
+impl Nvml {
+    /// Open and initialize NVML
+    pub fn load() -> Result<Self> {
+        // SAFETY: dlopen is given a valid C string and its result checked
+        let library = unsafe { libc::dlopen(NVML_LIBRARY.as_ptr(), libc::RTLD_NOW) };
+        if library.is_null() {
+            return Err(GpuCheckpointError::GpuDeviceError(format!(
+                "cannot load {}",
+                NVML_LIBRARY.to_string_lossy()
+            )));
+        }
+
+        // SAFETY: each symbol is cast to the signature nvml.h declares for it
+        let symbols = unsafe {
+            (|| {
+                let init: InitFn = symbol(library, c"nvmlInit_v2")?;
+                let nvml = Self {
+                    library,
+                    shutdown: symbol(library, c"nvmlShutdown")?,
+                    error_string: symbol(library, c"nvmlErrorString")?,
+                    device_get_count: symbol(library, c"nvmlDeviceGetCount_v2")?,
+                    device_get_handle_by_index: symbol(library, c"nvmlDeviceGetHandleByIndex_v2")?,
+                    device_get_compute_running_processes: symbol(
+                        library,
+                        c"nvmlDeviceGetComputeRunningProcesses_v3",
+                    )?,
+                };
+                Some((init, nvml))
+            })()
+        };
+        let Some((init, nvml)) = symbols else {
+            // SAFETY: the handle came from dlopen and is not used afterwards
+            unsafe { libc::dlclose(library) };
+            return Err(GpuCheckpointError::GpuDeviceError(format!(
+                "{} lacks the NVML functions used (driver too old?)",
+                NVML_LIBRARY.to_string_lossy()
+            )));
+        };
+
+        // SAFETY: nvmlInit_v2 takes no arguments. On failure `nvml` is
+        // dropped, which shuts down an NVML that was never initialized; NVML
+        // reports that as an error, which drop ignores.
+        nvml.check(unsafe { init() }, "nvmlInit_v2")?;
+        debug!("Loaded NVML from {}", NVML_LIBRARY.to_string_lossy());
+        Ok(nvml)
+    }
+
+    /// GPU memory of `pid` on every device it runs compute work on
+    pub fn process_usage(&self, pid: u32) -> Result<Vec<ProcessUsage>> {
+        let mut count: c_uint = 0;
+        // SAFETY: `count` is a valid out pointer
+        self.check(
+            unsafe { (self.device_get_count)(&mut count) },
+            "nvmlDeviceGetCount_v2",
+        )?;
+
+        let mut usage = Vec::new();
+        for index in 0..count {
+            let mut device: NvmlDevice = std::ptr::null_mut();
+            // SAFETY: `device` is a valid out pointer
+            self.check(
+                unsafe { (self.device_get_handle_by_index)(index, &mut device) },
+                "nvmlDeviceGetHandleByIndex_v2",
+            )?;
+
+            for process in self.compute_processes(device)? {
+                if process.pid == pid {
+                    usage.push(ProcessUsage {
+                        device_index: index,
+                        used_gpu_memory: (process.used_gpu_memory != NVML_VALUE_NOT_AVAILABLE)
+                            .then_some(process.used_gpu_memory),
+                    });
+                }
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Processes with a compute context on `device`
+    fn compute_processes(&self, device: NvmlDevice) -> Result<Vec<NvmlProcessInfo>> {
+        // Processes may start between asking for the count and the list,
+        // so leave room and retry if that was not enough
+        let mut capacity: c_uint = 0;
+        loop {
+            let mut processes = vec![NvmlProcessInfo::default(); capacity as usize];
+            let mut count = capacity;
+            // SAFETY: `processes` holds `count` entries
+            let ret = unsafe {
+                (self.device_get_compute_running_processes)(
+                    device,
+                    &mut count,
+                    processes.as_mut_ptr(),
+                )
+            };
+            match ret {
+                NVML_ERROR_INSUFFICIENT_SIZE => capacity = count + 8,
+                ret => {
+                    self.check(ret, "nvmlDeviceGetComputeRunningProcesses_v3")?;
+                    processes.truncate(count as usize);
+                    return Ok(processes);
+                }
+            }
+        }
+    }
+
+    /// Map an NVML return code to an error naming the failed `function`
+    fn check(&self, ret: c_int, function: &str) -> Result<()> {
+        if ret == NVML_SUCCESS {
+            return Ok(());
+        }
+        // SAFETY: nvmlErrorString returns a static string for any code
+        let message = unsafe { CStr::from_ptr((self.error_string)(ret)) };
+        Err(GpuCheckpointError::GpuDeviceError(format!(
+            "{function} failed: {}",
+            message.to_string_lossy()
+        )))
+    }
+}
+
+impl Drop for Nvml {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from dlopen and the functions from it
+        unsafe {
+            (self.shutdown)();
+            libc::dlclose(self.library);
+        }
+    }
+}
+
+/// Function `name` of `library` as an `F`, `None` if it is not exported.
+/// `F` must be the function pointer type of its declaration.
+unsafe fn symbol<F>(library: *mut c_void, name: &CStr) -> Option<F> {
+    let address = libc::dlsym(library, name.as_ptr());
+    (!address.is_null()).then(|| std::mem::transmute_copy::<*mut c_void, F>(&address))
+}
@@ -1,18 +1,59 @@
 #[allow(unused_imports)]
 use crate::detector::types::{AllocationType, GpuAllocation};
-#[cfg(target_os = "linux")]
 use crate::GpuCheckpointError;
 use crate::Result;
+use regex::Regex;
+use std::collections::BTreeMap;
 use std::fs;
 #[allow(unused_imports)]
 #[cfg(unix)]
 use std::os::unix::fs::MetadataExt;
 #[allow(unused_imports)]
 use std::path::Path;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
 use tracing::debug;
 #[cfg(target_os = "linux")]
 use tracing::trace;
 
+/// Matches NVIDIA device nodes and captures the device index
+static NVIDIA_DEVICE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^/dev/nvidia(\d+)$").expect("valid NVIDIA device regex"));
+
+/// Yama's restriction on which processes may be ptraced
+pub const YAMA_PTRACE_SCOPE_PATH: &str = "/proc/sys/kernel/yama/ptrace_scope";
+
+/// Capability number of `CAP_SYS_PTRACE`
+const CAP_SYS_PTRACE: u32 = 19;
+
+/// Pipe directory the MPS control daemon uses unless overridden
+pub const DEFAULT_MPS_PIPE_DIRECTORY: &str = "/tmp/nvidia-mps";
+
+/// Prefixes of the environment variables that configure GPU runtimes. Only
+/// these are captured into checkpoints, so unrelated secrets in the
+/// target's environment are never written out.
+pub const GPU_ENV_PREFIXES: &[&str] = &[
+    "CUDA_",
+    "NVIDIA_",
+    "NCCL_",
+    "CUBLAS_",
+    "CUDNN_",
+    "PYTORCH_CUDA_",
+    "ROCR_",
+    "HIP_",
+    "HSA_",
+];
+
+/// Allow-listed variables whose names still suggest a credential are redacted
+const REDACTED_ENV_MARKERS: &[&str] = &["TOKEN", "SECRET", "PASSWORD", "KEY", "CREDENTIAL"];
+
+/// Kernel symbols of GPU drivers; a thread waiting in one of them (per its
+/// `wchan` or kernel stack) is driving the GPU
+const GPU_DRIVER_SYMBOLS: &[&str] = &["nvidia", "uvm", "kfd", "amdgpu"];
+
+/// Name prefixes of the helper threads the CUDA and HIP runtimes start
+const GPU_RUNTIME_THREAD_PREFIXES: &[&str] = &["cuda-", "cuda0", "hip-"];
+
 pub struct ProcessScanner;
 
 impl ProcessScanner {
@@ -73,14 +114,13 @@ impl ProcessScanner {
     pub fn classify_fd(fd: &FileDescriptor) -> Option<GpuFdInfo> {
         // NVIDIA GPU device files
         if fd.target.starts_with("/dev/nvidia") {
-            let device_type = if fd.target.contains("nvidia-uvm") {
+            let device_type = if fd.target.contains("nvidia-uvm-tools") {
+                GpuDeviceType::NvidiaUvmTools
+            } else if fd.target.contains("nvidia-uvm") {
                 GpuDeviceType::NvidiaUvm
             } else if fd.target.contains("nvidiactl") {
                 GpuDeviceType::NvidiaControl
-            } else if let Some(captures) = regex::Regex::new(r"/dev/nvidia(\d+)")
-                .ok()
-                .and_then(|re| re.captures(&fd.target))
-            {
+            } else if let Some(captures) = NVIDIA_DEVICE_RE.captures(&fd.target) {
                 let device_id = captures[1].parse::<u32>().ok();
                 return Some(GpuFdInfo {
                     fd: fd.fd,
@@ -123,14 +163,211 @@ impl ProcessScanner {
         None
     }
 
-    pub fn check_process_cmdline(pid: u32) -> Result<String> {
+    /// Scheduler state of a process, read from `/proc/PID/stat`
+    pub fn process_state(pid: u32) -> Result<ProcessState> {
         #[cfg(target_os = "linux")]
         {
-            let cmdline_path = format!("/proc/{pid}/cmdline");
-            let cmdline = fs::read_to_string(&cmdline_path)?;
+            let stat = fs::read_to_string(format!("/proc/{pid}/stat")).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    GpuCheckpointError::ProcessNotFound(pid)
+                } else {
+                    GpuCheckpointError::IoError(e)
+                }
+            })?;
 
-            // Replace null bytes with spaces for readability
-            Ok(cmdline.replace('\0', " ").trim().to_string())
+            Self::parse_stat_state(&stat).ok_or_else(|| {
+                GpuCheckpointError::DetectionError(format!("Malformed /proc/{pid}/stat"))
+            })
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = pid;
+            Ok(ProcessState::Other('?'))
+        }
+    }
+
+    /// Extract the state field from the contents of `/proc/PID/stat`
+    pub fn parse_stat_state(stat: &str) -> Option<ProcessState> {
+        // The command name may contain spaces, the state follows the last ')'
+        let state = stat.rsplit_once(')')?.1.trim_start().chars().next()?;
+        Some(ProcessState::from(state))
+    }
+
+    /// Processes in process group `pgid`, in PID order
+    pub fn process_group(pgid: u32) -> Result<Vec<u32>> {
+        let mut pids = Vec::new();
+        for entry in fs::read_dir("/proc")? {
+            let Some(pid) = entry?.file_name().to_str().and_then(|s| s.parse().ok()) else {
+                continue;
+            };
+            // Processes may exit while /proc is scanned
+            let Ok(stat) = fs::read_to_string(format!("/proc/{pid}/stat")) else {
+                continue;
+            };
+            if Self::parse_stat_pgrp(&stat) == Some(pgid) {
+                pids.push(pid);
+            }
+        }
+        pids.sort_unstable();
+        Ok(pids)
+    }
+
+    /// Extract the process group from the contents of `/proc/PID/stat`
+    pub fn parse_stat_pgrp(stat: &str) -> Option<u32> {
+        // State and parent PID precede the process group
+        stat.rsplit_once(')')?
+            .1
+            .split_whitespace()
+            .nth(2)?
+            .parse()
+            .ok()
+    }
+
+    /// Whether the process exists and has not exited. Zombies have exited
+    /// and only wait to be reaped, so they are not considered alive.
+    pub fn is_alive(pid: u32) -> bool {
+        matches!(Self::process_state(pid), Ok(state) if !state.has_exited())
+    }
+
+    /// Poll until the process has exited, returning `false` on timeout
+    pub fn wait_for_exit(pid: u32, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if !Self::is_alive(pid) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Check that this process may read and write the memory of
+    /// `target_pid`, which takes the same access as attaching with ptrace.
+    /// A denial explains how to lift it.
+    pub fn check_ptrace_permissions(target_pid: u32) -> Result<()> {
+        // A missing process is reported by whatever looks for it next
+        if !Self::is_alive(target_pid) {
+            return Ok(());
+        }
+        let privileged = fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| Self::parse_effective_capabilities(&status))
+            .is_some_and(|caps| caps & (1 << CAP_SYS_PTRACE) != 0);
+        // Without Yama only the usual ownership rules apply
+        let scope = match fs::read_to_string(YAMA_PTRACE_SCOPE_PATH) {
+            Ok(value) => Self::parse_ptrace_scope(&value).ok_or_else(|| {
+                GpuCheckpointError::DetectionError(format!(
+                    "Unexpected {YAMA_PTRACE_SCOPE_PATH} value {:?}",
+                    value.trim()
+                ))
+            })?,
+            Err(_) => PtraceScope::Classic,
+        };
+
+        let denied = |reason: String| Err(GpuCheckpointError::PtraceDenied(reason));
+        match scope {
+            PtraceScope::NoAttach => denied(format!(
+                "{YAMA_PTRACE_SCOPE_PATH} is 3, which forbids attaching to any process \
+                 until reboot; boot with a lower kernel.yama.ptrace_scope"
+            )),
+            _ if privileged => Ok(()),
+            PtraceScope::AdminOnly => denied(format!(
+                "{YAMA_PTRACE_SCOPE_PATH} is 2, which limits access to processes to \
+                 CAP_SYS_PTRACE; run as root or with CAP_SYS_PTRACE"
+            )),
+            PtraceScope::Restricted if !Self::is_descendant(target_pid) => denied(format!(
+                "{YAMA_PTRACE_SCOPE_PATH} is 1, which limits access to child processes; \
+                 run as root, grant CAP_SYS_PTRACE or set ptrace_scope=0 \
+                 (sysctl kernel.yama.ptrace_scope=0)"
+            )),
+            _ => {
+                // The target's real, effective and saved UIDs must all be ours
+                let euid = Self::process_uids(std::process::id())
+                    .ok()
+                    .and_then(|uids| uids.get(1).copied());
+                match (euid, Self::process_uids(target_pid)) {
+                    (Some(euid), Ok(uids)) if uids.iter().any(|&uid| uid != euid) => {
+                        denied(format!(
+                            "PID {target_pid} belongs to another user; run as root or with \
+                             CAP_SYS_PTRACE"
+                        ))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Parse the contents of `YAMA_PTRACE_SCOPE_PATH`
+    pub fn parse_ptrace_scope(value: &str) -> Option<PtraceScope> {
+        match value.trim() {
+            "0" => Some(PtraceScope::Classic),
+            "1" => Some(PtraceScope::Restricted),
+            "2" => Some(PtraceScope::AdminOnly),
+            "3" => Some(PtraceScope::NoAttach),
+            _ => None,
+        }
+    }
+
+    /// Extract the effective capability set from the contents of
+    /// `/proc/PID/status`
+    pub fn parse_effective_capabilities(status: &str) -> Option<u64> {
+        let caps = status
+            .lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))?;
+        u64::from_str_radix(caps.trim(), 16).ok()
+    }
+
+    /// Real, effective and saved user IDs of a process
+    fn process_uids(pid: u32) -> Result<Vec<u32>> {
+        let status = fs::read_to_string(format!("/proc/{pid}/status"))?;
+        let uids = status
+            .lines()
+            .find_map(|line| line.strip_prefix("Uid:"))
+            .unwrap_or_default();
+        Ok(uids
+            .split_whitespace()
+            .take(3)
+            .filter_map(|uid| uid.parse().ok())
+            .collect())
+    }
+
+    /// Whether `pid` descends from this process
+    fn is_descendant(pid: u32) -> bool {
+        let me = std::process::id();
+        let mut current = pid;
+        // PID 1 and 0 end every chain
+        while current > 1 {
+            let Ok(stat) = fs::read_to_string(format!("/proc/{current}/stat")) else {
+                return false;
+            };
+            match Self::parse_stat_ppid(&stat) {
+                Some(parent) if parent == me => return true,
+                Some(parent) => current = parent,
+                None => return false,
+            }
+        }
+        false
+    }
+
+    /// Extract the parent PID from the contents of `/proc/PID/stat`
+    pub fn parse_stat_ppid(stat: &str) -> Option<u32> {
+        stat.rsplit_once(')')?
+            .1
+            .split_whitespace()
+            .nth(1)?
+            .parse()
+            .ok()
+    }
+
+    pub fn check_process_cmdline(pid: u32) -> Result<String> {
+        #[cfg(target_os = "linux")]
+        {
+            // Join the arguments with spaces for readability
+            Ok(Self::process_argv(pid)?.join(" ").trim().to_string())
         }
 
         #[cfg(not(target_os = "linux"))]
@@ -140,6 +377,38 @@ impl ProcessScanner {
         }
     }
 
+    /// Arguments the process was started with, from `/proc/PID/cmdline`.
+    /// Empty for kernel threads and zombies.
+    pub fn process_argv(pid: u32) -> Result<Vec<String>> {
+        #[cfg(target_os = "linux")]
+        {
+            let cmdline = fs::read(format!("/proc/{pid}/cmdline"))?;
+            Ok(Self::parse_cmdline(&cmdline))
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = pid;
+            Ok(Vec::new())
+        }
+    }
+
+    /// Split the NUL-terminated arguments of `/proc/PID/cmdline`
+    pub fn parse_cmdline(cmdline: &[u8]) -> Vec<String> {
+        cmdline
+            .strip_suffix(b"\0")
+            .unwrap_or(cmdline)
+            .split(|&byte| byte == 0)
+            .filter(|_| !cmdline.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect()
+    }
+
+    /// Working directory of the process
+    pub fn process_cwd(pid: u32) -> Result<std::path::PathBuf> {
+        Ok(fs::read_link(format!("/proc/{pid}/cwd"))?)
+    }
+
     pub fn check_process_environ(pid: u32) -> Result<Vec<(String, String)>> {
         #[cfg(target_os = "linux")]
         {
@@ -163,6 +432,137 @@ impl ProcessScanner {
         }
     }
 
+    /// Whether the process is an NVIDIA MPS client, judging by its
+    /// environment and descriptors
+    pub fn uses_mps(pid: u32, fds: &[FileDescriptor]) -> bool {
+        let env_vars = Self::check_process_environ(pid).unwrap_or_default();
+        Self::is_mps_client(&env_vars, fds)
+    }
+
+    /// MPS clients either have `CUDA_MPS_PIPE_DIRECTORY` set or hold
+    /// descriptors inside the (default) MPS pipe directory
+    pub fn is_mps_client(env_vars: &[(String, String)], fds: &[FileDescriptor]) -> bool {
+        let pipe_dir = env_vars
+            .iter()
+            .find(|(key, _)| key == "CUDA_MPS_PIPE_DIRECTORY")
+            .map(|(_, value)| value.as_str());
+        if pipe_dir.is_some_and(|dir| !dir.is_empty()) {
+            return true;
+        }
+
+        let pipe_dir = Path::new(pipe_dir.unwrap_or(DEFAULT_MPS_PIPE_DIRECTORY));
+        fds.iter()
+            .any(|fd| Path::new(&fd.target).starts_with(pipe_dir))
+    }
+
+    /// GPU-related environment variables of the process, for recording in a
+    /// checkpoint
+    pub fn gpu_environment(pid: u32) -> Result<BTreeMap<String, String>> {
+        Ok(Self::filter_gpu_environment(&Self::check_process_environ(
+            pid,
+        )?))
+    }
+
+    /// Keep the variables starting with one of `GPU_ENV_PREFIXES`, redacting
+    /// the values of any that look like credentials
+    pub fn filter_gpu_environment(env_vars: &[(String, String)]) -> BTreeMap<String, String> {
+        env_vars
+            .iter()
+            .filter(|(key, _)| {
+                GPU_ENV_PREFIXES
+                    .iter()
+                    .any(|prefix| key.starts_with(prefix))
+            })
+            .map(|(key, value)| {
+                let upper = key.to_ascii_uppercase();
+                if REDACTED_ENV_MARKERS
+                    .iter()
+                    .any(|marker| upper.contains(marker))
+                {
+                    (key.clone(), "<redacted>".to_string())
+                } else {
+                    (key.clone(), value.clone())
+                }
+            })
+            .collect()
+    }
+
+    /// Threads of the process that drive the GPU: the runtime's helper
+    /// threads and threads waiting in a GPU driver or calling `ioctl` on one
+    /// of `gpu_fds`. Memory is process-wide, but these threads are the ones
+    /// to stop first.
+    pub fn gpu_threads(pid: u32, gpu_fds: &[i32]) -> Result<Vec<u32>> {
+        #[cfg(target_os = "linux")]
+        {
+            let entries = fs::read_dir(format!("/proc/{pid}/task")).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    GpuCheckpointError::ProcessNotFound(pid)
+                } else {
+                    GpuCheckpointError::IoError(e)
+                }
+            })?;
+
+            let mut threads: Vec<u32> = entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+                .filter(|tid| {
+                    // Threads can exit while being scanned, and wchan, stack
+                    // and syscall may be unreadable without ptrace access
+                    let read = |file: &str| {
+                        fs::read_to_string(format!("/proc/{pid}/task/{tid}/{file}"))
+                            .unwrap_or_default()
+                    };
+                    let kernel_wait = read("wchan") + &read("stack");
+                    let is_gpu = Self::is_gpu_thread(
+                        read("comm").trim_end(),
+                        &kernel_wait,
+                        &read("syscall"),
+                        gpu_fds,
+                    );
+                    if is_gpu {
+                        trace!("Thread {} of PID {} drives the GPU", tid, pid);
+                    }
+                    is_gpu
+                })
+                .collect();
+            threads.sort_unstable();
+
+            debug!("Found {} GPU thread(s) for PID {}", threads.len(), pid);
+            Ok(threads)
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (pid, gpu_fds);
+            Ok(Vec::new())
+        }
+    }
+
+    /// Whether a thread with name `comm`, kernel wait location `kernel_wait`
+    /// and `/proc/PID/task/TID/syscall` contents `syscall` drives the GPU
+    pub fn is_gpu_thread(comm: &str, kernel_wait: &str, syscall: &str, gpu_fds: &[i32]) -> bool {
+        if GPU_RUNTIME_THREAD_PREFIXES
+            .iter()
+            .any(|prefix| comm.starts_with(prefix))
+        {
+            return true;
+        }
+
+        if GPU_DRIVER_SYMBOLS
+            .iter()
+            .any(|symbol| kernel_wait.contains(symbol))
+        {
+            return true;
+        }
+
+        // "NR ARG0 ARG1 ..." while in a system call, with hex arguments
+        let mut fields = syscall.split_whitespace();
+        let nr = fields.next().and_then(|nr| nr.parse::<libc::c_long>().ok());
+        let fd = fields
+            .next()
+            .and_then(|arg| i32::from_str_radix(arg.trim_start_matches("0x"), 16).ok());
+        matches!((nr, fd), (Some(nr), Some(fd)) if nr == libc::SYS_ioctl && gpu_fds.contains(&fd))
+    }
+
     pub fn has_gpu_environment(pid: u32) -> Result<bool> {
         let env_vars = Self::check_process_environ(pid)?;
 
@@ -209,11 +609,77 @@ pub struct GpuFdInfo {
     pub path: String,
 }
 
+/// Process state as reported by the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    /// R
+    Running,
+    /// S
+    Sleeping,
+    /// D, uninterruptible (usually I/O) wait
+    DiskSleep,
+    /// Z, exited but not yet reaped by its parent
+    Zombie,
+    /// T, stopped by a signal
+    Stopped,
+    /// t, stopped by a tracer
+    TracingStop,
+    /// X
+    Dead,
+    /// I
+    Idle,
+    Other(char),
+}
+
+/// Value of Yama's `ptrace_scope`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtraceScope {
+    /// Any process of the same user may be attached to
+    Classic,
+
+    /// Only descendants may be attached to, short of `CAP_SYS_PTRACE`
+    Restricted,
+
+    /// Only processes with `CAP_SYS_PTRACE` may attach
+    AdminOnly,
+
+    /// No process may attach
+    NoAttach,
+}
+
+impl ProcessState {
+    pub fn has_exited(&self) -> bool {
+        matches!(self, ProcessState::Zombie | ProcessState::Dead)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        matches!(self, ProcessState::Stopped | ProcessState::TracingStop)
+    }
+}
+
+impl From<char> for ProcessState {
+    fn from(state: char) -> Self {
+        match state {
+            'R' => ProcessState::Running,
+            'S' => ProcessState::Sleeping,
+            'D' => ProcessState::DiskSleep,
+            'Z' => ProcessState::Zombie,
+            'T' => ProcessState::Stopped,
+            't' => ProcessState::TracingStop,
+            'X' | 'x' => ProcessState::Dead,
+            'I' => ProcessState::Idle,
+            other => ProcessState::Other(other),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum GpuDeviceType {
     NvidiaDevice,
     NvidiaControl,
     NvidiaUvm,
+    /// UVM event/counter interface used by profilers and debuggers
+    NvidiaUvmTools,
     AmdGpu,
     SharedMemory,
     Unknown,
@@ -223,6 +689,51 @@ pub enum GpuDeviceType {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_ptrace_permissions() {
+        assert_eq!(
+            ProcessScanner::parse_ptrace_scope("1\n"),
+            Some(PtraceScope::Restricted)
+        );
+        assert_eq!(
+            ProcessScanner::parse_ptrace_scope("0"),
+            Some(PtraceScope::Classic)
+        );
+        assert_eq!(
+            ProcessScanner::parse_ptrace_scope("3\n"),
+            Some(PtraceScope::NoAttach)
+        );
+        assert_eq!(ProcessScanner::parse_ptrace_scope("4"), None);
+        assert_eq!(ProcessScanner::parse_ptrace_scope(""), None);
+
+        let status = "Name:\tcat\nCapInh:\t0000000000000000\nCapEff:\t000001ffffffffff\n";
+        let caps = ProcessScanner::parse_effective_capabilities(status).unwrap();
+        assert_ne!(caps & (1 << CAP_SYS_PTRACE), 0);
+        assert_eq!(
+            ProcessScanner::parse_effective_capabilities("CapEff:\t0000000000000000\n"),
+            Some(0)
+        );
+        assert_eq!(
+            ProcessScanner::parse_effective_capabilities("Name:\tcat\n"),
+            None
+        );
+
+        assert_eq!(
+            ProcessScanner::parse_stat_ppid("42 (a (b) c) S 7 42 42 0"),
+            Some(7)
+        );
+
+        // A process may always access its own children
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        assert!(ProcessScanner::is_descendant(child.id()));
+        assert!(ProcessScanner::check_ptrace_permissions(child.id()).is_ok());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
     #[test]
     fn test_classify_nvidia_fd() {
         let fd = FileDescriptor {
@@ -247,4 +758,205 @@ mod tests {
         let info = ProcessScanner::classify_fd(&fd).unwrap();
         assert_eq!(info.device_type, GpuDeviceType::NvidiaUvm);
     }
+
+    #[test]
+    fn test_classify_nvidia_uvm_tools_fd() {
+        let fd = FileDescriptor {
+            fd: 12,
+            target: "/dev/nvidia-uvm-tools".to_string(),
+            metadata: None,
+        };
+
+        let info = ProcessScanner::classify_fd(&fd).unwrap();
+        assert_eq!(info.device_type, GpuDeviceType::NvidiaUvmTools);
+        assert_eq!(info.device_id, None);
+    }
+
+    #[test]
+    fn test_is_mps_client() {
+        let fd = |target: &str| FileDescriptor {
+            fd: 3,
+            target: target.to_string(),
+            metadata: None,
+        };
+
+        let env = vec![(
+            "CUDA_MPS_PIPE_DIRECTORY".to_string(),
+            "/var/run/mps".to_string(),
+        )];
+        assert!(ProcessScanner::is_mps_client(&env, &[]));
+
+        let mps_fds = [fd("/dev/nvidiactl"), fd("/tmp/nvidia-mps/control")];
+        assert!(ProcessScanner::is_mps_client(&[], &mps_fds));
+
+        let plain_fds = [fd("/dev/nvidiactl"), fd("/dev/nvidia0")];
+        assert!(!ProcessScanner::is_mps_client(&[], &plain_fds));
+    }
+
+    #[test]
+    fn test_parse_cmdline() {
+        assert_eq!(
+            ProcessScanner::parse_cmdline(b"python\0train.py\0--name\0two words\0\0"),
+            vec!["python", "train.py", "--name", "two words", ""]
+        );
+        assert_eq!(
+            ProcessScanner::parse_cmdline(b"sleep\0inf\0"),
+            vec!["sleep", "inf"]
+        );
+        assert!(ProcessScanner::parse_cmdline(b"").is_empty());
+    }
+
+    #[test]
+    fn test_filter_gpu_environment() {
+        let env: Vec<(String, String)> = [
+            ("CUDA_VISIBLE_DEVICES", "0,1"),
+            ("NCCL_DEBUG", "INFO"),
+            ("NCCL_IB_HCA", "mlx5"),
+            ("HIP_VISIBLE_DEVICES", "2"),
+            ("NVIDIA_API_KEY", "hunter2"),
+            ("AWS_SECRET_ACCESS_KEY", "hunter2"),
+            ("HOME", "/root"),
+            ("MY_CUDA_HOME", "/opt/cuda"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let captured = ProcessScanner::filter_gpu_environment(&env);
+        assert_eq!(
+            captured.keys().collect::<Vec<_>>(),
+            vec![
+                "CUDA_VISIBLE_DEVICES",
+                "HIP_VISIBLE_DEVICES",
+                "NCCL_DEBUG",
+                "NCCL_IB_HCA",
+                "NVIDIA_API_KEY",
+            ]
+        );
+        assert_eq!(captured["CUDA_VISIBLE_DEVICES"], "0,1");
+        assert_eq!(captured["NVIDIA_API_KEY"], "<redacted>");
+    }
+
+    #[test]
+    fn test_parse_stat_state() {
+        let stat = "1234 (python3 (worker)) S 1 1234 1234 0 -1 4194560";
+        assert_eq!(
+            ProcessScanner::parse_stat_state(stat),
+            Some(ProcessState::Sleeping)
+        );
+
+        let zombie = "42 (train) Z 1 42 42 0 -1";
+        let state = ProcessScanner::parse_stat_state(zombie).unwrap();
+        assert!(state.has_exited());
+
+        assert_eq!(ProcessScanner::parse_stat_state("garbage"), None);
+    }
+
+    #[test]
+    fn test_process_group() {
+        let stat = "1234 (python3 (worker)) S 1 1200 1234 0 -1 4194560";
+        assert_eq!(ProcessScanner::parse_stat_pgrp(stat), Some(1200));
+        assert_eq!(ProcessScanner::parse_stat_pgrp("garbage"), None);
+
+        let pgid = nix::unistd::getpgrp().as_raw() as u32;
+        let members = ProcessScanner::process_group(pgid).unwrap();
+        assert!(members.contains(&std::process::id()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_liveness() {
+        assert!(ProcessScanner::is_alive(std::process::id()));
+        assert!(!ProcessScanner::is_alive(u32::MAX));
+        assert!(matches!(
+            ProcessScanner::process_state(u32::MAX),
+            Err(GpuCheckpointError::ProcessNotFound(_))
+        ));
+
+        // An exited but unreaped child is a zombie and no longer alive
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        assert!(ProcessScanner::wait_for_exit(
+            child.id(),
+            Duration::from_secs(5)
+        ));
+        assert_eq!(
+            ProcessScanner::process_state(child.id()).unwrap(),
+            ProcessState::Zombie
+        );
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_is_gpu_thread() {
+        let ioctl = format!("{} 0x5 0xc020462a 0x7ffc1000 0x0", libc::SYS_ioctl);
+        assert!(ProcessScanner::is_gpu_thread("python", "", &ioctl, &[3, 5]));
+        assert!(!ProcessScanner::is_gpu_thread("python", "", &ioctl, &[3]));
+
+        assert!(ProcessScanner::is_gpu_thread(
+            "cuda-EvtHandlr",
+            "",
+            "running",
+            &[]
+        ));
+        assert!(ProcessScanner::is_gpu_thread(
+            "python",
+            "uvm_spin_loop",
+            "running",
+            &[]
+        ));
+        assert!(!ProcessScanner::is_gpu_thread(
+            "python",
+            "futex_wait_queue",
+            "202 0x7f00 0x80 0x0",
+            &[5]
+        ));
+    }
+
+    #[test]
+    fn test_gpu_threads_finds_runtime_threads() {
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        let helper = std::thread::Builder::new()
+            .name("cuda-EvtHandlr".to_string())
+            .spawn(move || {
+                // SAFETY: gettid has no preconditions
+                ready_tx.send(unsafe { libc::gettid() } as u32).unwrap();
+                let _ = done_rx.recv();
+            })
+            .unwrap();
+        let tid = ready_rx.recv().unwrap();
+
+        let threads = ProcessScanner::gpu_threads(std::process::id(), &[]);
+        drop(done_tx);
+        helper.join().unwrap();
+
+        let threads = threads.unwrap();
+        assert!(threads.contains(&tid));
+        assert!(!threads.contains(&std::process::id()));
+    }
+
+    #[test]
+    fn test_classify_multi_digit_nvidia_fd() {
+        for (target, device_id) in [("/dev/nvidia7", 7), ("/dev/nvidia15", 15)] {
+            let fd = FileDescriptor {
+                fd: 12,
+                target: target.to_string(),
+                metadata: None,
+            };
+
+            let info = ProcessScanner::classify_fd(&fd).unwrap();
+            assert_eq!(info.device_type, GpuDeviceType::NvidiaDevice);
+            assert_eq!(info.device_id, Some(device_id));
+        }
+
+        // Not a device node, so no device ID is extracted
+        let fd = FileDescriptor {
+            fd: 13,
+            target: "/dev/nvidia-modeset".to_string(),
+            metadata: None,
+        };
+        let info = ProcessScanner::classify_fd(&fd).unwrap();
+        assert_eq!(info.device_type, GpuDeviceType::Unknown);
+        assert_eq!(info.device_id, None);
+    }
 }
@@ -1,8 +1,19 @@
+use crate::GpuCheckpointError;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
+use std::str::FromStr;
 use std::time::SystemTime;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Version of the `detect --format json` output. Bumped whenever a field of
+/// `DetectionReport` or anything it contains is added, renamed, removed or
+/// changes type.
+pub const DETECTION_SCHEMA_VERSION: u32 = 8;
+
+/// Size of an opaque `cudaIpcMemHandle_t`
+pub const CUDA_IPC_HANDLE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum GpuVendor {
     Nvidia,
     Amd,
@@ -21,6 +32,20 @@ impl fmt::Display for GpuVendor {
     }
 }
 
+impl FromStr for GpuVendor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nvidia" => Ok(GpuVendor::Nvidia),
+            "amd" => Ok(GpuVendor::Amd),
+            "intel" => Ok(GpuVendor::Intel),
+            "unknown" => Ok(GpuVendor::Unknown),
+            _ => Err(format!("Unknown GPU vendor: {s}")),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AllocationType {
     /// Standard GPU memory allocation (cudaMalloc)
@@ -88,6 +113,58 @@ pub struct AllocationMetadata {
 
     /// Is this a shared mapping?
     pub is_shared: bool,
+
+    /// Offset of the mapping within its backing file
+    #[serde(default)]
+    pub file_offset: u64,
+
+    /// Inode of the backing file, which identifies shared memory mapped by
+    /// several processes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+
+    /// Hex-encoded `cudaIpcMemHandle_t` the allocation was exported with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ipc_handle: Option<String>,
+
+    /// PCI BAR a `BarMapped` allocation maps, e.g. 1 for `resource1`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bar_index: Option<u32>,
+
+    /// Bytes of the mapping resident in RAM, from `/proc/PID/smaps`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rss_bytes: Option<u64>,
+
+    /// Physical frame number of each page of a `BarMapped` allocation, 0
+    /// for pages not present, from `/proc/PID/pagemap`. Only available
+    /// when detection runs with `CAP_SYS_ADMIN`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub physical_frames: Option<Vec<u64>>,
+
+    /// Backed by huge pages, from hugetlbfs or transparent huge pages.
+    /// Checkpoints copy such allocations in windows of whole huge pages.
+    #[serde(default)]
+    pub huge_page: bool,
+}
+
+/// Selects the allocations of a detection to report
+#[derive(Debug, Clone, Default)]
+pub struct AllocationFilter {
+    /// Only report allocations of at least this many bytes
+    pub min_size: u64,
+
+    /// Only report allocations of these types
+    pub types: Option<Vec<AllocationType>>,
+}
+
+impl AllocationFilter {
+    pub fn matches(&self, allocation: &GpuAllocation) -> bool {
+        allocation.size >= self.min_size
+            && self
+                .types
+                .as_ref()
+                .is_none_or(|types| types.contains(&allocation.alloc_type))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -109,6 +186,89 @@ pub struct DetectionResult {
 
     /// Summary statistics
     pub stats: DetectionStats,
+
+    /// Allocations grouped by the CUDA context (device) they belong to
+    #[serde(default)]
+    pub contexts: Vec<ContextAllocations>,
+
+    /// GPUs the process uses, as reported by the driver
+    #[serde(default)]
+    pub gpus: Vec<GpuDeviceInfo>,
+
+    /// The process shares its GPU context through the MPS server
+    #[serde(default)]
+    pub is_mps: bool,
+
+    /// The process has `/dev/nvidia-uvm-tools` open, i.e. is being profiled
+    /// or debugged
+    #[serde(default)]
+    pub uvm_tools_attached: bool,
+
+    /// CUDA IPC handles the process holds
+    #[serde(default)]
+    pub ipc_handles: Vec<IpcHandle>,
+
+    /// Threads (TIDs) that drive the GPU, which are frozen first
+    #[serde(default)]
+    pub gpu_threads: Vec<u32>,
+
+    /// GPU runtime and driver libraries the process has loaded, if resolved
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub libraries: Vec<GpuLibrary>,
+}
+
+/// A GPU library mapped into the process, e.g. `libcudart`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GpuLibrary {
+    /// Library name without the `.so` suffix, e.g. "libcudnn"
+    pub name: String,
+
+    /// Path of the mapped file
+    pub path: String,
+
+    /// Version from the file name, e.g. "12.2.140" for `libcudart.so.12.2.140`
+    pub version: Option<String>,
+}
+
+/// A `cudaIpcMemHandle_t` found in a shared memory handle file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpcHandle {
+    /// Shared memory file holding the handle
+    pub path: String,
+
+    /// Hex-encoded handle bytes
+    pub handle: String,
+}
+
+/// Model and architecture of a GPU
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GpuDeviceInfo {
+    /// Device minor number, matching `/dev/nvidiaN`
+    pub device_id: Option<u32>,
+
+    /// Marketing name, e.g. "NVIDIA H100 80GB HBM3"
+    pub model: String,
+
+    /// Architecture name, e.g. "Hopper", if the model is known
+    pub architecture: Option<String>,
+
+    /// CUDA compute capability, e.g. "9.0", if the model is known
+    pub compute_capability: Option<String>,
+}
+
+/// Maximum address gap between allocations considered part of the same context
+pub const CONTEXT_CLUSTER_GAP: u64 = 1 << 30;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContextAllocations {
+    /// GPU device the context lives on, if it could be determined
+    pub device_id: Option<u32>,
+
+    /// Indices into `DetectionResult::allocations`, in address order
+    pub allocation_indices: Vec<usize>,
+
+    /// Combined size of the context's allocations
+    pub total_size: u64,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -123,11 +283,13 @@ pub struct DetectionStats {
 }
 
 impl GpuAllocation {
+    /// Allocation spanning `start..end`. An inverted range gets size 0
+    /// instead of underflowing; `DetectionResult::validate` rejects it.
     pub fn new(start: u64, end: u64, alloc_type: AllocationType) -> Self {
         Self {
             vaddr_start: start,
             vaddr_end: end,
-            size: end - start,
+            size: end.saturating_sub(start),
             alloc_type,
             device_id: None,
             fd: None,
@@ -144,6 +306,64 @@ impl GpuAllocation {
                 | AllocationType::Distributed
         )
     }
+
+    /// Whether `next` continues this allocation: it starts where this one
+    /// ends, with the same type, protection and backing. Allocations with
+    /// an IPC handle are exported on their own and never merged.
+    fn can_merge(&self, next: &GpuAllocation) -> bool {
+        let (a, b) = (&self.metadata, &next.metadata);
+        self.vaddr_end == next.vaddr_start
+            && self.alloc_type == next.alloc_type
+            && self.device_id == next.device_id
+            && self.fd == next.fd
+            && a.protection == b.protection
+            && a.is_shared == b.is_shared
+            && a.is_distributed == b.is_distributed
+            && a.numa_node == b.numa_node
+            && a.backing_file == b.backing_file
+            && a.inode == b.inode
+            && a.bar_index == b.bar_index
+            && a.huge_page == b.huge_page
+            && a.ipc_handle.is_none()
+            && b.ipc_handle.is_none()
+            // A file-backed mapping must also continue in the file
+            && (a.backing_file.is_none() || a.file_offset + self.size == b.file_offset)
+    }
+
+    /// Extend this allocation over `next`, which `can_merge` accepted
+    fn merge(&mut self, next: &GpuAllocation) {
+        self.vaddr_end = next.vaddr_end;
+        self.size += next.size;
+        let (a, b) = (&mut self.metadata, &next.metadata);
+        a.rss_bytes = a.rss_bytes.zip(b.rss_bytes).map(|(a, b)| a + b);
+        a.physical_frames = match (a.physical_frames.take(), &b.physical_frames) {
+            (Some(mut frames), Some(more)) => {
+                frames.extend_from_slice(more);
+                Some(frames)
+            }
+            _ => None,
+        };
+    }
+
+    /// Start address rounded down to a page boundary
+    pub fn aligned_start(&self, page_size: u64) -> u64 {
+        self.vaddr_start - self.vaddr_start % page_size
+    }
+
+    /// End address rounded up to a page boundary
+    pub fn aligned_end(&self, page_size: u64) -> u64 {
+        self.vaddr_end.div_ceil(page_size) * page_size
+    }
+
+    /// Size of all pages the allocation touches
+    pub fn page_aligned_size(&self, page_size: u64) -> u64 {
+        self.aligned_end(page_size) - self.aligned_start(page_size)
+    }
+
+    /// Number of pages the allocation touches
+    pub fn page_count(&self, page_size: u64) -> u64 {
+        self.page_aligned_size(page_size) / page_size
+    }
 }
 
 impl fmt::Display for AllocationType {
@@ -161,6 +381,24 @@ impl fmt::Display for AllocationType {
     }
 }
 
+impl FromStr for AllocationType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "standard" => Ok(AllocationType::Standard),
+            "uvm" => Ok(AllocationType::Uvm),
+            "managed" => Ok(AllocationType::Managed),
+            "ipc" => Ok(AllocationType::Ipc),
+            "distributed" => Ok(AllocationType::Distributed),
+            "bar-mapped" => Ok(AllocationType::BarMapped),
+            "host-pinned" => Ok(AllocationType::HostPinned),
+            "unknown" => Ok(AllocationType::Unknown),
+            _ => Err(format!("Unknown allocation type: {s}")),
+        }
+    }
+}
+
 impl DetectionResult {
     pub fn new(pid: u32, vendor: GpuVendor) -> Self {
         Self {
@@ -170,10 +408,35 @@ impl DetectionResult {
             total_gpu_memory: 0,
             timestamp: SystemTime::now(),
             stats: DetectionStats::default(),
+            contexts: Vec::new(),
+            gpus: Vec::new(),
+            is_mps: false,
+            uvm_tools_attached: false,
+            ipc_handles: Vec::new(),
+            gpu_threads: Vec::new(),
+            libraries: Vec::new(),
         }
     }
 
     pub fn add_allocation(&mut self, allocation: GpuAllocation) {
+        self.count(&allocation);
+        self.allocations.push(allocation);
+    }
+
+    /// Rebuild `stats` and `total_gpu_memory` from `allocations`, after
+    /// allocations were removed, resized or reclassified in place
+    pub fn recompute_stats(&mut self) {
+        let allocations = std::mem::take(&mut self.allocations);
+        self.total_gpu_memory = 0;
+        self.stats = DetectionStats::default();
+        for allocation in &allocations {
+            self.count(allocation);
+        }
+        self.allocations = allocations;
+    }
+
+    /// Add `allocation` to the totals and statistics
+    fn count(&mut self, allocation: &GpuAllocation) {
         self.total_gpu_memory += allocation.size;
         self.stats.total_size += allocation.size;
 
@@ -189,11 +452,1045 @@ impl DetectionResult {
             AllocationType::Distributed => self.stats.distributed_allocations += 1,
             _ => {}
         }
+    }
 
-        self.allocations.push(allocation);
+    /// Architecture of the GPU an allocation on `device_id` lives on. Falls
+    /// back to the only GPU when the process uses a single one.
+    pub fn architecture_of(&self, device_id: Option<u32>) -> Option<&str> {
+        let gpu = match (device_id, self.gpus.as_slice()) {
+            (Some(id), gpus) => gpus.iter().find(|gpu| gpu.device_id == Some(id)),
+            (None, [only]) => Some(only),
+            (None, _) => None,
+        };
+        gpu?.architecture.as_deref()
     }
 
     pub fn has_problematic_allocations(&self) -> bool {
         self.allocations.iter().any(|a| a.is_problematic())
     }
+
+    /// Copy of this result holding only the allocations `keep` selects.
+    /// Contexts keep their selected allocations; those left without any
+    /// are dropped.
+    pub fn filtered(&self, keep: impl Fn(&GpuAllocation) -> bool) -> DetectionResult {
+        let mut allocations = Vec::new();
+        let mut new_index = vec![None; self.allocations.len()];
+        for (i, allocation) in self.allocations.iter().enumerate() {
+            if keep(allocation) {
+                new_index[i] = Some(allocations.len());
+                allocations.push(allocation.clone());
+            }
+        }
+
+        let contexts = self
+            .contexts
+            .iter()
+            .filter_map(|ctx| {
+                let allocation_indices: Vec<usize> = ctx
+                    .allocation_indices
+                    .iter()
+                    .filter_map(|&i| new_index.get(i).copied().flatten())
+                    .collect();
+                (!allocation_indices.is_empty()).then(|| ContextAllocations {
+                    device_id: ctx.device_id,
+                    total_size: allocation_indices
+                        .iter()
+                        .map(|&i| allocations[i].size)
+                        .sum(),
+                    allocation_indices,
+                })
+            })
+            .collect();
+
+        let mut filtered = DetectionResult {
+            allocations,
+            contexts,
+            ..self.clone()
+        };
+        filtered.recompute_stats();
+        filtered
+    }
+
+    /// Merge runs of allocations that are contiguous in memory, of the same
+    /// type, protection and backing, and in the same context into single
+    /// allocations, so fragmented processes need fewer allocation records.
+    /// Allocations end up in address order. Returns how many were merged
+    /// away.
+    pub fn coalesce_allocations(&mut self) -> usize {
+        let mut context_of = vec![None; self.allocations.len()];
+        for (ctx, context) in self.contexts.iter().enumerate() {
+            for &i in &context.allocation_indices {
+                if let Some(slot) = context_of.get_mut(i) {
+                    *slot = Some(ctx);
+                }
+            }
+        }
+
+        let mut order: Vec<usize> = (0..self.allocations.len()).collect();
+        order.sort_by_key(|&i| self.allocations[i].vaddr_start);
+
+        let mut allocations: Vec<GpuAllocation> = Vec::with_capacity(order.len());
+        let mut new_index = vec![0; self.allocations.len()];
+        let mut last_context = None;
+        for i in order {
+            let next = &self.allocations[i];
+            match allocations.last_mut() {
+                Some(prev) if last_context == context_of[i] && prev.can_merge(next) => {
+                    prev.merge(next)
+                }
+                _ => allocations.push(next.clone()),
+            }
+            new_index[i] = allocations.len() - 1;
+            last_context = context_of[i];
+        }
+
+        let merged = self.allocations.len() - allocations.len();
+        if merged == 0 {
+            return 0;
+        }
+        for context in &mut self.contexts {
+            let mut indices: Vec<usize> = context
+                .allocation_indices
+                .iter()
+                .filter_map(|&i| new_index.get(i).copied())
+                .collect();
+            indices.dedup();
+            context.allocation_indices = indices;
+        }
+        self.allocations = allocations;
+        self.recompute_stats();
+        merged
+    }
+
+    /// Bytes in allocations that need the BAR sliding path (UVM, managed,
+    /// IPC and distributed)
+    pub fn total_problematic_memory(&self) -> u64 {
+        self.allocations
+            .iter()
+            .filter(|a| a.is_problematic())
+            .map(|a| a.size)
+            .sum()
+    }
+
+    /// The allocation whose address range contains `address`
+    pub fn allocation_containing(&self, address: u64) -> Option<&GpuAllocation> {
+        self.allocations
+            .iter()
+            .find(|a| (a.vaddr_start..a.vaddr_end).contains(&address))
+    }
+
+    /// Group allocations into per-context clusters.
+    ///
+    /// Allocations are walked in address order and a new context starts
+    /// whenever the gap to the previous allocation exceeds
+    /// `CONTEXT_CLUSTER_GAP` or the allocation is attributed to a different
+    /// device. Clusters without an attributed allocation inherit the device
+    /// when the process only has a single GPU open (`device_ids`).
+    pub fn group_contexts(&mut self, device_ids: &[u32]) {
+        let mut order: Vec<usize> = (0..self.allocations.len()).collect();
+        order.sort_by_key(|&i| self.allocations[i].vaddr_start);
+
+        let mut contexts: Vec<ContextAllocations> = Vec::new();
+        let mut prev_end = 0u64;
+
+        for idx in order {
+            let alloc = &self.allocations[idx];
+            let starts_new = match contexts.last() {
+                None => true,
+                Some(ctx) => {
+                    alloc.vaddr_start.saturating_sub(prev_end) > CONTEXT_CLUSTER_GAP
+                        || matches!(
+                            (ctx.device_id, alloc.device_id),
+                            (Some(a), Some(b)) if a != b
+                        )
+                }
+            };
+
+            if starts_new {
+                contexts.push(ContextAllocations::default());
+            }
+
+            let ctx = contexts.last_mut().expect("context was just pushed");
+            ctx.device_id = ctx.device_id.or(alloc.device_id);
+            ctx.allocation_indices.push(idx);
+            ctx.total_size += alloc.size;
+            prev_end = prev_end.max(alloc.vaddr_end);
+        }
+
+        if let [only_device] = device_ids {
+            for ctx in contexts.iter_mut().filter(|c| c.device_id.is_none()) {
+                ctx.device_id = Some(*only_device);
+            }
+        }
+
+        self.contexts = contexts;
+    }
+}
+
+/// Changes in a process's allocations between two detections
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DetectionDiff {
+    /// Allocations only present in the newer detection
+    pub added: Vec<GpuAllocation>,
+
+    /// Allocations only present in the older detection
+    pub removed: Vec<GpuAllocation>,
+
+    /// Allocations starting at the same address with a different size,
+    /// as (older, newer) pairs
+    pub resized: Vec<(GpuAllocation, GpuAllocation)>,
+}
+
+/// Top-level JSON document produced by `detect --format json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DetectionReport {
+    /// `DETECTION_SCHEMA_VERSION` of the writer; 0 for output that predates
+    /// versioning
+    pub schema_version: u32,
+
+    pub results: Vec<DetectionResult>,
+
+    /// Changes per vendor since a saved detection, if one was compared
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changes: Option<Vec<(GpuVendor, DetectionDiff)>>,
+}
+
+impl DetectionReport {
+    pub fn new(results: Vec<DetectionResult>) -> Self {
+        Self {
+            schema_version: DETECTION_SCHEMA_VERSION,
+            results,
+            changes: None,
+        }
+    }
+
+    /// Parse saved detection output. Unversioned output (a bare array of
+    /// results or a single result) is accepted with `schema_version` 0.
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Saved {
+            Report(DetectionReport),
+            Results(Vec<DetectionResult>),
+            Result(Box<DetectionResult>),
+        }
+
+        let results = match serde_json::from_str(json)? {
+            Saved::Report(report) => return Ok(report),
+            Saved::Results(results) => results,
+            Saved::Result(result) => vec![*result],
+        };
+        Ok(Self {
+            schema_version: 0,
+            results,
+            changes: None,
+        })
+    }
+}
+
+/// JSON Schema (draft 2020-12) of the `detect --format json` output, so
+/// consumers can validate what they read. Describes the document of
+/// `DETECTION_SCHEMA_VERSION` and has to change with it.
+pub fn detection_schema() -> serde_json::Value {
+    use serde_json::json;
+
+    let unsigned = json!({ "type": "integer", "minimum": 0 });
+    let optional_unsigned = json!({ "type": ["integer", "null"], "minimum": 0 });
+    let optional_string = json!({ "type": ["string", "null"] });
+    let array_of =
+        |def: &str| json!({ "type": "array", "items": { "$ref": format!("#/$defs/{def}") } });
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": format!("DetectionReport (schema version {DETECTION_SCHEMA_VERSION})"),
+        "description": "Output of gpu-checkpoint detect --format json",
+        "type": "object",
+        "required": ["schema_version", "results"],
+        "properties": {
+            "schema_version": { "const": DETECTION_SCHEMA_VERSION },
+            "results": array_of("DetectionResult"),
+            "changes": {
+                "description": "Changes per vendor since a saved detection, as [vendor, diff] pairs",
+                "type": "array",
+                "items": {
+                    "type": "array",
+                    "prefixItems": [{ "$ref": "#/$defs/GpuVendor" }, { "$ref": "#/$defs/DetectionDiff" }],
+                    "minItems": 2,
+                    "maxItems": 2
+                }
+            }
+        },
+        "$defs": {
+            "GpuVendor": {
+                "enum": ["Nvidia", "Amd", "Intel", "Unknown"]
+            },
+            "AllocationType": {
+                "enum": [
+                    "Standard", "Uvm", "Managed", "Ipc", "Distributed", "BarMapped",
+                    "HostPinned", "Unknown"
+                ]
+            },
+            "DetectionResult": {
+                "type": "object",
+                "required": [
+                    "pid", "vendor", "allocations", "total_gpu_memory", "timestamp", "stats"
+                ],
+                "properties": {
+                    "pid": unsigned,
+                    "vendor": { "$ref": "#/$defs/GpuVendor" },
+                    "allocations": array_of("GpuAllocation"),
+                    "total_gpu_memory": unsigned,
+                    "timestamp": {
+                        "type": "object",
+                        "required": ["secs_since_epoch", "nanos_since_epoch"],
+                        "properties": {
+                            "secs_since_epoch": unsigned,
+                            "nanos_since_epoch": unsigned
+                        }
+                    },
+                    "stats": { "$ref": "#/$defs/DetectionStats" },
+                    "contexts": array_of("ContextAllocations"),
+                    "gpus": array_of("GpuDeviceInfo"),
+                    "is_mps": { "type": "boolean" },
+                    "uvm_tools_attached": { "type": "boolean" },
+                    "ipc_handles": array_of("IpcHandle"),
+                    "gpu_threads": { "type": "array", "items": unsigned },
+                    "libraries": array_of("GpuLibrary")
+                }
+            },
+            "GpuAllocation": {
+                "type": "object",
+                "required": [
+                    "vaddr_start", "vaddr_end", "size", "alloc_type", "device_id", "fd", "metadata"
+                ],
+                "properties": {
+                    "vaddr_start": unsigned,
+                    "vaddr_end": unsigned,
+                    "size": unsigned,
+                    "alloc_type": { "$ref": "#/$defs/AllocationType" },
+                    "device_id": optional_unsigned,
+                    "fd": { "type": ["integer", "null"] },
+                    "metadata": { "$ref": "#/$defs/AllocationMetadata" }
+                }
+            },
+            "AllocationMetadata": {
+                "type": "object",
+                "required": [
+                    "is_distributed", "numa_node", "backing_file", "protection", "is_shared"
+                ],
+                "properties": {
+                    "is_distributed": { "type": "boolean" },
+                    "numa_node": optional_unsigned,
+                    "backing_file": optional_string,
+                    "protection": { "type": "string" },
+                    "is_shared": { "type": "boolean" },
+                    "file_offset": unsigned,
+                    "inode": unsigned,
+                    "ipc_handle": { "type": "string", "pattern": "^[0-9a-f]*$" },
+                    "bar_index": unsigned,
+                    "rss_bytes": unsigned,
+                    "physical_frames": { "type": "array", "items": unsigned },
+                    "huge_page": { "type": "boolean" }
+                }
+            },
+            "DetectionStats": {
+                "type": "object",
+                "required": [
+                    "standard_allocations", "uvm_allocations", "managed_allocations",
+                    "ipc_allocations", "distributed_allocations", "total_size",
+                    "largest_allocation"
+                ],
+                "properties": {
+                    "standard_allocations": unsigned,
+                    "uvm_allocations": unsigned,
+                    "managed_allocations": unsigned,
+                    "ipc_allocations": unsigned,
+                    "distributed_allocations": unsigned,
+                    "total_size": unsigned,
+                    "largest_allocation": unsigned
+                }
+            },
+            "ContextAllocations": {
+                "type": "object",
+                "required": ["device_id", "allocation_indices", "total_size"],
+                "properties": {
+                    "device_id": optional_unsigned,
+                    "allocation_indices": { "type": "array", "items": unsigned },
+                    "total_size": unsigned
+                }
+            },
+            "GpuDeviceInfo": {
+                "type": "object",
+                "required": ["device_id", "model", "architecture", "compute_capability"],
+                "properties": {
+                    "device_id": optional_unsigned,
+                    "model": { "type": "string" },
+                    "architecture": optional_string,
+                    "compute_capability": optional_string
+                }
+            },
+            "IpcHandle": {
+                "type": "object",
+                "required": ["path", "handle"],
+                "properties": {
+                    "path": { "type": "string" },
+                    "handle": { "type": "string" }
+                }
+            },
+            "GpuLibrary": {
+                "type": "object",
+                "required": ["name", "path", "version"],
+                "properties": {
+                    "name": { "type": "string" },
+                    "path": { "type": "string" },
+                    "version": optional_string
+                }
+            },
+            "DetectionDiff": {
+                "type": "object",
+                "required": ["added", "removed", "resized"],
+                "properties": {
+                    "added": array_of("GpuAllocation"),
+                    "removed": array_of("GpuAllocation"),
+                    "resized": {
+                        "description": "(older, newer) pairs of allocations starting at the same address",
+                        "type": "array",
+                        "items": {
+                            "type": "array",
+                            "items": { "$ref": "#/$defs/GpuAllocation" },
+                            "minItems": 2,
+                            "maxItems": 2
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Detections of processes checkpointed together, e.g. the ranks of a
+/// distributed training job
+#[derive(Debug, Clone, Default)]
+pub struct ProcessGroup {
+    pub members: Vec<DetectionResult>,
+}
+
+impl ProcessGroup {
+    pub fn new(members: Vec<DetectionResult>) -> Self {
+        Self { members }
+    }
+
+    pub fn pids(&self) -> Vec<u32> {
+        self.members.iter().map(|member| member.pid).collect()
+    }
+
+    /// Allocations mapping the same shared memory (backing inode, offset
+    /// and size) as an allocation of an earlier member. Maps the PID and
+    /// start address of each to those of the allocation first seen.
+    pub fn shared_allocations(&self) -> HashMap<(u32, u64), (u32, u64)> {
+        let mut first_seen: HashMap<(u64, u64, u64), (u32, u64)> = HashMap::new();
+        let mut shared = HashMap::new();
+        for member in &self.members {
+            for alloc in &member.allocations {
+                let Some(inode) = alloc.metadata.inode.filter(|_| alloc.metadata.is_shared) else {
+                    continue;
+                };
+                let key = (inode, alloc.metadata.file_offset, alloc.size);
+                let this = (member.pid, alloc.vaddr_start);
+                match first_seen.get(&key) {
+                    Some(&owner) if owner.0 != member.pid => {
+                        shared.insert(this, owner);
+                    }
+                    Some(_) => {}
+                    None => {
+                        first_seen.insert(key, this);
+                    }
+                }
+            }
+        }
+        shared
+    }
+
+    /// GPU memory of all members, shared memory counted once
+    pub fn total_gpu_memory(&self) -> u64 {
+        let shared = self.shared_allocations();
+        self.members
+            .iter()
+            .flat_map(|member| {
+                member
+                    .allocations
+                    .iter()
+                    .filter(|alloc| !shared.contains_key(&(member.pid, alloc.vaddr_start)))
+            })
+            .map(|alloc| alloc.size)
+            .sum()
+    }
+}
+
+impl DetectionDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.resized.is_empty()
+    }
+}
+
+impl DetectionResult {
+    /// Check that the totals, statistics and contexts agree with the
+    /// allocations and that every allocation range is well-formed, not
+    /// empty and disjoint from the others. Catches detector bugs and
+    /// corrupted saved detections; the error lists every inconsistency
+    /// found.
+    pub fn validate(&self) -> crate::Result<()> {
+        let mut problems = Vec::new();
+
+        for alloc in &self.allocations {
+            if alloc.vaddr_end < alloc.vaddr_start {
+                problems.push(format!(
+                    "allocation 0x{:016x} ends before it starts (0x{:016x})",
+                    alloc.vaddr_start, alloc.vaddr_end
+                ));
+            } else if alloc.vaddr_end == alloc.vaddr_start {
+                problems.push(format!("allocation 0x{:016x} is empty", alloc.vaddr_start));
+            } else if alloc.size != alloc.vaddr_end - alloc.vaddr_start {
+                problems.push(format!(
+                    "allocation 0x{:016x} has size {} but spans {} bytes",
+                    alloc.vaddr_start,
+                    alloc.size,
+                    alloc.vaddr_end - alloc.vaddr_start
+                ));
+            }
+        }
+
+        let mut ranges: Vec<(u64, u64)> = self
+            .allocations
+            .iter()
+            .map(|alloc| (alloc.vaddr_start, alloc.vaddr_end))
+            .collect();
+        ranges.sort_unstable();
+        for pair in ranges.windows(2) {
+            if pair[0] == pair[1] {
+                problems.push(format!(
+                    "allocation 0x{:016x}-0x{:016x} is listed more than once",
+                    pair[0].0, pair[0].1
+                ));
+            } else if pair[1].0 < pair[0].1 {
+                problems.push(format!(
+                    "allocations 0x{:016x}-0x{:016x} and 0x{:016x}-0x{:016x} overlap",
+                    pair[0].0, pair[0].1, pair[1].0, pair[1].1
+                ));
+            }
+        }
+
+        let total: u64 = self.allocations.iter().map(|alloc| alloc.size).sum();
+        if self.total_gpu_memory != total {
+            problems.push(format!(
+                "total_gpu_memory is {} but allocations add up to {}",
+                self.total_gpu_memory, total
+            ));
+        }
+        if self.stats.total_size != total {
+            problems.push(format!(
+                "stats.total_size is {} but allocations add up to {}",
+                self.stats.total_size, total
+            ));
+        }
+        let largest = self.allocations.iter().map(|alloc| alloc.size).max();
+        if self.stats.largest_allocation != largest.unwrap_or(0) {
+            problems.push(format!(
+                "stats.largest_allocation is {} but the largest allocation is {}",
+                self.stats.largest_allocation,
+                largest.unwrap_or(0)
+            ));
+        }
+
+        let count = |alloc_type| {
+            self.allocations
+                .iter()
+                .filter(|alloc| alloc.alloc_type == alloc_type)
+                .count()
+        };
+        for (name, recorded, alloc_type) in [
+            (
+                "standard_allocations",
+                self.stats.standard_allocations,
+                AllocationType::Standard,
+            ),
+            (
+                "uvm_allocations",
+                self.stats.uvm_allocations,
+                AllocationType::Uvm,
+            ),
+            (
+                "managed_allocations",
+                self.stats.managed_allocations,
+                AllocationType::Managed,
+            ),
+            (
+                "ipc_allocations",
+                self.stats.ipc_allocations,
+                AllocationType::Ipc,
+            ),
+            (
+                "distributed_allocations",
+                self.stats.distributed_allocations,
+                AllocationType::Distributed,
+            ),
+        ] {
+            let actual = count(alloc_type);
+            if recorded != actual {
+                problems.push(format!(
+                    "stats.{name} is {recorded} but there are {actual} {alloc_type:?} allocations"
+                ));
+            }
+        }
+
+        for (i, context) in self.contexts.iter().enumerate() {
+            if let Some(&index) = context
+                .allocation_indices
+                .iter()
+                .find(|&&index| index >= self.allocations.len())
+            {
+                problems.push(format!(
+                    "context {i} refers to allocation {index} of {}",
+                    self.allocations.len()
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(GpuCheckpointError::DetectionError(format!(
+                "inconsistent detection result for PID {}: {}",
+                self.pid,
+                problems.join("; ")
+            )))
+        }
+    }
+
+    /// Compare against an `earlier` detection. Allocations are matched by
+    /// their start address.
+    pub fn diff(&self, earlier: &DetectionResult) -> DetectionDiff {
+        let mut diff = DetectionDiff::default();
+
+        for alloc in &self.allocations {
+            match earlier
+                .allocations
+                .iter()
+                .find(|old| old.vaddr_start == alloc.vaddr_start)
+            {
+                None => diff.added.push(alloc.clone()),
+                Some(old) if old.size != alloc.size => {
+                    diff.resized.push((old.clone(), alloc.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+
+        diff.removed = earlier
+            .allocations
+            .iter()
+            .filter(|old| {
+                !self
+                    .allocations
+                    .iter()
+                    .any(|alloc| alloc.vaddr_start == old.vaddr_start)
+            })
+            .cloned()
+            .collect();
+
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detection_schema() {
+        let schema = detection_schema();
+        let parsed: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string_pretty(&schema).unwrap()).unwrap();
+        assert_eq!(parsed, schema);
+
+        let alloc_types = schema["$defs"]["AllocationType"]["enum"]
+            .as_array()
+            .unwrap();
+        for alloc_type in [
+            AllocationType::Standard,
+            AllocationType::Uvm,
+            AllocationType::Managed,
+            AllocationType::Ipc,
+            AllocationType::Distributed,
+            AllocationType::BarMapped,
+            AllocationType::HostPinned,
+            AllocationType::Unknown,
+        ] {
+            assert!(alloc_types.contains(&serde_json::to_value(alloc_type).unwrap()));
+        }
+
+        // Every field the types serialize is described
+        let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+        let mut alloc = GpuAllocation::new(0x1000, 0x2000, AllocationType::Ipc);
+        alloc.metadata.ipc_handle = Some("00ff".to_string());
+        alloc.metadata.bar_index = Some(1);
+        alloc.metadata.rss_bytes = Some(4096);
+        result.add_allocation(alloc);
+        result.libraries.push(GpuLibrary {
+            name: "libcudart".to_string(),
+            path: "/usr/lib/libcudart.so.12".to_string(),
+            version: Some("12".to_string()),
+        });
+        let json = serde_json::to_value(DetectionReport::new(vec![result])).unwrap();
+        let described = |def: &str, value: &serde_json::Value| {
+            let properties = &schema["$defs"][def]["properties"];
+            for key in value.as_object().unwrap().keys() {
+                assert!(
+                    properties.get(key).is_some(),
+                    "{def}.{key} is not described"
+                );
+            }
+        };
+        for key in json.as_object().unwrap().keys() {
+            assert!(schema["properties"].get(key).is_some());
+        }
+        let result = &json["results"][0];
+        described("DetectionResult", result);
+        described("GpuAllocation", &result["allocations"][0]);
+        described("AllocationMetadata", &result["allocations"][0]["metadata"]);
+        described("DetectionStats", &result["stats"]);
+        described("GpuLibrary", &result["libraries"][0]);
+    }
+
+    #[test]
+    fn test_detection_report_versioning() {
+        let result = DetectionResult::new(1234, GpuVendor::Nvidia);
+        let json = serde_json::to_value(DetectionReport::new(vec![result.clone()])).unwrap();
+        assert_eq!(json["schema_version"], DETECTION_SCHEMA_VERSION);
+        assert_eq!(json["results"][0]["pid"], 1234);
+        assert!(json.get("changes").is_none());
+
+        let report = DetectionReport::from_json(&json.to_string()).unwrap();
+        assert_eq!(report.schema_version, DETECTION_SCHEMA_VERSION);
+        assert_eq!(report.results.len(), 1);
+
+        // Output written before versioning
+        let legacy = serde_json::to_string(&vec![result.clone()]).unwrap();
+        let report = DetectionReport::from_json(&legacy).unwrap();
+        assert_eq!(report.schema_version, 0);
+        assert_eq!(report.results[0].pid, 1234);
+
+        let single = serde_json::to_string(&result).unwrap();
+        assert_eq!(
+            DetectionReport::from_json(&single).unwrap().results.len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+        result.add_allocation(GpuAllocation::new(0x1000, 0x3000, AllocationType::Uvm));
+        result.add_allocation(GpuAllocation::new(0x8000, 0x9000, AllocationType::Ipc));
+        result.group_contexts(&[]);
+        result.validate().unwrap();
+
+        // Survives a round trip through JSON
+        let json = serde_json::to_string(&result).unwrap();
+        serde_json::from_str::<DetectionResult>(&json)
+            .unwrap()
+            .validate()
+            .unwrap();
+
+        let mut corrupted = result.clone();
+        corrupted.total_gpu_memory += 1;
+        corrupted.stats.uvm_allocations = 0;
+        corrupted.allocations.push(corrupted.allocations[1].clone());
+        corrupted.allocations[0].vaddr_end = 0;
+        let err = corrupted.validate().unwrap_err().to_string();
+        assert!(err.contains("ends before it starts"), "{err}");
+        assert!(err.contains("is listed more than once"), "{err}");
+        assert!(err.contains("total_gpu_memory is"), "{err}");
+        assert!(
+            err.contains("stats.uvm_allocations is 0 but there are 1"),
+            "{err}"
+        );
+        assert!(
+            err.contains("stats.ipc_allocations is 1 but there are 2"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_filter_by_size_and_type() {
+        let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+        result.add_allocation(GpuAllocation::new(0x1000, 0x2000, AllocationType::Uvm));
+        result.add_allocation(GpuAllocation::new(0x3000, 0x103000, AllocationType::Uvm));
+        result.add_allocation(GpuAllocation::new(0x200000, 0x400000, AllocationType::Ipc));
+        result.add_allocation(GpuAllocation::new(
+            0x1000_0000_0000,
+            0x1000_0000_1000,
+            AllocationType::Standard,
+        ));
+        result.group_contexts(&[]);
+        assert_eq!(result.contexts.len(), 2);
+
+        let by_size = AllocationFilter {
+            min_size: 0x100000,
+            types: None,
+        };
+        let filtered = result.filtered(|a| by_size.matches(a));
+        let starts: Vec<u64> = filtered.allocations.iter().map(|a| a.vaddr_start).collect();
+        assert_eq!(starts, vec![0x3000, 0x200000]);
+        assert_eq!(filtered.total_gpu_memory, 0x300000);
+        assert_eq!(filtered.stats.uvm_allocations, 1);
+        assert_eq!(filtered.stats.ipc_allocations, 1);
+        assert_eq!(filtered.stats.standard_allocations, 0);
+        // The context of the small standard allocation is gone
+        assert_eq!(filtered.contexts.len(), 1);
+        assert_eq!(filtered.contexts[0].allocation_indices, vec![0, 1]);
+        assert_eq!(filtered.contexts[0].total_size, 0x300000);
+        filtered.validate().unwrap();
+
+        let by_type = AllocationFilter {
+            min_size: 0x100000,
+            types: Some(vec![AllocationType::Ipc, AllocationType::Standard]),
+        };
+        let filtered = result.filtered(|a| by_type.matches(a));
+        assert_eq!(filtered.allocations.len(), 1);
+        assert_eq!(filtered.allocations[0].alloc_type, AllocationType::Ipc);
+        filtered.validate().unwrap();
+    }
+
+    #[test]
+    fn test_recompute_stats() {
+        let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+        result.add_allocation(GpuAllocation::new(0x1000, 0x9000, AllocationType::Standard));
+        result.add_allocation(GpuAllocation::new(0x10000, 0x12000, AllocationType::Uvm));
+        result.add_allocation(GpuAllocation::new(0x20000, 0x21000, AllocationType::Ipc));
+        result.add_allocation(GpuAllocation::new(
+            0x30000,
+            0x34000,
+            AllocationType::Managed,
+        ));
+
+        // Drop the largest, reclassify one and shrink another in place
+        result.allocations.remove(0);
+        result.allocations[0].alloc_type = AllocationType::Distributed;
+        result.allocations[2].vaddr_end = 0x31000;
+        result.allocations[2].size = 0x1000;
+        assert!(result.validate().is_err());
+
+        result.recompute_stats();
+        assert_eq!(result.total_gpu_memory, 0x4000);
+        assert_eq!(result.stats.total_size, 0x4000);
+        assert_eq!(result.stats.largest_allocation, 0x2000);
+        assert_eq!(result.stats.standard_allocations, 0);
+        assert_eq!(result.stats.uvm_allocations, 0);
+        assert_eq!(result.stats.managed_allocations, 1);
+        assert_eq!(result.stats.ipc_allocations, 1);
+        assert_eq!(result.stats.distributed_allocations, 1);
+        result.validate().unwrap();
+
+        result.allocations.clear();
+        result.recompute_stats();
+        assert_eq!(result.total_gpu_memory, 0);
+        assert_eq!(result.stats.largest_allocation, 0);
+        assert_eq!(result.stats.ipc_allocations, 0);
+    }
+
+    #[test]
+    fn test_validate_ranges() {
+        // An inverted range does not underflow
+        let inverted = GpuAllocation::new(0x3000, 0x1000, AllocationType::Standard);
+        assert_eq!(inverted.size, 0);
+
+        let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+        result.add_allocation(inverted);
+        let err = result.validate().unwrap_err().to_string();
+        assert!(err.contains("ends before it starts"), "{err}");
+
+        let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+        result.add_allocation(GpuAllocation::new(0x1000, 0x1000, AllocationType::Uvm));
+        let err = result.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("allocation 0x0000000000001000 is empty"),
+            "{err}"
+        );
+
+        let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+        result.add_allocation(GpuAllocation::new(0x4000, 0x8000, AllocationType::Uvm));
+        result.add_allocation(GpuAllocation::new(
+            0x1000,
+            0x5000,
+            AllocationType::HostPinned,
+        ));
+        let err = result.validate().unwrap_err().to_string();
+        assert!(
+            err.contains(
+                "allocations 0x0000000000001000-0x0000000000005000 and \
+                 0x0000000000004000-0x0000000000008000 overlap"
+            ),
+            "{err}"
+        );
+
+        // Adjacent ranges are fine
+        let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+        result.add_allocation(GpuAllocation::new(0x1000, 0x4000, AllocationType::Uvm));
+        result.add_allocation(GpuAllocation::new(0x4000, 0x8000, AllocationType::Uvm));
+        result.validate().unwrap();
+    }
+
+    #[test]
+    fn test_coalesce_allocations() {
+        let region = |start: u64, end: u64, alloc_type, protection: &str| {
+            let mut allocation = GpuAllocation::new(start, end, alloc_type);
+            allocation.metadata.protection = protection.to_string();
+            allocation.metadata.rss_bytes = Some(end - start);
+            allocation
+        };
+
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        for (start, end) in [(0x1000, 0x2000), (0x2000, 0x4000), (0x4000, 0x5000)] {
+            detection.add_allocation(region(start, end, AllocationType::Uvm, "rw-s"));
+        }
+        detection.contexts = vec![ContextAllocations {
+            device_id: None,
+            allocation_indices: vec![0, 1, 2],
+            total_size: 0x4000,
+        }];
+        assert_eq!(detection.coalesce_allocations(), 2);
+        assert_eq!(detection.allocations.len(), 1);
+        let merged = &detection.allocations[0];
+        assert_eq!((merged.vaddr_start, merged.vaddr_end), (0x1000, 0x5000));
+        assert_eq!(merged.size, 0x4000);
+        assert_eq!(merged.metadata.rss_bytes, Some(0x4000));
+        assert_eq!(detection.contexts[0].allocation_indices, vec![0]);
+        assert_eq!(detection.total_gpu_memory, 0x4000);
+        assert_eq!(detection.stats.uvm_allocations, 1);
+
+        // Differing types, protection or a gap keep allocations apart
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(region(0x1000, 0x2000, AllocationType::Uvm, "rw-s"));
+        detection.add_allocation(region(0x2000, 0x3000, AllocationType::Managed, "rw-s"));
+        detection.add_allocation(region(0x3000, 0x4000, AllocationType::Managed, "r--s"));
+        detection.add_allocation(region(0x5000, 0x6000, AllocationType::Managed, "r--s"));
+        assert_eq!(detection.coalesce_allocations(), 0);
+        assert_eq!(detection.allocations.len(), 4);
+
+        // Adjacent in memory but not in the list still merge
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(region(0x2000, 0x3000, AllocationType::Standard, "rw-s"));
+        detection.add_allocation(region(0x1000, 0x2000, AllocationType::Standard, "rw-s"));
+        assert_eq!(detection.coalesce_allocations(), 1);
+        assert_eq!(detection.allocations[0].vaddr_start, 0x1000);
+    }
+
+    #[test]
+    fn test_page_accounting() {
+        let aligned = GpuAllocation::new(0x2000, 0x5000, AllocationType::Standard);
+        assert_eq!(aligned.aligned_start(0x1000), 0x2000);
+        assert_eq!(aligned.aligned_end(0x1000), 0x5000);
+        assert_eq!(aligned.page_aligned_size(0x1000), 0x3000);
+        assert_eq!(aligned.page_count(0x1000), 3);
+
+        // Straddles page boundaries on both ends
+        let unaligned = GpuAllocation::new(0x2100, 0x4001, AllocationType::Standard);
+        assert_eq!(unaligned.aligned_start(0x1000), 0x2000);
+        assert_eq!(unaligned.aligned_end(0x1000), 0x5000);
+        assert_eq!(unaligned.page_aligned_size(0x1000), 0x3000);
+        assert_eq!(unaligned.page_count(0x1000), 3);
+
+        // Within a single page
+        let small = GpuAllocation::new(0x2010, 0x2020, AllocationType::Standard);
+        assert_eq!(small.page_count(0x1000), 1);
+
+        let empty = GpuAllocation::new(0x3000, 0x3000, AllocationType::Standard);
+        assert_eq!(empty.page_count(0x1000), 0);
+    }
+
+    #[test]
+    fn test_total_problematic_memory() {
+        let mut result = DetectionResult::new(1, GpuVendor::Nvidia);
+        assert_eq!(result.total_problematic_memory(), 0);
+
+        result.add_allocation(GpuAllocation::new(0x1000, 0x3000, AllocationType::Standard));
+        result.add_allocation(GpuAllocation::new(0x10000, 0x14000, AllocationType::Uvm));
+        result.add_allocation(GpuAllocation::new(0x20000, 0x21000, AllocationType::Ipc));
+
+        assert_eq!(result.total_problematic_memory(), 0x5000);
+        assert_eq!(result.total_gpu_memory, 0x7000);
+    }
+
+    #[test]
+    fn test_diff_detections() {
+        let mut earlier = DetectionResult::new(1234, GpuVendor::Nvidia);
+        earlier.add_allocation(GpuAllocation::new(0x1000, 0x2000, AllocationType::Uvm));
+        earlier.add_allocation(GpuAllocation::new(0x3000, 0x4000, AllocationType::Uvm));
+        earlier.add_allocation(GpuAllocation::new(0x5000, 0x6000, AllocationType::Ipc));
+
+        let mut current = DetectionResult::new(1234, GpuVendor::Nvidia);
+        current.add_allocation(GpuAllocation::new(0x1000, 0x2000, AllocationType::Uvm));
+        current.add_allocation(GpuAllocation::new(0x3000, 0x5000, AllocationType::Uvm));
+        current.add_allocation(GpuAllocation::new(0x8000, 0x9000, AllocationType::Standard));
+
+        let diff = current.diff(&earlier);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].vaddr_start, 0x8000);
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].vaddr_start, 0x5000);
+        assert_eq!(diff.resized.len(), 1);
+        assert_eq!(diff.resized[0].0.size, 0x1000);
+        assert_eq!(diff.resized[0].1.size, 0x2000);
+
+        assert!(current.diff(&current).is_empty());
+    }
+
+    #[test]
+    fn test_group_contexts_by_address_gap() {
+        let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+        result.add_allocation(GpuAllocation::new(
+            0x2000_0000,
+            0x3000_0000,
+            AllocationType::Uvm,
+        ));
+        result.add_allocation(GpuAllocation::new(
+            0x10_0000_0000,
+            0x10_1000_0000,
+            AllocationType::Uvm,
+        ));
+        result.add_allocation(GpuAllocation::new(
+            0x1000_0000,
+            0x2000_0000,
+            AllocationType::Uvm,
+        ));
+
+        result.group_contexts(&[0]);
+
+        assert_eq!(result.contexts.len(), 2);
+        assert_eq!(result.contexts[0].allocation_indices, vec![2, 0]);
+        assert_eq!(result.contexts[0].total_size, 0x2000_0000);
+        assert_eq!(result.contexts[0].device_id, Some(0));
+        assert_eq!(result.contexts[1].allocation_indices, vec![1]);
+    }
+
+    #[test]
+    fn test_group_contexts_by_device() {
+        let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+        let mut first = GpuAllocation::new(0x1000_0000, 0x2000_0000, AllocationType::Standard);
+        first.device_id = Some(0);
+        let mut second = GpuAllocation::new(0x2000_0000, 0x3000_0000, AllocationType::Standard);
+        second.device_id = Some(1);
+        let unattributed = GpuAllocation::new(0x3000_0000, 0x4000_0000, AllocationType::Uvm);
+        result.add_allocation(first);
+        result.add_allocation(second);
+        result.add_allocation(unattributed);
+
+        result.group_contexts(&[0, 1]);
+
+        assert_eq!(result.contexts.len(), 2);
+        assert_eq!(result.contexts[0].device_id, Some(0));
+        assert_eq!(result.contexts[1].device_id, Some(1));
+        assert_eq!(result.contexts[1].allocation_indices, vec![1, 2]);
+    }
 }
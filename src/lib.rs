@@ -1,6 +1,7 @@
 pub mod checkpoint;
 pub mod detector;
 pub mod restore;
+pub mod storage;
 pub mod utils;
 
 pub use checkpoint::{CheckpointEngine, CheckpointMetadata, CheckpointStrategy};
@@ -29,6 +30,10 @@ pub enum GpuCheckpointError {
     #[error("Permission denied")]
     PermissionDenied,
 
+    /// Access to another process's memory is blocked, with what to change
+    #[error("Permission denied: {0}")]
+    PtraceDenied(String),
+
     #[error("GPU device error: {0}")]
     GpuDeviceError(String),
 
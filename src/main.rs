@@ -1,12 +1,29 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use comfy_table::{presets::UTF8_FULL, CellAlignment, ContentArrangement, Table};
+use gpu_checkpoint::GpuCheckpointError;
 use gpu_checkpoint::{
-    checkpoint::{CheckpointConfig, CheckpointEngine, CheckpointStrategy},
-    detector::CompositeDetector,
-    utils,
+    checkpoint::{
+        bar_sliding::BarSlidingCheckpoint, checkpoint_schema, group, signing, CheckpointConfig,
+        CheckpointEngine, CheckpointFileFormat, CheckpointMetadata, CheckpointStrategy,
+        CudaCheckpointTool, CudaToggle, FreezeMethod, NameTemplate, DEFAULT_STORAGE_PATH,
+        STDOUT_STORAGE,
+    },
+    detector::{
+        detection_schema, AllocationFilter, AllocationType, CompositeDetector, DetectionDiff,
+        DetectionReport, DetectionResult, GpuVendor, MemoryMapParser, NvidiaDetector, ProcessGroup,
+        ProcessScanner,
+    },
+    restore::{http, restore_schema, RestoreFilter, RestoreMetadata, RestoreMode},
+    storage::{self, S3Config, S3Location, S3Source},
+    utils::{self, audit::OperationRecord, encryption::EncryptionKey},
 };
-use std::time::Duration;
-use tracing::{error, info, warn};
-use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use tracing_subscriber::fmt::{format::FmtSpan, writer::BoxMakeWriter};
+use tracing_subscriber::EnvFilter;
 
 #[derive(Parser)]
 #[command(name = "gpu-checkpoint")]
@@ -18,50 +35,434 @@ struct Cli {
     /// Enable verbose logging
     #[arg(short, long, global = true)]
     verbose: bool,
+
+    /// Append a JSON audit record for each operation to this file
+    #[arg(long, global = true, value_name = "PATH")]
+    append_log: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct DetectArgs {
+    /// Process ID to analyze
+    #[arg(short, long)]
+    pid: u32,
+
+    /// Output format (json, human, table)
+    #[arg(short, long, default_value = "human")]
+    format: String,
+
+    /// Report allocation changes since a saved detection (`detect --format json` output)
+    #[arg(long, value_name = "DETECTION_JSON")]
+    since: Option<PathBuf>,
+
+    /// Compare the detected NVIDIA GPU memory with what nvidia-smi reports
+    #[arg(long)]
+    compare_nvidia_smi: bool,
+
+    /// Explain why the recommended strategy was selected
+    #[arg(long)]
+    explain: bool,
+
+    /// Fail if any GPU detector fails instead of skipping it
+    #[arg(long)]
+    strict: bool,
+
+    /// Report the CUDA/ROCm and driver libraries the process has loaded
+    #[arg(long)]
+    resolve_libs: bool,
+
+    /// Only show allocations of at least this size (e.g. 2MiB); the summary
+    /// covers the allocations shown
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Only show allocations of these types (e.g. uvm,ipc)
+    #[arg(long = "type", value_name = "TYPES", value_delimiter = ',')]
+    types: Option<Vec<AllocationType>>,
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    utils::parse_memory(s).map_err(|e| e.to_string())
+}
+
+#[derive(Args)]
+struct CheckpointArgs {
+    /// Process ID to checkpoint; repeat it to checkpoint several processes
+    /// into one archive
+    #[arg(short, long, required_unless_present = "pgid")]
+    pid: Vec<u32>,
+
+    /// Checkpoint every process in this process group into one archive
+    #[arg(long, conflicts_with = "pid")]
+    pgid: Option<u32>,
+
+    /// Storage path for checkpoint data, - to stream the checkpoint to stdout,
+    /// or s3://bucket/prefix to upload it to an S3-compatible object store
+    #[arg(short, long, default_value = DEFAULT_STORAGE_PATH)]
+    storage: String,
+
+    /// Force specific strategy (auto, cuda, bar-sliding, hybrid, skip-gpu)
+    #[arg(long, default_value = "auto")]
+    strategy: CheckpointStrategy,
+
+    /// Storage bandwidth in MB/s
+    #[arg(long, default_value = "1000")]
+    bandwidth: u64,
+
+    /// Limit checkpoint writes to --bandwidth so shared storage is not
+    /// saturated (--bandwidth 0 leaves them unthrottled)
+    #[arg(long)]
+    throttle: bool,
+
+    /// Experimental: freeze only while forking a copy-on-write snapshot, then copy from it
+    #[arg(long)]
+    cow_snapshot: bool,
+
+    /// Only copy resident pages of each allocation, restoring the rest as holes
+    #[arg(long)]
+    limit_rss: bool,
+
+    /// Write never-touched pages as file holes so the checkpoint is sparse on disk
+    #[arg(long)]
+    sparse: bool,
+
+    /// Keep the process running while its memory is copied; the checkpoint
+    /// may be torn by writes during the copy
+    #[arg(long)]
+    no_freeze: bool,
+
+    /// How to pause the process (signal, ptrace, cgroup-freezer)
+    #[arg(long, default_value = "signal")]
+    freeze_method: FreezeMethod,
+
+    /// Checkpoint file format (bin, tar)
+    #[arg(long, default_value = "bin")]
+    format: CheckpointFileFormat,
+
+    /// Checkpoint file name with {pid}, {timestamp}, {hostname} and {strategy}
+    /// placeholders (default: checkpoint_{pid}.<format>)
+    #[arg(long, value_name = "TEMPLATE")]
+    name_template: Option<NameTemplate>,
+
+    /// Checkpoint file to write, relative to --storage (default: derived
+    /// from the PID or --name-template); existing files are not replaced
+    #[arg(long, value_name = "FILE", conflicts_with = "name_template")]
+    output: Option<PathBuf>,
+
+    /// Overwrite the --output file if it already exists
+    #[arg(long, requires = "output")]
+    force: bool,
+
+    /// Sign the checkpoint with this Ed25519 secret key (32 raw bytes or hex)
+    #[arg(long, value_name = "KEY_FILE")]
+    sign_key: Option<PathBuf>,
+
+    /// Encrypt allocation data with this AES-256 key (32 raw bytes or hex);
+    /// defaults to the hex key in GPU_CHECKPOINT_KEY, if set
+    #[arg(long, value_name = "KEY_FILE")]
+    key_file: Option<PathBuf>,
+
+    /// Record GPU-related environment variables (CUDA_*, NCCL_*, ...) in the metadata
+    #[arg(long)]
+    capture_env: bool,
+
+    /// Explain why the strategy was selected
+    #[arg(long)]
+    explain: bool,
+
+    /// Print the estimated size and duration at --bandwidth and exit
+    /// without checkpointing
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Bytes copied per window (e.g. 512MiB, 64MB), at most the GPU's BAR size
+    #[arg(long, value_name = "SIZE", value_parser = parse_window_size, conflicts_with = "auto_window")]
+    window_size: Option<usize>,
+
+    /// Benchmark the storage path first and copy with the fastest window size
+    #[arg(long)]
+    auto_window: bool,
+
+    /// Fault managed (cudaMallocManaged) allocations over to the host before copying
+    #[arg(long)]
+    managed_prefetch: bool,
+
+    /// Fail if GPU mappings change during the copy instead of flagging the checkpoint
+    #[arg(long)]
+    abort_on_change: bool,
+
+    /// Compress allocation data with deflate, window by window
+    #[arg(long)]
+    compress: bool,
+
+    /// Deflate level for --compress, from 0 (fastest) to 9 (smallest)
+    #[arg(long, default_value_t = gpu_checkpoint::utils::compression::DEFAULT_COMPRESSION_LEVEL, requires = "compress")]
+    compression_level: u32,
+
+    /// Compress up to N windows of each allocation concurrently with --compress
+    #[arg(long, value_name = "N", default_value_t = 1, requires = "compress")]
+    compression_threads: usize,
+
+    /// Checkpoint up to N allocations concurrently
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    parallelism: usize,
+
+    /// Store identical windows once, e.g. zero-filled regions; allocations
+    /// are then checkpointed one at a time
+    #[arg(long)]
+    dedup: bool,
+
+    /// Merge contiguous allocations of the same type and protection into
+    /// one record, for processes with many small adjacent mappings
+    #[arg(long)]
+    coalesce: bool,
+
+    /// Only capture the windows written since this checkpoint (file or .json manifest)
+    #[arg(long, value_name = "BASE")]
+    incremental_base: Option<PathBuf>,
+
+    /// Reset soft-dirty tracking so a later --incremental-base checkpoint against
+    /// this one only captures what changed after it
+    #[arg(long)]
+    track_dirty: bool,
+
+    /// Read process memory with process_vm_readv instead of /proc/PID/mem
+    /// where possible
+    #[arg(long)]
+    process_vm: bool,
+
+    /// Fail if any GPU detector fails instead of skipping it
+    #[arg(long)]
+    strict: bool,
+
+    /// Override the auto-selected strategy for a vendor (e.g. amd=bar-sliding)
+    #[arg(long = "vendor-strategy", value_name = "VENDOR=STRATEGY", value_parser = parse_vendor_strategy)]
+    vendor_strategies: Vec<(GpuVendor, CheckpointStrategy)>,
+}
+
+fn parse_vendor_strategy(s: &str) -> Result<(GpuVendor, CheckpointStrategy), String> {
+    let (vendor, strategy) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected VENDOR=STRATEGY, got {s:?}"))?;
+    Ok((
+        vendor.parse()?,
+        strategy
+            .parse()
+            .map_err(|e: GpuCheckpointError| e.to_string())?,
+    ))
+}
+
+#[derive(Args)]
+struct RestoreArgs {
+    /// Checkpoint file, its .json manifest, an http:// URL to stream it from,
+    /// an s3://bucket/key object to download it from, or - to read it from
+    /// stdin
+    #[arg(short, long)]
+    metadata: String,
+
+    /// Storage path for checkpoint data
+    #[arg(short, long, default_value = DEFAULT_STORAGE_PATH)]
+    storage: String,
+
+    /// Reject the checkpoint unless it is signed by this Ed25519 public key
+    #[arg(long, value_name = "PUBKEY_FILE")]
+    verify_key: Option<PathBuf>,
+
+    /// Decrypt an encrypted checkpoint with this AES-256 key (32 raw bytes
+    /// or hex); defaults to the hex key in GPU_CHECKPOINT_KEY, if set
+    #[arg(long, value_name = "KEY_FILE")]
+    key_file: Option<PathBuf>,
+
+    /// Restore into this process instead of the checkpointed PID, translating
+    /// its file descriptors
+    #[arg(short, long)]
+    pid: Option<u32>,
+
+    /// Start the checkpointed command again and restore into the new process
+    #[arg(long, conflicts_with_all = ["pid", "group_pids"])]
+    spawn: bool,
+
+    /// Seconds to wait for a spawned process to map its GPU context before
+    /// restoring into it anyway
+    #[arg(long, value_name = "SECS", default_value_t = 30, requires = "spawn")]
+    spawn_timeout: u64,
+
+    /// Resume the target process after restore (default)
+    #[arg(long, overrides_with = "no_resume_process")]
+    resume_process: bool,
+
+    /// Leave the target process stopped after restore
+    #[arg(long, overrides_with = "resume_process")]
+    no_resume_process: bool,
+
+    /// How to pause the target process (signal, ptrace, cgroup-freezer)
+    #[arg(long, default_value = "signal")]
+    freeze_method: FreezeMethod,
+
+    /// Retry failed writes into the target's memory this many times
+    #[arg(long, default_value_t = gpu_checkpoint::restore::bar_restore::DEFAULT_MAX_RETRIES)]
+    max_retries: u32,
+
+    /// Only restore allocations of these types (e.g. standard,uvm)
+    #[arg(long, value_name = "TYPES", value_delimiter = ',')]
+    only_type: Option<Vec<AllocationType>>,
+
+    /// Make allocations that were read-only at checkpoint time read-only again
+    #[arg(long)]
+    write_protect_after: bool,
+
+    /// Only restore the allocations containing these addresses
+    #[arg(long, value_name = "ADDRESS", value_delimiter = ',', value_parser = parse_address)]
+    only_address: Vec<u64>,
+
+    /// Restore the allocation that started at ORIGINAL at NEW instead, for
+    /// targets that have it mapped elsewhere (repeatable)
+    #[arg(long = "remap", value_name = "ORIGINAL=NEW", value_parser = parse_remap)]
+    remaps: Vec<(u64, u64)>,
+
+    /// Write into ranges the target already has mapped (existing), or map
+    /// the ranges it lacks first (map-fresh)
+    #[arg(long, default_value = "existing")]
+    restore_mode: RestoreMode,
+
+    /// Write process memory with process_vm_writev instead of /proc/PID/mem
+    /// where possible
+    #[arg(long)]
+    process_vm: bool,
+
+    /// Do not record progress in <checkpoint>.restore-state, which lets an
+    /// interrupted restore resume where it stopped
+    #[arg(long)]
+    no_journal: bool,
+
+    /// Base checkpoint of an incremental checkpoint (default: the one its
+    /// manifest names)
+    #[arg(long, value_name = "BASE")]
+    base: Option<PathBuf>,
+
+    /// Restore the member ORIGINAL of a process group checkpoint into NEW
+    /// (repeatable)
+    #[arg(long = "group-pid", value_name = "ORIGINAL=NEW", value_parser = parse_pid_map)]
+    group_pids: Vec<(u32, u32)>,
+}
+
+fn parse_pid_map(s: &str) -> Result<(u32, u32), String> {
+    let (original, new) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ORIGINAL=NEW, got {s:?}"))?;
+    let parse = |pid: &str| pid.parse().map_err(|e| format!("invalid PID {pid:?}: {e}"));
+    Ok((parse(original)?, parse(new)?))
+}
+
+fn parse_remap(s: &str) -> Result<(u64, u64), String> {
+    let (original, new) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ORIGINAL=NEW, got {s:?}"))?;
+    Ok((parse_address(original)?, parse_address(new)?))
+}
+
+#[derive(Args)]
+struct DumpArgs {
+    /// Process ID to read from
+    #[arg(short, long)]
+    pid: u32,
+
+    /// Any address inside the allocation to dump (hex with 0x, or decimal)
+    #[arg(short, long, value_parser = parse_address)]
+    address: u64,
+
+    /// File to write the raw allocation bytes to
+    #[arg(short, long)]
+    output: PathBuf,
+}
+
+fn parse_window_size(s: &str) -> Result<usize, String> {
+    let size = utils::parse_memory(s).map_err(|e| e.to_string())?;
+    match usize::try_from(size) {
+        Ok(0) => Err("the window size must not be 0".to_string()),
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("window size {s:?} does not fit in memory")),
+    }
+}
+
+fn parse_address(s: &str) -> Result<u64, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("invalid address {s:?}: {e}"))
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// Checkpoint file to verify
+    #[arg(short, long)]
+    metadata: String,
+}
+
+#[derive(Args)]
+struct PruneArgs {
+    /// Directory holding the checkpoints
+    #[arg(short, long, default_value = DEFAULT_STORAGE_PATH)]
+    storage: PathBuf,
+
+    /// Keep only this many of the most recent checkpoints
+    #[arg(long, value_name = "N", required_unless_present = "older_than")]
+    keep_last: Option<usize>,
+
+    /// Delete checkpoints taken longer ago than this (e.g. 12h, 7d)
+    #[arg(long, value_name = "AGE", value_parser = parse_age)]
+    older_than: Option<Duration>,
+}
+
+fn parse_age(s: &str) -> Result<Duration, String> {
+    utils::parse_duration(s).map_err(|e| e.to_string())
+}
+
+#[derive(Args)]
+struct InspectArgs {
+    /// Checkpoint file to inspect
+    path: PathBuf,
+
+    /// Output format (json, human)
+    #[arg(short, long, default_value = "json")]
+    format: String,
+}
+
+#[derive(Args)]
+struct SchemaArgs {
+    /// Output to describe: detection (`detect --format json`), checkpoint
+    /// (the checkpoint manifest) or restore (the restore result)
+    #[arg(default_value = "detection", value_parser = ["detection", "checkpoint", "restore"])]
+    output: String,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Detect GPU allocations in a process
-    Detect {
-        /// Process ID to analyze
-        #[arg(short, long)]
-        pid: u32,
-
-        /// Output format (json, human)
-        #[arg(short, long, default_value = "human")]
-        format: String,
-    },
+    Detect(DetectArgs),
 
     /// Checkpoint a process
-    Checkpoint {
-        /// Process ID to checkpoint
-        #[arg(short, long)]
-        pid: u32,
-
-        /// Storage path for checkpoint data
-        #[arg(short, long, default_value = "/tmp/gpu-checkpoint")]
-        storage: String,
-
-        /// Force specific strategy (auto, cuda, bar-sliding, hybrid)
-        #[arg(long, default_value = "auto")]
-        strategy: String,
-
-        /// Storage bandwidth in MB/s
-        #[arg(long, default_value = "1000")]
-        bandwidth: u64,
-    },
+    Checkpoint(CheckpointArgs),
 
     /// Restore a process from checkpoint
-    Restore {
-        /// Checkpoint metadata file
-        #[arg(short, long)]
-        metadata: String,
-
-        /// Storage path for checkpoint data
-        #[arg(short, long, default_value = "/tmp/gpu-checkpoint")]
-        storage: String,
-    },
+    Restore(RestoreArgs),
+
+    /// Write the raw contents of one GPU allocation to a file
+    Dump(DumpArgs),
+
+    /// Show the structure of a checkpoint file without restoring it
+    Inspect(InspectArgs),
+
+    /// Check that a checkpoint file is well-formed without restoring it
+    Verify(VerifyArgs),
+
+    /// Delete old checkpoints from a storage directory
+    Prune(PruneArgs),
+
+    /// Print the JSON Schema of the detection, checkpoint or restore output
+    #[command(name = "json-schema", alias = "schema")]
+    Schema(SchemaArgs),
 }
 
 #[tokio::main]
@@ -75,161 +476,1107 @@ async fn main() -> anyhow::Result<()> {
         EnvFilter::new("info")
     };
 
+    // A checkpoint streamed to stdout must not be interleaved with logs
+    let log_writer = if cli.command.streams_to_stdout() {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
     tracing_subscriber::fmt()
         .with_env_filter(filter)
         .with_span_events(FmtSpan::CLOSE)
+        .with_writer(log_writer)
         .init();
 
-    match cli.command {
-        Commands::Detect { pid, format } => {
-            info!("Detecting GPU allocations for PID {}", pid);
-
-            let detector = CompositeDetector::new();
-            let results = detector.detect_all(pid)?;
+    let (operation, pid) = cli.command.describe();
+    let started = Instant::now();
+    let outcome = run(cli.command, cli.verbose).await;
 
-            if results.is_empty() {
-                warn!("No GPU allocations detected for PID {}", pid);
-                return Ok(());
+    if let Some(path) = &cli.append_log {
+        let record = match &outcome {
+            Ok(result) => {
+                OperationRecord::success(operation, pid, started.elapsed(), result.clone())
             }
+            Err(e) => OperationRecord::failure(operation, pid, started.elapsed(), format!("{e:#}")),
+        };
+        if let Err(e) = record.append_to(path) {
+            warn!(
+                "Failed to append operation record to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    outcome.map(|_| ())
+}
+
+impl Commands {
+    /// Operation name and target PID recorded in the audit log
+    fn describe(&self) -> (&'static str, Option<u32>) {
+        match self {
+            Commands::Detect(args) => ("detect", Some(args.pid)),
+            Commands::Checkpoint(args) => ("checkpoint", args.pid.first().copied().or(args.pgid)),
+            Commands::Restore(args) => ("restore", args.pid),
+            Commands::Dump(args) => ("dump", Some(args.pid)),
+            Commands::Inspect(_) => ("inspect", None),
+            Commands::Verify(_) => ("verify", None),
+            Commands::Prune(_) => ("prune", None),
+            Commands::Schema(_) => ("json-schema", None),
+        }
+    }
+
+    /// Whether stdout carries checkpoint data rather than messages
+    fn streams_to_stdout(&self) -> bool {
+        matches!(self, Commands::Checkpoint(args) if args.storage == STDOUT_STORAGE)
+    }
+}
+
+/// Execute a command, returning its result metadata for the audit log
+async fn run(command: Commands, verbose: bool) -> anyhow::Result<Value> {
+    match command {
+        Commands::Detect(args) => detect(&args, verbose),
+        Commands::Checkpoint(args) => checkpoint(args, verbose).await,
+        // Restores from object stores and URLs wait on the network
+        Commands::Restore(args) => tokio::task::spawn_blocking(move || restore(&args)).await?,
+        Commands::Dump(args) => dump(&args),
+        Commands::Inspect(args) => inspect(&args),
+        Commands::Verify(args) => verify(&args),
+        Commands::Prune(args) => prune(&args),
+        Commands::Schema(args) => {
+            let schema = match args.output.as_str() {
+                "checkpoint" => checkpoint_schema(),
+                "restore" => restore_schema(),
+                _ => detection_schema(),
+            };
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            Ok(schema)
+        }
+    }
+}
+
+fn detect(args: &DetectArgs, verbose: bool) -> anyhow::Result<Value> {
+    let pid = args.pid;
+    let format = args.format.as_str();
+    info!("Detecting GPU allocations for PID {}", pid);
+
+    let detector = CompositeDetector::new().with_strict(args.strict);
+    let mut results = detector.detect_all(pid)?.results;
 
-            match format.as_str() {
-                "json" => {
-                    println!("{}", serde_json::to_string_pretty(&results)?);
+    if args.resolve_libs && !results.is_empty() {
+        let libraries = MemoryMapParser::gpu_libraries(&MemoryMapParser::parse_maps(pid)?);
+        for result in &mut results {
+            result.libraries = libraries.clone();
+        }
+    }
+
+    if results.is_empty() {
+        warn!("No GPU allocations detected for PID {}", pid);
+        if args.compare_nvidia_smi {
+            eprintln!("{}", compare_nvidia_smi(&results, pid));
+        }
+        return Ok(Value::Array(Vec::new()));
+    }
+
+    let changes = match &args.since {
+        Some(path) => Some(changes_since(&results, path)?),
+        None => None,
+    };
+
+    // Changes and the nvidia-smi comparison are about the whole process;
+    // the filter only narrows what is listed
+    let filter = AllocationFilter {
+        min_size: args.min_size.unwrap_or(0),
+        types: args.types.clone(),
+    };
+    let all_results = results;
+    let results: Vec<DetectionResult> = all_results
+        .iter()
+        .map(|result| result.filtered(|a| filter.matches(a)))
+        .collect();
+
+    match format {
+        "json" => {
+            let report = DetectionReport {
+                changes: changes.clone(),
+                ..DetectionReport::new(results.clone())
+            };
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        "human" => {
+            for result in &results {
+                println!("\n=== {} GPU Detection Results ===", result.vendor);
+                println!("Process ID: {}", result.pid);
+                println!(
+                    "Total GPU Memory: {}",
+                    utils::format_memory(result.total_gpu_memory)
+                );
+                println!(
+                    "Problematic memory: {} of {}",
+                    utils::format_memory(result.total_problematic_memory()),
+                    utils::format_memory(result.total_gpu_memory)
+                );
+                println!("Allocations: {}", result.allocations.len());
+
+                for gpu in &result.gpus {
+                    let device = gpu
+                        .device_id
+                        .map_or("unknown".to_string(), |id| id.to_string());
+                    match (&gpu.architecture, &gpu.compute_capability) {
+                        (Some(arch), Some(cc)) => println!(
+                            "GPU {}: {} ({}, compute capability {})",
+                            device, gpu.model, arch, cc
+                        ),
+                        _ => println!("GPU {}: {}", device, gpu.model),
+                    }
                 }
-                "human" => {
-                    for result in &results {
-                        println!("\n=== {} GPU Detection Results ===", result.vendor);
-                        println!("Process ID: {}", result.pid);
+
+                if !result.libraries.is_empty() {
+                    println!("Libraries:");
+                    for library in &result.libraries {
                         println!(
-                            "Total GPU Memory: {}",
-                            utils::format_memory(result.total_gpu_memory)
+                            "  {} {} ({})",
+                            library.name,
+                            library.version.as_deref().unwrap_or("unversioned"),
+                            library.path
                         );
-                        println!("Allocations: {}", result.allocations.len());
+                    }
+                }
 
-                        if result.has_problematic_allocations() {
-                            println!("\n⚠️  Problematic allocations detected!");
-                        }
+                if !result.gpu_threads.is_empty() {
+                    let threads: Vec<String> =
+                        result.gpu_threads.iter().map(u32::to_string).collect();
+                    println!("GPU threads: {}", threads.join(", "));
+                }
 
-                        println!("\nAllocation Summary:");
-                        println!("  Standard: {}", result.stats.standard_allocations);
-                        println!("  UVM: {}", result.stats.uvm_allocations);
-                        println!("  Managed: {}", result.stats.managed_allocations);
-                        println!("  IPC: {}", result.stats.ipc_allocations);
-                        println!("  Distributed: {}", result.stats.distributed_allocations);
-
-                        if cli.verbose {
-                            println!("\nDetailed Allocations:");
-                            for (i, alloc) in result.allocations.iter().enumerate() {
-                                println!("\n  [{}] {} allocation", i, alloc.alloc_type);
-                                println!(
-                                    "      Address: 0x{:016x} - 0x{:016x}",
-                                    alloc.vaddr_start, alloc.vaddr_end
-                                );
-                                println!("      Size: {}", utils::format_memory(alloc.size));
-                                if let Some(ref file) = alloc.metadata.backing_file {
-                                    println!("      Backing: {file}");
-                                }
-                            }
-                        }
+                if result.has_problematic_allocations() {
+                    println!("\n⚠️  Problematic allocations detected!");
+                }
 
-                        // Recommend strategy
-                        let strategy = CheckpointEngine::select_strategy(result);
-                        println!("\nRecommended checkpoint strategy: {strategy:?}");
+                if result.uvm_tools_attached {
+                    println!(
+                        "\n⚠️  /dev/nvidia-uvm-tools is open; a profiler or debugger is attached"
+                    );
+                }
+
+                if result.is_mps {
+                    println!(
+                        "\n⚠️  Process is an MPS client; its GPU state lives partly in the MPS server"
+                    );
+                }
+
+                println!("\nAllocation Summary:");
+                println!("  Standard: {}", result.stats.standard_allocations);
+                println!("  UVM: {}", result.stats.uvm_allocations);
+                println!("  Managed: {}", result.stats.managed_allocations);
+                println!("  IPC: {}", result.stats.ipc_allocations);
+                println!("  Distributed: {}", result.stats.distributed_allocations);
+
+                if !result.contexts.is_empty() {
+                    println!("\nContexts: {}", result.contexts.len());
+                    for (i, ctx) in result.contexts.iter().enumerate() {
+                        let device = ctx
+                            .device_id
+                            .map_or("unknown".to_string(), |id| id.to_string());
+                        println!(
+                            "  [{}] device {}: {} allocations, {}",
+                            i,
+                            device,
+                            ctx.allocation_indices.len(),
+                            utils::format_memory(ctx.total_size)
+                        );
                     }
                 }
-                _ => {
-                    error!("Unknown format: {}", format);
-                    std::process::exit(1);
+
+                if verbose {
+                    println!("\nDetailed Allocations:");
+                    for (i, alloc) in result.allocations.iter().enumerate() {
+                        println!("\n  [{}] {} allocation", i, alloc.alloc_type);
+                        println!(
+                            "      Address: 0x{:016x} - 0x{:016x}",
+                            alloc.vaddr_start, alloc.vaddr_end
+                        );
+                        println!("      Size: {}", utils::format_memory(alloc.size));
+                        if let Some(rss) = alloc.metadata.rss_bytes {
+                            println!("      Resident: {}", utils::format_memory(rss));
+                        }
+                        if let Some(ref file) = alloc.metadata.backing_file {
+                            println!("      Backing: {file}");
+                        }
+                    }
+                }
+
+                // Recommend strategy
+                let (strategy, reason) = CheckpointEngine::select_strategy_explained(result);
+                println!("\nRecommended checkpoint strategy: {strategy:?}");
+                if args.explain {
+                    println!("  Because {reason}");
                 }
             }
         }
+        "table" => {
+            for result in &results {
+                println!(
+                    "\n{} GPU allocations for PID {} ({} total)",
+                    result.vendor,
+                    result.pid,
+                    utils::format_memory(result.total_gpu_memory)
+                );
+                println!("{}", allocation_table(result));
+            }
+        }
+        _ => anyhow::bail!("Unknown format: {format}"),
+    }
+
+    if let (Some(changes), Some(path)) = (&changes, &args.since) {
+        if format != "json" {
+            print_changes(changes, path);
+        }
+    }
 
-        Commands::Checkpoint {
-            pid,
-            storage,
-            strategy,
-            bandwidth,
-        } => {
-            info!("Checkpointing PID {} to {}", pid, storage);
+    if args.compare_nvidia_smi {
+        let comparison = compare_nvidia_smi(&all_results, pid);
+        // Keep stdout parseable in JSON mode
+        if format == "json" {
+            eprintln!("{comparison}");
+        } else {
+            println!("\n{comparison}");
+        }
+    }
 
-            // First detect to determine strategy
-            let detector = CompositeDetector::new();
-            let results = detector.detect_all(pid)?;
+    Ok(serde_json::to_value(&results)?)
+}
 
-            if results.is_empty() {
-                warn!("No GPU state to checkpoint for PID {}", pid);
-                return Ok(());
-            }
+/// Side-by-side summary of our NVIDIA total and nvidia-smi's figure for `pid`
+fn compare_nvidia_smi(results: &[DetectionResult], pid: u32) -> String {
+    let detected = results
+        .iter()
+        .filter(|result| result.vendor == GpuVendor::Nvidia)
+        .map(|result| result.total_gpu_memory)
+        .sum::<u64>();
 
-            let checkpoint_strategy = match strategy.as_str() {
-                "auto" => CheckpointEngine::select_strategy(&results[0]),
-                "cuda" => CheckpointStrategy::CudaCheckpoint,
-                "bar-sliding" => CheckpointStrategy::BarSliding,
-                "hybrid" => CheckpointStrategy::Hybrid,
-                _ => {
-                    error!("Unknown strategy: {}", strategy);
-                    std::process::exit(1);
-                }
-            };
+    let reported = match NvidiaDetector::nvidia_smi_memory(pid) {
+        Ok(Some(reported)) => reported,
+        Ok(None) => return format!("nvidia-smi does not list PID {pid}"),
+        Err(e) => return format!("nvidia-smi comparison unavailable: {e}"),
+    };
 
-            // Create output directory if it doesn't exist
-            std::fs::create_dir_all(&storage)?;
+    let (sign, delta) = if detected >= reported {
+        ('+', detected - reported)
+    } else {
+        ('-', reported - detected)
+    };
+    format!(
+        "Detected: {}  nvidia-smi: {}  Delta: {}{}",
+        utils::format_memory(detected),
+        utils::format_memory(reported),
+        sign,
+        utils::format_memory(delta)
+    )
+}
 
-            let config = CheckpointConfig {
-                strategy: checkpoint_strategy,
-                storage_path: storage,
-                bandwidth_mbps: bandwidth,
-                timeout: Duration::from_secs(300),
-                compression: false,
-            };
+/// Diff live detection results against a saved detection of the same vendor
+fn changes_since(
+    results: &[DetectionResult],
+    path: &Path,
+) -> anyhow::Result<Vec<(GpuVendor, DetectionDiff)>> {
+    let saved = DetectionReport::from_json(&std::fs::read_to_string(path)?)?.results;
+    for result in &saved {
+        result.validate()?;
+    }
 
-            let engine = CheckpointEngine::new(config);
+    Ok(results
+        .iter()
+        .map(|result| {
+            let earlier = saved
+                .iter()
+                .find(|s| s.vendor == result.vendor)
+                .cloned()
+                .unwrap_or_else(|| DetectionResult::new(result.pid, result.vendor));
+            (result.vendor, result.diff(&earlier))
+        })
+        .collect())
+}
 
-            println!("Using checkpoint strategy: {checkpoint_strategy:?}");
+fn print_changes(changes: &[(GpuVendor, DetectionDiff)], since: &Path) {
+    for (vendor, diff) in changes {
+        println!("\n=== {} changes since {} ===", vendor, since.display());
+        if diff.is_empty() {
+            println!("No changes");
+            continue;
+        }
 
-            let metadata = engine.checkpoint(pid, &results[0]).await?;
+        for alloc in &diff.added {
             println!(
-                "Checkpoint completed in {}",
-                utils::format_duration(metadata.duration_ms)
+                "  + 0x{:016x} {} {}",
+                alloc.vaddr_start,
+                alloc.alloc_type,
+                utils::format_memory(alloc.size)
             );
+        }
+        for alloc in &diff.removed {
             println!(
-                "Checkpoint size: {}",
-                utils::format_memory(metadata.size_bytes)
+                "  - 0x{:016x} {} {}",
+                alloc.vaddr_start,
+                alloc.alloc_type,
+                utils::format_memory(alloc.size)
             );
-            println!("Strategy used: {:?}", metadata.strategy_used);
         }
+        for (old, new) in &diff.resized {
+            println!(
+                "  ~ 0x{:016x} {} {} -> {}",
+                new.vaddr_start,
+                new.alloc_type,
+                utils::format_memory(old.size),
+                utils::format_memory(new.size)
+            );
+        }
+    }
+}
 
-        Commands::Restore { metadata, storage } => {
-            info!("Restoring from {} using storage {}", metadata, storage);
+fn allocation_table(result: &DetectionResult) -> Table {
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(vec![
+            "#",
+            "Type",
+            "Address Range",
+            "Size",
+            "Device",
+            "Backing File",
+        ]);
 
-            // Parse the metadata path to get the checkpoint file
-            let checkpoint_path = std::path::Path::new(&metadata);
+    for (i, alloc) in result.allocations.iter().enumerate() {
+        table.add_row(vec![
+            i.to_string(),
+            alloc.alloc_type.to_string(),
+            format!("0x{:016x}-0x{:016x}", alloc.vaddr_start, alloc.vaddr_end),
+            utils::format_memory(alloc.size),
+            alloc.device_id.map_or("-".to_string(), |id| id.to_string()),
+            alloc
+                .metadata
+                .backing_file
+                .clone()
+                .unwrap_or_else(|| "-".to_string()),
+        ]);
+    }
 
-            // Create restore engine
-            let restore = gpu_checkpoint::restore::BarRestore::new();
+    for column in [0, 3, 4] {
+        if let Some(column) = table.column_mut(column) {
+            column.set_cell_alignment(CellAlignment::Right);
+        }
+    }
 
-            // Perform restore
-            match restore.restore_from_checkpoint(checkpoint_path, None) {
-                Ok(restore_metadata) => {
-                    println!("Restore completed successfully!");
-                    println!("Process ID: {}", restore_metadata.pid);
-                    println!("Allocations restored: {}", restore_metadata.num_allocations);
-                    println!(
-                        "Total size: {}",
-                        utils::format_memory(restore_metadata.total_size)
+    table
+}
+
+async fn checkpoint(args: CheckpointArgs, verbose: bool) -> anyhow::Result<Value> {
+    let CheckpointArgs {
+        pid,
+        pgid,
+        storage,
+        strategy,
+        bandwidth,
+        throttle,
+        cow_snapshot,
+        limit_rss,
+        sparse,
+        no_freeze,
+        freeze_method,
+        format,
+        name_template,
+        output,
+        force,
+        sign_key,
+        key_file,
+        capture_env,
+        explain,
+        dry_run,
+        window_size,
+        auto_window,
+        managed_prefetch,
+        abort_on_change,
+        compress,
+        compression_level,
+        compression_threads,
+        parallelism,
+        dedup,
+        coalesce,
+        incremental_base,
+        track_dirty,
+        process_vm,
+        strict,
+        vendor_strategies,
+    } = args;
+    let vendor_strategies: HashMap<_, _> = vendor_strategies.into_iter().collect();
+    let pids = match pgid {
+        Some(pgid) => ProcessScanner::process_group(pgid)?,
+        None => pid,
+    };
+    let group = pids.len() > 1;
+    if group && (dry_run || storage == STDOUT_STORAGE || storage::is_object_url(&storage)) {
+        anyhow::bail!(
+            "--dry-run, --storage - and s3:// storage only support checkpointing a single process"
+        );
+    }
+    info!("Checkpointing PIDs {:?} to {}", pids, storage);
+    if !dry_run {
+        for &pid in &pids {
+            ProcessScanner::check_ptrace_permissions(pid)?;
+        }
+    }
+
+    // First detect to determine strategy
+    let detector = CompositeDetector::new().with_strict(strict);
+    let mut results = Vec::new();
+    for &pid in &pids {
+        match detector.detect_all(pid)?.results.into_iter().next() {
+            Some(mut result) => {
+                if coalesce {
+                    let merged = result.coalesce_allocations();
+                    info!(
+                        "Coalesced {} allocations of PID {} into {}",
+                        merged + result.allocations.len(),
+                        pid,
+                        result.allocations.len()
                     );
+                }
+                results.push(result)
+            }
+            None => warn!("No GPU state to checkpoint for PID {}", pid),
+        }
+    }
+
+    if results.is_empty() {
+        return Ok(Value::Null);
+    }
+    let pid = results[0].pid;
+
+    // Create output directory if it doesn't exist
+    let streaming = storage == STDOUT_STORAGE && !dry_run;
+    if !streaming && !dry_run && !storage::is_object_url(&storage) {
+        std::fs::create_dir_all(&storage)?;
+    }
+    // Streamed checkpoint data owns stdout, so the summary goes to stderr
+    let report = |line: String| {
+        if streaming {
+            eprintln!("{line}");
+        } else {
+            println!("{line}");
+        }
+    };
+
+    let config = CheckpointConfig {
+        strategy,
+        bandwidth_mbps: bandwidth,
+        throttle,
+        compression: compress,
+        compression_level,
+        compression_threads,
+        parallelism,
+        dedup,
+        incremental_base: incremental_base
+            .as_deref()
+            .map(checkpoint_file_of)
+            .transpose()?,
+        track_dirty,
+        process_vm,
+        cow_snapshot,
+        limit_rss,
+        sparse,
+        freeze: !no_freeze,
+        freeze_method,
+        format,
+        name_template,
+        output_file: output,
+        force,
+        sign_key,
+        encryption_key: encryption_key(key_file.as_deref())?,
+        capture_env,
+        window_size,
+        auto_window,
+        managed_prefetch,
+        abort_on_change,
+        vendor_strategies,
+        ..CheckpointConfig::new(storage)
+    };
+
+    let engine = CheckpointEngine::new(config);
+
+    if group {
+        return checkpoint_group(&engine, ProcessGroup::new(results));
+    }
+
+    if dry_run {
+        let estimate = engine.estimate(pid, &results[0])?;
+        println!("Strategy: {:?}", estimate.strategy);
+        if explain {
+            println!("  Because {}", estimate.reason);
+        }
+        println!("Allocations: {}", estimate.num_allocations);
+        println!(
+            "Estimated size: {}",
+            utils::format_memory(estimate.size_bytes)
+        );
+        println!(
+            "Estimated duration: {} at {} MB/s",
+            utils::format_duration(estimate.duration_ms),
+            estimate.bandwidth_mbps
+        );
+        return Ok(serde_json::to_value(&estimate)?);
+    }
+
+    let (strategy, reason) = engine.resolve_strategy_explained(&results[0]);
+    report(format!("Using checkpoint strategy: {strategy:?}"));
+    if explain {
+        report(format!("  Because {reason}"));
+    }
+
+    let metadata = engine.checkpoint(pid, &results[0]).await?;
+    report(format!(
+        "Checkpoint completed in {}",
+        utils::format_duration(metadata.duration_ms)
+    ));
+    report(format!(
+        "Checkpoint size: {}",
+        utils::format_memory(metadata.size_bytes)
+    ));
+    report(format!("Strategy used: {:?}", metadata.strategy_used));
+    if let Some(path) = &metadata.path {
+        report(format!("Checkpoint file: {}", path.display()));
+    }
+    if let Some(manifest) = &metadata.manifest {
+        report(format!("Manifest: {}", manifest.display()));
+    }
+    if let Some(url) = &metadata.url {
+        report(format!("Checkpoint object: {url}"));
+    }
+    if let Some(base) = &metadata.base_checkpoint {
+        report(format!("Incremental on: {}", base.display()));
+    }
+    if let Some(compression) = &metadata.compression {
+        report(format!(
+            "Compressed {} to {} at {:.2} MB/s",
+            utils::format_memory(compression.raw_bytes),
+            utils::format_memory(compression.compressed_bytes),
+            compression.mb_per_sec
+        ));
+    }
+    if verbose {
+        if let Some(slowest) = metadata.slowest_allocation() {
+            report(format!(
+                "Slowest allocation: 0x{:016x} ({} in {}, {:.2} MB/s)",
+                slowest.vaddr_start,
+                utils::format_memory(slowest.size),
+                utils::format_duration(slowest.duration_ms),
+                slowest.mb_per_sec
+            ));
+        }
+    }
+    if metadata.layout_changed {
+        report(
+            "⚠️  GPU memory layout changed during checkpoint; it may be inconsistent".to_string(),
+        );
+    }
+    if let Some(toggle) = &metadata.cuda_toggle {
+        report(format!(
+            "CUDA state: {} -> {} (toggled with {})",
+            toggle.state_before,
+            toggle.state_after,
+            toggle.tool.display()
+        ));
+    }
+    if let Some(window_size) = metadata.tuned_window_size {
+        report(format!(
+            "Auto-tuned window size: {}",
+            utils::format_memory(window_size as u64)
+        ));
+    }
+
+    Ok(serde_json::to_value(&metadata)?)
+}
+
+fn checkpoint_group(engine: &CheckpointEngine, group: ProcessGroup) -> anyhow::Result<Value> {
+    let (metadata, path) = engine.checkpoint_group(&group)?;
+    println!(
+        "Checkpointed {} processes in {}",
+        metadata.members.len(),
+        utils::format_duration(metadata.duration_ms)
+    );
+    for member in &metadata.members {
+        println!(
+            "  PID {}: {} in {} allocations",
+            member.pid,
+            utils::format_memory(member.size_bytes),
+            member.num_allocations
+        );
+        if member.layout_changed {
+            println!(
+                "⚠️  GPU memory layout of PID {} changed during checkpoint; it may be inconsistent",
+                member.pid
+            );
+        }
+    }
+    println!("Shared allocations stored once: {}", metadata.shared.len());
+    println!(
+        "Checkpoint size: {}",
+        utils::format_memory(metadata.size_bytes)
+    );
+    println!("Checkpoint file: {}", path.display());
+
+    Ok(serde_json::to_value(&metadata)?)
+}
+
+fn dump(args: &DumpArgs) -> anyhow::Result<Value> {
+    let detector = CompositeDetector::new();
+    let results = detector.detect_all(args.pid)?.results;
+
+    let allocation = results
+        .iter()
+        .find_map(|result| result.allocation_containing(args.address))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "No GPU allocation of PID {} contains address 0x{:x}",
+                args.pid,
+                args.address
+            )
+        })?;
+
+    let written =
+        BarSlidingCheckpoint::new().dump_allocation(args.pid, allocation, &args.output)?;
+    println!(
+        "Dumped {} from 0x{:016x}-0x{:016x} to {}",
+        utils::format_memory(written),
+        allocation.vaddr_start,
+        allocation.vaddr_end,
+        args.output.display()
+    );
+
+    Ok(serde_json::json!({
+        "vaddr_start": allocation.vaddr_start,
+        "vaddr_end": allocation.vaddr_end,
+        "bytes": written,
+        "path": args.output,
+    }))
+}
+
+fn inspect(args: &InspectArgs) -> anyhow::Result<Value> {
+    let inspection = gpu_checkpoint::restore::inspect_checkpoint(&args.path)?;
+
+    match args.format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&inspection)?),
+        "human" => {
+            println!("Checkpoint: {}", inspection.path.display());
+            println!("File size: {}", utils::format_memory(inspection.file_size));
+            if let Some(header) = &inspection.header {
+                println!(
+                    "Version {}, PID {}, {} allocations, {} total, taken at {}",
+                    header.version,
+                    header.pid,
+                    header.num_allocations,
+                    utils::format_memory(header.total_size),
+                    header.timestamp
+                );
+                println!("Compression: {}", header.compression);
+                println!("Encrypted: {}", if header.encrypted { "yes" } else { "no" });
+                println!(
+                    "Deduplicated: {}",
+                    if header.deduplicated { "yes" } else { "no" }
+                );
+                if header.checkpoint_id != 0 {
+                    println!("Checkpoint ID: {:016x}", header.checkpoint_id);
+                }
+                if header.is_incremental() {
                     println!(
-                        "Duration: {}",
-                        utils::format_duration(restore_metadata.duration_ms)
+                        "Incremental on checkpoint {:016x}",
+                        header.base_checkpoint_id
                     );
                 }
-                Err(e) => {
-                    error!("Restore failed: {}", e);
-                    std::process::exit(1);
-                }
             }
+            for (idx, record) in inspection.allocations.iter().enumerate() {
+                println!(
+                    "  [{idx}] @{} 0x{:016x}-0x{:016x} {} (stored {}) device {} flags 0x{:x}",
+                    record.offset,
+                    record.header.vaddr_start,
+                    record.header.vaddr_end,
+                    utils::format_memory(record.header.size),
+                    utils::format_memory(record.stored_size),
+                    record.header.device_id,
+                    record.header.flags
+                );
+            }
+            if let (Some(offset), Some(error)) = (inspection.stopped_at, &inspection.error) {
+                println!("⚠️  Parsing stopped at offset {offset}: {error}");
+            }
+        }
+        other => anyhow::bail!("Unknown format: {other}"),
+    }
+
+    Ok(serde_json::to_value(&inspection)?)
+}
+
+fn verify(args: &VerifyArgs) -> anyhow::Result<Value> {
+    let inspection = gpu_checkpoint::restore::inspect_checkpoint(Path::new(&args.metadata))?;
+
+    if let Some(header) = &inspection.header {
+        println!("Checkpoint: {}", inspection.path.display());
+        println!("Allocations: {}", header.num_allocations);
+        println!("Total size: {}", utils::format_memory(header.total_size));
+        println!("Timestamp: {}", header.timestamp);
+    }
+
+    let problems = inspection.inconsistencies();
+    if !problems.is_empty() {
+        for problem in &problems {
+            println!("⚠️  {problem}");
+        }
+        anyhow::bail!(
+            "{} is not a valid checkpoint ({} problems)",
+            args.metadata,
+            problems.len()
+        );
+    }
+
+    println!("Checkpoint is well-formed");
+    Ok(serde_json::to_value(&inspection)?)
+}
+
+fn prune(args: &PruneArgs) -> anyhow::Result<Value> {
+    let report = gpu_checkpoint::checkpoint::prune_checkpoints(
+        &args.storage,
+        args.keep_last.unwrap_or(usize::MAX),
+        args.older_than.unwrap_or(Duration::MAX),
+    )?;
+
+    for path in &report.removed {
+        println!("Removed {}", path.display());
+    }
+    for path in &report.unrecognized {
+        println!("⚠️  Skipped {}: not a checkpoint", path.display());
+    }
+    println!(
+        "Removed {} checkpoints, kept {}",
+        report.removed.len(),
+        report.kept.len()
+    );
+    Ok(serde_json::to_value(&report)?)
+}
+
+/// Checkpoint file at `path`, or named by the manifest at `path`. A
+/// manifest names the checkpoint file it was written next to.
+fn checkpoint_file_of(path: &Path) -> anyhow::Result<PathBuf> {
+    if path.extension().is_some_and(|ext| ext == "json") {
+        CheckpointMetadata::read_manifest(path)?
+            .checkpoint_file(path)
+            .ok_or_else(|| anyhow::anyhow!("{} does not name a checkpoint file", path.display()))
+    } else {
+        Ok(path.to_path_buf())
+    }
+}
+
+/// Key from `key_file`, or else from `GPU_CHECKPOINT_KEY`
+fn encryption_key(key_file: Option<&Path>) -> anyhow::Result<Option<EncryptionKey>> {
+    match key_file {
+        Some(path) => Ok(Some(EncryptionKey::load(path)?)),
+        None => Ok(EncryptionKey::from_env()?),
+    }
+}
+
+fn restore(args: &RestoreArgs) -> anyhow::Result<Value> {
+    info!(
+        "Restoring from {} using storage {}",
+        args.metadata, args.storage
+    );
+
+    // URLs and stdin name no local file, so only a local argument is
+    // resolved through its manifest
+    let stdin = args.metadata == "-";
+    let remote = http::is_url(&args.metadata) || storage::is_object_url(&args.metadata);
+    let metadata_path = Path::new(&args.metadata);
+    // A checkpoint file's manifest is read from next to it, for the CUDA
+    // toggle of a hybrid checkpoint
+    let manifest_path = if metadata_path.extension().is_some_and(|ext| ext == "json") {
+        metadata_path.to_path_buf()
+    } else {
+        CheckpointMetadata::manifest_path(metadata_path)
+    };
+    let manifest = if !stdin && !remote && manifest_path.exists() {
+        Some(CheckpointMetadata::read_manifest(&manifest_path)?)
+    } else {
+        None
+    };
+    if let Some(manifest) = &manifest {
+        // The cuda strategy writes no checkpoint file, only the manifest
+        if let (None, None, Some(toggle)) = (&manifest.path, &manifest.url, &manifest.cuda_toggle) {
+            return restore_cuda_toggle(manifest.pid, toggle, args);
+        }
+    }
+    let checkpoint_path = if stdin || remote {
+        None
+    } else {
+        Some(checkpoint_file_of(metadata_path)?)
+    };
+    let base = match &args.base {
+        Some(base) => Some(checkpoint_file_of(base)?),
+        None => manifest
+            .as_ref()
+            .and_then(|manifest| manifest.base_checkpoint.clone()),
+    };
+
+    // Create restore engine
+    let mut restore = gpu_checkpoint::restore::BarRestore::new()
+        .with_resume_process(!args.no_resume_process)
+        .with_max_retries(args.max_retries)
+        .with_freeze_method(args.freeze_method)
+        .with_restore_protection(args.write_protect_after)
+        .with_restore_mode(args.restore_mode)
+        .with_process_vm(args.process_vm)
+        .with_journal(!args.no_journal)
+        .with_filter(RestoreFilter {
+            types: args.only_type.clone(),
+            addresses: args.only_address.clone(),
+        });
+    if let Some(key_path) = &args.verify_key {
+        restore = restore.with_verifying_key(signing::load_verifying_key(key_path)?);
+    }
+    if let Some(key) = encryption_key(args.key_file.as_deref())? {
+        restore = restore.with_encryption_key(key);
+    }
+    if !args.remaps.is_empty() {
+        restore = restore.with_address_translation(args.remaps.iter().copied().collect());
+    }
+    if let Some(base) = base {
+        restore = restore.with_base_checkpoint(base);
+    }
+
+    if let Some(checkpoint_path) = &checkpoint_path {
+        if group::is_group_checkpoint(checkpoint_path)? {
+            if args.spawn {
+                anyhow::bail!("--spawn is not supported for process group checkpoints");
+            }
+            return restore_group(&restore, checkpoint_path, args);
+        }
+    }
+
+    let mut spawned = if args.spawn {
+        let Some(checkpoint_path) = &checkpoint_path else {
+            anyhow::bail!("--spawn needs the checkpoint's manifest, so it cannot restore a stream");
+        };
+        Some(spawn_target(metadata_path, checkpoint_path, args)?)
+    } else {
+        None
+    };
+    let target_pid = spawned.as_ref().map(|child| child.id()).or(args.pid);
+
+    // Device memory a hybrid checkpoint toggled to the host goes back onto
+    // the GPU before the BAR-copied allocations are written. A spawned
+    // process was never toggled.
+    let mut cuda_toggle = None;
+    if let Some(manifest) = manifest.as_ref().filter(|_| spawned.is_none()) {
+        if let Some(toggle) = &manifest.cuda_toggle {
+            CudaCheckpointTool::at(&toggle.tool)
+                .restore(target_pid.unwrap_or(manifest.pid), toggle)
+                .map_err(|e| anyhow::anyhow!("Restore failed: {e}"))?;
+            cuda_toggle = Some(toggle.clone());
+        }
+    }
+
+    // Perform restore
+    let restored = match &checkpoint_path {
+        Some(checkpoint_path) => CheckpointFileFormat::detect(checkpoint_path).and_then(|format| {
+            format
+                .implementation()
+                .restore(&restore, checkpoint_path, target_pid)
+        }),
+        None if http::is_url(&args.metadata) => {
+            restore.restore_from_url(&args.metadata, target_pid)
+        }
+        None if storage::is_object_url(&args.metadata) => S3Location::parse(&args.metadata)
+            .and_then(|location| S3Source::new(S3Config::from_env(), location))
+            .and_then(|source| restore.restore_from_source(source, target_pid)),
+        None => restore.restore_from_stream(&mut std::io::stdin().lock(), target_pid),
+    };
+    let mut restore_metadata = match restored {
+        Ok(restore_metadata) => restore_metadata,
+        Err(e) => {
+            // A half-restored process is of no use
+            if let Some(child) = &mut spawned {
+                child.kill().ok();
+                child.wait().ok();
+            }
+            anyhow::bail!("Restore failed: {e}");
+        }
+    };
+    restore_metadata.cuda_toggle = cuda_toggle;
+
+    println!("Restore completed successfully!");
+    if spawned.is_some() {
+        println!("Spawned process: {}", restore_metadata.pid);
+    }
+    println!("Process ID: {}", restore_metadata.pid);
+    println!("Allocations restored: {}", restore_metadata.applied.len());
+    if let Some(toggle) = &restore_metadata.cuda_toggle {
+        println!("{}", describe_cuda_restore(toggle));
+    }
+    if !restore_metadata.previously_restored.is_empty() {
+        println!(
+            "Allocations restored by an interrupted earlier run: {}",
+            restore_metadata.previously_restored.len()
+        );
+    }
+    if !restore_metadata.skipped.is_empty() {
+        println!(
+            "Allocations skipped by filter: {}",
+            restore_metadata.skipped.len()
+        );
+    }
+    if !restore_metadata.resumed {
+        println!(
+            "Process left stopped; resume it with: kill -CONT {}",
+            restore_metadata.pid
+        );
+    }
+    if !restore_metadata.write_protected.is_empty() {
+        println!(
+            "Read-only allocations re-protected: {}",
+            restore_metadata.write_protected.len()
+        );
+    }
+    for (original, new) in &restore_metadata.remapped {
+        println!("Remapped allocation 0x{original:016x} -> 0x{new:016x}");
+    }
+    if !restore_metadata.mapped.is_empty() {
+        println!(
+            "Ranges mapped fresh in the target: {}",
+            restore_metadata.mapped.len()
+        );
+    }
+    for (recorded, target) in &restore_metadata.fd_translations {
+        println!("Remapped fd {recorded} -> {target}");
+    }
+    println!(
+        "Total size: {}",
+        utils::format_memory(restore_metadata.total_size)
+    );
+    println!(
+        "Duration: {}",
+        utils::format_duration(restore_metadata.duration_ms)
+    );
+
+    Ok(serde_json::to_value(&restore_metadata)?)
+}
+
+/// Toggle the CUDA state a cuda strategy checkpoint moved to the host back
+/// onto the GPU, in the checkpointed process or `--pid`
+fn restore_cuda_toggle(
+    checkpointed_pid: u32,
+    toggle: &CudaToggle,
+    args: &RestoreArgs,
+) -> anyhow::Result<Value> {
+    if args.spawn {
+        anyhow::bail!("--spawn cannot restore a checkpoint that only toggled CUDA state");
+    }
+
+    let start = Instant::now();
+    let pid = args.pid.unwrap_or(checkpointed_pid);
+    CudaCheckpointTool::at(&toggle.tool)
+        .restore(pid, toggle)
+        .map_err(|e| anyhow::anyhow!("Restore failed: {e}"))?;
+    let restore_metadata =
+        RestoreMetadata::cuda_only(pid, toggle.clone(), start.elapsed().as_millis() as u64);
+
+    println!("Restore completed successfully!");
+    println!("Process ID: {pid}");
+    println!("{}", describe_cuda_restore(toggle));
+    Ok(serde_json::to_value(&restore_metadata)?)
+}
+
+/// "CUDA state: checkpointed -> running (toggled with nvidia-cuda-checkpoint)"
+fn describe_cuda_restore(toggle: &CudaToggle) -> String {
+    format!(
+        "CUDA state: {} -> {} (toggled with {})",
+        toggle.state_after,
+        toggle.state_before,
+        toggle.tool.display()
+    )
+}
+
+/// Start the command recorded in the manifest of the checkpoint and wait
+/// for it to map its GPU context
+fn spawn_target(
+    metadata_path: &Path,
+    checkpoint_path: &Path,
+    args: &RestoreArgs,
+) -> anyhow::Result<std::process::Child> {
+    let manifest_path = if metadata_path.extension().is_some_and(|ext| ext == "json") {
+        metadata_path.to_path_buf()
+    } else {
+        CheckpointMetadata::manifest_path(checkpoint_path)
+    };
+    let manifest = CheckpointMetadata::read_manifest(&manifest_path)?;
+    let command = manifest.command.as_ref().ok_or_else(|| {
+        anyhow::anyhow!(
+            "{} does not record the command line of PID {}",
+            manifest_path.display(),
+            manifest.pid
+        )
+    })?;
+
+    let mut child = gpu_checkpoint::restore::spawn_target(command, &manifest.environment)?;
+    let timeout = Duration::from_secs(args.spawn_timeout);
+    match gpu_checkpoint::restore::wait_for_gpu_context(
+        &mut child,
+        &CompositeDetector::new(),
+        timeout,
+    ) {
+        Ok(true) => {}
+        Ok(false) => warn!(
+            "Spawned PID {} did not map GPU memory within {}s, restoring anyway",
+            child.id(),
+            args.spawn_timeout
+        ),
+        Err(e) => {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(e.into());
+        }
+    }
+    Ok(child)
+}
+
+fn restore_group(
+    restore: &gpu_checkpoint::restore::BarRestore,
+    checkpoint_path: &Path,
+    args: &RestoreArgs,
+) -> anyhow::Result<Value> {
+    if args.pid.is_some() {
+        anyhow::bail!(
+            "use --group-pid ORIGINAL=NEW to restore a process group into other processes"
+        );
+    }
+
+    let pids = args.group_pids.iter().copied().collect();
+    let restored = group::restore_group(restore, checkpoint_path, &pids)
+        .map_err(|e| anyhow::anyhow!("Restore failed: {e}"))?;
+
+    println!("Restored {} processes", restored.len());
+    for restore_metadata in &restored {
+        println!(
+            "  PID {}: {} allocations, {} in {}",
+            restore_metadata.pid,
+            restore_metadata.applied.len(),
+            utils::format_memory(restore_metadata.total_size),
+            utils::format_duration(restore_metadata.duration_ms)
+        );
+        if !restore_metadata.resumed {
+            println!(
+                "  Process left stopped; resume it with: kill -CONT {}",
+                restore_metadata.pid
+            );
         }
     }
 
-    Ok(())
+    Ok(serde_json::to_value(&restored)?)
 }
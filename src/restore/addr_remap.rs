@@ -0,0 +1,45 @@
+use std::collections::BTreeMap;
+
+/// Translates the start addresses of checkpointed allocations to the
+/// addresses the process being restored into has them mapped at.
+///
+/// A freshly started target rarely gets its mappings at the addresses the
+/// checkpointed process had, so each allocation is written at the new base
+/// recorded for its original start address.
+#[derive(Debug, Clone, Default)]
+pub struct AddressTranslation {
+    /// Original start address -> new start address
+    table: BTreeMap<u64, u64>,
+}
+
+impl AddressTranslation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restore the allocation that started at `original` at `new` instead
+    pub fn insert(&mut self, original: u64, new: u64) {
+        self.table.insert(original, new);
+    }
+
+    /// New start address of the allocation that started at `original`
+    pub fn translate(&self, original: u64) -> Option<u64> {
+        self.table.get(&original).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    pub fn table(&self) -> &BTreeMap<u64, u64> {
+        &self.table
+    }
+}
+
+impl FromIterator<(u64, u64)> for AddressTranslation {
+    fn from_iter<I: IntoIterator<Item = (u64, u64)>>(iter: I) -> Self {
+        Self {
+            table: iter.into_iter().collect(),
+        }
+    }
+}
@@ -1,14 +1,46 @@
 use crate::checkpoint::bar_sliding::{
-    AllocationHeader, CheckpointHeader, CHECKPOINT_MAGIC, CHECKPOINT_VERSION,
+    AllocationDescriptor, AllocationHeader, CheckpointHeader, DedupWindows, Segment,
+    ALLOC_FLAG_DESCRIPTOR, CHECKSUM_SIZE, DEFAULT_SHM_DIR,
 };
+use crate::checkpoint::cuda::CudaToggle;
+use crate::checkpoint::freeze::{FreezeMethod, ProcessController};
+use crate::checkpoint::signing;
+use crate::detector::{
+    AllocationType, GpuDeviceInfo, MemoryMapParser, NvidiaDetector, ProcessScanner,
+};
+use crate::restore::addr_remap::AddressTranslation;
+use crate::restore::fd_remap::FdTranslation;
+use crate::restore::http::HttpBody;
+use crate::restore::journal::RestoreJournal;
+use crate::storage::{CheckpointSource, SourceReader};
+use crate::utils::checksum::ChecksumReader;
+use crate::utils::compression::{Compression, DecompressReader};
+use crate::utils::encryption::{self, DecryptReader, EncryptionKey, RecordContext};
+use crate::utils::lock;
+use crate::utils::process_vm::ProcessMemory;
+use crate::utils::progress::{CheckpointEvent, ProgressCallback, TransferProgress};
+use crate::utils::{is_addressable, window_len};
 use crate::{GpuCheckpointError, Result};
-use indicatif::{ProgressBar, ProgressStyle};
+use ed25519_dalek::VerifyingKey;
+use nix::fcntl::Flock;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::time::Instant;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
 
+/// Default number of times a failed write into the target is retried
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// Pause between retries of a failed write into the target
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
 /// BAR restore engine for restoring GPU state from checkpoint
 #[derive(Debug)]
 pub struct BarRestore {
@@ -17,344 +49,2923 @@ pub struct BarRestore {
 
     /// Progress reporting
     show_progress: bool,
+
+    /// Caller's sink for progress, besides the bar
+    progress_callback: Option<ProgressCallback>,
+
+    /// Caller's channel for lifecycle events
+    events: Option<Sender<CheckpointEvent>>,
+
+    /// Directory shared memory segments are recreated in
+    shm_dir: PathBuf,
+
+    /// Reject checkpoints not signed by this key
+    verifying_key: Option<VerifyingKey>,
+
+    /// Key encrypted checkpoints are decrypted with
+    encryption_key: Option<EncryptionKey>,
+
+    /// Resume the target after restore instead of leaving it stopped
+    resume_process: bool,
+
+    /// How often a failed write into the target's memory is retried
+    max_retries: u32,
+
+    /// How a live target is paused during restore
+    freeze_method: FreezeMethod,
+
+    /// Which allocations of the checkpoint are restored
+    filter: RestoreFilter,
+
+    /// Make allocations that were read-only at checkpoint time read-only
+    /// again once their contents are restored
+    restore_protection: bool,
+
+    /// Where allocations are mapped in the target, if it differs from the
+    /// checkpointed process
+    address_translation: Option<AddressTranslation>,
+
+    /// File written instead of the target's `/proc/PID/mem`
+    target_memory: Option<PathBuf>,
+
+    /// Full checkpoint applied before an incremental one
+    base_checkpoint: Option<PathBuf>,
+
+    /// Whether allocation ranges the target lacks are mapped before writing
+    restore_mode: RestoreMode,
+
+    /// Write the target's memory with `process_vm_writev` where possible
+    process_vm: bool,
+
+    /// Record progress next to the checkpoint file so an interrupted
+    /// restore resumes where it stopped
+    journal: bool,
 }
 
-#[derive(Debug)]
+/// How allocations are placed in the target's address space
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestoreMode {
+    /// Write into ranges the target already has mapped, e.g. because it
+    /// re-ran its allocations before restore. Allocations it lacks fail
+    /// to restore.
+    #[default]
+    Existing,
+
+    /// Map anonymous memory in the target for the parts of each
+    /// allocation's range it has nothing mapped in, then write into it.
+    /// The layout is rebuilt from the checkpoint's allocation headers.
+    MapFresh,
+}
+
+impl FromStr for RestoreMode {
+    type Err = GpuCheckpointError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "existing" => Ok(RestoreMode::Existing),
+            "map-fresh" => Ok(RestoreMode::MapFresh),
+            _ => Err(GpuCheckpointError::RestoreError(format!(
+                "Unknown restore mode: {s}"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for RestoreMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestoreMode::Existing => write!(f, "existing"),
+            RestoreMode::MapFresh => write!(f, "map-fresh"),
+        }
+    }
+}
+
+/// Selects the allocations of a checkpoint to restore. Data of the others is
+/// read past without touching the target.
+#[derive(Debug, Clone, Default)]
+pub struct RestoreFilter {
+    /// Only restore allocations of these types. Allocations of checkpoints
+    /// that did not record a type never match.
+    pub types: Option<Vec<AllocationType>>,
+
+    /// Only restore allocations containing one of these addresses
+    pub addresses: Vec<u64>,
+}
+
+impl RestoreFilter {
+    pub fn matches(
+        &self,
+        alloc_header: &AllocationHeader,
+        descriptor: &AllocationDescriptor,
+    ) -> bool {
+        let type_matches = match (&self.types, descriptor.alloc_type) {
+            (None, _) => true,
+            (Some(types), Some(alloc_type)) => types.contains(&alloc_type),
+            (Some(_), None) => false,
+        };
+        let address_matches = self.addresses.is_empty()
+            || self
+                .addresses
+                .iter()
+                .any(|addr| (alloc_header.vaddr_start..alloc_header.vaddr_end).contains(addr));
+        type_matches && address_matches
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct RestoreMetadata {
     pub pid: u32,
     pub num_allocations: usize,
     pub total_size: u64,
     pub duration_ms: u64,
+
+    /// Checkpointed file descriptors and the target descriptors they were
+    /// translated to
+    pub fd_translations: BTreeMap<i32, i32>,
+
+    /// Whether the target was resumed after restore or left stopped
+    pub resumed: bool,
+
+    /// Start addresses of the allocations that were restored
+    pub applied: Vec<u64>,
+
+    /// Start addresses of the allocations left out by the restore filter
+    pub skipped: Vec<u64>,
+
+    /// Start addresses of the allocations made read-only again after restore
+    pub write_protected: Vec<u64>,
+
+    /// Checkpointed start addresses and the addresses the allocations were
+    /// restored at instead
+    pub remapped: BTreeMap<u64, u64>,
+
+    /// Start addresses of the ranges mapped fresh in the target
+    pub mapped: Vec<u64>,
+
+    /// Start addresses of the allocations an interrupted restore had
+    /// completed, which were not restored again
+    pub previously_restored: Vec<u64>,
+
+    /// CUDA toggle of the checkpoint that was reversed, moving the device
+    /// state back onto the GPU
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cuda_toggle: Option<CudaToggle>,
 }
 
-impl Default for BarRestore {
-    fn default() -> Self {
+impl RestoreMetadata {
+    /// Result of restoring a checkpoint that only toggled the CUDA state of
+    /// `pid`, which writes no memory
+    pub fn cuda_only(pid: u32, toggle: CudaToggle, duration_ms: u64) -> Self {
         Self {
-            window_size: 256 * 1024 * 1024, // 256MB
-            show_progress: true,
+            pid,
+            num_allocations: 0,
+            total_size: 0,
+            duration_ms,
+            fd_translations: BTreeMap::new(),
+            resumed: true,
+            applied: Vec::new(),
+            skipped: Vec::new(),
+            write_protected: Vec::new(),
+            remapped: BTreeMap::new(),
+            mapped: Vec::new(),
+            previously_restored: Vec::new(),
+            cuda_toggle: Some(toggle),
         }
     }
 }
 
-impl BarRestore {
-    pub fn new() -> Self {
-        Self::default()
-    }
+/// JSON Schema (draft 2020-12) of `RestoreMetadata`, the result
+/// `--append-log` records for a restore
+pub fn restore_schema() -> serde_json::Value {
+    use serde_json::json;
 
-    pub fn restore_from_checkpoint(
-        &self,
-        checkpoint_path: &Path,
-        target_pid: Option<u32>,
-    ) -> Result<RestoreMetadata> {
-        info!("Starting BAR restore from {:?}", checkpoint_path);
-        let start_time = Instant::now();
+    let unsigned = json!({ "type": "integer", "minimum": 0 });
+    let addresses = json!({ "type": "array", "items": unsigned });
 
-        // Open checkpoint file
-        let mut file = OpenOptions::new()
-            .read(true)
-            .open(checkpoint_path)
-            .map_err(GpuCheckpointError::IoError)?;
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "RestoreMetadata",
+        "description": "Result of gpu-checkpoint restore",
+        "type": "object",
+        "required": [
+            "pid", "num_allocations", "total_size", "duration_ms", "fd_translations", "resumed",
+            "applied", "skipped", "write_protected", "remapped", "mapped", "previously_restored"
+        ],
+        "properties": {
+            "pid": unsigned,
+            "num_allocations": unsigned,
+            "total_size": unsigned,
+            "duration_ms": unsigned,
+            "fd_translations": {
+                "description": "Target descriptors by checkpointed descriptor",
+                "type": "object",
+                "propertyNames": { "pattern": "^-?[0-9]+$" },
+                "additionalProperties": { "type": "integer" }
+            },
+            "resumed": { "type": "boolean" },
+            "applied": addresses,
+            "skipped": addresses,
+            "write_protected": addresses,
+            "remapped": {
+                "description": "Restored start address by checkpointed start address",
+                "type": "object",
+                "propertyNames": { "pattern": "^[0-9]+$" },
+                "additionalProperties": unsigned
+            },
+            "mapped": addresses,
+            "previously_restored": addresses,
+            "cuda_toggle": crate::checkpoint::checkpoint_schema()["$defs"]["CudaToggle"].clone()
+        }
+    })
+}
 
-        // Read and validate header
-        let header = self.read_header(&mut file)?;
-        self.validate_header(&header)?;
+/// Restores individual allocation records into one target process
+pub struct RecordRestorer<'a> {
+    restore: &'a BarRestore,
+    pid: u32,
+    fd_translation: FdTranslation,
+    gpus: Vec<GpuDeviceInfo>,
+    architecture_mismatches: BTreeSet<u32>,
+    progress: Option<TransferProgress>,
+    applied: Vec<u64>,
+    skipped: Vec<u64>,
+    remapped: BTreeMap<u64, u64>,
 
-        let pid = target_pid.unwrap_or(header.pid);
-        info!(
-            "Restoring checkpoint for PID {} ({} allocations, {} bytes)",
-            pid, header.num_allocations, header.total_size
-        );
+    /// Whether each record's data is followed by its CRC32
+    checksums: bool,
 
-        // Set up progress bar
-        let progress = if self.show_progress {
-            let pb = ProgressBar::new(header.total_size);
-            pb.set_style(
-                ProgressStyle::default_bar()
-                    .template(
-                        "[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} ({eta})",
-                    )
-                    .unwrap()
-                    .progress_chars("=>-"),
-            );
-            Some(pb)
-        } else {
-            None
-        };
+    /// How each record's data is compressed
+    compression: Compression,
 
-        // Restore each allocation
-        let mut total_restored = 0u64;
-        for idx in 0..header.num_allocations {
-            debug!(
-                "Restoring allocation {} of {}",
-                idx + 1,
-                header.num_allocations
-            );
+    /// Whether each record's data and checksum are encrypted
+    encrypted: bool,
 
-            let alloc_header = self.read_allocation_header(&mut file)?;
-            let bytes_restored =
-                self.restore_allocation(pid, &alloc_header, &mut file, &progress)?;
+    /// Identity of the checkpoint, which encrypted records are bound to
+    checkpoint_id: u64,
 
-            total_restored += bytes_restored;
-        }
+    /// Page-aligned ranges to make read-only again, with their protection
+    read_only: Vec<(u64, u64, i32)>,
 
-        if let Some(pb) = progress {
-            pb.finish_with_message("Restore complete");
-        }
+    /// Pauses the target and changes its mappings
+    controller: ProcessController,
 
-        let duration = start_time.elapsed();
-        info!(
-            "Restore completed: {} bytes in {:.2}s ({:.2} MB/s)",
-            total_restored,
-            duration.as_secs_f64(),
-            (total_restored as f64 / (1024.0 * 1024.0)) / duration.as_secs_f64()
-        );
+    /// Start and end of the target's mappings, read when the first range
+    /// has to be mapped
+    target_maps: Option<BTreeMap<u64, u64>>,
 
-        Ok(RestoreMetadata {
-            pid,
-            num_allocations: header.num_allocations as usize,
-            total_size: total_restored,
-            duration_ms: duration.as_millis() as u64,
-        })
-    }
+    /// Start addresses of the ranges mapped fresh in the target
+    mapped: Vec<u64>,
 
-    fn restore_allocation(
-        &self,
-        pid: u32,
+    /// Where the windows of a deduplicated checkpoint restored so far are
+    /// in the target, by content hash
+    windows: HashMap<String, u64>,
+}
+
+impl RecordRestorer<'_> {
+    /// Restore allocation record `index`, whose stored data is read from
+    /// `input`, returning the number of data bytes consumed
+    pub fn restore(
+        &mut self,
+        index: u32,
         alloc_header: &AllocationHeader,
-        input: &mut File,
-        progress: &Option<ProgressBar>,
+        descriptor: &AllocationDescriptor,
+        input: &mut impl Read,
     ) -> Result<u64> {
-        debug!(
-            "Restoring allocation at 0x{:016x}-0x{:016x} ({} bytes)",
-            alloc_header.vaddr_start, alloc_header.vaddr_end, alloc_header.size
-        );
+        if !self.encrypted {
+            return self.restore_data(alloc_header, descriptor, input);
+        }
 
-        // For real implementation, we would:
-        // 1. Pause the target process
-        // 2. Map the GPU memory via BAR at the original addresses
-        // 3. Restore memory contents in sliding windows
-        // 4. Resume the process
+        let key = self
+            .restore
+            .encryption_key
+            .as_ref()
+            .ok_or_else(encrypted_without_key)?;
+        let record = RecordContext::new(self.checkpoint_id, index, alloc_header);
+        let mut decrypted = DecryptReader::new(input, key, record);
+        let restored = self
+            .restore_data(alloc_header, descriptor, &mut decrypted)
+            .and_then(|restored| {
+                decrypted.finish()?;
+                Ok(restored)
+            })
+            .map_err(|e| match e {
+                GpuCheckpointError::IoError(e) if encryption::is_authentication_failure(&e) => {
+                    GpuCheckpointError::RestoreError(format!(
+                        "cannot decrypt allocation at 0x{:016x}: {e}",
+                        alloc_header.vaddr_start
+                    ))
+                }
+                other => other,
+            })?;
+        Ok(restored)
+    }
 
-        // For now, simulate by reading the data
-        let mem_path = format!("/proc/{pid}/mem");
+    /// `restore` with the data read from `input` as stored, but decrypted
+    fn restore_data(
+        &mut self,
+        alloc_header: &AllocationHeader,
+        descriptor: &AllocationDescriptor,
+        input: &mut impl Read,
+    ) -> Result<u64> {
+        // Every path consumes the record's data in full, so the checksum
+        // covers exactly the stored bytes
+        let stored_size = descriptor.stored_size(alloc_header.size);
+        let mut data = ChecksumReader::new(match self.compression {
+            Compression::None => DataReader::Raw(&mut *input),
+            Compression::Deflate { .. } => {
+                DataReader::Compressed(DecompressReader::new(&mut *input, stored_size))
+            }
+        });
 
-        if Path::new(&mem_path).exists() {
-            match self.restore_memory_sliding(
-                &mem_path,
-                alloc_header.vaddr_start,
-                alloc_header.size,
-                input,
-                progress,
-            ) {
-                Ok(()) => Ok(alloc_header.size),
-                Err(e) => {
-                    warn!("Failed to restore to process memory: {}", e);
-                    // Fall back to just reading and discarding the data
-                    self.skip_allocation_data(alloc_header.size, input, progress)?;
-                    Ok(alloc_header.size)
-                }
+        if !self.restore.filter.matches(alloc_header, descriptor) {
+            debug!(
+                "Skipping allocation at 0x{:016x} excluded by the restore filter",
+                alloc_header.vaddr_start
+            );
+            self.restore
+                .skip_allocation_data(stored_size, &mut data, &self.progress)?;
+            self.verify_checksum(alloc_header, data.finalize(), input)?;
+            if !self.skipped.contains(&alloc_header.vaddr_start) {
+                self.skipped.push(alloc_header.vaddr_start);
             }
-        } else {
-            // No target process, just skip the data
-            warn!("Target process {} not found, skipping restore", pid);
-            self.skip_allocation_data(alloc_header.size, input, progress)?;
-            Ok(alloc_header.size)
+            return Ok(0);
         }
-    }
 
-    fn restore_memory_sliding(
-        &self,
-        mem_path: &str,
-        start_addr: u64,
-        size: u64,
-        input: &mut File,
-        progress: &Option<ProgressBar>,
-    ) -> Result<()> {
-        let mut mem_file = OpenOptions::new().write(true).open(mem_path).map_err(|e| {
-            if e.kind() == std::io::ErrorKind::PermissionDenied {
-                GpuCheckpointError::PermissionDenied
-            } else {
-                GpuCheckpointError::IoError(e)
-            }
-        })?;
+        let target = self.translate(alloc_header)?;
+        if !is_addressable(target.vaddr_start, target.vaddr_end) {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "allocation at 0x{:016x} ({} bytes) exceeds the address space of this host",
+                target.vaddr_start, target.size
+            )));
+        }
 
-        mem_file.seek(SeekFrom::Start(start_addr))?;
+        if let Some(architecture) = &descriptor.architecture {
+            BarRestore::check_architecture(
+                &self.gpus,
+                alloc_header.device_id,
+                architecture,
+                &mut self.architecture_mismatches,
+            );
+        }
 
-        let mut remaining = size;
-        let mut buffer = vec![0u8; self.window_size.min(size as usize)];
+        if descriptor.ipc_handle.is_some() {
+            debug!(
+                "Allocation at 0x{:016x} carries a CUDA IPC handle; re-importing it is not \
+                 supported yet, restoring its contents instead",
+                alloc_header.vaddr_start
+            );
+        }
 
-        while remaining > 0 {
-            let to_read = remaining.min(self.window_size as u64) as usize;
-            let bytes_read = input.read(&mut buffer[..to_read])?;
+        if descriptor.shm_name.is_none() && self.restore.maps_fresh_memory() {
+            self.map_missing(&target)?;
+        }
 
-            if bytes_read == 0 {
-                break;
+        let target_fd = descriptor
+            .fd
+            .as_ref()
+            .and_then(|recorded| self.fd_translation.translate(recorded));
+        let restore = self.restore;
+        let data_input = &mut data;
+        let restored = match (&descriptor.shm_name, &descriptor.segments) {
+            (Some(shm_name), _) => restore.restore_shm_allocation(
+                &restore.shm_segment_path(self.pid, shm_name, target_fd)?,
+                descriptor.shm_offset,
+                alloc_header,
+                data_input,
+                &self.progress,
+            ),
+            (None, Some(segments)) => {
+                restore.restore_segments(self.pid, &target, segments, data_input, &self.progress)
             }
+            (None, None) if descriptor.changed_windows.is_some() => restore.restore_segments(
+                self.pid,
+                &target,
+                &descriptor.changed_segments(alloc_header.size),
+                data_input,
+                &self.progress,
+            ),
+            (None, None) if descriptor.dedup.is_some() => restore.restore_deduplicated(
+                self.pid,
+                &target,
+                descriptor.dedup.as_ref().expect("checked above"),
+                data_input,
+                &self.progress,
+                &mut self.windows,
+            ),
+            (None, None) if descriptor.holes.is_some() => {
+                restore.restore_sparse(self.pid, &target, descriptor, data_input, &self.progress)
+            }
+            (None, None) => {
+                restore.restore_allocation(self.pid, &target, data_input, &self.progress)
+            }
+        }?;
 
-            mem_file.write_all(&buffer[..bytes_read])?;
+        debug!(
+            "Read {} bytes for allocation at 0x{:016x}",
+            data.bytes_read(),
+            alloc_header.vaddr_start
+        );
+        self.verify_checksum(alloc_header, data.finalize(), input)?;
+        // The base and incremental checkpoint both hold most allocations
+        if !self.applied.contains(&alloc_header.vaddr_start) {
+            self.applied.push(alloc_header.vaddr_start);
+        }
+        if let Some(prot) = descriptor.read_only_protection() {
+            let page_size = crate::utils::page_size();
+            let start = target.vaddr_start - target.vaddr_start % page_size;
+            let end = target.vaddr_end.div_ceil(page_size) * page_size;
+            self.read_only.push((start, end, prot));
+        }
+        Ok(restored)
+    }
 
-            remaining -= bytes_read as u64;
+    /// Read the records that follow as part of a checkpoint with `header`
+    fn use_header(&mut self, header: &CheckpointHeader) {
+        self.checksums = header.checksum_size() > 0;
+        self.compression = header.compression;
+        self.encrypted = header.encrypted;
+        // Windows of another checkpoint may since have been overwritten
+        self.windows.clear();
+    }
 
-            if let Some(pb) = progress {
-                pb.inc(bytes_read as u64);
-            }
-        }
+    /// Header of the allocation moved to where the target has it mapped.
+    /// Allocations the address translation has no entry for keep their
+    /// checkpointed address.
+    fn translate(&mut self, alloc_header: &AllocationHeader) -> Result<AllocationHeader> {
+        let Some(translation) = &self.restore.address_translation else {
+            return Ok(alloc_header.clone());
+        };
+        let Some(new_start) = translation.translate(alloc_header.vaddr_start) else {
+            warn!(
+                "No address translation for allocation at 0x{:016x}, restoring it at its \
+                 checkpointed address",
+                alloc_header.vaddr_start
+            );
+            return Ok(alloc_header.clone());
+        };
+
+        let new_end = new_start
+            .checked_add(alloc_header.vaddr_end - alloc_header.vaddr_start)
+            .ok_or_else(|| {
+                GpuCheckpointError::RestoreError(format!(
+                    "allocation at 0x{:016x} does not fit at 0x{:016x}",
+                    alloc_header.vaddr_start, new_start
+                ))
+            })?;
+        debug!(
+            "Allocation at 0x{:016x} moved to 0x{:016x}",
+            alloc_header.vaddr_start, new_start
+        );
+        self.remapped.insert(alloc_header.vaddr_start, new_start);
+        Ok(AllocationHeader {
+            vaddr_start: new_start,
+            vaddr_end: new_end,
+            ..alloc_header.clone()
+        })
+    }
+
+    /// Map anonymous memory over the parts of `target`'s page-aligned range
+    /// nothing is mapped in yet
+    fn map_missing(&mut self, target: &AllocationHeader) -> Result<()> {
+        let page_size = crate::utils::page_size();
+        let start = target.vaddr_start - target.vaddr_start % page_size;
+        let end = target.vaddr_end.div_ceil(page_size) * page_size;
+
+        let target_maps = match &mut self.target_maps {
+            Some(target_maps) => target_maps,
+            None => self.target_maps.insert(
+                MemoryMapParser::parse_maps(self.pid)?
+                    .into_iter()
+                    .map(|region| (region.start, region.end))
+                    .collect(),
+            ),
+        };
 
+        for (gap_start, gap_end) in unmapped_ranges(target_maps, start, end) {
+            debug!(
+                "Mapping 0x{:016x}-0x{:016x} in PID {}",
+                gap_start, gap_end, self.pid
+            );
+            self.controller
+                .map_anonymous(
+                    gap_start,
+                    gap_end - gap_start,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                )
+                .map_err(|e| match e {
+                    GpuCheckpointError::PermissionDenied => e,
+                    e => GpuCheckpointError::RestoreError(format!(
+                        "cannot map 0x{gap_start:016x}-0x{gap_end:016x} in PID {}: {e}",
+                        self.pid
+                    )),
+                })?;
+            target_maps.insert(gap_start, gap_end);
+            self.mapped.push(gap_start);
+        }
         Ok(())
     }
 
-    fn skip_allocation_data(
+    /// Compare the CRC32 of the data just read with the one stored after it.
+    /// Version 1 checkpoints carry no checksums and are not checked.
+    fn verify_checksum(
         &self,
-        size: u64,
-        input: &mut File,
-        progress: &Option<ProgressBar>,
+        alloc_header: &AllocationHeader,
+        computed: u32,
+        input: &mut impl Read,
     ) -> Result<()> {
-        let mut remaining = size;
-        let mut buffer = vec![0u8; self.window_size.min(size as usize)];
+        if !self.checksums {
+            return Ok(());
+        }
 
-        while remaining > 0 {
-            let to_read = remaining.min(self.window_size as u64) as usize;
-            let bytes_read = input.read(&mut buffer[..to_read])?;
+        let mut stored = [0u8; CHECKSUM_SIZE as usize];
+        input.read_exact(&mut stored)?;
+        let stored = u32::from_le_bytes(stored);
+        if stored != computed {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "checksum mismatch for allocation at 0x{:016x}: stored {:08x}, computed {:08x}",
+                alloc_header.vaddr_start, stored, computed
+            )));
+        }
+        Ok(())
+    }
+}
 
-            if bytes_read == 0 {
-                break;
-            }
+fn encrypted_without_key() -> GpuCheckpointError {
+    GpuCheckpointError::RestoreError(
+        "checkpoint is encrypted; its key is needed to restore it".to_string(),
+    )
+}
 
-            remaining -= bytes_read as u64;
+/// Parts of `start..end` not covered by any of the `mapped` ranges (start
+/// mapped to end)
+fn unmapped_ranges(mapped: &BTreeMap<u64, u64>, start: u64, end: u64) -> Vec<(u64, u64)> {
+    let mut gaps = Vec::new();
+    let mut cursor = start;
+    // The range before `start` may reach into the allocation
+    let preceding = mapped.range(..start).next_back();
+    for (&map_start, &map_end) in preceding.into_iter().chain(mapped.range(start..end)) {
+        if map_start > cursor {
+            gaps.push((cursor, map_start));
+        }
+        cursor = cursor.max(map_end);
+        if cursor >= end {
+            return gaps;
+        }
+    }
+    gaps.push((cursor, end));
+    gaps
+}
 
-            if let Some(pb) = progress {
-                pb.inc(bytes_read as u64);
-            }
+/// Source of one allocation's data
+enum DataReader<R: Read> {
+    Raw(R),
+    Compressed(DecompressReader<R>),
+}
+
+impl<R: Read> Read for DataReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            DataReader::Raw(input) => input.read(buf),
+            DataReader::Compressed(reader) => reader.read(buf),
         }
+    }
+}
 
-        Ok(())
+impl Default for BarRestore {
+    fn default() -> Self {
+        Self {
+            window_size: 256 * 1024 * 1024, // 256MB
+            show_progress: true,
+            progress_callback: None,
+            events: None,
+            shm_dir: PathBuf::from(DEFAULT_SHM_DIR),
+            verifying_key: None,
+            encryption_key: None,
+            resume_process: true,
+            max_retries: DEFAULT_MAX_RETRIES,
+            freeze_method: FreezeMethod::default(),
+            filter: RestoreFilter::default(),
+            restore_protection: false,
+            address_translation: None,
+            target_memory: None,
+            base_checkpoint: None,
+            restore_mode: RestoreMode::default(),
+            process_vm: false,
+            journal: true,
+        }
     }
+}
 
-    fn read_header(&self, file: &mut File) -> Result<CheckpointHeader> {
-        let mut buf = [0u8; 4];
+impl BarRestore {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        // Read magic
-        file.read_exact(&mut buf)?;
-        let magic = u32::from_le_bytes(buf);
+    /// Directory shared memory backed allocations are restored into
+    pub fn with_shm_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.shm_dir = dir.into();
+        self
+    }
 
-        // Read version
-        file.read_exact(&mut buf)?;
-        let version = u32::from_le_bytes(buf);
+    /// Only restore checkpoints carrying a valid signature from this key
+    pub fn with_verifying_key(mut self, key: VerifyingKey) -> Self {
+        self.verifying_key = Some(key);
+        self
+    }
 
-        // Read pid
-        file.read_exact(&mut buf)?;
-        let pid = u32::from_le_bytes(buf);
+    /// Decrypt encrypted checkpoints with `key`
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
 
-        // Read num_allocations
-        file.read_exact(&mut buf)?;
-        let num_allocations = u32::from_le_bytes(buf);
+    /// Whether to resume the target once restore finished (the default) or
+    /// leave it stopped, e.g. to attach a debugger or chain another restore
+    pub fn with_resume_process(mut self, resume: bool) -> Self {
+        self.resume_process = resume;
+        self
+    }
 
-        // Read total_size
-        let mut buf8 = [0u8; 8];
-        file.read_exact(&mut buf8)?;
-        let total_size = u64::from_le_bytes(buf8);
+    /// Mechanism used to pause a live target during restore
+    pub fn with_freeze_method(mut self, method: FreezeMethod) -> Self {
+        self.freeze_method = method;
+        self
+    }
 
-        // Read timestamp
-        file.read_exact(&mut buf8)?;
-        let timestamp = u64::from_le_bytes(buf8);
+    /// Retry failed writes into the target's memory up to `retries` times,
+    /// e.g. for pages a still-initializing target has not faulted in yet
+    pub fn with_max_retries(mut self, retries: u32) -> Self {
+        self.max_retries = retries;
+        self
+    }
 
-        Ok(CheckpointHeader {
-            magic,
-            version,
-            pid,
-            num_allocations,
-            total_size,
-            timestamp,
-        })
+    /// Only restore the allocations selected by `filter`
+    pub fn with_filter(mut self, filter: RestoreFilter) -> Self {
+        self.filter = filter;
+        self
     }
 
-    fn read_allocation_header(&self, file: &mut File) -> Result<AllocationHeader> {
-        let mut buf8 = [0u8; 8];
-        let mut buf4 = [0u8; 4];
+    /// Re-apply the read-only protection allocations had at checkpoint
+    /// time once their contents are written
+    pub fn with_restore_protection(mut self, enabled: bool) -> Self {
+        self.restore_protection = enabled;
+        self
+    }
 
-        // Read vaddr_start
-        file.read_exact(&mut buf8)?;
-        let vaddr_start = u64::from_le_bytes(buf8);
+    /// Restore allocations at the addresses `translation` maps their
+    /// checkpointed start addresses to, e.g. into a freshly started process
+    pub fn with_address_translation(mut self, translation: AddressTranslation) -> Self {
+        self.address_translation = Some(translation);
+        self
+    }
 
-        // Read vaddr_end
-        file.read_exact(&mut buf8)?;
-        let vaddr_end = u64::from_le_bytes(buf8);
+    /// Write allocation contents into `path`, addressed like `/proc/PID/mem`,
+    /// instead of the target's memory
+    pub fn with_target_memory(mut self, path: impl Into<PathBuf>) -> Self {
+        self.target_memory = Some(path.into());
+        self
+    }
 
-        // Read size
-        file.read_exact(&mut buf8)?;
-        let size = u64::from_le_bytes(buf8);
+    /// Checkpoint an incremental checkpoint was taken against. Its
+    /// allocations are restored first and the changed windows written over
+    /// them, with the target frozen throughout.
+    pub fn with_base_checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.base_checkpoint = Some(path.into());
+        self
+    }
 
-        // Read device_id
-        file.read_exact(&mut buf4)?;
-        let device_id = u32::from_le_bytes(buf4);
+    /// How allocations are placed in the target; `MapFresh` maps the
+    /// ranges a freshly started target lacks before writing them
+    pub fn with_restore_mode(mut self, mode: RestoreMode) -> Self {
+        self.restore_mode = mode;
+        self
+    }
 
-        // Read flags
-        file.read_exact(&mut buf4)?;
-        let flags = u32::from_le_bytes(buf4);
+    /// Write the target's memory with `process_vm_writev`, which moves a
+    /// window per syscall without opening `/proc/PID/mem`. Windows it
+    /// cannot write, such as those of write-protected pages, are written
+    /// through `/proc/PID/mem` instead.
+    pub fn with_process_vm(mut self, enabled: bool) -> Self {
+        self.process_vm = enabled;
+        self
+    }
 
-        Ok(AllocationHeader {
-            vaddr_start,
-            vaddr_end,
-            size,
-            device_id,
-            flags,
-        })
+    /// Draw a progress bar on stderr while transferring (the default).
+    /// Embedders that own the terminal turn it off, and may report
+    /// progress through `with_progress_callback` instead.
+    pub fn with_progress(mut self, enabled: bool) -> Self {
+        self.show_progress = enabled;
+        self
     }
 
-    fn validate_header(&self, header: &CheckpointHeader) -> Result<()> {
-        if header.magic != CHECKPOINT_MAGIC {
-            return Err(GpuCheckpointError::RestoreError(format!(
-                "Invalid checkpoint magic: 0x{:08x} (expected 0x{:08x})",
-                header.magic, CHECKPOINT_MAGIC
-            )));
-        }
+    /// Call `callback` with the bytes processed so far as the transfer
+    /// advances, whether or not the progress bar is drawn
+    pub fn with_progress_callback(mut self, callback: impl FnMut(u64) + Send + 'static) -> Self {
+        self.progress_callback = Some(ProgressCallback::new(callback));
+        self
+    }
 
-        if header.version != CHECKPOINT_VERSION {
+    /// Send restore lifecycle events to `events`: started, each allocation
+    /// begun, bytes processed and finished. Events are dropped once the
+    /// receiver hangs up.
+    pub fn with_events(mut self, events: Sender<CheckpointEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Keep a progress journal while restoring from a checkpoint file, and
+    /// resume from the one an interrupted restore left (default)
+    pub fn with_journal(mut self, enabled: bool) -> Self {
+        self.journal = enabled;
+        self
+    }
+
+    /// Progress of transferring `total_bytes`, if it is reported at all
+    fn progress(&self, total_bytes: u64) -> Option<TransferProgress> {
+        (self.show_progress || self.progress_callback.is_some() || self.events.is_some()).then(
+            || {
+                TransferProgress::with_reporting(
+                    total_bytes,
+                    self.show_progress,
+                    self.progress_callback.clone(),
+                )
+                .with_events(self.events.clone())
+            },
+        )
+    }
+
+    /// Whether missing ranges are mapped in the target process itself,
+    /// which needs a live target rather than a memory file
+    fn maps_fresh_memory(&self) -> bool {
+        self.restore_mode == RestoreMode::MapFresh && self.target_memory.is_none()
+    }
+
+    pub fn restore_from_checkpoint(
+        &self,
+        checkpoint_path: &Path,
+        target_pid: Option<u32>,
+    ) -> Result<RestoreMetadata> {
+        info!("Starting BAR restore from {:?}", checkpoint_path);
+        let start_time = Instant::now();
+
+        // Keep a concurrent checkpoint from rewriting the file under us
+        let mut file = lock::open_shared(checkpoint_path)?;
+
+        self.verify_signature(&mut file)?;
+
+        let header = CheckpointHeader::read_from(&mut *file)?;
+        header.validate()?;
+        // Resuming an incremental restore would have to track both files,
+        // a deduplicated one the windows restored before the interruption
+        if !self.journal || header.is_incremental() || header.deduplicated {
+            file.rewind()?;
+            return self.restore_from_reader(&mut *file, target_pid, start_time);
+        }
+        self.restore_journaled(&mut file, checkpoint_path, &header, target_pid, start_time)
+    }
+
+    /// Restore the records of the full checkpoint at `checkpoint_path`
+    /// following `header`, skipping those a previous restore into the same
+    /// process completed
+    fn restore_journaled(
+        &self,
+        file: &mut File,
+        checkpoint_path: &Path,
+        header: &CheckpointHeader,
+        target_pid: Option<u32>,
+        start_time: Instant,
+    ) -> Result<RestoreMetadata> {
+        let pid = target_pid.unwrap_or(header.pid);
+        let journal_path = RestoreJournal::path_for(checkpoint_path);
+        let mut journal = match RestoreJournal::load(&journal_path, header, pid)? {
+            Some(journal) => {
+                info!(
+                    "Resuming restore after {} of {} allocations from {}",
+                    journal.completed.len(),
+                    header.num_allocations,
+                    journal_path.display()
+                );
+                file.seek(SeekFrom::Start(journal.offset))?;
+                journal
+            }
+            None => RestoreJournal::new(header, pid, file.stream_position()?),
+        };
+        let previously_restored = journal.completed.clone();
+
+        let mut metadata = self.restore_records(header, target_pid, start_time, |records| {
+            let mut total_restored = 0u64;
+            let mut journaling = true;
+            for idx in journal.completed.len() as u32..header.num_allocations {
+                let (alloc_header, restored) = self.restore_record(header, idx, file, records)?;
+                total_restored += restored;
+
+                journal.complete(alloc_header.vaddr_start, file.stream_position()?);
+                if journaling {
+                    if let Err(e) = journal.save(&journal_path) {
+                        warn!(
+                            "Cannot write restore journal {}, an interrupted restore will \
+                             start over: {}",
+                            journal_path.display(),
+                            e
+                        );
+                        journaling = false;
+                    }
+                }
+            }
+            Ok(total_restored)
+        })?;
+
+        RestoreJournal::remove(&journal_path)?;
+        metadata.previously_restored = previously_restored;
+        Ok(metadata)
+    }
+
+    /// Restore a checkpoint streamed from an `http://` or `https://` URL. The body is
+    /// consumed as it arrives, without downloading the checkpoint first.
+    pub fn restore_from_url(&self, url: &str, target_pid: Option<u32>) -> Result<RestoreMetadata> {
+        info!("Starting BAR restore from {}", url);
+        let start_time = Instant::now();
+
+        if self.verifying_key.is_some() {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "cannot verify the signature of {url} while streaming it; download the \
+                 checkpoint to verify it"
+            )));
+        }
+
+        let mut body = HttpBody::get(url)?;
+        self.restore_from_reader(&mut body, target_pid, start_time)
+            .map_err(|e| match e {
+                GpuCheckpointError::IoError(e) => {
+                    GpuCheckpointError::RestoreError(format!("transfer of {url} failed: {e}"))
+                }
+                other => other,
+            })
+    }
+
+    /// Restore a checkpoint streamed through `input`, e.g. a pipe from
+    /// `checkpoint --storage -`. Like a URL, a stream cannot be checked
+    /// against a signature.
+    pub fn restore_from_stream(
+        &self,
+        input: &mut impl Read,
+        target_pid: Option<u32>,
+    ) -> Result<RestoreMetadata> {
+        info!("Starting BAR restore from a stream");
+        let start_time = Instant::now();
+
+        if self.verifying_key.is_some() {
+            return Err(GpuCheckpointError::RestoreError(
+                "cannot verify the signature of a streamed checkpoint; write it to a file to \
+                 verify it"
+                    .to_string(),
+            ));
+        }
+        self.restore_from_reader(input, target_pid, start_time)
+    }
+
+    /// Restore a checkpoint read chunk by chunk from `source`, e.g. an
+    /// object store. Like a stream, it cannot be checked against a
+    /// signature.
+    pub fn restore_from_source(
+        &self,
+        source: impl CheckpointSource,
+        target_pid: Option<u32>,
+    ) -> Result<RestoreMetadata> {
+        let mut reader = SourceReader::new(source);
+        self.restore_from_stream(&mut reader, target_pid)
+            .map_err(|e| match e {
+                GpuCheckpointError::IoError(_) => reader.take_error(e),
+                other => other,
+            })
+    }
+
+    /// Restore a checkpoint read front to back from `input`
+    fn restore_from_reader(
+        &self,
+        input: &mut impl Read,
+        target_pid: Option<u32>,
+        start_time: Instant,
+    ) -> Result<RestoreMetadata> {
+        // Read and validate header
+        let header = CheckpointHeader::read_from(input)?;
+        header.validate()?;
+
+        let Some((mut base, base_header)) = self.open_base_checkpoint(&header)? else {
+            return self.restore_records(&header, target_pid, start_time, |records| {
+                self.restore_all_records(&header, input, records)
+            });
+        };
+
+        // Progress covers the data of both checkpoints
+        let combined = CheckpointHeader {
+            total_size: base_header.total_size + header.total_size,
+            ..header.clone()
+        };
+        self.restore_records(&combined, target_pid, start_time, |records| {
+            records.use_header(&base_header);
+            let mut total_restored = self.restore_all_records(&base_header, &mut *base, records)?;
+            records.use_header(&header);
+            total_restored += self.restore_all_records(&header, input, records)?;
+            Ok(total_restored)
+        })
+    }
+
+    /// Open the base checkpoint of an incremental checkpoint with `header`,
+    /// positioned after its header. `None` for a full checkpoint.
+    fn open_base_checkpoint(
+        &self,
+        header: &CheckpointHeader,
+    ) -> Result<Option<(Flock<File>, CheckpointHeader)>> {
+        if !header.is_incremental() {
+            return Ok(None);
+        }
+
+        let path = self.base_checkpoint.as_ref().ok_or_else(|| {
+            GpuCheckpointError::RestoreError(format!(
+                "checkpoint is incremental on checkpoint {:016x}; its base checkpoint is needed \
+                 to restore it",
+                header.base_checkpoint_id
+            ))
+        })?;
+
+        let mut file = lock::open_shared(path)?;
+        self.verify_signature(&mut file)?;
+        let base_header = CheckpointHeader::read_from(&mut *file)?;
+        base_header.validate()?;
+        if base_header.checkpoint_id != header.base_checkpoint_id {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "{} is checkpoint {:016x}, but the incremental checkpoint was taken against {:016x}",
+                path.display(),
+                base_header.checkpoint_id,
+                header.base_checkpoint_id
+            )));
+        }
+        if base_header.is_incremental() {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "base checkpoint {} is itself incremental; restore it with its own base first",
+                path.display()
+            )));
+        }
+
+        info!("Applying base checkpoint {:?} first", path);
+        Ok(Some((file, base_header)))
+    }
+
+    /// Restore every allocation record of a checkpoint with `header`, read
+    /// from `input` right after the header
+    fn restore_all_records(
+        &self,
+        header: &CheckpointHeader,
+        input: &mut impl Read,
+        records: &mut RecordRestorer<'_>,
+    ) -> Result<u64> {
+        let mut total_restored = 0u64;
+        for idx in 0..header.num_allocations {
+            total_restored += self.restore_record(header, idx, input, records)?.1;
+        }
+        Ok(total_restored)
+    }
+
+    /// Read record `idx` of a checkpoint with `header` from `input` and
+    /// restore it, returning its header and the number of bytes restored
+    fn restore_record(
+        &self,
+        header: &CheckpointHeader,
+        idx: u32,
+        input: &mut impl Read,
+        records: &mut RecordRestorer<'_>,
+    ) -> Result<(AllocationHeader, u64)> {
+        debug!(
+            "Restoring allocation {} of {}",
+            idx + 1,
+            header.num_allocations
+        );
+
+        let alloc_header = AllocationHeader::read_from(input).map_err(|e| match e {
+            GpuCheckpointError::IoError(ref io)
+                if io.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                GpuCheckpointError::RestoreError(format!(
+                    "Checkpoint declares {} allocations but only {} are present",
+                    header.num_allocations, idx
+                ))
+            }
+            other => other,
+        })?;
+        if let Some(pb) = &records.progress {
+            pb.emit(CheckpointEvent::AllocationStarted {
+                index: idx,
+                count: header.num_allocations,
+                vaddr_start: alloc_header.vaddr_start,
+                size: alloc_header.size,
+            });
+        }
+        let descriptor = self
+            .read_allocation_descriptor(input, &alloc_header)?
+            .unwrap_or_default();
+
+        let restored = records.restore(idx, &alloc_header, &descriptor, input)?;
+        Ok((alloc_header, restored))
+    }
+
+    /// Check the signature of an open checkpoint if a verifying key is
+    /// configured, leaving the file rewound
+    pub fn verify_signature(&self, file: &mut File) -> Result<()> {
+        match &self.verifying_key {
+            Some(key) => signing::verify_checkpoint(file, key),
+            None => Ok(()),
+        }
+    }
+
+    /// Restore the allocation records of a checkpoint with `header` through
+    /// `restore_all`. The target is frozen while records are restored.
+    pub fn restore_records(
+        &self,
+        header: &CheckpointHeader,
+        target_pid: Option<u32>,
+        start_time: Instant,
+        restore_all: impl FnOnce(&mut RecordRestorer<'_>) -> Result<u64>,
+    ) -> Result<RestoreMetadata> {
+        if header.encrypted && self.encryption_key.is_none() {
+            return Err(encrypted_without_key());
+        }
+
+        let pid = target_pid.unwrap_or(header.pid);
+        info!(
+            "Restoring checkpoint for PID {} ({} allocations, {} bytes)",
+            pid, header.num_allocations, header.total_size
+        );
+
+        let mut records = RecordRestorer {
+            restore: self,
+            pid,
+            // The target may have been launched fresh, so its descriptors are
+            // matched up with the recorded ones as allocations reference them
+            fd_translation: FdTranslation::for_process(pid),
+            gpus: NvidiaDetector::gpu_devices(),
+            architecture_mismatches: BTreeSet::new(),
+            progress: self.progress(header.total_size),
+            applied: Vec::new(),
+            skipped: Vec::new(),
+            remapped: BTreeMap::new(),
+            checksums: header.checksum_size() > 0,
+            compression: header.compression,
+            encrypted: header.encrypted,
+            checkpoint_id: header.checkpoint_id,
+            read_only: Vec::new(),
+            controller: ProcessController::new(pid).with_method(self.freeze_method),
+            target_maps: None,
+            mapped: Vec::new(),
+            windows: HashMap::new(),
+        };
+
+        // Keep a live target from running on partially restored memory
+        if ProcessScanner::is_alive(pid) {
+            if self.target_memory.is_none() {
+                ProcessScanner::check_ptrace_permissions(pid)?;
+            }
+            records.controller.freeze()?;
+        }
+
+        if let Some(pb) = &records.progress {
+            pb.emit(CheckpointEvent::Started {
+                pid,
+                num_allocations: header.num_allocations,
+                total_bytes: header.total_size,
+            });
+        }
+        let restored = restore_all(&mut records);
+
+        // The contents are written through /proc/PID/mem, which ignores
+        // protection, so regions are only locked down afterwards
+        let mut write_protected = Vec::new();
+        if self.restore_protection && restored.is_ok() {
+            for &(start, end, prot) in &records.read_only {
+                match records.controller.mprotect(start, end - start, prot) {
+                    Ok(()) => write_protected.push(start),
+                    Err(e) => warn!(
+                        "Cannot restore protection of 0x{:016x}-0x{:016x} in PID {}: {}",
+                        start, end, pid, e
+                    ),
+                }
+            }
+        }
+
+        if self.resume_process {
+            records.controller.resume()?;
+        } else if records.controller.is_frozen() {
+            info!("Leaving PID {} stopped after restore", pid);
+            records.controller.leave_stopped()?;
+        }
+        let total_restored = restored?;
+
+        if let Some(pb) = &records.progress {
+            pb.finish_with_message("Restore complete");
+            pb.emit(CheckpointEvent::Finished {
+                bytes: total_restored,
+            });
+        }
+
+        let duration = start_time.elapsed();
+        info!(
+            "Restore completed: {} bytes in {:.2}s ({:.2} MB/s)",
+            total_restored,
+            duration.as_secs_f64(),
+            (total_restored as f64 / (1024.0 * 1024.0)) / duration.as_secs_f64()
+        );
+
+        Ok(RestoreMetadata {
+            pid,
+            num_allocations: header.num_allocations as usize,
+            total_size: total_restored,
+            duration_ms: duration.as_millis() as u64,
+            fd_translations: records.fd_translation.table().clone(),
+            resumed: self.resume_process,
+            applied: records.applied,
+            skipped: records.skipped,
+            write_protected,
+            remapped: records.remapped,
+            mapped: records.mapped,
+            previously_restored: Vec::new(),
+            cuda_toggle: None,
+        })
+    }
+
+    /// Warn (once per device) when the checkpoint was taken on a different
+    /// GPU architecture than the one it is restored onto. Returns whether
+    /// the architectures differ.
+    fn check_architecture(
+        gpus: &[GpuDeviceInfo],
+        device_id: u32,
+        architecture: &str,
+        warned: &mut BTreeSet<u32>,
+    ) -> bool {
+        let current = gpus
+            .iter()
+            .find(|gpu| gpu.device_id == Some(device_id))
+            .or(match gpus {
+                [only] => Some(only),
+                _ => None,
+            })
+            .and_then(|gpu| gpu.architecture.as_deref());
+
+        match current {
+            Some(current) if current != architecture => {
+                if warned.insert(device_id) {
+                    warn!(
+                        "Checkpoint was taken on a {} GPU but device {} is {}; restore may not work",
+                        architecture, device_id, current
+                    );
+                }
+                true
+            }
+            Some(_) => false,
+            None => {
+                debug!(
+                    "Architecture of device {} unknown, cannot compare with {}",
+                    device_id, architecture
+                );
+                false
+            }
+        }
+    }
+
+    fn restore_allocation(
+        &self,
+        pid: u32,
+        alloc_header: &AllocationHeader,
+        input: &mut impl Read,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        debug!(
+            "Restoring allocation at 0x{:016x}-0x{:016x} ({} bytes)",
+            alloc_header.vaddr_start, alloc_header.vaddr_end, alloc_header.size
+        );
+
+        // For real implementation, we would:
+        // 1. Pause the target process
+        // 2. Map the GPU memory via BAR at the original addresses
+        // 3. Restore memory contents in sliding windows
+        // 4. Resume the process
+
+        // For now, simulate by reading the data
+        let mem_path = self.mem_path(pid);
+
+        if Path::new(&mem_path).exists() {
+            let mut data = input.take(alloc_header.size);
+            match self.restore_memory_sliding(
+                pid,
+                alloc_header.vaddr_start,
+                alloc_header.size,
+                &mut data,
+                progress,
+            ) {
+                Ok(()) => Ok(alloc_header.size),
+                Err(e) => {
+                    warn!("Failed to restore to process memory: {}", e);
+                    // Fall back to just reading and discarding the rest of
+                    // the data
+                    self.skip_allocation_data(data.limit(), &mut data, progress)?;
+                    Ok(alloc_header.size)
+                }
+            }
+        } else {
+            // No target process, just skip the data
+            warn!("Target process {} not found, skipping restore", pid);
+            self.skip_allocation_data(alloc_header.size, input, progress)?;
+            Ok(alloc_header.size)
+        }
+    }
+
+    /// Restore the copied segments of an allocation, leaving the holes
+    /// between them untouched
+    fn restore_segments(
+        &self,
+        pid: u32,
+        alloc_header: &AllocationHeader,
+        segments: &[Segment],
+        input: &mut impl Read,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        let mem_path = self.mem_path(pid);
+        let mut restored = 0u64;
+
+        for segment in segments {
+            if segment.offset + segment.len > alloc_header.size {
+                return Err(GpuCheckpointError::RestoreError(format!(
+                    "Segment at offset {} exceeds allocation at 0x{:016x}",
+                    segment.offset, alloc_header.vaddr_start
+                )));
+            }
+
+            let mut data = (&mut *input).take(segment.len);
+            let result = if Path::new(&mem_path).exists() {
+                self.restore_memory_sliding(
+                    pid,
+                    alloc_header.vaddr_start + segment.offset,
+                    segment.len,
+                    &mut data,
+                    progress,
+                )
+            } else {
+                Err(GpuCheckpointError::ProcessNotFound(pid))
+            };
+
+            if let Err(e) = result {
+                warn!("Failed to restore segment to process memory: {}", e);
+                self.skip_allocation_data(data.limit(), &mut data, progress)?;
+            }
+            restored += segment.len;
+        }
+
+        Ok(restored)
+    }
+
+    /// Restore an allocation of a deduplicated checkpoint. Stored windows
+    /// are read from `input`; repeated ones are copied from where the first
+    /// window with the same contents was restored, recorded in `windows`.
+    fn restore_deduplicated(
+        &self,
+        pid: u32,
+        alloc_header: &AllocationHeader,
+        dedup: &DedupWindows,
+        input: &mut impl Read,
+        progress: &Option<TransferProgress>,
+        windows: &mut HashMap<String, u64>,
+    ) -> Result<u64> {
+        let mem_path = self.mem_path(pid);
+        if !Path::new(&mem_path).exists() {
+            warn!("Target process {} not found, skipping restore", pid);
+            let stored_size = dedup
+                .layout(alloc_header.size)
+                .filter(|(_, window)| window.duplicate_of.is_none())
+                .map(|(segment, _)| segment.len)
+                .sum();
+            self.skip_allocation_data(stored_size, input, progress)?;
+            return Ok(alloc_header.size);
+        }
+
+        for (segment, window) in dedup.layout(alloc_header.size) {
+            let addr = alloc_header.vaddr_start + segment.offset;
+            let Some(index) = window.duplicate_of else {
+                let mut data = (&mut *input).take(segment.len);
+                match self.restore_memory_sliding(pid, addr, segment.len, &mut data, progress) {
+                    Ok(()) => {
+                        windows.insert(window.hash.clone(), addr);
+                    }
+                    Err(e) => {
+                        warn!("Failed to restore window to process memory: {}", e);
+                        self.skip_allocation_data(data.limit(), &mut data, progress)?;
+                    }
+                }
+                continue;
+            };
+
+            let source = *windows.get(&window.hash).ok_or_else(|| {
+                GpuCheckpointError::RestoreError(format!(
+                    "window at 0x{addr:016x} repeats window {index}, which was not restored"
+                ))
+            })?;
+            if let Err(e) = self.copy_memory(pid, source, addr, segment.len) {
+                warn!("Failed to copy repeated window to process memory: {}", e);
+            }
+            if let Some(pb) = progress {
+                pb.inc(0, segment.len);
+            }
+        }
+
+        Ok(alloc_header.size)
+    }
+
+    /// Copy `size` bytes of process memory from `source` to `dest`
+    fn copy_memory(&self, pid: u32, source: u64, dest: u64, size: u64) -> Result<()> {
+        let input = match &self.target_memory {
+            Some(path) => ProcessMemory::file_at(path, false),
+            None => ProcessMemory::open(pid, false, self.process_vm),
+        }?;
+        self.restore_memory_sliding(
+            pid,
+            dest,
+            size,
+            &mut input.reader_at(source).take(size),
+            &None,
+        )
+    }
+
+    /// Restore an allocation written with holes. Holes read back as zeros
+    /// from the checkpoint and are zero-filled in the target rather than
+    /// copied.
+    fn restore_sparse(
+        &self,
+        pid: u32,
+        alloc_header: &AllocationHeader,
+        descriptor: &AllocationDescriptor,
+        input: &mut impl Read,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        let mem_path = self.mem_path(pid);
+
+        for (segment, is_hole) in descriptor.layout(alloc_header.size) {
+            if segment.offset + segment.len > alloc_header.size {
+                return Err(GpuCheckpointError::RestoreError(format!(
+                    "Hole at offset {} exceeds allocation at 0x{:016x}",
+                    segment.offset, alloc_header.vaddr_start
+                )));
+            }
+
+            let addr = alloc_header.vaddr_start + segment.offset;
+            if is_hole {
+                self.skip_allocation_data(segment.len, input, progress)?;
+                if let Err(e) = self.zero_memory(pid, addr, segment.len) {
+                    warn!("Failed to zero hole in process memory: {}", e);
+                }
+                continue;
+            }
+
+            let mut data = (&mut *input).take(segment.len);
+            let result = if Path::new(&mem_path).exists() {
+                self.restore_memory_sliding(pid, addr, segment.len, &mut data, progress)
+            } else {
+                Err(GpuCheckpointError::ProcessNotFound(pid))
+            };
+
+            if let Err(e) = result {
+                warn!("Failed to restore segment to process memory: {}", e);
+                self.skip_allocation_data(data.limit(), &mut data, progress)?;
+            }
+        }
+
+        Ok(alloc_header.size)
+    }
+
+    /// Segment an IPC allocation is restored into. If the target process
+    /// has a matching segment open as `target_fd`, that segment is used even
+    /// when its name differs.
+    fn shm_segment_path(
+        &self,
+        pid: u32,
+        shm_name: &str,
+        target_fd: Option<i32>,
+    ) -> Result<PathBuf> {
+        if shm_name.is_empty() || shm_name.contains('/') || shm_name == "." || shm_name == ".." {
             return Err(GpuCheckpointError::RestoreError(format!(
-                "Unsupported checkpoint version: {} (expected {})",
-                header.version, CHECKPOINT_VERSION
+                "Invalid shared memory segment name: {shm_name:?}"
             )));
         }
 
-        Ok(())
+        Ok(match target_fd {
+            Some(fd) => PathBuf::from(format!("/proc/{pid}/fd/{fd}")),
+            None => self.shm_dir.join(shm_name),
+        })
+    }
+
+    /// Write an IPC allocation back into its shared memory segment,
+    /// recreating the segment if it no longer exists
+    fn restore_shm_allocation(
+        &self,
+        segment: &Path,
+        shm_offset: u64,
+        alloc_header: &AllocationHeader,
+        input: &mut impl Read,
+        progress: &Option<TransferProgress>,
+    ) -> Result<u64> {
+        debug!(
+            "Restoring IPC allocation at 0x{:016x} into {}",
+            alloc_header.vaddr_start,
+            segment.display()
+        );
+
+        let mut shm_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(segment)?;
+
+        let required_len = shm_offset + alloc_header.size;
+        if shm_file.metadata()?.len() < required_len {
+            shm_file.set_len(required_len)?;
+        }
+
+        shm_file.seek(SeekFrom::Start(shm_offset))?;
+        let mut remaining = alloc_header.size;
+        let mut buffer = vec![0u8; window_len(self.window_size, alloc_header.size)];
+
+        while remaining > 0 {
+            let to_read = remaining.min(self.window_size as u64) as usize;
+            let bytes_read = input.read(&mut buffer[..to_read])?;
+
+            if bytes_read == 0 {
+                break;
+            }
+
+            shm_file.write_all(&buffer[..bytes_read])?;
+
+            remaining -= bytes_read as u64;
+
+            if let Some(pb) = progress {
+                pb.inc(bytes_read as u64, bytes_read as u64);
+            }
+        }
+
+        Ok(alloc_header.size)
+    }
+
+    fn restore_memory_sliding(
+        &self,
+        pid: u32,
+        start_addr: u64,
+        size: u64,
+        input: &mut impl Read,
+        progress: &Option<TransferProgress>,
+    ) -> Result<()> {
+        let memory = self.open_memory(pid).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::PermissionDenied {
+                GpuCheckpointError::PermissionDenied
+            } else {
+                GpuCheckpointError::IoError(e)
+            }
+        })?;
+
+        let mut offset = start_addr;
+        let mut remaining = size;
+        let mut buffer = vec![0u8; window_len(self.window_size, size)];
+
+        while remaining > 0 {
+            let to_read = remaining.min(self.window_size as u64) as usize;
+            let bytes_read = input.read(&mut buffer[..to_read])?;
+
+            if bytes_read == 0 {
+                break;
+            }
+
+            self.write_at_with_retry(&memory, &buffer[..bytes_read], offset)?;
+
+            offset += bytes_read as u64;
+            remaining -= bytes_read as u64;
+
+            if let Some(pb) = progress {
+                pb.inc(bytes_read as u64, bytes_read as u64);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fill `size` bytes of process memory at `start_addr` with zeros
+    fn zero_memory(&self, pid: u32, start_addr: u64, size: u64) -> Result<()> {
+        let memory = self.open_memory(pid)?;
+        let zeros = vec![0u8; window_len(self.window_size, size)];
+
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(zeros.len() as u64) as usize;
+            self.write_at_with_retry(&memory, &zeros[..len], start_addr + offset)?;
+            offset += len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Write `data` at `offset`, retrying failed writes up to `max_retries`
+    /// times before giving up
+    fn write_at_with_retry(&self, file: &impl FileExt, data: &[u8], offset: u64) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            match file.write_all_at(data, offset) {
+                Ok(()) => {
+                    if attempt > 0 {
+                        info!(
+                            "Write of {} bytes at 0x{:016x} succeeded after {} retries",
+                            data.len(),
+                            offset,
+                            attempt
+                        );
+                    }
+                    return Ok(());
+                }
+                Err(e) if attempt < self.max_retries => {
+                    attempt += 1;
+                    warn!(
+                        "Write of {} bytes at 0x{:016x} failed ({}), retry {}/{}",
+                        data.len(),
+                        offset,
+                        e,
+                        attempt,
+                        self.max_retries
+                    );
+                    std::thread::sleep(RETRY_DELAY);
+                }
+                Err(e) => return Err(GpuCheckpointError::IoError(e)),
+            }
+        }
+    }
+
+    /// Memory of `pid` for writing
+    fn open_memory(&self, pid: u32) -> std::io::Result<ProcessMemory> {
+        match &self.target_memory {
+            Some(path) => ProcessMemory::file_at(path, true),
+            None => ProcessMemory::open(pid, true, self.process_vm),
+        }
+    }
+
+    /// Memory of `pid` as written by restore
+    fn mem_path(&self, pid: u32) -> String {
+        match &self.target_memory {
+            Some(path) => path.display().to_string(),
+            None => format!("/proc/{pid}/mem"),
+        }
+    }
+
+    fn skip_allocation_data(
+        &self,
+        size: u64,
+        input: &mut impl Read,
+        progress: &Option<TransferProgress>,
+    ) -> Result<()> {
+        let mut remaining = size;
+        let mut buffer = vec![0u8; window_len(self.window_size, size)];
+
+        while remaining > 0 {
+            let to_read = remaining.min(self.window_size as u64) as usize;
+            let bytes_read = input.read(&mut buffer[..to_read])?;
+
+            if bytes_read == 0 {
+                break;
+            }
+
+            remaining -= bytes_read as u64;
+
+            if let Some(pb) = progress {
+                pb.inc(bytes_read as u64, 0);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn read_allocation_descriptor(
+        &self,
+        file: &mut impl Read,
+        header: &AllocationHeader,
+    ) -> Result<Option<AllocationDescriptor>> {
+        if header.flags & ALLOC_FLAG_DESCRIPTOR == 0 {
+            return Ok(None);
+        }
+
+        let mut buf4 = [0u8; 4];
+        file.read_exact(&mut buf4)?;
+        let len = u32::from_le_bytes(buf4) as usize;
+
+        let mut encoded = vec![0u8; len];
+        file.read_exact(&mut encoded)?;
+
+        serde_json::from_slice(&encoded).map(Some).map_err(|e| {
+            GpuCheckpointError::RestoreError(format!("Invalid allocation descriptor: {e}"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::bar_sliding::{
+        BarSlidingCheckpoint, CHECKPOINT_HEADER_SIZE, CHECKPOINT_VERSION,
+    };
+    use crate::detector::{AllocationType, DetectionResult, GpuAllocation, GpuVendor};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_checkpoint_restore_roundtrip() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("test.ckpt");
+
+        // Create a mock detection result
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            0x100000,
+            0x200000,
+            AllocationType::Standard,
+        ));
+
+        // Create checkpoint
+        let checkpoint = BarSlidingCheckpoint::new();
+        let ckpt_metadata = checkpoint
+            .checkpoint_process(1234, &detection, &checkpoint_path)
+            .unwrap();
+
+        assert_eq!(ckpt_metadata.num_allocations, 1);
+
+        // Restore checkpoint
+        let restore = BarRestore::new();
+        let restore_metadata = restore
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .unwrap();
+
+        assert_eq!(restore_metadata.num_allocations, 1);
+        assert_eq!(restore_metadata.total_size, ckpt_metadata.size_bytes);
+    }
+
+    #[test]
+    fn test_unreadable_allocation_does_not_fail_checkpoint() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("unreadable.ckpt");
+        let page_size = crate::utils::page_size() as usize;
+        let pid = std::process::id();
+        let allocation = |start: u64, len: usize| {
+            GpuAllocation::new(start, start + len as u64, AllocationType::Standard)
+        };
+
+        let mut first = memmap2::MmapMut::map_anon(2 * page_size).unwrap();
+        first.fill(0x11);
+        let mut second = memmap2::MmapMut::map_anon(2 * page_size).unwrap();
+        second.fill(0x22);
+        // Mapped past the end of an empty file, so every read of it fails
+        let empty = tempfile::tempfile().unwrap();
+        let beyond_eof = unsafe {
+            memmap2::MmapOptions::new()
+                .len(page_size)
+                .map(&empty)
+                .unwrap()
+        };
+
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(allocation(first.as_ptr() as u64, first.len()));
+        detection.add_allocation(allocation(beyond_eof.as_ptr() as u64, page_size));
+        // A bogus address far above anything mapped
+        detection.add_allocation(allocation(0x7fff_0000_0000, page_size));
+        detection.add_allocation(allocation(second.as_ptr() as u64, second.len()));
+
+        let metadata = BarSlidingCheckpoint::new()
+            .with_progress(false)
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+        assert_eq!(metadata.num_allocations, 4);
+
+        first.fill(0);
+        second.fill(0);
+        BarRestore::new()
+            .with_progress(false)
+            .with_filter(RestoreFilter {
+                types: None,
+                addresses: vec![first.as_ptr() as u64, second.as_ptr() as u64],
+            })
+            .restore_from_checkpoint(&checkpoint_path, Some(pid))
+            .unwrap();
+        assert!(first.iter().all(|&byte| byte == 0x11));
+        assert!(second.iter().all(|&byte| byte == 0x22));
+    }
+
+    #[test]
+    fn test_roundtrip_without_progress_bar() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("quiet.ckpt");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(4 * page_size).unwrap();
+        buffer.fill(0x5a);
+        let pid = std::process::id();
+        let start = buffer.as_ptr() as u64;
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + buffer.len() as u64,
+            AllocationType::Standard,
+        ));
+
+        let checkpointed = Arc::new(AtomicU64::new(0));
+        let sink = Arc::clone(&checkpointed);
+        let metadata = BarSlidingCheckpoint::new()
+            .with_progress(false)
+            .with_progress_callback(move |processed| sink.store(processed, Ordering::Relaxed))
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+        assert_eq!(metadata.num_allocations, 1);
+        assert_eq!(checkpointed.load(Ordering::Relaxed), buffer.len() as u64);
+
+        buffer.fill(0);
+        let restored = Arc::new(AtomicU64::new(0));
+        let sink = Arc::clone(&restored);
+        BarRestore::new()
+            .with_progress(false)
+            .with_progress_callback(move |processed| sink.store(processed, Ordering::Relaxed))
+            .restore_from_checkpoint(&checkpoint_path, Some(pid))
+            .unwrap();
+        assert_eq!(restored.load(Ordering::Relaxed), buffer.len() as u64);
+        assert!(buffer.iter().all(|&byte| byte == 0x5a));
+    }
+
+    /// Events of a transfer with the byte counts collapsed, checking that
+    /// they only ever grow up to `total`
+    fn lifecycle(events: Vec<CheckpointEvent>, total: u64) -> Vec<CheckpointEvent> {
+        let mut done = 0;
+        let mut lifecycle = Vec::new();
+        for event in events {
+            match event {
+                CheckpointEvent::BytesDone { bytes } => {
+                    assert!(bytes > done && bytes <= total, "{bytes} after {done}");
+                    done = bytes;
+                    if lifecycle.last() != Some(&CheckpointEvent::BytesDone { bytes: 0 }) {
+                        lifecycle.push(CheckpointEvent::BytesDone { bytes: 0 });
+                    }
+                }
+                event => lifecycle.push(event),
+            }
+        }
+        assert_eq!(done, total);
+        lifecycle
+    }
+
+    #[test]
+    fn test_lifecycle_events() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("events.ckpt");
+
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            0x100000,
+            0x104000,
+            AllocationType::Standard,
+        ));
+        detection.add_allocation(GpuAllocation::new(
+            0x200000,
+            0x201000,
+            AllocationType::Standard,
+        ));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let metadata = BarSlidingCheckpoint::new()
+            .with_progress(false)
+            .with_window_size(0x1000)
+            .with_events(sender)
+            .checkpoint_process(1234, &detection, &checkpoint_path)
+            .unwrap();
+        assert_eq!(metadata.size_bytes, 0x5000);
+
+        // The checkpoint dropped its sender once done, ending the iteration
+        let expected = |pid| {
+            vec![
+                CheckpointEvent::Started {
+                    pid,
+                    num_allocations: 2,
+                    total_bytes: 0x5000,
+                },
+                CheckpointEvent::AllocationStarted {
+                    index: 0,
+                    count: 2,
+                    vaddr_start: 0x100000,
+                    size: 0x4000,
+                },
+                CheckpointEvent::BytesDone { bytes: 0 },
+                CheckpointEvent::AllocationStarted {
+                    index: 1,
+                    count: 2,
+                    vaddr_start: 0x200000,
+                    size: 0x1000,
+                },
+                CheckpointEvent::BytesDone { bytes: 0 },
+                CheckpointEvent::Finished { bytes: 0x5000 },
+            ]
+        };
+        assert_eq!(lifecycle(receiver.iter().collect(), 0x5000), expected(1234));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        BarRestore::new()
+            .with_progress(false)
+            .with_events(sender)
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .unwrap();
+        assert_eq!(lifecycle(receiver.iter().collect(), 0x5000), expected(5678));
+    }
+
+    #[test]
+    fn test_restore_filter_skips_allocations() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("filtered.ckpt");
+
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(0x100000, 0x102000, AllocationType::Ipc));
+        detection.add_allocation(GpuAllocation::new(
+            0x200000,
+            0x201000,
+            AllocationType::Standard,
+        ));
+        detection.add_allocation(GpuAllocation::new(0x300000, 0x303000, AllocationType::Uvm));
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(1234, &detection, &checkpoint_path)
+            .unwrap();
+
+        let restore = BarRestore::new().with_filter(RestoreFilter {
+            types: Some(vec![AllocationType::Standard, AllocationType::Uvm]),
+            addresses: Vec::new(),
+        });
+        let metadata = restore
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .unwrap();
+        assert_eq!(metadata.applied, vec![0x200000, 0x300000]);
+        assert_eq!(metadata.skipped, vec![0x100000]);
+        assert_eq!(metadata.total_size, 0x4000);
+
+        let restore = BarRestore::new().with_filter(RestoreFilter {
+            types: None,
+            addresses: vec![0x302000],
+        });
+        let metadata = restore
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .unwrap();
+        assert_eq!(metadata.applied, vec![0x300000]);
+        assert_eq!(metadata.skipped, vec![0x100000, 0x200000]);
+    }
+
+    #[test]
+    fn test_write_retries_then_gives_up() {
+        let mem = OpenOptions::new()
+            .write(true)
+            .open("/proc/self/mem")
+            .unwrap();
+
+        // Address 0 is never mapped, so every attempt fails
+        let start = Instant::now();
+        let restore = BarRestore::new().with_max_retries(2);
+        assert!(restore.write_at_with_retry(&mem, &[0u8; 8], 0).is_err());
+        assert!(start.elapsed() >= RETRY_DELAY * 2);
+
+        let mut value = 0u64;
+        let addr = &mut value as *mut u64 as u64;
+        restore
+            .write_at_with_retry(&mem, &42u64.to_ne_bytes(), addr)
+            .unwrap();
+        assert_eq!(unsafe { std::ptr::read_volatile(&value) }, 42);
+    }
+
+    #[test]
+    fn test_shm_segment_roundtrip() {
+        let dir = tempdir().unwrap();
+        let source_shm = dir.path().join("source");
+        let target_shm = dir.path().join("target");
+        std::fs::create_dir_all(&source_shm).unwrap();
+        std::fs::create_dir_all(&target_shm).unwrap();
+
+        let contents: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8).collect();
+        let segment = source_shm.join("cuda.ipc.42");
+        std::fs::write(&segment, &contents).unwrap();
+
+        let mut ipc = GpuAllocation::new(0x7f0000000000, 0x7f0000002000, AllocationType::Ipc);
+        ipc.metadata.backing_file = Some(segment.to_string_lossy().to_string());
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(ipc);
+
+        let checkpoint_path = dir.path().join("shm.ckpt");
+        BarSlidingCheckpoint::new()
+            .with_shm_dir(&source_shm)
+            .checkpoint_process(1234, &detection, &checkpoint_path)
+            .unwrap();
+
+        let restore_metadata = BarRestore::new()
+            .with_shm_dir(&target_shm)
+            .restore_from_checkpoint(&checkpoint_path, Some(1234))
+            .unwrap();
+
+        assert_eq!(restore_metadata.num_allocations, 1);
+        assert_eq!(
+            std::fs::read(target_shm.join("cuda.ipc.42")).unwrap(),
+            contents
+        );
+    }
+
+    #[test]
+    fn test_limit_rss_roundtrip() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("sparse.ckpt");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(4 * page_size).unwrap();
+        // Only pages 0 and 2 are touched and therefore resident
+        buffer[..page_size].fill(0x11);
+        buffer[2 * page_size..3 * page_size].fill(0x22);
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + buffer.len() as u64,
+            AllocationType::Managed,
+        ));
+
+        let ckpt_metadata = BarSlidingCheckpoint::new()
+            .with_limit_rss(true)
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+        assert_eq!(ckpt_metadata.size_bytes, 2 * page_size as u64);
+
+        buffer[..page_size].fill(0x33);
+        buffer[3 * page_size..].fill(0x44);
+
+        let restore_metadata = BarRestore::new()
+            .restore_from_checkpoint(&checkpoint_path, Some(pid))
+            .unwrap();
+        assert_eq!(restore_metadata.total_size, 2 * page_size as u64);
+
+        // Resident pages are restored, holes keep whatever they hold now
+        assert!(buffer[..page_size].iter().all(|&b| b == 0x11));
+        assert!(buffer[2 * page_size..3 * page_size]
+            .iter()
+            .all(|&b| b == 0x22));
+        assert!(buffer[3 * page_size..].iter().all(|&b| b == 0x44));
+    }
+
+    #[test]
+    fn test_restore_allocation_spanning_gap() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("gap.ckpt");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(3 * page_size).unwrap();
+        buffer[..page_size].fill(0x11);
+        buffer[2 * page_size..].fill(0x22);
+        let start = buffer.as_ptr() as u64;
+        // Punch an unmapped gap into the middle of the allocation
+        let ret =
+            unsafe { libc::munmap((start + page_size as u64) as *mut libc::c_void, page_size) };
+        assert_eq!(ret, 0);
+
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + 3 * page_size as u64,
+            AllocationType::Standard,
+        ));
+
+        let ckpt_metadata = BarSlidingCheckpoint::new()
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+        assert_eq!(ckpt_metadata.size_bytes, 2 * page_size as u64);
+
+        let inspection = crate::restore::inspect_checkpoint(&checkpoint_path).unwrap();
+        let descriptor = inspection.allocations[0].descriptor.clone().unwrap();
+        assert_eq!(
+            descriptor.segments,
+            Some(vec![
+                Segment {
+                    offset: 0,
+                    len: page_size as u64
+                },
+                Segment {
+                    offset: 2 * page_size as u64,
+                    len: page_size as u64
+                },
+            ])
+        );
+
+        buffer[..page_size].fill(0x33);
+        buffer[2 * page_size..].fill(0x44);
+
+        let restore_metadata = BarRestore::new()
+            .restore_from_checkpoint(&checkpoint_path, Some(pid))
+            .unwrap();
+        assert_eq!(restore_metadata.applied, vec![start]);
+        assert_eq!(restore_metadata.total_size, 2 * page_size as u64);
+        assert!(buffer[..page_size].iter().all(|&b| b == 0x11));
+        assert!(buffer[2 * page_size..].iter().all(|&b| b == 0x22));
+    }
+
+    #[test]
+    fn test_incremental_roundtrip() {
+        let dir = tempdir().unwrap();
+        let base_path = dir.path().join("base.ckpt");
+        let delta_path = dir.path().join("delta.ckpt");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(8 * page_size).unwrap();
+        buffer.fill(0x11);
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + buffer.len() as u64,
+            AllocationType::Managed,
+        ));
+
+        BarSlidingCheckpoint::new()
+            .with_window_size(page_size)
+            .with_dirty_tracking(true)
+            .checkpoint_process(pid, &detection, &base_path)
+            .unwrap();
+
+        // Two of the eight windows change after the base checkpoint
+        buffer[page_size..2 * page_size].fill(0x22);
+        buffer[5 * page_size..6 * page_size].fill(0x33);
+        let expected = buffer.to_vec();
+
+        let delta = BarSlidingCheckpoint::new()
+            .with_window_size(page_size)
+            .with_incremental_base(&base_path)
+            .checkpoint_process(pid, &detection, &delta_path)
+            .unwrap();
+        if crate::detector::MemoryMapParser::soft_dirty_supported() {
+            assert_eq!(delta.size_bytes, 2 * page_size as u64);
+        }
+
+        let base_header =
+            CheckpointHeader::read_from(&mut File::open(&base_path).unwrap()).unwrap();
+        let header = CheckpointHeader::read_from(&mut File::open(&delta_path).unwrap()).unwrap();
+        assert!(!base_header.is_incremental());
+        assert!(header.is_incremental());
+        assert_eq!(header.base_checkpoint_id, base_header.checkpoint_id);
+
+        buffer.fill(0);
+
+        // The base is required, and must be the checkpoint the delta was
+        // taken against
+        assert!(BarRestore::new()
+            .restore_from_checkpoint(&delta_path, Some(pid))
+            .is_err());
+        assert!(BarRestore::new()
+            .with_base_checkpoint(&delta_path)
+            .restore_from_checkpoint(&delta_path, Some(pid))
+            .is_err());
+        assert!(buffer.iter().all(|&b| b == 0));
+
+        let restore_metadata = BarRestore::new()
+            .with_base_checkpoint(&base_path)
+            .restore_from_checkpoint(&delta_path, Some(pid))
+            .unwrap();
+        assert_eq!(restore_metadata.applied, vec![start]);
+        assert_eq!(&buffer[..], &expected[..]);
+    }
+
+    #[test]
+    fn test_parallel_checkpoint_matches_serial() {
+        let dir = tempdir().unwrap();
+        let serial_path = dir.path().join("serial.ckpt");
+        let parallel_path = dir.path().join("parallel.ckpt");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(8 * 2 * page_size).unwrap();
+        for (idx, chunk) in buffer.chunks_mut(2 * page_size).enumerate() {
+            chunk.fill(idx as u8 + 1);
+        }
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        for idx in 0..8 {
+            let alloc_start = start + (idx * 2 * page_size) as u64;
+            detection.add_allocation(GpuAllocation::new(
+                alloc_start,
+                alloc_start + 2 * page_size as u64,
+                AllocationType::Uvm,
+            ));
+        }
+
+        let serial = BarSlidingCheckpoint::new()
+            .checkpoint_process(pid, &detection, &serial_path)
+            .unwrap();
+        let parallel = BarSlidingCheckpoint::new()
+            .with_parallelism(4)
+            .checkpoint_process(pid, &detection, &parallel_path)
+            .unwrap();
+        assert_eq!(parallel.num_allocations, 8);
+        assert_eq!(parallel.size_bytes, serial.size_bytes);
+
+        // Identical apart from the timestamp in the header
+        let serial = std::fs::read(&serial_path).unwrap();
+        let parallel = std::fs::read(&parallel_path).unwrap();
+        let header_size = CHECKPOINT_HEADER_SIZE as usize;
+        assert_eq!(parallel[..24], serial[..24]);
+        assert_eq!(parallel[header_size..], serial[header_size..]);
+
+        buffer.fill(0);
+        let metadata = BarRestore::new()
+            .restore_from_checkpoint(&parallel_path, Some(pid))
+            .unwrap();
+        assert_eq!(metadata.applied.len(), 8);
+        for (idx, chunk) in buffer.chunks(2 * page_size).enumerate() {
+            assert!(chunk.iter().all(|&b| b == idx as u8 + 1));
+        }
+    }
+
+    #[test]
+    fn test_restore_at_translated_address() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("moved.ckpt");
+        let target_memory = dir.path().join("mem");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(2 * page_size).unwrap();
+        buffer[..page_size].fill(0x5a);
+        buffer[page_size..].fill(0xa5);
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        for offset in [0, page_size as u64] {
+            detection.add_allocation(GpuAllocation::new(
+                start + offset,
+                start + offset + page_size as u64,
+                AllocationType::Standard,
+            ));
+        }
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+
+        // Only the first allocation moved; the second keeps its address
+        let new_start = 4 * page_size as u64;
+        std::fs::write(&target_memory, vec![0u8; 8 * page_size]).unwrap();
+        let metadata = BarRestore::new()
+            .with_target_memory(&target_memory)
+            .with_address_translation([(start, new_start)].into_iter().collect())
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .unwrap();
+        assert_eq!(metadata.applied, vec![start, start + page_size as u64]);
+        assert_eq!(metadata.remapped, BTreeMap::from([(start, new_start)]));
+
+        let memory = std::fs::read(&target_memory).unwrap();
+        let moved = &memory[new_start as usize..new_start as usize + page_size];
+        assert!(moved.iter().all(|&b| b == 0x5a));
+        assert!(memory[..new_start as usize].iter().all(|&b| b == 0));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::checkpoint::bar_sliding::BarSlidingCheckpoint;
-    use crate::detector::{AllocationType, DetectionResult, GpuAllocation, GpuVendor};
-    use tempfile::tempdir;
+    #[test]
+    fn test_restore_protection() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("protected.ckpt");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(page_size).unwrap();
+        buffer.fill(0x5a);
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut allocation =
+            GpuAllocation::new(start, start + page_size as u64, AllocationType::Standard);
+        allocation.metadata.protection = "r--p".to_string();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(allocation);
+
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+        buffer.fill(0);
+
+        let restore_metadata = BarRestore::new()
+            .with_restore_protection(true)
+            .restore_from_checkpoint(&checkpoint_path, Some(pid))
+            .unwrap();
+        assert_eq!(restore_metadata.write_protected, vec![start]);
+        assert!(buffer.iter().all(|&b| b == 0x5a));
+
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        let perms = maps
+            .lines()
+            .find(|line| line.starts_with(&format!("{start:x}-")))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .unwrap();
+        assert_eq!(perms, "r--p");
+    }
 
     #[test]
-    fn test_checkpoint_restore_roundtrip() {
+    fn test_sparse_roundtrip() {
+        use std::os::unix::fs::MetadataExt;
+
         let dir = tempdir().unwrap();
-        let checkpoint_path = dir.path().join("test.ckpt");
+        let checkpoint_path = dir.path().join("sparse.ckpt");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(64 * page_size).unwrap();
+        // Only the first page is touched, the rest becomes holes
+        buffer[..page_size].fill(0x11);
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + buffer.len() as u64,
+            AllocationType::Managed,
+        ));
+
+        let ckpt_metadata = BarSlidingCheckpoint::new()
+            .with_sparse(true)
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+        assert_eq!(ckpt_metadata.size_bytes, buffer.len() as u64);
+
+        let file_metadata = std::fs::metadata(&checkpoint_path).unwrap();
+        assert!(file_metadata.len() > buffer.len() as u64);
+        assert!(file_metadata.blocks() * 512 < file_metadata.len());
+
+        buffer[..page_size].fill(0x33);
+        buffer[10 * page_size..11 * page_size].fill(0x44);
+
+        let restore_metadata = BarRestore::new()
+            .restore_from_checkpoint(&checkpoint_path, Some(pid))
+            .unwrap();
+        assert_eq!(restore_metadata.total_size, buffer.len() as u64);
+
+        // Data is restored and holes are zeroed rather than left untouched
+        assert!(buffer[..page_size].iter().all(|&b| b == 0x11));
+        assert!(buffer[page_size..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_compressed_roundtrip() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("compressed.ckpt");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(1024 * page_size).unwrap();
+        buffer[..page_size].fill(0x11);
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + buffer.len() as u64,
+            AllocationType::Standard,
+        ));
+
+        let metadata = BarSlidingCheckpoint::new()
+            .with_window_size(64 * page_size)
+            .with_compression(Compression::deflate(6).unwrap())
+            .with_compression_threads(4)
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+        let compression = metadata.compression.unwrap();
+        assert_eq!(compression.raw_bytes, buffer.len() as u64);
+
+        // Zeros compress to almost nothing
+        let file_size = std::fs::metadata(&checkpoint_path).unwrap().len();
+        assert!(
+            file_size < buffer.len() as u64 / 100,
+            "{file_size} bytes for a {} byte allocation",
+            buffer.len()
+        );
+
+        buffer[..page_size].fill(0x33);
+        buffer[100 * page_size..101 * page_size].fill(0x44);
+
+        let restore_metadata = BarRestore::new()
+            .restore_from_checkpoint(&checkpoint_path, Some(pid))
+            .unwrap();
+        assert_eq!(restore_metadata.total_size, buffer.len() as u64);
+        assert!(buffer[..page_size].iter().all(|&b| b == 0x11));
+        assert!(buffer[page_size..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_deduplicated_roundtrip() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("dedup.ckpt");
+
+        // Blocks of a few distinct patterns, repeated within and across
+        // allocations
+        const BLOCK: usize = 4 * 1024 * 1024;
+        let patterns: [&[u8]; 4] = [b"A", b"B", b"", b"C"];
+        let fill = |block: &mut [u8], pattern: &[u8]| match pattern {
+            [] => block.fill(0),
+            _ => block
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, byte)| *byte = pattern[0].wrapping_add((i % 251) as u8)),
+        };
+        let layouts: [&[usize]; 2] = [&[0, 1, 0, 1, 2, 2, 0, 3], &[1, 2, 3, 0]];
+        let mut buffers: Vec<_> = layouts
+            .iter()
+            .map(|layout| memmap2::MmapMut::map_anon(layout.len() * BLOCK).unwrap())
+            .collect();
+        for (buffer, layout) in buffers.iter_mut().zip(layouts) {
+            for (block, &pattern) in buffer.chunks_mut(BLOCK).zip(layout) {
+                fill(block, patterns[pattern]);
+            }
+        }
+        let expected: Vec<Vec<u8>> = buffers.iter().map(|buffer| buffer.to_vec()).collect();
+
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        for buffer in &buffers {
+            let start = buffer.as_ptr() as u64;
+            detection.add_allocation(GpuAllocation::new(
+                start,
+                start + buffer.len() as u64,
+                AllocationType::Standard,
+            ));
+        }
+
+        let metadata = BarSlidingCheckpoint::new()
+            .with_window_size(BLOCK)
+            .with_dedup(true)
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+
+        // Only the four distinct blocks are stored
+        assert_eq!(detection.total_gpu_memory, 12 * BLOCK as u64);
+        assert_eq!(metadata.size_bytes, 4 * BLOCK as u64);
+        let file_size = std::fs::metadata(&checkpoint_path).unwrap().len();
+        assert!(file_size < 5 * BLOCK as u64, "{file_size} bytes stored");
+
+        let mut file = File::open(&checkpoint_path).unwrap();
+        let header = CheckpointHeader::read_from(&mut file).unwrap();
+        assert!(header.deduplicated);
+        assert_eq!(header.total_size, 4 * BLOCK as u64);
+
+        for buffer in &mut buffers {
+            buffer.fill(0xee);
+        }
+        let restore_metadata = BarRestore::new()
+            .restore_from_checkpoint(&checkpoint_path, Some(pid))
+            .unwrap();
+        assert_eq!(restore_metadata.applied.len(), 2);
+        for (buffer, expected) in buffers.iter().zip(&expected) {
+            assert!(buffer[..] == expected[..], "restored contents differ");
+        }
+    }
+
+    #[test]
+    fn test_interrupted_restore_resumes() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("resume.ckpt");
+        let journal_path = RestoreJournal::path_for(&checkpoint_path);
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut first = memmap2::MmapMut::map_anon(2 * page_size).unwrap();
+        let mut second = memmap2::MmapMut::map_anon(2 * page_size).unwrap();
+        first.fill(0x11);
+        second.fill(0x22);
+
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        for buffer in [&first, &second] {
+            let start = buffer.as_ptr() as u64;
+            detection.add_allocation(GpuAllocation::new(
+                start,
+                start + buffer.len() as u64,
+                AllocationType::Standard,
+            ));
+        }
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+        let contents = std::fs::read(&checkpoint_path).unwrap();
+
+        // Interrupt the restore partway through the second record
+        let inspection = crate::restore::inspect_checkpoint(&checkpoint_path).unwrap();
+        let (done, interrupted) = (&inspection.allocations[0], &inspection.allocations[1]);
+        std::fs::write(
+            &checkpoint_path,
+            &contents[..interrupted.data_offset as usize + 100],
+        )
+        .unwrap();
+        first.fill(0);
+        second.fill(0);
+        assert!(BarRestore::new()
+            .restore_from_checkpoint(&checkpoint_path, Some(pid))
+            .is_err());
+
+        let header = CheckpointHeader::read_from(&mut contents.as_slice()).unwrap();
+        let journal = RestoreJournal::load(&journal_path, &header, pid)
+            .unwrap()
+            .unwrap();
+        assert_eq!(journal.completed, vec![done.header.vaddr_start]);
+        assert_eq!(journal.offset, interrupted.offset);
+
+        // The second run only restores what is left
+        std::fs::write(&checkpoint_path, &contents).unwrap();
+        let (done_buffer, remaining_buffer) = if done.header.vaddr_start == first.as_ptr() as u64 {
+            (&mut first, &mut second)
+        } else {
+            (&mut second, &mut first)
+        };
+        done_buffer.fill(0);
+        remaining_buffer.fill(0);
+        let metadata = BarRestore::new()
+            .restore_from_checkpoint(&checkpoint_path, Some(pid))
+            .unwrap();
+        assert_eq!(metadata.previously_restored, vec![done.header.vaddr_start]);
+        assert_eq!(metadata.applied, vec![interrupted.header.vaddr_start]);
+        assert!(done_buffer.iter().all(|&b| b == 0));
+        assert!(remaining_buffer.iter().all(|&b| b != 0));
+        assert!(!journal_path.exists());
+    }
+
+    #[test]
+    fn test_encrypted_roundtrip() {
+        let dir = tempdir().unwrap();
+        let key = EncryptionKey::new(&[0x5a; 32]);
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(16 * page_size).unwrap();
+        let pattern = b"proprietary weights ";
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = pattern[i % pattern.len()];
+        }
+        let contents = buffer.to_vec();
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + buffer.len() as u64,
+            AllocationType::Standard,
+        ));
+
+        for compression in [Compression::None, Compression::deflate(6).unwrap()] {
+            let checkpoint_path = dir.path().join("encrypted.ckpt");
+            BarSlidingCheckpoint::new()
+                .with_window_size(4 * page_size)
+                .with_compression(compression)
+                .with_encryption_key(key.clone())
+                .checkpoint_process(pid, &detection, &checkpoint_path)
+                .unwrap();
+
+            let stored = std::fs::read(&checkpoint_path).unwrap();
+            let header = CheckpointHeader::read_from(&mut stored.as_slice()).unwrap();
+            assert!(header.encrypted);
+            assert!(!stored.windows(pattern.len()).any(|w| w == pattern));
+
+            buffer.fill(0);
+            BarRestore::new()
+                .with_encryption_key(key.clone())
+                .restore_from_checkpoint(&checkpoint_path, Some(pid))
+                .unwrap();
+            assert!(buffer[..] == contents[..]);
+        }
+    }
+
+    #[test]
+    fn test_encrypted_restore_needs_the_right_key() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("encrypted.ckpt");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(2 * page_size).unwrap();
+        buffer.fill(0x77);
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + buffer.len() as u64,
+            AllocationType::Standard,
+        ));
+        BarSlidingCheckpoint::new()
+            .with_encryption_key(EncryptionKey::new(&[1; 32]))
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+        buffer.fill(0);
+
+        for restore in [
+            BarRestore::new().with_encryption_key(EncryptionKey::new(&[2; 32])),
+            BarRestore::new(),
+        ] {
+            let err = restore
+                .restore_from_checkpoint(&checkpoint_path, Some(pid))
+                .unwrap_err();
+            assert!(
+                matches!(err, GpuCheckpointError::RestoreError(_)),
+                "unexpected error: {err}"
+            );
+        }
+        // Nothing unauthenticated reached the allocation
+        assert!(buffer.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_process_vm_roundtrip() {
+        let dir = tempdir().unwrap();
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(4 * page_size).unwrap();
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = (i % 253) as u8;
+        }
+        let expected = buffer.to_vec();
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + buffer.len() as u64,
+            AllocationType::Standard,
+        ));
+
+        // Small windows so the data moves in several syscalls
+        let checkpoints: Vec<Vec<u8>> = [false, true]
+            .into_iter()
+            .map(|process_vm| {
+                let path = dir.path().join(format!("vm-{process_vm}.ckpt"));
+                BarSlidingCheckpoint::new()
+                    .with_window_size(page_size)
+                    .with_process_vm(process_vm)
+                    .checkpoint_process(pid, &detection, &path)
+                    .unwrap();
+                std::fs::read(&path).unwrap()
+            })
+            .collect();
+        // Identical apart from the header's timestamp and checkpoint ID
+        assert_eq!(
+            checkpoints[0][CHECKPOINT_HEADER_SIZE as usize..],
+            checkpoints[1][CHECKPOINT_HEADER_SIZE as usize..]
+        );
+
+        buffer.fill(0);
+        BarRestore::new()
+            .with_process_vm(true)
+            .restore_from_checkpoint(&dir.path().join("vm-true.ckpt"), None)
+            .unwrap();
+        assert_eq!(buffer[..], expected[..]);
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(8 * page_size).unwrap();
+        // Untouched pages become holes, which the stream writes as zeros
+        buffer[..page_size].fill(0x21);
+        buffer[5 * page_size..6 * page_size].fill(0x43);
+        let expected = buffer.to_vec();
+
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        for half in [0, 4 * page_size as u64] {
+            detection.add_allocation(GpuAllocation::new(
+                start + half,
+                start + half + 4 * page_size as u64,
+                AllocationType::Managed,
+            ));
+        }
+
+        let mut stream = Vec::new();
+        let ckpt_metadata = BarSlidingCheckpoint::new()
+            .with_sparse(true)
+            .with_parallelism(2)
+            .checkpoint_to_writer(pid, &detection, &mut stream)
+            .unwrap();
+        assert_eq!(ckpt_metadata.num_allocations, 2);
+        assert_eq!(ckpt_metadata.path, PathBuf::new());
+        let header = CheckpointHeader::read_from(&mut stream.as_slice()).unwrap();
+        assert_eq!(header.num_allocations, 2);
+        assert_eq!(header.total_size, buffer.len() as u64);
+
+        buffer.fill(0x7f);
+        let restore_metadata = BarRestore::new()
+            .restore_from_stream(&mut std::io::Cursor::new(&stream), None)
+            .unwrap();
+        assert_eq!(restore_metadata.applied.len(), 2);
+        assert_eq!(buffer[..], expected[..]);
+    }
+
+    #[test]
+    fn test_unmapped_ranges() {
+        let mapped = BTreeMap::from([(0x1000, 0x3000), (0x5000, 0x6000), (0x8000, 0x9000)]);
+        assert_eq!(
+            unmapped_ranges(&mapped, 0x3000, 0x5000),
+            vec![(0x3000, 0x5000)]
+        );
+        assert_eq!(
+            unmapped_ranges(&mapped, 0x2000, 0x8000),
+            vec![(0x3000, 0x5000), (0x6000, 0x8000)]
+        );
+        assert_eq!(
+            unmapped_ranges(&mapped, 0x5000, 0xa000),
+            vec![(0x6000, 0x8000), (0x9000, 0xa000)]
+        );
+        assert!(unmapped_ranges(&mapped, 0x1000, 0x3000).is_empty());
+        assert!(unmapped_ranges(&BTreeMap::new(), 0x1000, 0x1000).len() == 1);
+    }
+
+    #[test]
+    fn test_restore_maps_fresh_memory_in_child() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("fresh.ckpt");
+
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(2 * page_size).unwrap();
+        buffer.fill(0x5a);
+        let start = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            start,
+            start + 2 * page_size as u64,
+            AllocationType::Standard,
+        ));
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(pid, &detection, &checkpoint_path)
+            .unwrap();
+
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let child_pid = child.id();
+        // Wait for exec so the restore lands in sleep's address space
+        std::thread::sleep(Duration::from_millis(50));
+
+        // Nothing is mapped at the new address in the child
+        let new_start = 0x1000_0000_0000u64;
+        let restore = |mode| {
+            BarRestore::new()
+                .with_restore_mode(mode)
+                .with_address_translation([(start, new_start)].into_iter().collect())
+                .restore_from_checkpoint(&checkpoint_path, Some(child_pid))
+        };
+        let existing = restore(RestoreMode::Existing).unwrap();
+        let fresh = restore(RestoreMode::MapFresh);
+        let mut restored = vec![0u8; 2 * page_size];
+        let read = File::open(format!("/proc/{child_pid}/mem"))
+            .and_then(|mem| mem.read_exact_at(&mut restored, new_start));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert!(existing.mapped.is_empty());
+        match fresh {
+            Ok(metadata) => {
+                assert_eq!(metadata.mapped, vec![new_start]);
+                read.unwrap();
+                assert!(restored.iter().all(|&b| b == 0x5a));
+            }
+            // ptrace may be disallowed in the test environment
+            Err(GpuCheckpointError::PermissionDenied) => {}
+            Err(e) => panic!("restore into fresh mapping failed: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_restore_leaves_target_stopped() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("empty.ckpt");
+        let detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(1234, &detection, &checkpoint_path)
+            .unwrap();
+
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+
+        let metadata = BarRestore::new()
+            .with_resume_process(false)
+            .restore_from_checkpoint(&checkpoint_path, Some(child.id()))
+            .unwrap();
+        assert!(!metadata.resumed);
+        let state = ProcessScanner::process_state(child.id()).unwrap();
+
+        let metadata = BarRestore::new()
+            .restore_from_checkpoint(&checkpoint_path, Some(child.id()))
+            .unwrap();
+        assert!(metadata.resumed);
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(state.is_stopped());
+    }
+
+    #[test]
+    fn test_check_architecture() {
+        let gpus = [GpuDeviceInfo {
+            device_id: Some(0),
+            model: "NVIDIA A100-SXM4-80GB".to_string(),
+            architecture: Some("Ampere".to_string()),
+            compute_capability: Some("8.0".to_string()),
+        }];
+        let mut warned = BTreeSet::new();
+
+        assert!(!BarRestore::check_architecture(
+            &gpus,
+            0,
+            "Ampere",
+            &mut warned
+        ));
+        assert!(BarRestore::check_architecture(
+            &gpus,
+            0,
+            "Hopper",
+            &mut warned
+        ));
+        // A single GPU is compared even if the recorded device differs
+        assert!(BarRestore::check_architecture(
+            &gpus,
+            3,
+            "Hopper",
+            &mut warned
+        ));
+        assert!(!BarRestore::check_architecture(
+            &[],
+            0,
+            "Hopper",
+            &mut warned
+        ));
+    }
+
+    #[test]
+    fn test_restore_requires_valid_signature() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("signed.ckpt");
+
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            0x100000,
+            0x101000,
+            AllocationType::Standard,
+        ));
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(1234, &detection, &checkpoint_path)
+            .unwrap();
+
+        let key = ed25519_dalek::SigningKey::from_bytes(&[3u8; 32]);
+        let other = ed25519_dalek::SigningKey::from_bytes(&[4u8; 32]);
+
+        // Unsigned checkpoints are rejected when a key is required
+        assert!(BarRestore::new()
+            .with_verifying_key(key.verifying_key())
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .is_err());
+
+        signing::sign_checkpoint(&checkpoint_path, &key).unwrap();
+
+        let restored = BarRestore::new()
+            .with_verifying_key(key.verifying_key())
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .unwrap();
+        assert_eq!(restored.num_allocations, 1);
+
+        assert!(BarRestore::new()
+            .with_verifying_key(other.verifying_key())
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .is_err());
+    }
+
+    #[test]
+    fn test_restore_missing_allocation() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("truncated.ckpt");
 
-        // Create a mock detection result
         let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
         detection.add_allocation(GpuAllocation::new(
             0x100000,
+            0x101000,
+            AllocationType::Standard,
+        ));
+        detection.add_allocation(GpuAllocation::new(
             0x200000,
+            0x201000,
             AllocationType::Standard,
         ));
 
-        // Create checkpoint
         let checkpoint = BarSlidingCheckpoint::new();
-        let ckpt_metadata = checkpoint
+        checkpoint
             .checkpoint_process(1234, &detection, &checkpoint_path)
             .unwrap();
 
-        assert_eq!(ckpt_metadata.num_allocations, 1);
+        // Drop the second allocation; both records after the checkpoint
+        // header are the same size
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&checkpoint_path)
+            .unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - (len - CHECKPOINT_HEADER_SIZE) / 2)
+            .unwrap();
 
-        // Restore checkpoint
         let restore = BarRestore::new();
-        let restore_metadata = restore
+        let err = restore
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("declares 2 allocations but only 1 are present"));
+    }
+
+    #[test]
+    fn test_restore_detects_corruption() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("corrupt.ckpt");
+
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            0x100000,
+            0x101000,
+            AllocationType::Standard,
+        ));
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(1234, &detection, &checkpoint_path)
+            .unwrap();
+        let intact = std::fs::read(&checkpoint_path).unwrap();
+
+        // Flip the last data byte, just before the checksum trailer
+        let mut corrupt = intact.clone();
+        let last_data = corrupt.len() - CHECKSUM_SIZE as usize - 1;
+        corrupt[last_data] ^= 0xff;
+        std::fs::write(&checkpoint_path, &corrupt).unwrap();
+
+        let err = BarRestore::new()
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .unwrap_err();
+        assert!(
+            matches!(&err, GpuCheckpointError::RestoreError(msg)
+                if msg.contains("checksum mismatch for allocation at 0x0000000000100000")),
+            "{err}"
+        );
+
+        // Skipped allocations are still verified
+        let err = BarRestore::new()
+            .with_filter(RestoreFilter {
+                addresses: vec![0x200000],
+                ..Default::default()
+            })
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{err}");
+    }
+
+    #[test]
+    fn test_restore_unchecked_v1_checkpoint() {
+        let dir = tempdir().unwrap();
+        let checkpoint_path = dir.path().join("v1.ckpt");
+
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            0x100000,
+            0x101000,
+            AllocationType::Standard,
+        ));
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(1234, &detection, &checkpoint_path)
+            .unwrap();
+
+        // A version 1 file is the same without the header extension and
+        // the checksum trailer
+        let mut checkpoint = std::fs::read(&checkpoint_path).unwrap();
+        checkpoint.truncate(checkpoint.len() - CHECKSUM_SIZE as usize);
+        checkpoint.drain(32..CHECKPOINT_HEADER_SIZE as usize);
+        checkpoint[4..8].copy_from_slice(&1u32.to_le_bytes());
+        std::fs::write(&checkpoint_path, &checkpoint).unwrap();
+
+        let metadata = BarRestore::new()
             .restore_from_checkpoint(&checkpoint_path, Some(5678))
             .unwrap();
+        assert_eq!(metadata.applied, vec![0x100000]);
 
-        assert_eq!(restore_metadata.num_allocations, 1);
-        assert_eq!(restore_metadata.total_size, ckpt_metadata.size_bytes);
+        checkpoint[4..8].copy_from_slice(&(CHECKPOINT_VERSION + 1).to_le_bytes());
+        std::fs::write(&checkpoint_path, &checkpoint).unwrap();
+        let err = BarRestore::new()
+            .restore_from_checkpoint(&checkpoint_path, Some(5678))
+            .unwrap_err();
+        assert!(err.to_string().contains("Unsupported checkpoint version"));
     }
 }
@@ -0,0 +1,142 @@
+use crate::checkpoint::bar_sliding::RecordedFd;
+use crate::detector::{FileDescriptor, ProcessScanner};
+use std::collections::BTreeMap;
+use tracing::{debug, warn};
+
+/// Translates file descriptors recorded in a checkpoint to the descriptors
+/// of the process being restored into.
+///
+/// A recorded descriptor maps to an unclaimed descriptor of the target that
+/// refers to the same path, or failing that to the same kind of GPU device
+/// (as classified by `ProcessScanner::classify_fd`).
+#[derive(Debug, Default)]
+pub struct FdTranslation {
+    /// Descriptors currently open in the target process
+    current: Vec<FileDescriptor>,
+
+    /// Recorded descriptor -> target descriptor
+    table: BTreeMap<i32, i32>,
+}
+
+impl FdTranslation {
+    pub fn new(current: Vec<FileDescriptor>) -> Self {
+        Self {
+            current,
+            table: BTreeMap::new(),
+        }
+    }
+
+    /// Translation table for the descriptors `pid` currently has open
+    pub fn for_process(pid: u32) -> Self {
+        match ProcessScanner::scan_file_descriptors(pid) {
+            Ok(fds) => Self::new(fds),
+            Err(e) => {
+                debug!("Cannot scan file descriptors of PID {}: {}", pid, e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Target process descriptor corresponding to `recorded`, if any
+    pub fn translate(&mut self, recorded: &RecordedFd) -> Option<i32> {
+        if let Some(fd) = self.table.get(&recorded.fd) {
+            return Some(*fd);
+        }
+
+        let unclaimed =
+            |fd: &&FileDescriptor| !self.table.values().any(|claimed| *claimed == fd.fd);
+        let recorded_type = ProcessScanner::classify_fd(&FileDescriptor {
+            fd: recorded.fd,
+            target: recorded.path.clone(),
+            metadata: None,
+        })
+        .map(|info| info.device_type);
+
+        let matched = self
+            .current
+            .iter()
+            .filter(unclaimed)
+            .find(|fd| fd.target == recorded.path)
+            .or_else(|| {
+                let recorded_type = recorded_type.as_ref()?;
+                self.current.iter().filter(unclaimed).find(|fd| {
+                    ProcessScanner::classify_fd(fd)
+                        .is_some_and(|info| &info.device_type == recorded_type)
+                })
+            })
+            .map(|fd| fd.fd);
+
+        match matched {
+            Some(fd) => {
+                debug!(
+                    "Translated fd {} ({}) to fd {}",
+                    recorded.fd, recorded.path, fd
+                );
+                self.table.insert(recorded.fd, fd);
+            }
+            None => warn!(
+                "No descriptor in the target process matches fd {} ({})",
+                recorded.fd, recorded.path
+            ),
+        }
+
+        matched
+    }
+
+    /// All translations made so far
+    pub fn table(&self) -> &BTreeMap<i32, i32> {
+        &self.table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fd(fd: i32, target: &str) -> FileDescriptor {
+        FileDescriptor {
+            fd,
+            target: target.to_string(),
+            metadata: None,
+        }
+    }
+
+    fn recorded(fd: i32, path: &str) -> RecordedFd {
+        RecordedFd {
+            fd,
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_translate_by_path_then_device_type() {
+        let mut translation = FdTranslation::new(vec![
+            fd(0, "/dev/null"),
+            fd(9, "/dev/nvidia0"),
+            fd(10, "/dev/nvidia-uvm"),
+            fd(11, "/dev/nvidia2"),
+        ]);
+
+        // Same path
+        assert_eq!(translation.translate(&recorded(5, "/dev/nvidia0")), Some(9));
+        assert_eq!(
+            translation.translate(&recorded(6, "/dev/nvidia-uvm")),
+            Some(10)
+        );
+        // Different device node of the same kind, /dev/nvidia0 is already claimed
+        assert_eq!(
+            translation.translate(&recorded(7, "/dev/nvidia1")),
+            Some(11)
+        );
+        // Repeated lookups are stable
+        assert_eq!(translation.translate(&recorded(5, "/dev/nvidia0")), Some(9));
+        // Nothing left to match, and non-GPU files only match by path
+        assert_eq!(translation.translate(&recorded(8, "/dev/nvidia3")), None);
+        assert_eq!(translation.translate(&recorded(12, "/tmp/data")), None);
+
+        assert_eq!(
+            translation.table().iter().collect::<Vec<_>>(),
+            vec![(&5, &9), (&6, &10), (&7, &11)]
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! Streaming checkpoints from an HTTP(S) server
+//!
+//! The BAR sliding format is read strictly front to back, so a restore can
+//! consume the body of a plain GET as it arrives instead of downloading the
+//! checkpoint first. `https://` URLs are fetched over TLS (rustls), with
+//! server certificates checked against the bundled web PKI roots.
+
+use crate::{GpuCheckpointError, Result};
+use reqwest::blocking::{Client, Response};
+use reqwest::StatusCode;
+use std::io::{self, Read};
+use std::time::Duration;
+use tracing::debug;
+
+/// How long to wait for the connection to the server
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a read may stall before the transfer is considered dead
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Whether `source` names a remote checkpoint rather than a local path
+pub fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Body of a successful GET, read as it arrives
+pub struct HttpBody {
+    inner: Response,
+
+    /// Bytes still expected according to `Content-Length`, if sent
+    remaining: Option<u64>,
+}
+
+impl HttpBody {
+    /// Send a GET for `url` and return its body once the server answered
+    /// with `200 OK`
+    pub fn get(url: &str) -> Result<Self> {
+        if !is_url(url) {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "{url} is not an http:// or https:// URL"
+            )));
+        }
+
+        // The timeout bounds each read of the body, not the whole transfer
+        let client = Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(READ_TIMEOUT)
+            .user_agent(concat!("gpu-checkpoint/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| restore_error(url, &e))?;
+        let inner = client.get(url).send().map_err(|e| restore_error(url, &e))?;
+        if inner.status() != StatusCode::OK {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "GET {url}: server answered {}",
+                inner.status()
+            )));
+        }
+
+        let remaining = inner.content_length();
+        debug!(
+            "Streaming checkpoint from {} ({})",
+            url,
+            remaining.map_or("unknown length".to_string(), |len| format!("{len} bytes"))
+        );
+
+        Ok(Self { inner, remaining })
+    }
+
+    /// Bytes still expected, if the server announced the length
+    pub fn remaining(&self) -> Option<u64> {
+        self.remaining
+    }
+}
+
+impl Read for HttpBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = match self.remaining {
+            Some(0) => return Ok(0),
+            Some(remaining) => buf
+                .len()
+                .min(usize::try_from(remaining).unwrap_or(usize::MAX)),
+            None => buf.len(),
+        };
+
+        // Not UnexpectedEof: that would read as a truncated checkpoint
+        // rather than a dropped connection
+        let incomplete = |remaining: u64, cause: String| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("incomplete transfer, {remaining} bytes missing{cause}"),
+            )
+        };
+        let n = match (self.inner.read(&mut buf[..len]), self.remaining) {
+            (Ok(n), _) => n,
+            (Err(e), Some(remaining)) => return Err(incomplete(remaining, format!(": {e}"))),
+            (Err(e), None) => return Err(e),
+        };
+        if let Some(remaining) = &mut self.remaining {
+            if n == 0 {
+                return Err(incomplete(*remaining, String::new()));
+            }
+            *remaining -= n as u64;
+        }
+        Ok(n)
+    }
+}
+
+/// Describe a failed request with the errors that caused it, which say why
+/// e.g. a connection or TLS handshake failed
+fn restore_error(url: &str, e: &dyn std::error::Error) -> GpuCheckpointError {
+    let mut message = format!("transfer of {url} failed: {e}");
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    GpuCheckpointError::RestoreError(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Serve one connection with `response`. Returns the URL to request and
+    /// a handle yielding the request line that was received.
+    fn serve_once(response: Vec<u8>) -> (String, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/checkpoints/test.ckpt",
+            listener.local_addr().unwrap()
+        );
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            // Drain the rest of the request head
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            (&stream).write_all(&response).unwrap();
+            request_line
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn test_is_url() {
+        assert!(is_url("http://store:8080/a/b.ckpt"));
+        assert!(is_url("https://store/a.ckpt"));
+        assert!(!is_url("/tmp/gpu-checkpoint/checkpoint_1234.json"));
+        assert!(!is_url("s3://bucket/checkpoint_1234.bin"));
+        assert!(HttpBody::get("ftp://store/a.ckpt").is_err());
+    }
+
+    #[test]
+    fn test_https_uses_tls() {
+        // A plaintext server cannot complete the TLS handshake
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://{}/test.ckpt", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            (&stream)
+                .write_all(b"HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello")
+                .unwrap();
+        });
+
+        let err = HttpBody::get(&url).err().unwrap().to_string();
+        assert!(err.contains(&format!("transfer of {url} failed")), "{err}");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_get_streams_body() {
+        let (url, server) =
+            serve_once(b"HTTP/1.0 200 OK\r\nContent-Length: 5\r\n\r\nhello".to_vec());
+
+        let mut body = HttpBody::get(&url).unwrap();
+        assert_eq!(body.remaining(), Some(5));
+        let mut data = String::new();
+        body.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello");
+
+        assert_eq!(
+            server.join().unwrap().trim_end(),
+            "GET /checkpoints/test.ckpt HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn test_restore_from_url() {
+        use crate::checkpoint::bar_sliding::BarSlidingCheckpoint;
+        use crate::detector::{AllocationType, DetectionResult, GpuAllocation, GpuVendor};
+        use crate::restore::BarRestore;
+
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint_path = dir.path().join("remote.ckpt");
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            0x100000,
+            0x102000,
+            AllocationType::Standard,
+        ));
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(1234, &detection, &checkpoint_path)
+            .unwrap();
+        let checkpoint = std::fs::read(&checkpoint_path).unwrap();
+
+        let response = |body: &[u8], len: usize| {
+            let mut response =
+                format!("HTTP/1.0 200 OK\r\nContent-Length: {len}\r\n\r\n").into_bytes();
+            response.extend_from_slice(body);
+            response
+        };
+
+        let (url, server) = serve_once(response(&checkpoint, checkpoint.len()));
+        let metadata = BarRestore::new()
+            .restore_from_url(&url, Some(5678))
+            .unwrap();
+        assert_eq!(metadata.applied, vec![0x100000]);
+        assert_eq!(metadata.total_size, 0x2000);
+        server.join().unwrap();
+
+        // The connection drops halfway through the allocation data
+        let (url, server) = serve_once(response(
+            &checkpoint[..checkpoint.len() - 0x1000],
+            checkpoint.len(),
+        ));
+        let err = BarRestore::new()
+            .restore_from_url(&url, Some(5678))
+            .unwrap_err();
+        assert!(
+            matches!(&err, GpuCheckpointError::RestoreError(msg) if msg.contains("incomplete transfer")),
+            "{err}"
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_get_reports_errors() {
+        let (url, server) = serve_once(b"HTTP/1.0 404 Not Found\r\n\r\n".to_vec());
+        let err = HttpBody::get(&url).err().unwrap().to_string();
+        assert!(err.contains("404 Not Found"), "{err}");
+        server.join().unwrap();
+
+        let (url, server) =
+            serve_once(b"HTTP/1.0 200 OK\r\nContent-Length: 10\r\n\r\nhello".to_vec());
+        let mut body = HttpBody::get(&url).unwrap();
+        server.join().unwrap();
+        let err = body.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(err.to_string().contains("5 bytes missing"));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::checkpoint::bar_sliding::{AllocationDescriptor, AllocationHeader, CheckpointHeader};
+use crate::checkpoint::signing::SIGNATURE_TRAILER_LEN;
+use crate::restore::BarRestore;
+use crate::utils::{compression, encryption};
+use crate::Result;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Structure of a binary checkpoint file, parsed without touching any
+/// process
+#[derive(Debug, Serialize)]
+pub struct CheckpointInspection {
+    pub path: PathBuf,
+    pub file_size: u64,
+
+    /// `None` if the file is too short to hold a header
+    pub header: Option<CheckpointHeader>,
+    pub allocations: Vec<AllocationRecord>,
+
+    /// Bytes after the last record, e.g. a signature trailer
+    pub trailing_bytes: u64,
+
+    /// Whether every declared allocation was parsed
+    pub complete: bool,
+
+    /// File offset parsing stopped at and why, if it did not complete
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stopped_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// One allocation record of a checkpoint file
+#[derive(Debug, Serialize)]
+pub struct AllocationRecord {
+    /// File offset of the allocation header
+    pub offset: u64,
+    pub header: AllocationHeader,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub descriptor: Option<AllocationDescriptor>,
+
+    /// File offset and length of the allocation's data section
+    pub data_offset: u64,
+    pub stored_size: u64,
+}
+
+impl CheckpointInspection {
+    /// Everything that makes the file unfit for restore: parsing errors,
+    /// allocations whose size disagrees with their address range, and
+    /// bytes after the last record that are not a signature trailer
+    pub fn inconsistencies(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if let (Some(offset), Some(error)) = (self.stopped_at, &self.error) {
+            problems.push(format!("parsing stopped at offset {offset}: {error}"));
+        }
+
+        for (idx, record) in self.allocations.iter().enumerate() {
+            let header = &record.header;
+            let span = header.vaddr_end.checked_sub(header.vaddr_start);
+            if span != Some(header.size) {
+                problems.push(format!(
+                    "allocation {idx} at 0x{:016x}: size {} does not match its range \
+                     0x{:016x}-0x{:016x}",
+                    header.vaddr_start, header.size, header.vaddr_start, header.vaddr_end
+                ));
+            }
+        }
+
+        if self.complete && self.trailing_bytes != 0 && self.trailing_bytes != SIGNATURE_TRAILER_LEN
+        {
+            problems.push(format!(
+                "{} unexpected bytes after the last allocation",
+                self.trailing_bytes
+            ));
+        }
+
+        problems
+    }
+}
+
+/// Parse the header and every allocation record of `path`, skipping over
+/// allocation data. Parsing a truncated or corrupt file stops at the first
+/// record that cannot be read; everything before it is still reported.
+pub fn inspect_checkpoint(path: &Path) -> Result<CheckpointInspection> {
+    let mut file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let reader = BarRestore::new();
+
+    let mut inspection = CheckpointInspection {
+        path: path.to_path_buf(),
+        file_size,
+        header: None,
+        allocations: Vec::new(),
+        trailing_bytes: 0,
+        complete: false,
+        stopped_at: None,
+        error: None,
+    };
+
+    let header = match CheckpointHeader::read_from(&mut file)
+        .and_then(|header| header.validate().map(|()| header))
+    {
+        Ok(header) => header,
+        Err(e) => {
+            inspection.stopped_at = Some(0);
+            inspection.error = Some(e.to_string());
+            return Ok(inspection);
+        }
+    };
+    let num_allocations = header.num_allocations;
+    let compression = header.compression;
+    let encrypted = header.encrypted;
+    // The checksum of an encrypted record is inside its last frame
+    let checksum_size = if encrypted { 0 } else { header.checksum_size() };
+    inspection.header = Some(header);
+
+    for idx in 0..num_allocations {
+        let offset = file.stream_position()?;
+        let record = AllocationHeader::read_from(&mut file).and_then(|header| {
+            let descriptor = reader.read_allocation_descriptor(&mut file, &header)?;
+            Ok((header, descriptor))
+        });
+
+        let (header, descriptor) = match record {
+            Ok(record) => record,
+            Err(e) => {
+                inspection.stopped_at = Some(offset);
+                inspection.error = Some(format!("allocation {idx}: {e}"));
+                return Ok(inspection);
+            }
+        };
+
+        let data_offset = file.stream_position()?;
+        let stored_size = descriptor
+            .as_ref()
+            .map_or(header.size, |d| d.stored_size(header.size));
+        let data_len = if encrypted {
+            encryption::skip_frames(&mut file)
+        } else if compression.is_enabled() {
+            compression::skip_frames(&mut file, stored_size)
+        } else {
+            Ok(stored_size)
+        };
+
+        inspection.allocations.push(AllocationRecord {
+            offset,
+            header,
+            descriptor,
+            data_offset,
+            stored_size,
+        });
+
+        let data_len = match data_len {
+            Ok(len) => len,
+            Err(e) => {
+                inspection.stopped_at = Some(file.stream_position()?);
+                inspection.error = Some(format!("allocation {idx}: {e}"));
+                return Ok(inspection);
+            }
+        };
+        let data_end = data_offset
+            .saturating_add(data_len)
+            .saturating_add(checksum_size);
+        let truncated = data_end > file_size;
+
+        if truncated {
+            inspection.stopped_at = Some(file_size);
+            inspection.error = Some(format!(
+                "allocation {idx}: data ends at {data_end} but the file is {file_size} bytes"
+            ));
+            return Ok(inspection);
+        }
+        file.seek(SeekFrom::Start(data_end))?;
+    }
+
+    inspection.trailing_bytes = file_size - file.stream_position()?;
+    inspection.complete = true;
+    Ok(inspection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::bar_sliding::{BarSlidingCheckpoint, CHECKPOINT_HEADER_SIZE};
+    use crate::detector::{AllocationType, DetectionResult, GpuAllocation, GpuVendor};
+    use crate::utils::encryption::EncryptionKey;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_inspect_complete_and_truncated() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("inspect.ckpt");
+
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(0x100000, 0x101000, AllocationType::Uvm));
+        detection.add_allocation(GpuAllocation::new(0x200000, 0x202000, AllocationType::Uvm));
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(1234, &detection, &path)
+            .unwrap();
+
+        let inspection = inspect_checkpoint(&path).unwrap();
+        assert!(inspection.complete);
+        assert_eq!(inspection.header.as_ref().unwrap().pid, 1234);
+        assert_eq!(inspection.allocations.len(), 2);
+        assert_eq!(inspection.allocations[0].offset, CHECKPOINT_HEADER_SIZE);
+        assert_eq!(inspection.allocations[1].header.vaddr_start, 0x200000);
+        assert_eq!(inspection.allocations[1].stored_size, 0x2000);
+        assert_eq!(inspection.trailing_bytes, 0);
+
+        // Cut the file in the middle of the second allocation's data
+        let second = &inspection.allocations[1];
+        let cut = second.data_offset + 100;
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(cut)
+            .unwrap();
+
+        let truncated = inspect_checkpoint(&path).unwrap();
+        assert!(!truncated.complete);
+        assert_eq!(truncated.allocations.len(), 2);
+        assert_eq!(truncated.stopped_at, Some(cut));
+
+        // ...and inside the second allocation header
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(second.offset + 10)
+            .unwrap();
+
+        let truncated = inspect_checkpoint(&path).unwrap();
+        assert_eq!(truncated.allocations.len(), 1);
+        assert_eq!(truncated.stopped_at, Some(second.offset));
+        assert!(truncated.error.is_some());
+        assert_eq!(truncated.inconsistencies().len(), 1);
+    }
+
+    #[test]
+    fn test_inspect_encrypted() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("encrypted.ckpt");
+
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(0x100000, 0x101000, AllocationType::Uvm));
+        detection.add_allocation(GpuAllocation::new(0x200000, 0x202000, AllocationType::Uvm));
+        BarSlidingCheckpoint::new()
+            .with_encryption_key(EncryptionKey::new(&[7; 32]))
+            .checkpoint_process(1234, &detection, &path)
+            .unwrap();
+
+        // The record layout is readable without the key
+        let inspection = inspect_checkpoint(&path).unwrap();
+        assert!(inspection.complete, "{:?}", inspection.error);
+        assert!(inspection.header.as_ref().unwrap().encrypted);
+        assert_eq!(inspection.allocations.len(), 2);
+        assert_eq!(inspection.trailing_bytes, 0);
+        assert!(inspection.inconsistencies().is_empty());
+    }
+
+    #[test]
+    fn test_inconsistencies() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("verify.ckpt");
+
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(0x100000, 0x101000, AllocationType::Uvm));
+        detection.add_allocation(GpuAllocation::new(0x200000, 0x202000, AllocationType::Uvm));
+        BarSlidingCheckpoint::new()
+            .checkpoint_process(1234, &detection, &path)
+            .unwrap();
+        assert!(inspect_checkpoint(&path)
+            .unwrap()
+            .inconsistencies()
+            .is_empty());
+
+        // A signature trailer is expected after the last record, other
+        // bytes are not
+        let mut file = File::options().append(true).open(&path).unwrap();
+        file.write_all(&[0u8; SIGNATURE_TRAILER_LEN as usize])
+            .unwrap();
+        assert!(inspect_checkpoint(&path)
+            .unwrap()
+            .inconsistencies()
+            .is_empty());
+        file.write_all(b"junk").unwrap();
+        let problems = inspect_checkpoint(&path).unwrap().inconsistencies();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("unexpected bytes"), "{problems:?}");
+
+        // Move the end of the first allocation without touching its size
+        let inspection = inspect_checkpoint(&path).unwrap();
+        let mut file = File::options().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(inspection.allocations[0].offset + 8))
+            .unwrap();
+        file.write_all(&0x100800u64.to_le_bytes()).unwrap();
+        let problems = inspect_checkpoint(&path).unwrap().inconsistencies();
+        assert_eq!(problems.len(), 2);
+        assert!(
+            problems[0].contains("does not match its range"),
+            "{problems:?}"
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Progress journal of a restore from a checkpoint file
+//!
+//! While a checkpoint file is restored, `<checkpoint>.restore-state` records
+//! the allocations restored so far and the file offset of the next record.
+//! A restore that was interrupted resumes from there instead of starting
+//! over; the journal is removed once the restore completes.
+
+use crate::checkpoint::bar_sliding::CheckpointHeader;
+use crate::{GpuCheckpointError, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Suffix appended to the checkpoint path to name its journal
+pub const JOURNAL_SUFFIX: &str = ".restore-state";
+
+/// Allocations of one checkpoint restored into one process so far
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestoreJournal {
+    /// Checkpoint the journal belongs to
+    pub checkpoint_id: u64,
+    pub timestamp: u64,
+
+    /// Process the allocations were restored into
+    pub pid: u32,
+
+    /// Start addresses of the completed allocations, in record order
+    pub completed: Vec<u64>,
+
+    /// File offset of the first record not restored yet
+    pub offset: u64,
+}
+
+impl RestoreJournal {
+    /// Empty journal of restoring the checkpoint with `header` into `pid`,
+    /// whose first record is at `offset`
+    pub fn new(header: &CheckpointHeader, pid: u32, offset: u64) -> Self {
+        Self {
+            checkpoint_id: header.checkpoint_id,
+            timestamp: header.timestamp,
+            pid,
+            completed: Vec::new(),
+            offset,
+        }
+    }
+
+    /// Journal of restoring the checkpoint at `checkpoint`
+    pub fn path_for(checkpoint: &Path) -> PathBuf {
+        let mut path = OsString::from(checkpoint.as_os_str());
+        path.push(JOURNAL_SUFFIX);
+        PathBuf::from(path)
+    }
+
+    /// Journal at `path` left by an interrupted restore of the checkpoint
+    /// with `header` into `pid`. A journal of another checkpoint or process,
+    /// or one that cannot be parsed, is ignored.
+    pub fn load(path: &Path, header: &CheckpointHeader, pid: u32) -> Result<Option<Self>> {
+        let json = match std::fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let journal: Self = match serde_json::from_slice(&json) {
+            Ok(journal) => journal,
+            Err(e) => {
+                warn!(
+                    "Ignoring unreadable restore journal {}: {}",
+                    path.display(),
+                    e
+                );
+                return Ok(None);
+            }
+        };
+
+        let fresh = Self::new(header, pid, 0);
+        if (journal.checkpoint_id, journal.timestamp, journal.pid)
+            != (fresh.checkpoint_id, fresh.timestamp, fresh.pid)
+        {
+            warn!(
+                "Ignoring restore journal {} of another checkpoint or process",
+                path.display()
+            );
+            return Ok(None);
+        }
+        if journal.completed.len() > header.num_allocations as usize {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "restore journal {} records {} allocations but the checkpoint has {}",
+                path.display(),
+                journal.completed.len(),
+                header.num_allocations
+            )));
+        }
+        Ok(Some(journal))
+    }
+
+    /// Record the allocation at `vaddr_start` as restored, with the next
+    /// record at `offset`
+    pub fn complete(&mut self, vaddr_start: u64, offset: u64) {
+        self.completed.push(vaddr_start);
+        self.offset = offset;
+    }
+
+    /// Write the journal to `path`, replacing it atomically so an
+    /// interruption never leaves a partial journal behind
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec(self).map_err(|e| {
+            GpuCheckpointError::RestoreError(format!("Cannot serialize restore journal: {e}"))
+        })?;
+        let mut staging = OsString::from(path.as_os_str());
+        staging.push(".tmp");
+        std::fs::write(&staging, json)?;
+        std::fs::rename(&staging, path)?;
+        Ok(())
+    }
+
+    /// Remove the journal at `path` after a completed restore
+    pub fn remove(path: &Path) -> Result<()> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,23 +1,92 @@
+pub mod addr_remap;
 pub mod bar_restore;
+pub mod fd_remap;
+pub mod http;
+pub mod inspect;
+pub mod journal;
+pub mod spawn;
 
-use crate::checkpoint::CheckpointMetadata;
-use crate::Result;
+use crate::checkpoint::{
+    CheckpointFileFormat, CheckpointMetadata, CheckpointStrategy, CudaCheckpointTool,
+};
+use crate::storage::{S3Config, S3Location, S3Source};
+use crate::{GpuCheckpointError, Result};
+use std::path::{Path, PathBuf};
 
-pub use bar_restore::{BarRestore, RestoreMetadata};
+pub use addr_remap::AddressTranslation;
+pub use bar_restore::{restore_schema, BarRestore, RestoreFilter, RestoreMetadata, RestoreMode};
+pub use fd_remap::FdTranslation;
+pub use inspect::{inspect_checkpoint, CheckpointInspection};
+pub use journal::RestoreJournal;
+pub use spawn::{spawn_target, wait_for_gpu_context};
 
 pub struct RestoreEngine {
-    _storage_path: String,
+    storage_path: String,
 }
 
 impl RestoreEngine {
     pub fn new(storage_path: String) -> Self {
-        Self {
-            _storage_path: storage_path,
+        Self { storage_path }
+    }
+
+    /// Restore the checkpoint `metadata` describes into the process it was
+    /// taken of, returning that PID. The checkpoint is looked up in the
+    /// storage directory under the name it was written with, or
+    /// `checkpoint_<pid>.bin`.
+    pub async fn restore(&self, metadata: &CheckpointMetadata) -> Result<u32> {
+        if let Some(toggle) = &metadata.cuda_toggle {
+            // Toggle the device state back onto the GPU
+            CudaCheckpointTool::at(&toggle.tool).restore(metadata.pid, toggle)?;
+        }
+        // Nothing else was captured by these strategies
+        let bar_data = metadata.path.is_some() || metadata.url.is_some();
+        if metadata.strategy_used == CheckpointStrategy::SkipGpu
+            || (metadata.cuda_toggle.is_some() && !bar_data)
+        {
+            return Ok(metadata.pid);
         }
+
+        let mut restore = BarRestore::new();
+        if let Some(base) = &metadata.base_checkpoint {
+            restore = restore.with_base_checkpoint(self.locate(base));
+        }
+
+        let restored = match &metadata.url {
+            Some(url) => {
+                // The source waits on the download, so the restore runs
+                // where it may block
+                let location = S3Location::parse(url)?;
+                let pid = metadata.pid;
+                tokio::task::spawn_blocking(move || {
+                    let source = S3Source::new(S3Config::from_env(), location)?;
+                    restore.restore_from_source(source, Some(pid))
+                })
+                .await
+                .map_err(|e| {
+                    GpuCheckpointError::RestoreError(format!("download of {url} failed: {e}"))
+                })??
+            }
+            None => {
+                let default = PathBuf::from(format!("checkpoint_{}.bin", metadata.pid));
+                let checkpoint_path = self.locate(metadata.path.as_ref().unwrap_or(&default));
+                if !checkpoint_path.exists() {
+                    return Err(GpuCheckpointError::RestoreError(format!(
+                        "checkpoint of PID {} not found at {}",
+                        metadata.pid,
+                        checkpoint_path.display()
+                    )));
+                }
+                CheckpointFileFormat::detect(&checkpoint_path)?
+                    .implementation()
+                    .restore(&restore, &checkpoint_path, Some(metadata.pid))?
+            }
+        };
+        Ok(restored.pid)
     }
 
-    pub async fn restore(&self, _metadata: &CheckpointMetadata) -> Result<u32> {
-        // This would implement the actual restore
-        todo!("Implement restore")
+    /// File of the name of `path` in the storage directory
+    fn locate(&self, path: &Path) -> PathBuf {
+        let name = path.file_name().map_or(path.as_os_str(), |name| name);
+        Path::new(&self.storage_path).join(name)
     }
 }
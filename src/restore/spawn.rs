@@ -0,0 +1,73 @@
+//! Relaunching a checkpointed process as the target of its restore
+//!
+//! A checkpoint's manifest records the command line the process was started
+//! with. `restore --spawn` starts it again, waits for the new process to
+//! create its GPU context, and restores into it instead of the original PID.
+
+use crate::checkpoint::LaunchCommand;
+use crate::detector::CompositeDetector;
+use crate::{GpuCheckpointError, Result};
+use std::collections::BTreeMap;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// How often a spawned process is checked for GPU mappings
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Start `command` again, in its recorded working directory, with the
+/// current environment plus `environment` (the GPU variables captured at
+/// checkpoint time)
+pub fn spawn_target(
+    command: &LaunchCommand,
+    environment: &BTreeMap<String, String>,
+) -> Result<Child> {
+    let Some((program, args)) = command.argv.split_first() else {
+        return Err(GpuCheckpointError::RestoreError(
+            "the checkpoint does not record a command line to spawn".to_string(),
+        ));
+    };
+
+    let mut spawn = Command::new(program);
+    spawn.args(args).envs(environment);
+    if let Some(cwd) = command.cwd.as_ref().filter(|cwd| cwd.is_dir()) {
+        spawn.current_dir(cwd);
+    }
+    let child = spawn
+        .spawn()
+        .map_err(|e| GpuCheckpointError::RestoreError(format!("Cannot spawn {program}: {e}")))?;
+    info!("Spawned {} as PID {}", command.argv.join(" "), child.id());
+    Ok(child)
+}
+
+/// Wait up to `timeout` for `child` to map GPU memory that `detector` finds.
+/// Returns whether it did; fails if the child exits first.
+pub fn wait_for_gpu_context(
+    child: &mut Child,
+    detector: &CompositeDetector,
+    timeout: Duration,
+) -> Result<bool> {
+    let pid = child.id();
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(GpuCheckpointError::RestoreError(format!(
+                "spawned process {pid} exited ({status}) before it could be restored"
+            )));
+        }
+
+        match detector.detect_all(pid) {
+            Ok(detections) if detections.results.iter().any(|r| !r.allocations.is_empty()) => {
+                debug!("PID {} has mapped its GPU context", pid);
+                return Ok(true);
+            }
+            Ok(_) => {}
+            Err(e) => debug!("Cannot detect GPU mappings of PID {}: {}", pid, e),
+        }
+
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
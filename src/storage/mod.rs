@@ -0,0 +1,374 @@
+//! Where checkpoints are written to and read back from
+//!
+//! The BAR sliding format is written and read strictly front to back, so a
+//! checkpoint can be handed to a `CheckpointSink` a chunk at a time as
+//! windows are copied, and restored from a `CheckpointSource` as chunks
+//! arrive, without staging it in a local file. Local files and
+//! S3-compatible object stores are supported.
+
+pub mod s3;
+
+pub use s3::{S3Config, S3Location, S3Sink, S3Source};
+
+use crate::{GpuCheckpointError, Result};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Bytes an object store upload sends per part, and a `FileSource` reads
+/// at a time
+pub const CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Destination of a checkpoint written front to back
+pub trait CheckpointSink {
+    /// Append `chunk` to the checkpoint
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<()>;
+
+    /// Make the checkpoint durable and visible once every chunk is written.
+    /// A sink dropped without being finalized discards what it was given.
+    fn finalize(&mut self) -> Result<()>;
+
+    /// Where the finished checkpoint can be read back from
+    fn location(&self) -> String;
+}
+
+/// Origin of a checkpoint read front to back
+pub trait CheckpointSource {
+    /// Next chunk of the checkpoint, empty once all of it has been read
+    fn read_chunk(&mut self) -> Result<Vec<u8>>;
+}
+
+/// Whether `storage` names an object store location rather than a local
+/// path
+pub fn is_object_url(storage: &str) -> bool {
+    storage.starts_with(s3::SCHEME)
+}
+
+/// `Write` over a sink, handing it each write as a chunk. The error a sink
+/// failed with is kept, as `io::Error` would lose its kind.
+pub struct SinkWriter<S> {
+    sink: S,
+    error: Option<GpuCheckpointError>,
+}
+
+impl<S: CheckpointSink> SinkWriter<S> {
+    pub fn new(sink: S) -> Self {
+        Self { sink, error: None }
+    }
+
+    /// Error the sink failed with, in place of `e` it surfaced as
+    pub fn take_error(&mut self, e: GpuCheckpointError) -> GpuCheckpointError {
+        self.error.take().unwrap_or(e)
+    }
+
+    pub fn into_inner(self) -> S {
+        self.sink
+    }
+}
+
+impl<S: CheckpointSink> Write for SinkWriter<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Err(e) = self.sink.write_chunk(buf) {
+            let message = e.to_string();
+            self.error = Some(e);
+            return Err(io::Error::other(message));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `Read` over a source, a chunk at a time
+pub struct SourceReader<S> {
+    source: S,
+    chunk: Vec<u8>,
+    position: usize,
+    finished: bool,
+    error: Option<GpuCheckpointError>,
+}
+
+impl<S: CheckpointSource> SourceReader<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            chunk: Vec::new(),
+            position: 0,
+            finished: false,
+            error: None,
+        }
+    }
+
+    /// Error the source failed with, in place of `e` it surfaced as
+    pub fn take_error(&mut self, e: GpuCheckpointError) -> GpuCheckpointError {
+        self.error.take().unwrap_or(e)
+    }
+}
+
+impl<S: CheckpointSource> Read for SourceReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            match self.source.read_chunk() {
+                Ok(chunk) => {
+                    self.finished = chunk.is_empty();
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                Err(e) => {
+                    let message = e.to_string();
+                    self.error = Some(e);
+                    return Err(io::Error::other(message));
+                }
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Checkpoint written to a local file. Data goes to `<path>.partial`, which
+/// is renamed into place once finalized.
+pub struct FileSink {
+    path: PathBuf,
+    partial: PathBuf,
+    file: Option<BufWriter<File>>,
+}
+
+impl FileSink {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        let file = File::create(&partial)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            partial,
+            file: Some(BufWriter::new(file)),
+        })
+    }
+
+    fn file(&mut self) -> Result<&mut BufWriter<File>> {
+        self.file.as_mut().ok_or_else(|| {
+            GpuCheckpointError::CheckpointError(format!(
+                "{} is already finalized",
+                self.path.display()
+            ))
+        })
+    }
+}
+
+impl CheckpointSink for FileSink {
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        self.file()?.write_all(chunk)?;
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        let file = self.file()?;
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        self.file = None;
+        std::fs::rename(&self.partial, &self.path)?;
+        Ok(())
+    }
+
+    fn location(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+impl Drop for FileSink {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            std::fs::remove_file(&self.partial).ok();
+        }
+    }
+}
+
+/// Checkpoint read from a local file
+pub struct FileSource {
+    file: File,
+    chunk_size: usize,
+}
+
+impl FileSource {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+            chunk_size: CHUNK_SIZE,
+        })
+    }
+
+    /// Read chunks of `chunk_size` bytes instead of `CHUNK_SIZE`
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+}
+
+impl CheckpointSource for FileSource {
+    fn read_chunk(&mut self) -> Result<Vec<u8>> {
+        let mut chunk = Vec::with_capacity(self.chunk_size);
+        (&mut self.file)
+            .take(self.chunk_size as u64)
+            .read_to_end(&mut chunk)?;
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::bar_sliding::BarSlidingCheckpoint;
+    use crate::detector::{AllocationType, DetectionResult, GpuAllocation, GpuVendor};
+    use crate::restore::BarRestore;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    /// Chunks shared between a `MemorySink` and the `MemorySource` reading
+    /// them back
+    type Chunks = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    struct MemorySink {
+        chunks: Chunks,
+        finalized: bool,
+    }
+
+    impl CheckpointSink for MemorySink {
+        fn write_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+            assert!(!self.finalized);
+            self.chunks.lock().unwrap().push(chunk.to_vec());
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> Result<()> {
+            self.finalized = true;
+            Ok(())
+        }
+
+        fn location(&self) -> String {
+            "memory".to_string()
+        }
+    }
+
+    /// Hands out the stored bytes in chunks of a size unrelated to the
+    /// writes that produced them
+    struct MemorySource {
+        data: VecDeque<u8>,
+        chunk_size: usize,
+    }
+
+    impl CheckpointSource for MemorySource {
+        fn read_chunk(&mut self) -> Result<Vec<u8>> {
+            let n = self.chunk_size.min(self.data.len());
+            Ok(self.data.drain(..n).collect())
+        }
+    }
+
+    fn detection() -> DetectionResult {
+        let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            0x100000,
+            0x104000,
+            AllocationType::Standard,
+        ));
+        detection.add_allocation(GpuAllocation::new(
+            0x200000,
+            0x201000,
+            AllocationType::Standard,
+        ));
+        detection
+    }
+
+    #[test]
+    fn test_memory_roundtrip() {
+        let chunks = Chunks::default();
+        let sink = MemorySink {
+            chunks: chunks.clone(),
+            finalized: false,
+        };
+        let metadata = BarSlidingCheckpoint::new()
+            .with_progress(false)
+            .checkpoint_to_sink(1234, &detection(), sink)
+            .unwrap();
+        assert_eq!(metadata.size_bytes, 0x5000);
+
+        // Windows are pushed as they are copied, not once at the end
+        let chunks = chunks.lock().unwrap();
+        assert!(chunks.len() > 2, "{} chunks", chunks.len());
+        let data: VecDeque<u8> = chunks.concat().into();
+
+        for chunk_size in [1000, 4096, CHUNK_SIZE] {
+            let source = MemorySource {
+                data: data.clone(),
+                chunk_size,
+            };
+            let restored = BarRestore::new()
+                .restore_from_source(source, Some(5678))
+                .unwrap();
+            assert_eq!(restored.applied, vec![0x100000, 0x200000]);
+            assert_eq!(restored.total_size, 0x5000);
+        }
+
+        // A source that ends early is a truncated checkpoint
+        let source = MemorySource {
+            data: data.iter().take(data.len() - 100).copied().collect(),
+            chunk_size: 4096,
+        };
+        assert!(BarRestore::new()
+            .restore_from_source(source, Some(5678))
+            .is_err());
+    }
+
+    #[test]
+    fn test_file_sink_and_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sink.ckpt");
+
+        // Nothing is left behind by a sink that is never finalized
+        let mut sink = FileSink::create(&path).unwrap();
+        sink.write_chunk(b"partial").unwrap();
+        drop(sink);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let sink = FileSink::create(&path).unwrap();
+        assert_eq!(sink.location(), path.display().to_string());
+        BarSlidingCheckpoint::new()
+            .with_progress(false)
+            .checkpoint_to_sink(1234, &detection(), sink)
+            .unwrap();
+        assert!(path.exists());
+
+        let source = FileSource::open(&path).unwrap().with_chunk_size(777);
+        let restored = BarRestore::new()
+            .restore_from_source(source, Some(5678))
+            .unwrap();
+        assert_eq!(restored.applied, vec![0x100000, 0x200000]);
+    }
+
+    #[test]
+    fn test_source_errors_are_kept() {
+        struct FailingSource;
+        impl CheckpointSource for FailingSource {
+            fn read_chunk(&mut self) -> Result<Vec<u8>> {
+                Err(GpuCheckpointError::RestoreError("bucket gone".to_string()))
+            }
+        }
+
+        let err = BarRestore::new()
+            .restore_from_source(FailingSource, Some(5678))
+            .unwrap_err();
+        assert!(
+            matches!(&err, GpuCheckpointError::RestoreError(msg) if msg == "bucket gone"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,500 @@
+//! S3-compatible object storage
+//!
+//! Requests go through the `object_store` crate, over TLS unless plain HTTP
+//! is allowed explicitly. A checkpoint smaller than `CHUNK_SIZE` is sent
+//! with a single PUT, a larger one as a multipart upload of `CHUNK_SIZE`
+//! parts with at most `MAX_PARTS_IN_FLIGHT` of them uploading at a time, so
+//! memory stays bounded however large the checkpoint. Downloads stream a
+//! single GET.
+//!
+//! The client is async while `CheckpointSink` and `CheckpointSource` are
+//! not: its futures are driven on the runtime of the calling thread, or on
+//! one of the sink's own outside a runtime. The calling thread must not be
+//! an async task, which is why the async engines copy on a blocking thread.
+
+use super::{CheckpointSink, CheckpointSource, CHUNK_SIZE};
+use crate::{GpuCheckpointError, Result};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use object_store::aws::AmazonS3Builder;
+use object_store::buffered::BufWriter;
+use object_store::path::Path as ObjectPath;
+use object_store::ObjectStore;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::{Handle, Runtime};
+use tracing::{info, warn};
+
+/// Scheme of object store locations
+pub const SCHEME: &str = "s3://";
+
+/// Parts of a multipart upload sent concurrently
+const MAX_PARTS_IN_FLIGHT: usize = 4;
+
+/// Variable that has to be `true` to reach an `http://` endpoint
+const ALLOW_HTTP_VAR: &str = "AWS_ALLOW_HTTP";
+
+/// Object a checkpoint is stored in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Location {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl S3Location {
+    /// Parse `s3://bucket/key`. The key may be empty, for a location that
+    /// is only used as a prefix.
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix(SCHEME).ok_or_else(|| {
+            GpuCheckpointError::CheckpointError(format!("{url} is not an {SCHEME} URL"))
+        })?;
+        let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "{url} does not name a bucket"
+            )));
+        }
+        Ok(Self {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+
+    /// The object `name` under this location, treating its key as a prefix
+    pub fn join(&self, name: &str) -> Self {
+        let prefix = self.key.trim_end_matches('/');
+        Self {
+            bucket: self.bucket.clone(),
+            key: if prefix.is_empty() {
+                name.to_string()
+            } else {
+                format!("{prefix}/{name}")
+            },
+        }
+    }
+}
+
+impl fmt::Display for S3Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{SCHEME}{}/{}", self.bucket, self.key)
+    }
+}
+
+/// Endpoint and credentials of an object store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Config {
+    /// `https://host[:port]`, addressed path-style (`/bucket/key`). AWS's
+    /// regional endpoint, addressed virtual-host style, if not set.
+    pub endpoint: Option<String>,
+    pub region: String,
+
+    /// Requests are sent unsigned without credentials, e.g. to a bucket
+    /// that allows anonymous access
+    pub credentials: Option<S3Credentials>,
+
+    /// Whether an `http://` endpoint may be used, sending checkpoint data
+    /// and request signatures in cleartext
+    pub allow_http: bool,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl fmt::Debug for S3Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl S3Config {
+    /// Configuration from the standard AWS variables: `AWS_ENDPOINT_URL_S3`
+    /// or `AWS_ENDPOINT_URL`, `AWS_REGION` or `AWS_DEFAULT_REGION`,
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`,
+    /// and `AWS_ALLOW_HTTP`
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let region = var("AWS_REGION")
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = var("AWS_ENDPOINT_URL_S3").or_else(|| var("AWS_ENDPOINT_URL"));
+        let credentials = match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+            (Some(access_key_id), Some(secret_access_key)) => Some(S3Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token: var("AWS_SESSION_TOKEN"),
+            }),
+            _ => None,
+        };
+
+        Self {
+            endpoint,
+            region,
+            credentials,
+            allow_http: var(ALLOW_HTTP_VAR).is_some_and(|value| value.eq_ignore_ascii_case("true")),
+        }
+    }
+
+    /// Client for `bucket`
+    pub fn store(&self, bucket: &str) -> Result<Arc<dyn ObjectStore>> {
+        let mut builder = AmazonS3Builder::new()
+            .with_bucket_name(bucket)
+            .with_region(&self.region);
+
+        if let Some(endpoint) = &self.endpoint {
+            if endpoint.starts_with("http://") {
+                if !self.allow_http {
+                    return Err(GpuCheckpointError::CheckpointError(format!(
+                        "endpoint {endpoint} is not encrypted; use https:// or set \
+                         {ALLOW_HTTP_VAR}=true to send checkpoints and credentials in cleartext"
+                    )));
+                }
+                warn!("Sending checkpoint data to {} without TLS", endpoint);
+            } else if !endpoint.starts_with("https://") {
+                return Err(GpuCheckpointError::CheckpointError(format!(
+                    "endpoint {endpoint} is not an https:// URL"
+                )));
+            }
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(self.allow_http)
+                .with_virtual_hosted_style_request(false);
+        }
+
+        builder = match &self.credentials {
+            Some(credentials) => {
+                let builder = builder
+                    .with_access_key_id(&credentials.access_key_id)
+                    .with_secret_access_key(&credentials.secret_access_key);
+                match &credentials.session_token {
+                    Some(token) => builder.with_token(token),
+                    None => builder,
+                }
+            }
+            None => builder.with_skip_signature(true),
+        };
+
+        let store = builder
+            .build()
+            .map_err(|e| GpuCheckpointError::CheckpointError(e.to_string()))?;
+        Ok(Arc::new(store))
+    }
+}
+
+/// Drives the client's futures for the synchronous sink and source
+enum Executor {
+    /// Runtime of the thread the sink or source was created on
+    Current(Handle),
+
+    /// Runtime of its own, for threads outside any runtime
+    Owned(Runtime),
+}
+
+impl Executor {
+    fn new() -> Result<Self> {
+        match Handle::try_current() {
+            Ok(handle) => Ok(Self::Current(handle)),
+            Err(_) => Ok(Self::Owned(
+                tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(2)
+                    .enable_all()
+                    .build()?,
+            )),
+        }
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        match self {
+            Self::Current(handle) => handle.block_on(future),
+            Self::Owned(runtime) => runtime.block_on(future),
+        }
+    }
+}
+
+/// Checkpoint uploaded to an object store
+pub struct S3Sink {
+    executor: Executor,
+    location: S3Location,
+
+    /// Upload in progress, until finalized
+    writer: Option<BufWriter>,
+}
+
+impl S3Sink {
+    pub fn new(config: S3Config, location: S3Location) -> Result<Self> {
+        let store = config.store(&location.bucket)?;
+        Self::with_store(store, location)
+    }
+
+    /// Upload to `location` of `store` rather than a configured endpoint
+    pub fn with_store(store: Arc<dyn ObjectStore>, location: S3Location) -> Result<Self> {
+        if location.key.is_empty() || location.key.ends_with('/') {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "{location} does not name an object"
+            )));
+        }
+        let writer =
+            BufWriter::with_capacity(store, ObjectPath::from(location.key.as_str()), CHUNK_SIZE)
+                .with_max_concurrency(MAX_PARTS_IN_FLIGHT);
+        Ok(Self {
+            executor: Executor::new()?,
+            location,
+            writer: Some(writer),
+        })
+    }
+
+    fn error(&self, e: impl fmt::Display) -> GpuCheckpointError {
+        GpuCheckpointError::CheckpointError(format!("cannot upload {}: {e}", self.location))
+    }
+}
+
+impl CheckpointSink for S3Sink {
+    fn write_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        let Some(writer) = self.writer.as_mut() else {
+            return Err(self.error("already finalized"));
+        };
+        // Waits for a part to finish uploading once the most allowed are
+        // in flight
+        self.executor
+            .block_on(writer.write_all(chunk))
+            .map_err(|e| self.error(e))
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        let Some(mut writer) = self.writer.take() else {
+            return Err(self.error("already finalized"));
+        };
+        self.executor
+            .block_on(writer.shutdown())
+            .map_err(|e| self.error(e))?;
+        info!("Uploaded {}", self.location);
+        Ok(())
+    }
+
+    fn location(&self) -> String {
+        self.location.to_string()
+    }
+}
+
+impl Drop for S3Sink {
+    fn drop(&mut self) {
+        // Parts of an abandoned upload are stored (and billed) until aborted
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(e) = self.executor.block_on(writer.abort()) {
+                warn!("Cannot abort the upload of {}: {}", self.location, e);
+            }
+        }
+    }
+}
+
+/// Checkpoint downloaded from an object store as a single streamed GET
+pub struct S3Source {
+    executor: Executor,
+    store: Arc<dyn ObjectStore>,
+    location: S3Location,
+
+    /// Body of the GET, once sent
+    body: Option<BoxStream<'static, object_store::Result<bytes::Bytes>>>,
+}
+
+impl S3Source {
+    pub fn new(config: S3Config, location: S3Location) -> Result<Self> {
+        let store = config.store(&location.bucket).map_err(|e| match e {
+            GpuCheckpointError::CheckpointError(msg) => GpuCheckpointError::RestoreError(msg),
+            other => other,
+        })?;
+        Self::with_store(store, location)
+    }
+
+    /// Download from `location` of `store` rather than a configured endpoint
+    pub fn with_store(store: Arc<dyn ObjectStore>, location: S3Location) -> Result<Self> {
+        Ok(Self {
+            executor: Executor::new()?,
+            store,
+            location,
+            body: None,
+        })
+    }
+
+    fn error(&self, e: impl fmt::Display) -> GpuCheckpointError {
+        GpuCheckpointError::RestoreError(format!("cannot download {}: {e}", self.location))
+    }
+}
+
+impl CheckpointSource for S3Source {
+    fn read_chunk(&mut self) -> Result<Vec<u8>> {
+        let body = match self.body.as_mut() {
+            Some(body) => body,
+            None => {
+                let path = ObjectPath::from(self.location.key.as_str());
+                let response = self
+                    .executor
+                    .block_on(self.store.get(&path))
+                    .map_err(|e| self.error(e))?;
+                self.body.insert(response.into_stream())
+            }
+        };
+
+        loop {
+            match self.executor.block_on(body.next()) {
+                Some(Ok(chunk)) if chunk.is_empty() => continue,
+                Some(Ok(chunk)) => return Ok(chunk.to_vec()),
+                Some(Err(e)) => {
+                    return Err(GpuCheckpointError::RestoreError(format!(
+                        "cannot download {}: {e}",
+                        self.location
+                    )))
+                }
+                None => return Ok(Vec::new()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+
+    #[test]
+    fn test_parse_location() {
+        let location = S3Location::parse("s3://bucket/checkpoints/a.bin").unwrap();
+        assert_eq!(location.bucket, "bucket");
+        assert_eq!(location.key, "checkpoints/a.bin");
+        assert_eq!(location.to_string(), "s3://bucket/checkpoints/a.bin");
+
+        let prefix = S3Location::parse("s3://bucket").unwrap();
+        assert_eq!(prefix.join("a.bin").key, "a.bin");
+        assert_eq!(
+            S3Location::parse("s3://bucket/ckpt/")
+                .unwrap()
+                .join("a.bin")
+                .key,
+            "ckpt/a.bin"
+        );
+        assert!(S3Location::parse("s3:///key").is_err());
+        assert!(S3Location::parse("/tmp/key").is_err());
+        assert!(S3Sink::with_store(Arc::new(InMemory::new()), prefix).is_err());
+    }
+
+    #[test]
+    fn test_endpoints_require_tls() {
+        let config = |endpoint: Option<&str>, allow_http| S3Config {
+            endpoint: endpoint.map(str::to_string),
+            region: "us-east-1".to_string(),
+            credentials: Some(S3Credentials {
+                access_key_id: "AKIDEXAMPLE".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: Some("token".to_string()),
+            }),
+            allow_http,
+        };
+
+        assert!(config(None, false).store("bucket").is_ok());
+        assert!(config(Some("https://minio:9000"), false)
+            .store("bucket")
+            .is_ok());
+        let err = config(Some("http://minio:9000"), false)
+            .store("bucket")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains(ALLOW_HTTP_VAR), "{err}");
+        assert!(config(Some("http://minio:9000"), true)
+            .store("bucket")
+            .is_ok());
+        assert!(config(Some("minio:9000"), true).store("bucket").is_err());
+
+        // Credentials never end up in logs
+        let debug = format!("{:?}", config(None, false));
+        assert!(debug.contains("AKIDEXAMPLE") && !debug.contains("secret\""));
+    }
+
+    fn download(store: &Arc<dyn ObjectStore>, location: &S3Location) -> Result<Vec<u8>> {
+        let mut source = S3Source::with_store(store.clone(), location.clone())?;
+        let mut data = Vec::new();
+        loop {
+            let chunk = source.read_chunk()?;
+            if chunk.is_empty() {
+                return Ok(data);
+            }
+            data.extend_from_slice(&chunk);
+        }
+    }
+
+    #[test]
+    fn test_upload_and_download() {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let location = S3Location::parse("s3://bucket/dir/a b.bin").unwrap();
+
+        // A multipart upload, and a single PUT
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut sink = S3Sink::with_store(store.clone(), location.clone()).unwrap();
+        for chunk in data.chunks(1 << 20) {
+            sink.write_chunk(chunk).unwrap();
+        }
+        sink.finalize().unwrap();
+        assert!(sink.write_chunk(b"late").is_err());
+        assert_eq!(sink.location(), "s3://bucket/dir/a b.bin");
+        drop(sink);
+        assert_eq!(download(&store, &location).unwrap(), data);
+
+        let small = location.join("small.bin");
+        let mut sink = S3Sink::with_store(store.clone(), small.clone()).unwrap();
+        sink.write_chunk(b"hello").unwrap();
+        sink.finalize().unwrap();
+        assert_eq!(download(&store, &small).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_abandoned_upload_and_missing_object() {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let location = S3Location::parse("s3://bucket/abandoned.bin").unwrap();
+
+        // An upload that is never finalized leaves no object behind
+        let mut sink = S3Sink::with_store(store.clone(), location.clone()).unwrap();
+        sink.write_chunk(&vec![0u8; CHUNK_SIZE + 1]).unwrap();
+        drop(sink);
+
+        let err = download(&store, &location).unwrap_err();
+        assert!(
+            matches!(&err, GpuCheckpointError::RestoreError(msg)
+                if msg.contains("s3://bucket/abandoned.bin")),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocking_thread_of_a_runtime() {
+        // The async engines upload and download from spawn_blocking, with
+        // the client's futures driven by the engine's runtime
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let location = S3Location::parse("s3://bucket/async.bin").unwrap();
+        let data = vec![7u8; CHUNK_SIZE + 4096];
+
+        let (upload_store, upload_location, upload_data) =
+            (store.clone(), location.clone(), data.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut sink = S3Sink::with_store(upload_store, upload_location)?;
+            sink.write_chunk(&upload_data)?;
+            sink.finalize()
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        let downloaded = tokio::task::spawn_blocking(move || download(&store, &location))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(downloaded, data);
+    }
+}
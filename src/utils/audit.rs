@@ -0,0 +1,114 @@
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// One line of the `--append-log` audit trail
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OperationRecord {
+    /// Command that was executed (detect, checkpoint, restore, ...)
+    pub command: String,
+
+    /// Target process, if the command has one
+    pub pid: Option<u32>,
+
+    /// Seconds since the Unix epoch when the record was written
+    pub timestamp: u64,
+
+    pub duration_ms: u64,
+
+    pub success: bool,
+
+    /// Result metadata of a successful operation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+
+    /// Error message of a failed operation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl OperationRecord {
+    pub fn success(command: &str, pid: Option<u32>, duration: Duration, result: Value) -> Self {
+        Self::new(command, pid, duration, Some(result), None)
+    }
+
+    pub fn failure(command: &str, pid: Option<u32>, duration: Duration, error: String) -> Self {
+        Self::new(command, pid, duration, None, Some(error))
+    }
+
+    fn new(
+        command: &str,
+        pid: Option<u32>,
+        duration: Duration,
+        result: Option<Value>,
+        error: Option<String>,
+    ) -> Self {
+        Self {
+            command: command.to_string(),
+            pid,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            duration_ms: duration.as_millis() as u64,
+            success: error.is_none(),
+            result,
+            error,
+        }
+    }
+
+    /// Append this record as a single JSON line, creating the file if needed
+    pub fn append_to(&self, path: &Path) -> Result<()> {
+        let mut line = serde_json::to_string(self)
+            .map_err(|e| crate::GpuCheckpointError::IoError(e.into()))?;
+        line.push('\n');
+
+        // A single write keeps concurrent appenders from interleaving records
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_append_records() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+
+        OperationRecord::success(
+            "checkpoint",
+            Some(42),
+            Duration::from_millis(1500),
+            serde_json::json!({ "size_bytes": 1024 }),
+        )
+        .append_to(&path)
+        .unwrap();
+        OperationRecord::failure("restore", None, Duration::ZERO, "boom".to_string())
+            .append_to(&path)
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<OperationRecord> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(records.len(), 2);
+        assert!(records[0].success);
+        assert_eq!(records[0].pid, Some(42));
+        assert_eq!(records[0].duration_ms, 1500);
+        assert_eq!(records[0].result.as_ref().unwrap()["size_bytes"], 1024);
+        assert!(!records[1].success);
+        assert_eq!(records[1].error.as_deref(), Some("boom"));
+        assert!(records[1].result.is_none());
+    }
+}
@@ -0,0 +1,242 @@
+//! Streaming CRC32 checksums over checkpoint data
+//!
+//! The wrappers update a running CRC32 as bytes pass through them, so
+//! checkpoint data can be checksummed while it is copied instead of in a
+//! second pass over the file.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crc32fast::Hasher;
+
+/// Reader that checksums every byte read through it
+pub struct ChecksumReader<R: Read> {
+    inner: R,
+    hasher: Hasher,
+    bytes: u64,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Number of bytes read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes
+    }
+
+    /// CRC32 of the bytes read so far
+    pub fn finalize(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+
+    /// Start a new checksum without giving up the reader, e.g. at the start
+    /// of the next allocation
+    pub fn reset(&mut self) {
+        self.hasher.reset();
+        self.bytes = 0;
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+/// Writer that checksums every byte written through it
+pub struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+    bytes: u64,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Number of bytes written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes
+    }
+
+    /// CRC32 of the bytes written so far
+    pub fn finalize(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+
+    /// Start a new checksum without giving up the writer
+    pub fn reset(&mut self) {
+        self.hasher.reset();
+        self.bytes = 0;
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Account for `len` bytes with CRC32 `crc` that reached the inner
+    /// writer without passing through this one, e.g. copied in the kernel
+    pub fn add_written(&mut self, crc: u32, len: u64) {
+        self.hasher.combine(&Hasher::new_with_initial_len(crc, len));
+        self.bytes += len;
+    }
+
+    /// Seek `len` bytes forward without writing, checksumming them as zeros.
+    /// Used for file holes, which read back as zeros.
+    pub fn skip_zeros(&mut self, len: u64) -> io::Result<()>
+    where
+        W: Seek,
+    {
+        static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+        self.inner.seek(SeekFrom::Current(len as i64))?;
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = remaining.min(ZEROS.len() as u64) as usize;
+            self.hasher.update(&ZEROS[..chunk]);
+            remaining -= chunk as u64;
+        }
+        self.bytes += len;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Only the bytes the inner writer accepted are part of the stream
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// CRC32 of `data` in one call, matching what the wrappers compute
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_value() {
+        // Standard CRC-32 (IEEE) check value
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_reader_matches_one_shot() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let mut reader = ChecksumReader::new(&data[..]);
+
+        // Read in uneven chunks so the running state is exercised
+        let mut buf = [0u8; 333];
+        let mut out = Vec::new();
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+
+        assert_eq!(out, data);
+        assert_eq!(reader.bytes_read(), data.len() as u64);
+        assert_eq!(reader.finalize(), crc32(&data));
+    }
+
+    #[test]
+    fn test_writer_matches_reader() {
+        let data = b"checkpoint payload".repeat(100);
+        let mut writer = ChecksumWriter::new(Vec::new());
+        for chunk in data.chunks(7) {
+            writer.write_all(chunk).unwrap();
+        }
+
+        let mut reader = ChecksumReader::new(&data[..]);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+
+        assert_eq!(writer.bytes_written(), data.len() as u64);
+        assert_eq!(writer.finalize(), reader.finalize());
+        assert_eq!(writer.into_inner(), data);
+    }
+
+    #[test]
+    fn test_add_written_continues_checksum() {
+        let data = b"checkpoint payload".repeat(100);
+        let mut writer = ChecksumWriter::new(Vec::new());
+        writer.write_all(&data[..700]).unwrap();
+        writer.add_written(crc32(&data[700..1500]), 800);
+        writer.write_all(&data[1500..]).unwrap();
+
+        assert_eq!(writer.bytes_written(), data.len() as u64);
+        assert_eq!(writer.finalize(), crc32(&data));
+    }
+
+    #[test]
+    fn test_reset_and_finalize_is_idempotent() {
+        let mut reader = ChecksumReader::new(&b"firstsecond"[..]);
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(reader.finalize(), reader.finalize());
+        assert_eq!(reader.finalize(), crc32(b"first"));
+
+        reader.reset();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(reader.finalize(), crc32(b"second"));
+        assert_eq!(reader.bytes_read(), 6);
+    }
+
+    #[test]
+    fn test_skip_zeros_matches_written_zeros() {
+        let mut sparse = ChecksumWriter::new(io::Cursor::new(Vec::new()));
+        sparse.write_all(b"data").unwrap();
+        sparse.skip_zeros(100_000).unwrap();
+        sparse.write_all(b"tail").unwrap();
+
+        let mut dense = b"data".to_vec();
+        dense.extend(std::iter::repeat_n(0, 100_000));
+        dense.extend_from_slice(b"tail");
+
+        assert_eq!(sparse.bytes_written(), dense.len() as u64);
+        assert_eq!(sparse.finalize(), crc32(&dense));
+        assert_eq!(sparse.into_inner().into_inner(), dense);
+    }
+
+    #[test]
+    fn test_corruption_changes_checksum() {
+        let mut data = vec![0xabu8; 4096];
+        let original = crc32(&data);
+        data[2048] ^= 1;
+        assert_ne!(crc32(&data), original);
+    }
+}
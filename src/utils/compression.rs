@@ -0,0 +1,444 @@
+//! Window-by-window compression of allocation data
+//!
+//! A compressed allocation is stored as a sequence of frames, one per copy
+//! window: the uncompressed and compressed lengths as little-endian `u32`s
+//! followed by the compressed bytes. Frames are inflated as the data is read,
+//! so restore holds no more than one window in memory, and checkpoint one
+//! window per compression thread.
+
+use crate::{GpuCheckpointError, Result};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of the lengths preceding each frame's compressed bytes
+pub const FRAME_HEADER_SIZE: u64 = 8;
+
+/// Upper bound on the uncompressed size of a frame, independent of the
+/// window size, so a corrupted length cannot trigger a huge allocation
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Compression level used when none is given
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+
+/// Identifiers of the algorithms in the checkpoint header
+const ALGORITHM_NONE: u8 = 0;
+const ALGORITHM_DEFLATE: u8 = 1;
+
+/// How allocation data is compressed on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    /// Data is stored as is
+    #[default]
+    None,
+
+    /// Each window is compressed with deflate at `level` (0-9)
+    Deflate { level: u32 },
+}
+
+impl Compression {
+    /// Deflate at `level`, rejecting levels outside 0-9
+    pub fn deflate(level: u32) -> Result<Self> {
+        if level > 9 {
+            return Err(GpuCheckpointError::CheckpointError(format!(
+                "invalid compression level {level} (expected 0 to 9)"
+            )));
+        }
+        Ok(Compression::Deflate { level })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self != Compression::None
+    }
+
+    /// Algorithm identifier and level as stored in the checkpoint header
+    pub fn to_header(self) -> (u8, u8) {
+        match self {
+            Compression::None => (ALGORITHM_NONE, 0),
+            Compression::Deflate { level } => (ALGORITHM_DEFLATE, level as u8),
+        }
+    }
+
+    /// Parse the algorithm identifier and level of a checkpoint header
+    pub fn from_header(algorithm: u8, level: u8) -> Result<Self> {
+        match algorithm {
+            ALGORITHM_NONE => Ok(Compression::None),
+            ALGORITHM_DEFLATE => Compression::deflate(u32::from(level)).map_err(|_| {
+                GpuCheckpointError::RestoreError(format!(
+                    "invalid deflate level {level} in checkpoint header"
+                ))
+            }),
+            other => Err(GpuCheckpointError::RestoreError(format!(
+                "unsupported compression algorithm {other} in checkpoint header"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Deflate { level } => write!(f, "deflate (level {level})"),
+        }
+    }
+}
+
+/// Bytes compressed and time spent compressing them, summed over the
+/// writers of one checkpoint. The time excludes writing the frames out, so
+/// compression throughput can be told apart from I/O throughput.
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    raw_bytes: AtomicU64,
+    compressed_bytes: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl CompressionStats {
+    pub fn reset(&self) {
+        self.raw_bytes.store(0, Ordering::Relaxed);
+        self.compressed_bytes.store(0, Ordering::Relaxed);
+        self.nanos.store(0, Ordering::Relaxed);
+    }
+
+    fn record(&self, raw_bytes: u64, compressed_bytes: u64, elapsed: Duration) {
+        self.raw_bytes.fetch_add(raw_bytes, Ordering::Relaxed);
+        self.compressed_bytes
+            .fetch_add(compressed_bytes, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// What has been recorded so far
+    pub fn throughput(&self) -> CompressionThroughput {
+        let raw_bytes = self.raw_bytes.load(Ordering::Relaxed);
+        let elapsed = Duration::from_nanos(self.nanos.load(Ordering::Relaxed));
+        let secs = elapsed.as_secs_f64();
+        CompressionThroughput {
+            raw_bytes,
+            compressed_bytes: self.compressed_bytes.load(Ordering::Relaxed),
+            duration_ms: elapsed.as_millis() as u64,
+            mb_per_sec: if secs > 0.0 {
+                (raw_bytes as f64 / (1024.0 * 1024.0)) / secs
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// How much data a checkpoint compressed and how fast
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompressionThroughput {
+    /// Data bytes before compression
+    pub raw_bytes: u64,
+
+    /// Compressed bytes, without the frame lengths
+    pub compressed_bytes: u64,
+
+    /// Time spent compressing, summed over allocations copied concurrently
+    pub duration_ms: u64,
+
+    /// Uncompressed bytes per second of `duration_ms`
+    pub mb_per_sec: f64,
+}
+
+/// Writer that compresses everything written through it into frames of up
+/// to `frame_size` uncompressed bytes. With more than one thread, that many
+/// frames are buffered and compressed in parallel, then written in order,
+/// so the output does not depend on the thread count.
+pub struct CompressWriter<W: Write> {
+    inner: W,
+    level: flate2::Compression,
+    frame_size: usize,
+    threads: usize,
+    pending: Vec<u8>,
+    stats: Option<Arc<CompressionStats>>,
+}
+
+impl<W: Write> CompressWriter<W> {
+    pub fn new(inner: W, level: u32, frame_size: usize) -> Self {
+        Self {
+            inner,
+            level: flate2::Compression::new(level),
+            frame_size: frame_size.clamp(1, MAX_FRAME_SIZE),
+            threads: 1,
+            pending: Vec::new(),
+            stats: None,
+        }
+    }
+
+    /// Compress up to `threads` frames at a time
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Add the bytes compressed and the time taken to `stats`
+    pub fn with_stats(mut self, stats: Arc<CompressionStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Write out the last, partial frames and return the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_frames()?;
+        Ok(self.inner)
+    }
+
+    fn write_frames(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let level = self.level;
+        let frames: Vec<&[u8]> = self.pending.chunks(self.frame_size).collect();
+        let compressed = if frames.len() == 1 {
+            vec![deflate(frames[0], level)]
+        } else {
+            thread::scope(|scope| {
+                let workers: Vec<_> = frames
+                    .iter()
+                    .map(|frame| scope.spawn(move || deflate(frame, level)))
+                    .collect();
+                workers
+                    .into_iter()
+                    .map(|worker| worker.join().expect("compression thread panicked"))
+                    .collect()
+            })
+        };
+        let compressed = compressed.into_iter().collect::<io::Result<Vec<_>>>()?;
+        if let Some(stats) = &self.stats {
+            let compressed_bytes = compressed.iter().map(|c| c.len() as u64).sum();
+            stats.record(self.pending.len() as u64, compressed_bytes, start.elapsed());
+        }
+
+        for (frame, compressed) in frames.iter().zip(&compressed) {
+            self.inner.write_all(&(frame.len() as u32).to_le_bytes())?;
+            self.inner
+                .write_all(&(compressed.len() as u32).to_le_bytes())?;
+            self.inner.write_all(compressed)?;
+        }
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+fn deflate(frame: &[u8], level: flate2::Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), level);
+    encoder.write_all(frame)?;
+    encoder.finish()
+}
+
+impl<W: Write> Write for CompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let batch = self.frame_size * self.threads;
+        let len = buf.len().min(batch - self.pending.len());
+        self.pending.extend_from_slice(&buf[..len]);
+        if self.pending.len() == batch {
+            self.write_frames()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that inflates the frames of `len` uncompressed bytes. Nothing
+/// past the last frame is read from the inner reader.
+pub struct DecompressReader<R: Read> {
+    inner: R,
+    remaining: u64,
+    frame: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> DecompressReader<R> {
+    pub fn new(inner: R, len: u64) -> Self {
+        Self {
+            inner,
+            remaining: len,
+            frame: Vec::new(),
+            position: 0,
+        }
+    }
+
+    fn read_frame(&mut self) -> io::Result<()> {
+        let (raw_len, compressed_len) = read_frame_header(&mut self.inner, self.remaining)?;
+
+        self.frame.clear();
+        self.frame.reserve(raw_len as usize);
+        let mut compressed = (&mut self.inner).take(compressed_len);
+        DeflateDecoder::new(&mut compressed)
+            .take(raw_len + 1)
+            .read_to_end(&mut self.frame)?;
+        // The decoder may stop short of the padding at the end of the stream
+        io::copy(&mut compressed, &mut io::sink())?;
+
+        if self.frame.len() as u64 != raw_len {
+            return Err(invalid_frame(format!(
+                "frame inflated to {} bytes, expected {raw_len}",
+                self.frame.len()
+            )));
+        }
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecompressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.position == self.frame.len() {
+            self.read_frame()?;
+        }
+
+        let len = buf.len().min(self.frame.len() - self.position);
+        buf[..len].copy_from_slice(&self.frame[self.position..self.position + len]);
+        self.position += len;
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+/// Seek past the frames holding `len` uncompressed bytes, returning the
+/// number of bytes they occupy
+pub fn skip_frames(input: &mut (impl Read + Seek), len: u64) -> io::Result<u64> {
+    let mut remaining = len;
+    let mut stored = 0;
+    while remaining > 0 {
+        let (raw_len, compressed_len) = read_frame_header(input, remaining)?;
+        input.seek(SeekFrom::Current(compressed_len as i64))?;
+        remaining -= raw_len;
+        stored += FRAME_HEADER_SIZE + compressed_len;
+    }
+    Ok(stored)
+}
+
+/// Read and sanity check the lengths of the next frame when `remaining`
+/// uncompressed bytes are still expected
+fn read_frame_header(input: &mut impl Read, remaining: u64) -> io::Result<(u64, u64)> {
+    let mut lengths = [0u8; FRAME_HEADER_SIZE as usize];
+    input.read_exact(&mut lengths)?;
+    let raw_len = u64::from(u32::from_le_bytes(lengths[..4].try_into().unwrap()));
+    let compressed_len = u64::from(u32::from_le_bytes(lengths[4..].try_into().unwrap()));
+
+    if raw_len == 0 || raw_len > remaining || raw_len > MAX_FRAME_SIZE as u64 {
+        return Err(invalid_frame(format!(
+            "frame of {raw_len} bytes with {remaining} bytes remaining"
+        )));
+    }
+    Ok((raw_len, compressed_len))
+}
+
+fn invalid_frame(msg: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt compressed data: {msg}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_frames_roundtrip() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut writer = CompressWriter::new(Vec::new(), DEFAULT_COMPRESSION_LEVEL, 16 * 1024);
+        writer.write_all(&data).unwrap();
+        let mut stored = writer.finish().unwrap();
+        let stored_len = stored.len() as u64;
+        stored.extend_from_slice(b"tail");
+
+        let mut input = Cursor::new(&stored);
+        let mut restored = Vec::new();
+        DecompressReader::new(&mut input, data.len() as u64)
+            .read_to_end(&mut restored)
+            .unwrap();
+        assert_eq!(restored, data);
+        // Nothing past the frames was consumed
+        assert_eq!(input.position(), stored_len);
+
+        let mut input = Cursor::new(&stored);
+        assert_eq!(
+            skip_frames(&mut input, data.len() as u64).unwrap(),
+            stored_len
+        );
+    }
+
+    #[test]
+    fn test_parallel_compression_matches_sequential() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let compress = |threads: usize, stats: Arc<CompressionStats>| {
+            let mut writer = CompressWriter::new(Vec::new(), DEFAULT_COMPRESSION_LEVEL, 16 * 1024)
+                .with_threads(threads)
+                .with_stats(stats);
+            writer.write_all(&data).unwrap();
+            writer.finish().unwrap()
+        };
+
+        let stats = Arc::new(CompressionStats::default());
+        let sequential = compress(1, Arc::new(CompressionStats::default()));
+        let parallel = compress(4, stats.clone());
+        assert_eq!(parallel, sequential);
+
+        let throughput = stats.throughput();
+        assert_eq!(throughput.raw_bytes, data.len() as u64);
+        // Every frame adds its two lengths to the compressed bytes
+        let frames = data.len().div_ceil(16 * 1024) as u64;
+        assert_eq!(
+            throughput.compressed_bytes + frames * FRAME_HEADER_SIZE,
+            parallel.len() as u64
+        );
+
+        stats.reset();
+        assert_eq!(stats.throughput().raw_bytes, 0);
+    }
+
+    #[test]
+    fn test_corrupt_frames_are_rejected() {
+        let mut writer = CompressWriter::new(Vec::new(), DEFAULT_COMPRESSION_LEVEL, 4096);
+        writer.write_all(&[7u8; 10_000]).unwrap();
+        let stored = writer.finish().unwrap();
+
+        // Declared as more data than the frames hold
+        let err = DecompressReader::new(Cursor::new(&stored), 20_000)
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Frame larger than the data still expected
+        let err = DecompressReader::new(Cursor::new(&stored), 100)
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_header_encoding() {
+        for compression in [Compression::None, Compression::deflate(9).unwrap()] {
+            let (algorithm, level) = compression.to_header();
+            assert_eq!(
+                Compression::from_header(algorithm, level).unwrap(),
+                compression
+            );
+        }
+        assert!(Compression::deflate(10).is_err());
+        assert!(Compression::from_header(7, 0).is_err());
+    }
+}
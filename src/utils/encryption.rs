@@ -0,0 +1,494 @@
+//! Window-by-window encryption of allocation data with AES-256-GCM
+//!
+//! An encrypted allocation is stored as a sequence of frames, one per copy
+//! window: the plaintext length as a little-endian `u32` whose top bit marks
+//! the allocation's last frame, a random nonce, the ciphertext and the GCM
+//! tag. Each frame is authenticated along with its position, its
+//! last-frame bit and the record it belongs to: the checkpoint's identity,
+//! the record's index and its allocation header. Frames cannot be
+//! reordered, dropped, appended or moved to another record or checkpoint,
+//! nor the allocation header edited, unnoticed. Encryption applies to the
+//! compressed data, and the allocation's CRC32 trailer is encrypted as part
+//! of its last frame.
+
+use crate::checkpoint::bar_sliding::{AllocationHeader, ALLOCATION_HEADER_SIZE};
+use crate::utils::compression::MAX_FRAME_SIZE;
+use crate::{GpuCheckpointError, Result};
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce, Tag};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Size of an AES-256 key
+pub const KEY_SIZE: usize = 32;
+
+/// Size of a GCM nonce
+pub const NONCE_SIZE: usize = 12;
+
+/// Size of a GCM authentication tag
+pub const TAG_SIZE: usize = 16;
+
+/// Environment variable holding the key as 64 hex characters
+pub const KEY_ENV_VAR: &str = "GPU_CHECKPOINT_KEY";
+
+/// Size of the length and nonce preceding each frame's ciphertext
+pub const FRAME_HEADER_SIZE: u64 = 4 + NONCE_SIZE as u64;
+
+/// Bit of the frame length marking the last frame of an allocation
+const LAST_FRAME: u32 = 1 << 31;
+
+/// AES-256 key checkpoint data is encrypted with
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: Aes256Gcm,
+}
+
+impl EncryptionKey {
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new(key.into()),
+        }
+    }
+
+    /// Load a key file holding 32 raw bytes or 64 hex characters
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self::new(&crate::checkpoint::signing::read_key_file(path)?))
+    }
+
+    /// Key given in `KEY_ENV_VAR`, if it is set
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(hex) = std::env::var(KEY_ENV_VAR) else {
+            return Ok(None);
+        };
+        let key = crate::checkpoint::signing::parse_hex_key(&hex).ok_or_else(|| {
+            GpuCheckpointError::CheckpointError(format!(
+                "{KEY_ENV_VAR} is not a 32 byte key in hex form"
+            ))
+        })?;
+        Ok(Some(Self::new(&key)))
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Error of a frame whose tag does not match, because the data was
+/// modified or the key is wrong
+#[derive(Debug)]
+struct AuthenticationFailed(u64);
+
+impl fmt::Display for AuthenticationFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "authentication of encrypted frame {} failed; wrong key or tampered data",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for AuthenticationFailed {}
+
+/// Whether `error` is a `DecryptReader` rejecting a frame's tag
+pub fn is_authentication_failure(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<AuthenticationFailed>())
+}
+
+/// Record the frames of an allocation belong to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordContext {
+    checkpoint_id: u64,
+    index: u32,
+    header: [u8; ALLOCATION_HEADER_SIZE as usize],
+}
+
+impl RecordContext {
+    /// Record `index` of checkpoint `checkpoint_id`, with `header`
+    pub fn new(checkpoint_id: u64, index: u32, header: &AllocationHeader) -> Self {
+        let mut encoded = [0u8; ALLOCATION_HEADER_SIZE as usize];
+        header
+            .write_to(&mut &mut encoded[..])
+            .expect("allocation header fits its encoded size");
+        Self {
+            checkpoint_id,
+            index,
+            header: encoded,
+        }
+    }
+
+    /// Data authenticated along with frame `frame` of the record
+    fn frame_aad(&self, frame: u64, last: bool) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(8 + 4 + self.header.len() + 8 + 1);
+        aad.extend_from_slice(&self.checkpoint_id.to_le_bytes());
+        aad.extend_from_slice(&self.index.to_le_bytes());
+        aad.extend_from_slice(&self.header);
+        aad.extend_from_slice(&frame.to_le_bytes());
+        aad.push(u8::from(last));
+        aad
+    }
+}
+
+fn random_nonce() -> io::Result<[u8; NONCE_SIZE]> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    let mut filled = 0;
+    while filled < NONCE_SIZE {
+        // SAFETY: the pointer and length describe the unfilled part of nonce
+        let ret =
+            unsafe { libc::getrandom(nonce[filled..].as_mut_ptr().cast(), NONCE_SIZE - filled, 0) };
+        if ret < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+            continue;
+        }
+        filled += ret as usize;
+    }
+    Ok(nonce)
+}
+
+/// Writer that encrypts everything written through it into frames of up
+/// to `frame_size` bytes
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    cipher: Aes256Gcm,
+    record: RecordContext,
+    frame_size: usize,
+    pending: Vec<u8>,
+    index: u64,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(inner: W, key: &EncryptionKey, record: RecordContext, frame_size: usize) -> Self {
+        Self {
+            inner,
+            cipher: key.cipher.clone(),
+            record,
+            frame_size: frame_size.clamp(1, MAX_FRAME_SIZE),
+            pending: Vec::new(),
+            index: 0,
+        }
+    }
+
+    /// Write out the last frame and return the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_frame(true)?;
+        Ok(self.inner)
+    }
+
+    fn write_frame(&mut self, last: bool) -> io::Result<()> {
+        let nonce = random_nonce()?;
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                Nonce::from_slice(&nonce),
+                &self.record.frame_aad(self.index, last),
+                &mut self.pending,
+            )
+            .map_err(|_| io::Error::other("cannot encrypt a frame this large"))?;
+
+        let len = self.pending.len() as u32 | if last { LAST_FRAME } else { 0 };
+        self.inner.write_all(&len.to_le_bytes())?;
+        self.inner.write_all(&nonce)?;
+        self.inner.write_all(&self.pending)?;
+        self.inner.write_all(&tag)?;
+        self.pending.clear();
+        self.index += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full frame is only written once more data follows, so the last
+        // frame is never empty unless nothing was written at all
+        if self.pending.len() == self.frame_size && !buf.is_empty() {
+            self.write_frame(false)?;
+        }
+        let len = buf.len().min(self.frame_size - self.pending.len());
+        self.pending.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that decrypts and authenticates the frames of one allocation.
+/// Nothing past its last frame is read from the inner reader.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    cipher: Aes256Gcm,
+    record: RecordContext,
+    frame: Vec<u8>,
+    position: usize,
+    index: u64,
+    last: bool,
+    /// Frame that failed authentication, after which every read fails
+    failed: Option<u64>,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(inner: R, key: &EncryptionKey, record: RecordContext) -> Self {
+        Self {
+            inner,
+            cipher: key.cipher.clone(),
+            record,
+            frame: Vec::new(),
+            position: 0,
+            index: 0,
+            last: false,
+            failed: None,
+        }
+    }
+
+    /// Check that everything up to the end of the last frame was read
+    pub fn finish(mut self) -> io::Result<()> {
+        let mut rest = [0u8; 1];
+        if self.read(&mut rest)? != 0 {
+            return Err(invalid_frame(
+                "data continues past the end of the allocation".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_frame(&mut self) -> io::Result<()> {
+        if let Some(index) = self.failed {
+            return Err(authentication_failed(index));
+        }
+        let result = self.read_next_frame();
+        // Never hand out a partially read or unauthenticated frame
+        if result.is_err() {
+            self.frame.clear();
+            self.position = 0;
+        }
+        result
+    }
+
+    fn read_next_frame(&mut self) -> io::Result<()> {
+        let (len, last) = read_frame_header(&mut self.inner)?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        self.inner.read_exact(&mut nonce)?;
+        self.frame.resize(len as usize, 0);
+        self.inner.read_exact(&mut self.frame)?;
+        let mut tag = [0u8; TAG_SIZE];
+        self.inner.read_exact(&mut tag)?;
+
+        let aad = self.record.frame_aad(self.index, last);
+        let opened = self.cipher.decrypt_in_place_detached(
+            Nonce::from_slice(&nonce),
+            &aad,
+            &mut self.frame,
+            Tag::from_slice(&tag),
+        );
+        if opened.is_err() {
+            // Callers skipping past a failed read must not resync on a
+            // later frame
+            self.failed = Some(self.index);
+            return Err(authentication_failed(self.index));
+        }
+        self.position = 0;
+        self.index += 1;
+        self.last = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.position == self.frame.len() {
+            if self.last {
+                return Ok(0);
+            }
+            self.read_frame()?;
+        }
+
+        let len = buf.len().min(self.frame.len() - self.position);
+        buf[..len].copy_from_slice(&self.frame[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Seek past the frames of one allocation, returning the number of bytes
+/// they occupy. Needs no key, the frame lengths are not encrypted.
+pub fn skip_frames(input: &mut (impl Read + Seek)) -> io::Result<u64> {
+    let mut stored = 0;
+    loop {
+        let (len, last) = read_frame_header(input)?;
+        input.seek(SeekFrom::Current(
+            NONCE_SIZE as i64 + len as i64 + TAG_SIZE as i64,
+        ))?;
+        stored += FRAME_HEADER_SIZE + len + TAG_SIZE as u64;
+        if last {
+            return Ok(stored);
+        }
+    }
+}
+
+/// Read the length of the next frame and whether it is the last
+fn read_frame_header(input: &mut impl Read) -> io::Result<(u64, bool)> {
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    let (len, last) = (u64::from(len & !LAST_FRAME), len & LAST_FRAME != 0);
+
+    if len > MAX_FRAME_SIZE as u64 {
+        return Err(invalid_frame(format!("frame of {len} bytes")));
+    }
+    Ok((len, last))
+}
+
+fn authentication_failed(index: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, AuthenticationFailed(index))
+}
+
+fn invalid_frame(msg: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt encrypted data: {msg}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn record(checkpoint_id: u64, index: u32, vaddr_start: u64) -> RecordContext {
+        let header = AllocationHeader {
+            vaddr_start,
+            vaddr_end: vaddr_start + 0x10000,
+            size: 0x10000,
+            device_id: 0,
+            flags: 0,
+        };
+        RecordContext::new(checkpoint_id, index, &header)
+    }
+
+    fn seal(key: &EncryptionKey, data: &[u8], frame_size: usize) -> Vec<u8> {
+        seal_record(key, record(1, 0, 0x100000), data, frame_size)
+    }
+
+    fn seal_record(
+        key: &EncryptionKey,
+        record: RecordContext,
+        data: &[u8],
+        frame_size: usize,
+    ) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), key, record, frame_size);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_frames_roundtrip() {
+        let key = EncryptionKey::new(&[0x42; KEY_SIZE]);
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        // Data ending exactly on a frame boundary as well as partway
+        for frame_size in [10_000, 16 * 1024] {
+            let mut stored = seal(&key, &data, frame_size);
+            let stored_len = stored.len() as u64;
+            assert_ne!(stored[FRAME_HEADER_SIZE as usize..][..64], data[..64]);
+            stored.extend_from_slice(b"tail");
+
+            let mut input = Cursor::new(&stored);
+            let mut reader = DecryptReader::new(&mut input, &key, record(1, 0, 0x100000));
+            let mut restored = vec![0u8; data.len()];
+            reader.read_exact(&mut restored).unwrap();
+            reader.finish().unwrap();
+            assert_eq!(restored, data);
+            // Nothing past the frames was consumed
+            assert_eq!(input.position(), stored_len);
+
+            let mut input = Cursor::new(&stored);
+            assert_eq!(skip_frames(&mut input).unwrap(), stored_len);
+        }
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let key = EncryptionKey::new(&[0x42; KEY_SIZE]);
+        let data = vec![7u8; 10_000];
+        let stored = seal(&key, &data, 4096);
+        let frame_len = FRAME_HEADER_SIZE as usize + 4096 + TAG_SIZE;
+        let decrypt = |stored: &[u8], key: &EncryptionKey| {
+            let mut restored = Vec::new();
+            DecryptReader::new(Cursor::new(stored), key, record(1, 0, 0x100000))
+                .read_to_end(&mut restored)
+                .map(|_| restored)
+        };
+
+        let wrong_key = EncryptionKey::new(&[0x43; KEY_SIZE]);
+        assert!(is_authentication_failure(
+            &decrypt(&stored, &wrong_key).unwrap_err()
+        ));
+
+        let mut flipped = stored.clone();
+        flipped[frame_len + 100] ^= 1;
+        assert!(is_authentication_failure(
+            &decrypt(&flipped, &key).unwrap_err()
+        ));
+
+        // Swapping the first two frames, or marking the first as the last
+        let mut swapped = stored[frame_len..2 * frame_len].to_vec();
+        swapped.extend_from_slice(&stored[..frame_len]);
+        swapped.extend_from_slice(&stored[2 * frame_len..]);
+        assert!(is_authentication_failure(
+            &decrypt(&swapped, &key).unwrap_err()
+        ));
+        let mut truncated = stored[..frame_len].to_vec();
+        truncated[3] |= 0x80;
+        assert!(is_authentication_failure(
+            &decrypt(&truncated, &key).unwrap_err()
+        ));
+
+        assert_eq!(decrypt(&stored, &key).unwrap(), data);
+    }
+
+    #[test]
+    fn test_frames_are_bound_to_their_record() {
+        let key = EncryptionKey::new(&[0x42; KEY_SIZE]);
+        let data = vec![7u8; 10_000];
+        let sealed = record(1, 3, 0x100000);
+        let stored = seal_record(&key, sealed, &data, 4096);
+        let decrypt = |record: RecordContext| {
+            let mut restored = Vec::new();
+            DecryptReader::new(Cursor::new(&stored), &key, record)
+                .read_to_end(&mut restored)
+                .map(|_| restored)
+        };
+
+        assert_eq!(decrypt(sealed).unwrap(), data);
+
+        // Another checkpoint, another record, or an edited allocation header
+        let mut edited = AllocationHeader {
+            vaddr_start: 0x100000,
+            vaddr_end: 0x110000,
+            size: 0x10000,
+            device_id: 0,
+            flags: 0,
+        };
+        edited.size = 0x8000;
+        for other in [
+            record(2, 3, 0x100000),
+            record(1, 4, 0x100000),
+            record(1, 3, 0x200000),
+            RecordContext::new(1, 3, &edited),
+        ] {
+            assert!(is_authentication_failure(&decrypt(other).unwrap_err()));
+        }
+    }
+}
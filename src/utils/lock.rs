@@ -0,0 +1,84 @@
+//! Advisory locking of checkpoint files
+//!
+//! Writers hold an exclusive `flock` and readers a shared one, so a restore
+//! never reads a checkpoint that is being rewritten at the same path. Locks
+//! are never waited for: contention is reported as an error right away.
+
+use crate::{GpuCheckpointError, Result};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+/// Create or truncate `path` for writing. The file is only truncated once
+/// the exclusive lock is held, so readers never see it change underneath.
+pub fn create_locked(path: &Path) -> Result<Flock<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let locked = lock_exclusive(file, path)?;
+    locked.set_len(0)?;
+    Ok(locked)
+}
+
+/// Open `path` for reading under a shared lock
+pub fn open_shared(path: &Path) -> Result<Flock<File>> {
+    lock_shared(File::open(path)?, path)
+}
+
+/// Take an exclusive lock on `file`, which was opened from `path`
+pub fn lock_exclusive(file: File, path: &Path) -> Result<Flock<File>> {
+    Flock::lock(file, FlockArg::LockExclusiveNonblock).map_err(|(_, errno)| match errno {
+        Errno::EWOULDBLOCK => GpuCheckpointError::CheckpointError(format!(
+            "checkpoint file {} is in use by another process",
+            path.display()
+        )),
+        errno => GpuCheckpointError::IoError(errno.into()),
+    })
+}
+
+/// Take a shared lock on `file`, which was opened from `path`
+pub fn lock_shared(file: File, path: &Path) -> Result<Flock<File>> {
+    Flock::lock(file, FlockArg::LockSharedNonblock).map_err(|(_, errno)| match errno {
+        Errno::EWOULDBLOCK => GpuCheckpointError::RestoreError(format!(
+            "checkpoint file {} is being written by another process",
+            path.display()
+        )),
+        errno => GpuCheckpointError::IoError(errno.into()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_writer_excludes_readers() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("checkpoint.bin");
+
+        let mut writer = create_locked(&path).unwrap();
+        writer.write_all(b"partial").unwrap();
+
+        let err = open_shared(&path).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("is being written by another process"));
+        assert!(create_locked(&path).is_err());
+
+        drop(writer);
+        let reader = open_shared(&path).unwrap();
+        assert_eq!(reader.metadata().unwrap().len(), 7);
+
+        // Readers share, but keep writers from truncating the file
+        let _second_reader = open_shared(&path).unwrap();
+        let err = create_locked(&path).unwrap_err();
+        assert!(err.to_string().contains("is in use by another process"));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 7);
+    }
+}
@@ -1,3 +1,53 @@
+pub mod audit;
+pub mod checksum;
+pub mod compression;
+pub mod encryption;
+pub mod lock;
+pub mod process_vm;
+pub mod progress;
+pub mod throttle;
+
+/// Size of a base memory page on this system
+pub fn page_size() -> u64 {
+    // SAFETY: sysconf has no preconditions
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 {
+        size as u64
+    } else {
+        4096
+    }
+}
+
+/// Length of the buffer used to move `size` bytes in windows of
+/// `window_size`. The size is compared as a `u64`, so allocations larger than
+/// the address space of a 32-bit host clamp to the window instead of being
+/// truncated.
+pub fn window_len(window_size: usize, size: u64) -> usize {
+    usize::try_from(size).map_or(window_size, |size| window_size.min(size))
+}
+
+/// Whether the address range `start..end` lies within the address space of
+/// this host. Always true on 64-bit hosts.
+pub fn is_addressable(start: u64, end: u64) -> bool {
+    start <= end && usize::try_from(end.saturating_sub(1)).is_ok()
+}
+
+/// Host name of this machine, or "localhost" if it cannot be determined
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for writes of its full length
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret != 0 {
+        return "localhost".to_string();
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    match std::str::from_utf8(&buf[..len]) {
+        Ok(name) if !name.is_empty() => name.to_string(),
+        _ => "localhost".to_string(),
+    }
+}
+
 pub fn format_memory(bytes: u64) -> String {
     const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
 
@@ -20,6 +70,76 @@ pub fn format_memory(bytes: u64) -> String {
     }
 }
 
+/// Parse a size such as "512MiB", "1.5 GiB" or "64kb" into bytes. Binary
+/// (KiB, MiB, ...) and decimal (KB, MB, ...) suffixes are accepted in any
+/// case; a bare number is a count of bytes.
+pub fn parse_memory(s: &str) -> crate::Result<u64> {
+    let invalid = |reason: String| {
+        crate::GpuCheckpointError::CheckpointError(format!("invalid size {s:?}: {reason}"))
+    };
+
+    let s = s.trim();
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_start);
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        "pib" => 1 << 50,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "pb" => 1_000_000_000_000_000,
+        other => return Err(invalid(format!("unknown unit {other:?}"))),
+    };
+
+    if let Ok(count) = number.parse::<u64>() {
+        return count
+            .checked_mul(multiplier)
+            .ok_or_else(|| invalid("does not fit in 64 bits".to_string()));
+    }
+    let count: f64 = number
+        .parse()
+        .map_err(|_| invalid("expected a number followed by an optional unit".to_string()))?;
+    let bytes = (count * multiplier as f64).round();
+    if bytes >= u64::MAX as f64 {
+        return Err(invalid("does not fit in 64 bits".to_string()));
+    }
+    Ok(bytes as u64)
+}
+
+/// Parse an age such as "30m", "12h" or "7d" into a duration. The units
+/// are s, m, h, d and w; a bare number is a count of seconds.
+pub fn parse_duration(s: &str) -> crate::Result<std::time::Duration> {
+    let invalid = |reason: &str| {
+        crate::GpuCheckpointError::CheckpointError(format!("invalid duration {s:?}: {reason}"))
+    };
+
+    let s = s.trim();
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_start);
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid("expected a whole number of s, m, h, d or w")),
+    };
+    let count: u64 = number
+        .parse()
+        .map_err(|_| invalid("expected a whole number of s, m, h, d or w"))?;
+    count
+        .checked_mul(multiplier)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| invalid("too long"))
+}
+
 pub fn format_duration(ms: u64) -> String {
     if ms < 1000 {
         format!("{ms}ms")
@@ -48,6 +168,30 @@ mod tests {
         assert_eq!(format_memory(1024u64.pow(5)), "1.00 PiB");
     }
 
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("0 B").unwrap(), 0);
+        assert_eq!(parse_memory("512 B").unwrap(), 512);
+        assert_eq!(parse_memory("1.00 KiB").unwrap(), 1024);
+        assert_eq!(parse_memory("1.50 KiB").unwrap(), 1536);
+        assert_eq!(parse_memory("1.00 MiB").unwrap(), 1024 * 1024);
+        assert_eq!(parse_memory("1.00 GiB").unwrap(), 1024 * 1024 * 1024);
+        assert_eq!(parse_memory("1.00 TiB").unwrap(), 1024u64.pow(4));
+        assert_eq!(parse_memory("1.00 PiB").unwrap(), 1024u64.pow(5));
+
+        assert_eq!(parse_memory("4096").unwrap(), 4096);
+        assert_eq!(parse_memory("  512MiB  ").unwrap(), 512 << 20);
+        assert_eq!(parse_memory("512mib").unwrap(), 512 << 20);
+        assert_eq!(parse_memory("2 GIB").unwrap(), 2 << 30);
+        assert_eq!(parse_memory("64KB").unwrap(), 64_000);
+        assert_eq!(parse_memory("1.5 mb").unwrap(), 1_500_000);
+
+        for malformed in ["", "MiB", "12 parsecs", "1.2.3 KiB", "-1 KiB", "1 K iB"] {
+            assert!(parse_memory(malformed).is_err(), "{malformed:?} parsed");
+        }
+        assert!(parse_memory("16384 PiB").is_err());
+    }
+
     #[test]
     fn test_format_duration() {
         assert_eq!(format_duration(0), "0ms");
@@ -58,4 +202,57 @@ mod tests {
         assert_eq!(format_duration(65_500), "1m5s");
         assert_eq!(format_duration(125_000), "2m5s");
     }
+
+    #[test]
+    fn test_parse_duration() {
+        use std::time::Duration;
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(30 * 60));
+        assert_eq!(
+            parse_duration(" 12 H ").unwrap(),
+            Duration::from_secs(12 * 3600)
+        );
+        assert_eq!(
+            parse_duration("7d").unwrap(),
+            Duration::from_secs(7 * 86400)
+        );
+        assert_eq!(
+            parse_duration("2w").unwrap(),
+            Duration::from_secs(14 * 86400)
+        );
+
+        for malformed in [
+            "",
+            "d",
+            "1.5h",
+            "-1d",
+            "3 fortnights",
+            "99999999999999999999w",
+        ] {
+            assert!(parse_duration(malformed).is_err(), "{malformed:?} parsed");
+        }
+    }
+
+    #[test]
+    fn test_window_len_clamps() {
+        let window = 1024 * 1024;
+        assert_eq!(window_len(window, 0), 0);
+        assert_eq!(window_len(window, 4096), 4096);
+        assert_eq!(window_len(window, window as u64), window);
+        // Sizes past 4 GiB must not wrap to a small buffer on 32-bit hosts
+        assert_eq!(window_len(window, (1u64 << 32) + 16), window);
+        assert_eq!(window_len(window, u64::MAX), window);
+    }
+
+    #[test]
+    fn test_is_addressable() {
+        assert!(is_addressable(0, 0));
+        assert!(is_addressable(0x1000, 0x2000));
+        assert!(!is_addressable(0x2000, 0x1000));
+        assert!(is_addressable(0, 1 << 32));
+
+        let beyond_4g = is_addressable(1 << 32, (1 << 32) + 0x1000);
+        assert_eq!(beyond_4g, cfg!(target_pointer_width = "64"));
+    }
 }
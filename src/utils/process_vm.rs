@@ -0,0 +1,243 @@
+//! Access to the memory of another process
+//!
+//! `/proc/PID/mem` has to be opened and costs a positioned syscall per
+//! window. `process_vm_readv`/`process_vm_writev` copy between the address
+//! spaces directly, without a file handle, but honour page protection and
+//! may be unsupported or disallowed. `ProcessMemory` uses them when enabled
+//! and falls back to `/proc/PID/mem` for any transfer they cannot do.
+
+use nix::errno::Errno;
+use nix::sys::uio::{process_vm_readv, process_vm_writev, RemoteIoVec};
+use nix::unistd::Pid;
+use std::cell::{Cell, OnceCell};
+use std::fs::{File, OpenOptions};
+use std::io::{self, IoSlice, IoSliceMut, Read};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use tracing::debug;
+
+/// Memory of one process, addressed like `/proc/PID/mem`
+#[derive(Debug)]
+pub struct ProcessMemory {
+    pid: Pid,
+
+    /// File transfers fall back to
+    path: PathBuf,
+
+    writable: bool,
+
+    /// Whether `process_vm_readv`/`process_vm_writev` are still tried
+    process_vm: Cell<bool>,
+
+    /// Opened on first use when the syscalls are enabled
+    file: OnceCell<File>,
+}
+
+impl ProcessMemory {
+    /// Memory of `pid` for reading, or writing if `writable`. Without
+    /// `process_vm` everything goes through `/proc/PID/mem`, which is
+    /// opened right away.
+    pub fn open(pid: u32, writable: bool, process_vm: bool) -> io::Result<Self> {
+        let memory = Self {
+            pid: Pid::from_raw(pid as i32),
+            path: PathBuf::from(format!("/proc/{pid}/mem")),
+            writable,
+            process_vm: Cell::new(process_vm),
+            file: OnceCell::new(),
+        };
+        if !process_vm {
+            memory.file()?;
+        }
+        Ok(memory)
+    }
+
+    /// Memory in the file at `path`, addressed like `/proc/PID/mem`, e.g.
+    /// a copy of a process's memory
+    pub fn file_at(path: impl Into<PathBuf>, writable: bool) -> io::Result<Self> {
+        let memory = Self {
+            pid: Pid::from_raw(0),
+            path: path.into(),
+            writable,
+            process_vm: Cell::new(false),
+            file: OnceCell::new(),
+        };
+        memory.file()?;
+        Ok(memory)
+    }
+
+    /// Read front to back from `addr`
+    pub fn reader_at(&self, addr: u64) -> MemoryReader<'_> {
+        MemoryReader { memory: self, addr }
+    }
+
+    fn file(&self) -> io::Result<&File> {
+        if let Some(file) = self.file.get() {
+            return Ok(file);
+        }
+        let file = OpenOptions::new()
+            .read(!self.writable)
+            .write(self.writable)
+            .open(&self.path)?;
+        Ok(self.file.get_or_init(|| file))
+    }
+
+    /// Whether a transfer the syscalls failed with `errno` is retried
+    /// through the file. Syscalls that are unsupported or not permitted are
+    /// not tried again.
+    fn falls_back(&self, errno: Errno) -> bool {
+        match errno {
+            // Unmapped or, for writes, write-protected pages
+            Errno::EFAULT => true,
+            Errno::ENOSYS | Errno::EPERM => {
+                debug!(
+                    "process_vm transfers with PID {} failed ({}), using {}",
+                    self.pid,
+                    errno,
+                    self.path.display()
+                );
+                self.process_vm.set(false);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FileExt for ProcessMemory {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if self.process_vm.get() && !buf.is_empty() {
+            let remote = [RemoteIoVec {
+                base: offset as usize,
+                len: buf.len(),
+            }];
+            match process_vm_readv(self.pid, &mut [IoSliceMut::new(buf)], &remote) {
+                Ok(len) => return Ok(len),
+                Err(e) if self.falls_back(e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.file()?.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        if self.process_vm.get() && !buf.is_empty() {
+            let remote = [RemoteIoVec {
+                base: offset as usize,
+                len: buf.len(),
+            }];
+            match process_vm_writev(self.pid, &[IoSlice::new(buf)], &remote) {
+                Ok(len) => return Ok(len),
+                Err(e) if self.falls_back(e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.file()?.write_at(buf, offset)
+    }
+}
+
+/// Reads a process's memory sequentially
+pub struct MemoryReader<'a> {
+    memory: &'a ProcessMemory,
+    addr: u64,
+}
+
+impl Read for MemoryReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.memory.read_at(buf, self.addr)?;
+        self.addr += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_vm_matches_proc_mem() {
+        let page_size = crate::utils::page_size() as usize;
+        let mut buffer = memmap2::MmapMut::map_anon(3 * page_size).unwrap();
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+        let addr = buffer.as_ptr() as u64;
+        let pid = std::process::id();
+
+        let read = |process_vm| {
+            let memory = ProcessMemory::open(pid, false, process_vm).unwrap();
+            let mut data = Vec::new();
+            memory
+                .reader_at(addr)
+                .take(buffer.len() as u64)
+                .read_to_end(&mut data)
+                .unwrap();
+            data
+        };
+        assert_eq!(read(true), read(false));
+        assert_eq!(read(true), buffer[..]);
+    }
+
+    #[test]
+    fn test_child_memory_matches_proc_mem() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let pid = child.id();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        nix::sys::signal::kill(Pid::from_raw(pid as i32), nix::sys::signal::SIGSTOP).unwrap();
+
+        let regions = crate::detector::MemoryMapParser::parse_maps(pid).unwrap();
+        let fast = ProcessMemory::open(pid, false, true).unwrap();
+        let slow = ProcessMemory::open(pid, false, false).unwrap();
+        let mut compared = 0;
+        for region in regions.iter().filter(|r| r.perms.starts_with('r')) {
+            let mut a = vec![0u8; (region.end - region.start) as usize];
+            let mut b = a.clone();
+            // Special mappings such as [vvar] cannot be read either way
+            let (Ok(()), Ok(())) = (
+                fast.read_exact_at(&mut a, region.start),
+                slow.read_exact_at(&mut b, region.start),
+            ) else {
+                continue;
+            };
+            assert!(a == b, "contents of 0x{:x} differ", region.start);
+            compared += 1;
+        }
+
+        // Write into the child and read back through /proc/PID/mem
+        let writable = regions
+            .iter()
+            .find(|r| r.perms == "rw-p" && r.pathname.is_none())
+            .expect("sleep has an anonymous writable mapping");
+        let result = ProcessMemory::open(pid, true, true)
+            .and_then(|memory| memory.write_all_at(&[0x5a; 64], writable.start));
+        let mut written = [0u8; 64];
+        slow.read_exact_at(&mut written, writable.start).unwrap();
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        assert!(compared > 0);
+        result.unwrap();
+        assert_eq!(written, [0x5a; 64]);
+    }
+
+    #[test]
+    fn test_write_falls_back_for_protected_pages() {
+        let page_size = crate::utils::page_size() as usize;
+        let buffer = memmap2::MmapMut::map_anon(page_size).unwrap();
+        let buffer = buffer.make_read_only().unwrap();
+        let addr = buffer.as_ptr() as u64;
+
+        // process_vm_writev fails with EFAULT on a read-only page, while
+        // /proc/PID/mem writes through the protection
+        let memory = ProcessMemory::open(std::process::id(), true, true).unwrap();
+        memory.write_all_at(&[0x42; 16], addr).unwrap();
+        assert!(buffer[..16].iter().all(|&b| b == 0x42));
+        assert!(memory.process_vm.get());
+
+        // Unmapped addresses fail either way
+        assert!(memory.write_all_at(&[0; 8], 0).is_err());
+    }
+}
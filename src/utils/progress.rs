@@ -0,0 +1,239 @@
+#[cfg(feature = "progress")]
+use indicatif::{HumanDuration, ProgressBar, ProgressState, ProgressStyle};
+#[cfg(feature = "progress")]
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Caller-supplied sink for progress, called with the input bytes processed
+/// so far each time a transfer advances. Clones share the callback.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<Mutex<dyn FnMut(u64) + Send>>);
+
+impl ProgressCallback {
+    pub fn new(callback: impl FnMut(u64) + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(callback)))
+    }
+
+    fn call(&self, processed: u64) {
+        if let Ok(mut callback) = self.0.lock() {
+            callback(processed);
+        }
+    }
+}
+
+impl std::fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// Lifecycle of a checkpoint or restore, for embedders that relay progress
+/// to their own clients. Byte counts are of input processed: memory read
+/// while checkpointing, checkpoint data consumed while restoring.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckpointEvent {
+    /// The transfer of `total_bytes` in `num_allocations` allocations of
+    /// `pid` began
+    Started {
+        pid: u32,
+        num_allocations: u32,
+        total_bytes: u64,
+    },
+    /// Allocation `index` of `count`, at `vaddr_start`, is being transferred
+    AllocationStarted {
+        index: u32,
+        count: u32,
+        vaddr_start: u64,
+        size: u64,
+    },
+    /// `bytes` have been processed so far
+    BytesDone { bytes: u64 },
+    /// The transfer completed, having written (checkpoint) or restored
+    /// (restore) `bytes`
+    Finished { bytes: u64 },
+}
+
+/// Progress of a checkpoint or restore transfer.
+///
+/// Progress advances with input bytes (memory read or checkpoint data
+/// consumed) while output bytes are tracked separately, so the ETA follows
+/// the effective processing rate even when output is smaller than input,
+/// e.g. when compression and not I/O is the bottleneck.
+///
+/// It is drawn as a bar on stderr if the crate is built with the
+/// `progress` feature (the default), and reported to a [`ProgressCallback`] and as
+/// [`CheckpointEvent`]s if given.
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+    #[cfg(feature = "progress")]
+    bar: Option<ProgressBar>,
+    callback: Option<ProgressCallback>,
+    events: Option<Sender<CheckpointEvent>>,
+    input_bytes: Arc<AtomicU64>,
+    output_bytes: Arc<AtomicU64>,
+}
+
+impl TransferProgress {
+    /// Progress of a transfer of `total_bytes` drawn as a bar
+    pub fn new(total_bytes: u64) -> Self {
+        Self::with_reporting(total_bytes, true, None)
+    }
+
+    /// Progress of a transfer of `total_bytes`, drawn as a bar if
+    /// `show_bar` and reported to `callback`
+    pub fn with_reporting(
+        total_bytes: u64,
+        show_bar: bool,
+        callback: Option<ProgressCallback>,
+    ) -> Self {
+        #[cfg(not(feature = "progress"))]
+        let _ = (total_bytes, show_bar);
+
+        Self {
+            #[cfg(feature = "progress")]
+            bar: show_bar.then(|| Self::bar(total_bytes)),
+            callback,
+            events: None,
+            input_bytes: Arc::new(AtomicU64::new(0)),
+            output_bytes: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Also send lifecycle events to `events`
+    pub fn with_events(mut self, events: Option<Sender<CheckpointEvent>>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Send `event` to the events channel, if any. A receiver that hung up
+    /// does not stop the transfer.
+    pub fn emit(&self, event: CheckpointEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    #[cfg(feature = "progress")]
+    fn bar(total_bytes: u64) -> ProgressBar {
+        let bar = ProgressBar::new(total_bytes);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} ({eta})")
+                .unwrap()
+                .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
+                    if let Some(eta) =
+                        estimate_remaining(state.pos(), state.len().unwrap_or(0), state.elapsed())
+                    {
+                        let _ = write!(w, "{:#}", HumanDuration(eta));
+                    }
+                })
+                .progress_chars("=>-"),
+        );
+        bar
+    }
+
+    /// Record `input` bytes processed, producing `output` bytes
+    pub fn inc(&self, input: u64, output: u64) {
+        self.output_bytes.fetch_add(output, Ordering::Relaxed);
+        let processed = self.input_bytes.fetch_add(input, Ordering::Relaxed) + input;
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.bar {
+            bar.inc(input);
+        }
+        if let Some(callback) = &self.callback {
+            callback.call(processed);
+        }
+        self.emit(CheckpointEvent::BytesDone { bytes: processed });
+    }
+
+    pub fn input_bytes(&self) -> u64 {
+        self.input_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn output_bytes(&self) -> u64 {
+        self.output_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn finish_with_message(&self, message: &'static str) {
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.bar {
+            bar.finish_with_message(message);
+        }
+        #[cfg(not(feature = "progress"))]
+        let _ = message;
+    }
+}
+
+/// Time left to process `total` input bytes at the average rate so far
+pub fn estimate_remaining(processed: u64, total: u64, elapsed: Duration) -> Option<Duration> {
+    if processed == 0 || elapsed.is_zero() {
+        return None;
+    }
+
+    let rate = processed as f64 / elapsed.as_secs_f64();
+    let remaining = total.saturating_sub(processed) as f64;
+    Some(Duration::from_secs_f64(remaining / rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_remaining() {
+        assert_eq!(estimate_remaining(0, 100, Duration::from_secs(1)), None);
+        assert_eq!(
+            estimate_remaining(25, 100, Duration::from_secs(10)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            estimate_remaining(100, 100, Duration::from_secs(10)),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_tracks_input_and_output() {
+        let progress = TransferProgress::new(1000);
+        progress.inc(400, 100);
+        progress.inc(100, 100);
+        assert_eq!(progress.input_bytes(), 500);
+        assert_eq!(progress.output_bytes(), 200);
+    }
+
+    #[test]
+    fn test_reports_to_callback() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&reported);
+        let progress = TransferProgress::with_reporting(
+            1000,
+            false,
+            Some(ProgressCallback::new(move |processed| {
+                sink.lock().unwrap().push(processed)
+            })),
+        );
+        progress.inc(400, 100);
+        progress.clone().inc(100, 100);
+        assert_eq!(*reported.lock().unwrap(), vec![400, 500]);
+    }
+
+    #[test]
+    fn test_sends_events() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let progress =
+            TransferProgress::with_reporting(1000, false, None).with_events(Some(sender));
+        progress.inc(400, 100);
+        progress.emit(CheckpointEvent::Finished { bytes: 100 });
+        drop(progress);
+        assert_eq!(
+            receiver.iter().collect::<Vec<_>>(),
+            vec![
+                CheckpointEvent::BytesDone { bytes: 400 },
+                CheckpointEvent::Finished { bytes: 100 },
+            ]
+        );
+    }
+}
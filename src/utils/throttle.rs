@@ -0,0 +1,99 @@
+//! Pacing of checkpoint writes to a storage bandwidth
+//!
+//! A checkpoint otherwise writes as fast as the disk accepts data, which can
+//! saturate storage shared with colocated jobs. The writer sleeps whenever
+//! it gets ahead of the configured rate, so over the whole checkpoint the
+//! average rate stays at or below it.
+
+use std::io::{self, Seek, SeekFrom, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Bytes in the megabyte of a `bandwidth_mbps`
+const MEGABYTE: f64 = 1024.0 * 1024.0;
+
+/// Writer that paces the bytes written through it to a rate
+pub struct ThrottledWriter<W: Write> {
+    inner: W,
+    bytes_per_sec: Option<f64>,
+    start: Instant,
+    written: u64,
+}
+
+impl<W: Write> ThrottledWriter<W> {
+    /// Writer limited to `bandwidth_mbps` MB/s, or unthrottled if it is 0
+    pub fn new(inner: W, bandwidth_mbps: u64) -> Self {
+        Self {
+            inner,
+            bytes_per_sec: (bandwidth_mbps > 0).then_some(bandwidth_mbps as f64 * MEGABYTE),
+            start: Instant::now(),
+            written: 0,
+        }
+    }
+
+    /// Number of bytes written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    /// Whether writes are paced at all
+    pub fn is_throttled(&self) -> bool {
+        self.bytes_per_sec.is_some()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Sleep until writing the bytes so far is within the rate
+    fn pace(&self) {
+        let Some(bytes_per_sec) = self.bytes_per_sec else {
+            return;
+        };
+        let due = Duration::from_secs_f64(self.written as f64 / bytes_per_sec);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+impl<W: Write> Write for ThrottledWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        self.pace();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for ThrottledWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paces_writes() {
+        let mut output = ThrottledWriter::new(Vec::new(), 1);
+        let start = Instant::now();
+        for _ in 0..4 {
+            output.write_all(&[0u8; 64 * 1024]).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(output.bytes_written(), 256 * 1024);
+
+        // A rate of 0 does not throttle
+        let mut output = ThrottledWriter::new(Vec::new(), 0);
+        let start = Instant::now();
+        output.write_all(&[0u8; 1 << 20]).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(250));
+    }
+}
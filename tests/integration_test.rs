@@ -1,9 +1,26 @@
+// The CLI tests pass their arguments as borrowed arrays
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use gpu_checkpoint::{
-    checkpoint::{bar_sliding::BarSlidingCheckpoint, CheckpointEngine, CheckpointStrategy},
-    detector::{AllocationType, CompositeDetector, DetectionResult, GpuAllocation, GpuVendor},
-    restore::BarRestore,
+    checkpoint::{
+        bar_sliding::BarSlidingCheckpoint, checkpoint_schema, AllocationTiming, CheckpointConfig,
+        CheckpointEngine, CheckpointMetadata, CheckpointStrategy, CudaProcessState, CudaToggle,
+        LaunchCommand, DEFAULT_STORAGE_PATH,
+    },
+    detector::{
+        AllocationType, CompositeDetector, DetectionResult, GpuAllocation, GpuVendor,
+        NvidiaDetector,
+    },
+    restore::{
+        inspect_checkpoint, restore_schema, spawn_target, wait_for_gpu_context, BarRestore,
+        RestoreEngine, RestoreMetadata, RestoreMode,
+    },
+    utils::compression::CompressionThroughput,
 };
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::fs::FileExt;
 use std::process::Command;
+use std::time::{Duration, Instant};
 use tempfile::tempdir;
 
 #[test]
@@ -13,11 +30,18 @@ fn test_composite_detector_no_gpu() {
 
     // Use current process PID for testing
     let pid = std::process::id();
-    let results = detector.detect_all(pid).expect("Detection should not fail");
+    let detections = detector.detect_all(pid).expect("Detection should not fail");
+    assert!(detections.errors.is_empty(), "{:?}", detections.errors);
+    let results = detections.results;
 
     // On non-GPU systems or processes, results should be empty
     // This test will pass on CI where no GPUs are present
     assert!(results.is_empty() || results.iter().all(|r| r.allocations.is_empty()));
+    for result in &results {
+        result
+            .validate()
+            .expect("Detection result should be consistent");
+    }
 }
 
 #[test]
@@ -54,6 +78,171 @@ fn test_strategy_selection() {
     );
 }
 
+#[test]
+fn test_strategy_selection_per_vendor() {
+    // CUDA checkpoint is never selected for non-NVIDIA processes
+    let mut amd_result = DetectionResult::new(1234, GpuVendor::Amd);
+    amd_result.add_allocation(GpuAllocation::new(
+        0x100000000,
+        0x200000000,
+        AllocationType::Standard,
+    ));
+    assert_eq!(
+        CheckpointEngine::select_strategy(&amd_result),
+        CheckpointStrategy::BarSliding
+    );
+
+    // Per-vendor overrides take precedence over the heuristics
+    let mut nvidia_result = DetectionResult::new(1234, GpuVendor::Nvidia);
+    nvidia_result.add_allocation(GpuAllocation::new(
+        0x100000000,
+        0x200000000,
+        AllocationType::Standard,
+    ));
+    let overrides = HashMap::from([(GpuVendor::Nvidia, CheckpointStrategy::BarSliding)]);
+    assert_eq!(
+        CheckpointEngine::select_strategy_with_overrides(&nvidia_result, &overrides),
+        CheckpointStrategy::BarSliding
+    );
+    assert_eq!(
+        CheckpointEngine::select_strategy_with_overrides(&amd_result, &overrides),
+        CheckpointStrategy::BarSliding
+    );
+
+    // ...but a process without allocations is still skipped
+    let empty = DetectionResult::new(1234, GpuVendor::Nvidia);
+    assert_eq!(
+        CheckpointEngine::select_strategy_with_overrides(&empty, &overrides),
+        CheckpointStrategy::SkipGpu
+    );
+}
+
+#[test]
+fn test_strategy_explanation() {
+    let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+    for i in 0..12 {
+        let start = 0x100000000 + i * 0x10000000;
+        result.add_allocation(GpuAllocation::new(
+            start,
+            start + 0x10000000,
+            AllocationType::Uvm,
+        ));
+    }
+    result.add_allocation(GpuAllocation::new(0x1000, 0x2000, AllocationType::Ipc));
+
+    let (strategy, reason) = CheckpointEngine::select_strategy_explained(&result);
+    assert_eq!(strategy, CheckpointStrategy::BarSliding);
+    assert_eq!(
+        reason,
+        "12 UVM allocations (3.00 GiB) and 1 IPC allocation (4.00 KiB) are not \
+         CUDA-checkpointable, so BAR sliding is required"
+    );
+
+    // Pinned host memory does not need BAR sliding
+    let mut result = DetectionResult::new(1234, GpuVendor::Nvidia);
+    result.add_allocation(GpuAllocation::new(
+        0x100000000,
+        0x140000000,
+        AllocationType::Standard,
+    ));
+    result.add_allocation(GpuAllocation::new(
+        0x7f0000000000,
+        0x7f0000400000,
+        AllocationType::HostPinned,
+    ));
+    let (strategy, reason) = CheckpointEngine::select_strategy_explained(&result);
+    assert_eq!(strategy, CheckpointStrategy::CudaCheckpoint);
+    assert_eq!(
+        reason,
+        "all 1 device allocations (1.00 GiB) are standard device memory, which the CUDA \
+         checkpoint API can capture; 1 pinned host allocation (4.00 MiB) is captured with \
+         process memory"
+    );
+
+    let empty = DetectionResult::new(1234, GpuVendor::Nvidia);
+    let (strategy, reason) = CheckpointEngine::select_strategy_explained(&empty);
+    assert_eq!(strategy, CheckpointStrategy::SkipGpu);
+    assert!(reason.contains("no GPU allocations"));
+
+    // A forced strategy says so
+    let engine = CheckpointEngine::new(CheckpointConfig {
+        strategy: CheckpointStrategy::BarSliding,
+        ..CheckpointConfig::new(DEFAULT_STORAGE_PATH.to_string())
+    });
+    let (strategy, reason) = engine.resolve_strategy_explained(&empty);
+    assert_eq!(strategy, CheckpointStrategy::BarSliding);
+    assert!(reason.contains("requested explicitly"));
+}
+
+#[tokio::test]
+async fn test_auto_strategy_resolved_by_engine() {
+    let engine = CheckpointEngine::new(CheckpointConfig {
+        strategy: CheckpointStrategy::Auto,
+        vendor_strategies: HashMap::from([(GpuVendor::Amd, CheckpointStrategy::Auto)]),
+        ..CheckpointConfig::new(DEFAULT_STORAGE_PATH.to_string())
+    });
+
+    let mut uvm_result = DetectionResult::new(1234, GpuVendor::Nvidia);
+    uvm_result.add_allocation(GpuAllocation::new(0x1000, 0x2000, AllocationType::Uvm));
+    assert_eq!(
+        engine.resolve_strategy(&uvm_result),
+        CheckpointStrategy::BarSliding
+    );
+
+    // An Auto override defers to the heuristics
+    let mut amd_result = DetectionResult::new(1234, GpuVendor::Amd);
+    amd_result.add_allocation(GpuAllocation::new(0x1000, 0x2000, AllocationType::Standard));
+    assert_eq!(
+        engine.resolve_strategy(&amd_result),
+        CheckpointStrategy::BarSliding
+    );
+
+    // The resolved strategy is what gets recorded
+    let empty = DetectionResult::new(1234, GpuVendor::Nvidia);
+    let metadata = engine.checkpoint(1234, &empty).await.unwrap();
+    assert_eq!(metadata.strategy_used, CheckpointStrategy::SkipGpu);
+}
+
+#[test]
+fn test_checkpoint_estimate() {
+    let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+    detection.add_allocation(GpuAllocation::new(
+        0x100000,
+        0x100000 + (64 << 20),
+        AllocationType::Standard,
+    ));
+    detection.add_allocation(GpuAllocation::new(
+        0x8000000,
+        0x8000000 + (192 << 20),
+        AllocationType::Uvm,
+    ));
+
+    let estimate_at = |bandwidth_mbps| {
+        CheckpointEngine::new(CheckpointConfig {
+            bandwidth_mbps,
+            ..CheckpointConfig::new(DEFAULT_STORAGE_PATH.to_string())
+        })
+        .estimate(1234, &detection)
+        .unwrap()
+    };
+
+    let estimate = estimate_at(128);
+    assert_eq!(estimate.size_bytes, detection.total_gpu_memory);
+    assert_eq!(estimate.num_allocations, 2);
+    assert_eq!(estimate.strategy, CheckpointStrategy::BarSliding);
+    // 256 MiB at 128 MB/s
+    assert_eq!(estimate.duration_ms, 2000);
+    assert_eq!(estimate_at(256).duration_ms, 1000);
+    assert_eq!(estimate_at(64).duration_ms, 4000);
+
+    assert!(CheckpointEngine::new(CheckpointConfig {
+        bandwidth_mbps: 0,
+        ..CheckpointConfig::new(DEFAULT_STORAGE_PATH.to_string())
+    })
+    .estimate(1234, &detection)
+    .is_err());
+}
+
 #[test]
 fn test_allocation_classification() {
     // Test that allocations are properly classified as problematic
@@ -73,6 +262,470 @@ fn test_allocation_classification() {
     assert!(distributed.is_problematic());
 }
 
+#[test]
+fn test_default_constructible_engines() {
+    fn build<T: Default>() -> T {
+        T::default()
+    }
+
+    let _: CompositeDetector = build();
+    let _: NvidiaDetector = build();
+    let _: BarSlidingCheckpoint = build();
+    let _: BarRestore = build();
+}
+
+#[tokio::test]
+async fn test_checkpoint_manifest_roundtrip() {
+    let dir = tempdir().unwrap();
+    let config = CheckpointConfig::new(dir.path().to_str().unwrap().to_string());
+
+    let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+    detection.add_allocation(GpuAllocation::new(
+        0x100000,
+        0x102000,
+        AllocationType::Standard,
+    ));
+    let metadata = CheckpointEngine::new(config)
+        .checkpoint(1234, &detection)
+        .await
+        .unwrap();
+
+    let checkpoint_path = dir.path().join("checkpoint_1234.bin");
+    let manifest_path = dir.path().join("checkpoint_1234.json");
+    assert_eq!(metadata.path.as_deref(), Some(checkpoint_path.as_path()));
+    assert_eq!(
+        CheckpointMetadata::manifest_path(&checkpoint_path),
+        manifest_path
+    );
+
+    let manifest = CheckpointMetadata::read_manifest(&manifest_path).unwrap();
+    assert_eq!(manifest.pid, metadata.pid);
+    assert_eq!(manifest.strategy_used, metadata.strategy_used);
+    assert_eq!(manifest.timestamp, metadata.timestamp);
+    assert_eq!(manifest.size_bytes, metadata.size_bytes);
+    assert_eq!(manifest.duration_ms, metadata.duration_ms);
+    assert_eq!(manifest.signed, metadata.signed);
+    assert_eq!(manifest.path, metadata.path);
+    assert_eq!(
+        manifest.checkpoint_file(&manifest_path),
+        Some(checkpoint_path.clone())
+    );
+
+    let restore_metadata = BarRestore::new()
+        .restore_from_checkpoint(&checkpoint_path, Some(5678))
+        .unwrap();
+    assert_eq!(restore_metadata.total_size, manifest.size_bytes);
+}
+
+#[test]
+fn test_output_schemas() {
+    // Every field the types serialize is described
+    let described = |schema: &serde_json::Value, value: &serde_json::Value| {
+        for key in value.as_object().unwrap().keys() {
+            assert!(
+                schema["properties"].get(key).is_some(),
+                "{key} is not described by {}",
+                schema["title"]
+            );
+        }
+    };
+
+    let metadata = CheckpointMetadata {
+        pid: 1234,
+        strategy_used: CheckpointStrategy::BarSliding,
+        timestamp: std::time::SystemTime::now(),
+        size_bytes: 8192,
+        duration_ms: 5,
+        signed: true,
+        gpus: Vec::new(),
+        environment: BTreeMap::from([("CUDA_VISIBLE_DEVICES".into(), "0".into())]),
+        tuned_window_size: Some(1 << 20),
+        layout_changed: false,
+        allocation_timings: vec![AllocationTiming::new(
+            0x100000,
+            8192,
+            Duration::from_millis(1),
+        )],
+        compression: Some(CompressionThroughput {
+            raw_bytes: 8192,
+            compressed_bytes: 512,
+            duration_ms: 1,
+            mb_per_sec: 7.8,
+        }),
+        cuda_toggle: Some(CudaToggle {
+            tool: "nvidia-cuda-checkpoint".into(),
+            state_before: CudaProcessState::Running,
+            state_after: CudaProcessState::Checkpointed,
+        }),
+        path: Some("checkpoint_1234.bin".into()),
+        manifest: Some("checkpoint_1234.json".into()),
+        base_checkpoint: Some("checkpoint_1233.bin".into()),
+        command: Some(LaunchCommand {
+            argv: vec!["python".into(), "train.py".into()],
+            cwd: Some("/work".into()),
+        }),
+        url: Some("s3://bucket/checkpoint_1234.bin".into()),
+    };
+    let schema = checkpoint_schema();
+    let json = serde_json::to_value(&metadata).unwrap();
+    described(&schema, &json);
+    let defs = &schema["$defs"];
+    for (def, value) in [
+        ("AllocationTiming", &json["allocation_timings"][0]),
+        ("CompressionThroughput", &json["compression"]),
+        ("CudaToggle", &json["cuda_toggle"]),
+        ("LaunchCommand", &json["command"]),
+    ] {
+        described(&defs[def], value);
+    }
+    let states = defs["CudaToggle"]["properties"]["state_after"]["enum"]
+        .as_array()
+        .unwrap();
+    assert!(states.contains(&json["cuda_toggle"]["state_after"]));
+    assert!(defs["CheckpointStrategy"]["enum"]
+        .as_array()
+        .unwrap()
+        .contains(&json["strategy_used"]));
+
+    let restored = RestoreMetadata {
+        pid: 5678,
+        num_allocations: 1,
+        total_size: 8192,
+        duration_ms: 2,
+        fd_translations: BTreeMap::from([(5, 7)]),
+        resumed: true,
+        applied: vec![0x100000],
+        skipped: Vec::new(),
+        write_protected: Vec::new(),
+        remapped: BTreeMap::from([(0x100000, 0x200000)]),
+        mapped: vec![0x200000],
+        previously_restored: Vec::new(),
+        cuda_toggle: Some(CudaToggle {
+            tool: "nvidia-cuda-checkpoint".into(),
+            state_before: CudaProcessState::Running,
+            state_after: CudaProcessState::Checkpointed,
+        }),
+    };
+    let schema = restore_schema();
+    let json = serde_json::to_value(&restored).unwrap();
+    described(&schema, &json);
+    for key in schema["required"].as_array().unwrap() {
+        assert!(json.get(key.as_str().unwrap()).is_some());
+    }
+}
+
+#[tokio::test]
+async fn test_checkpoint_output_file() {
+    let dir = tempdir().unwrap();
+    let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+    detection.add_allocation(GpuAllocation::new(
+        0x100000,
+        0x102000,
+        AllocationType::Standard,
+    ));
+    let checkpoint = |output_file: &str, force: bool| {
+        CheckpointEngine::new(CheckpointConfig {
+            output_file: Some(output_file.into()),
+            force,
+            ..CheckpointConfig::new(dir.path().to_str().unwrap().to_string())
+        })
+    };
+
+    let first = checkpoint("before-upgrade.bin", false)
+        .checkpoint(1234, &detection)
+        .await
+        .unwrap();
+    let second = checkpoint("after-upgrade.bin", false)
+        .checkpoint(1234, &detection)
+        .await
+        .unwrap();
+    let first_path = dir.path().join("before-upgrade.bin");
+    let second_path = dir.path().join("after-upgrade.bin");
+    assert_eq!(first.path.as_deref(), Some(first_path.as_path()));
+    assert_eq!(second.path.as_deref(), Some(second_path.as_path()));
+    for path in [&first_path, &second_path] {
+        assert!(path.exists());
+        assert!(CheckpointMetadata::manifest_path(path).exists());
+        BarRestore::new()
+            .restore_from_checkpoint(path, Some(5678))
+            .unwrap();
+    }
+    assert!(!dir.path().join("checkpoint_1234.bin").exists());
+
+    // An existing output file is only replaced with force
+    let modified = std::fs::metadata(&first_path).unwrap().modified().unwrap();
+    let err = checkpoint("before-upgrade.bin", false)
+        .checkpoint(1234, &detection)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("already exists"), "{err}");
+    assert_eq!(
+        std::fs::metadata(&first_path).unwrap().modified().unwrap(),
+        modified
+    );
+    checkpoint("before-upgrade.bin", true)
+        .checkpoint(1234, &detection)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_throttled_checkpoint_paces_writes() {
+    let dir = tempdir().unwrap();
+    let size = 256 * 1024;
+    let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+    detection.add_allocation(GpuAllocation::new(
+        0x100000,
+        0x100000 + size,
+        AllocationType::Standard,
+    ));
+
+    let config = CheckpointConfig {
+        bandwidth_mbps: 1,
+        throttle: true,
+        ..CheckpointConfig::new(dir.path().to_str().unwrap().to_string())
+    };
+    let started = Instant::now();
+    let metadata = CheckpointEngine::new(config)
+        .checkpoint(1234, &detection)
+        .await
+        .unwrap();
+    assert_eq!(metadata.size_bytes, size);
+    // 256 KiB at 1 MB/s
+    assert!(
+        started.elapsed() >= Duration::from_millis(250),
+        "{:?}",
+        started.elapsed()
+    );
+}
+
+#[tokio::test]
+async fn test_restore_engine_restores_checkpoint() {
+    let dir = tempdir().unwrap();
+    let storage_path = dir.path().to_str().unwrap().to_string();
+    let config = CheckpointConfig::new(storage_path.clone());
+
+    // Restore writes into the PID the checkpoint was taken of, so use one
+    // above the kernel's PID limit that no process can have
+    let pid = 4_194_305;
+    let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+    detection.add_allocation(GpuAllocation::new(
+        0x100000,
+        0x102000,
+        AllocationType::Standard,
+    ));
+    let metadata = CheckpointEngine::new(config)
+        .checkpoint(pid, &detection)
+        .await
+        .unwrap();
+
+    let engine = RestoreEngine::new(storage_path);
+    assert_eq!(engine.restore(&metadata).await.unwrap(), pid);
+
+    // The checkpoint is found in the engine's storage, not where the
+    // metadata says it was written
+    let moved = tempdir().unwrap();
+    std::fs::rename(
+        dir.path().join(format!("checkpoint_{pid}.bin")),
+        moved.path().join(format!("checkpoint_{pid}.bin")),
+    )
+    .unwrap();
+    assert!(engine.restore(&metadata).await.is_err());
+    let engine = RestoreEngine::new(moved.path().to_str().unwrap().to_string());
+    assert_eq!(engine.restore(&metadata).await.unwrap(), pid);
+}
+
+#[tokio::test]
+async fn test_hybrid_checkpoint_splits_allocations() {
+    let dir = tempdir().unwrap();
+    let tool = install_fake_cuda_tool(dir.path());
+
+    let storage = dir.path().join("storage");
+    std::fs::create_dir(&storage).unwrap();
+    let engine = CheckpointEngine::new(CheckpointConfig {
+        strategy: CheckpointStrategy::Hybrid,
+        cuda_tool: Some(tool.clone()),
+        ..CheckpointConfig::new(storage.to_str().unwrap().to_string())
+    });
+
+    let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+    detection.add_allocation(GpuAllocation::new(
+        0x100000,
+        0x102000,
+        AllocationType::Standard,
+    ));
+    detection.add_allocation(GpuAllocation::new(0x200000, 0x201000, AllocationType::Uvm));
+    detection.add_allocation(GpuAllocation::new(
+        0x300000,
+        0x303000,
+        AllocationType::Managed,
+    ));
+    let metadata = engine.checkpoint(1234, &detection).await.unwrap();
+    assert_eq!(metadata.strategy_used, CheckpointStrategy::Hybrid);
+
+    // The standard allocation went through the CUDA toggle...
+    let toggle = metadata.cuda_toggle.as_ref().unwrap();
+    assert_eq!(toggle.tool, tool);
+
+    // ...and only the problematic ones into the BAR checkpoint
+    let checkpoint_path = metadata.path.clone().unwrap();
+    assert_eq!(metadata.size_bytes, 0x4000);
+    let inspection = inspect_checkpoint(&checkpoint_path).unwrap();
+    let stored: Vec<u64> = inspection
+        .allocations
+        .iter()
+        .map(|record| record.header.vaddr_start)
+        .collect();
+    assert_eq!(stored, vec![0x200000, 0x300000]);
+
+    let manifest =
+        CheckpointMetadata::read_manifest(&CheckpointMetadata::manifest_path(&checkpoint_path))
+            .unwrap();
+    assert_eq!(manifest.strategy_used, CheckpointStrategy::Hybrid);
+    assert_eq!(manifest.cuda_toggle.as_ref(), Some(toggle));
+}
+
+/// Stand-in for nvidia-cuda-checkpoint keeping the state of every PID in a
+/// `state-<pid>` file next to it
+fn install_fake_cuda_tool(dir: &std::path::Path) -> std::path::PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tool = dir.join("nvidia-cuda-checkpoint");
+    std::fs::write(
+        &tool,
+        "#!/bin/sh\n\
+         state=\"$(dirname \"$0\")/state-$3\"\n\
+         [ -f \"$state\" ] || echo running > \"$state\"\n\
+         case \"$1\" in\n\
+         --get-state) cat \"$state\" ;;\n\
+         --toggle)\n\
+             if [ \"$(cat \"$state\")\" = running ]; then\n\
+                 echo checkpointed > \"$state\"\n\
+             else\n\
+                 echo running > \"$state\"\n\
+             fi ;;\n\
+         esac\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+    tool
+}
+
+#[tokio::test]
+async fn test_cli_restores_cuda_checkpoint() {
+    let dir = tempdir().unwrap();
+    let tool = install_fake_cuda_tool(dir.path());
+    let state = || std::fs::read_to_string(dir.path().join("state-1234")).unwrap();
+
+    let storage = dir.path().join("storage");
+    std::fs::create_dir(&storage).unwrap();
+    let engine = CheckpointEngine::new(CheckpointConfig {
+        strategy: CheckpointStrategy::CudaCheckpoint,
+        cuda_tool: Some(tool.clone()),
+        ..CheckpointConfig::new(storage.to_str().unwrap().to_string())
+    });
+    let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+    detection.add_allocation(GpuAllocation::new(
+        0x100000,
+        0x102000,
+        AllocationType::Standard,
+    ));
+    let metadata = engine.checkpoint(1234, &detection).await.unwrap();
+    assert_eq!(state().trim(), "checkpointed");
+
+    // No checkpoint file, but a manifest holding the toggle
+    assert!(metadata.path.is_none());
+    let manifest_path = metadata.manifest.clone().unwrap();
+    assert_eq!(manifest_path, storage.join("checkpoint_1234.json"));
+    let manifest = CheckpointMetadata::read_manifest(&manifest_path).unwrap();
+    assert_eq!(manifest.cuda_toggle, metadata.cuda_toggle);
+
+    let output = Command::new("cargo")
+        .args(["build", "--bin", "gpu-checkpoint"])
+        .output()
+        .expect("Failed to build binary");
+    assert!(output.status.success());
+
+    let output = Command::new("target/debug/gpu-checkpoint")
+        .args(["restore", "--metadata", manifest_path.to_str().unwrap()])
+        .output()
+        .expect("Failed to run restore command");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("CUDA state: checkpointed -> running"));
+    assert_eq!(state().trim(), "running");
+}
+
+#[tokio::test]
+async fn test_cli_restores_hybrid_checkpoint() {
+    let dir = tempdir().unwrap();
+    let tool = install_fake_cuda_tool(dir.path());
+    let storage = dir.path().join("storage");
+    std::fs::create_dir(&storage).unwrap();
+    let engine = CheckpointEngine::new(CheckpointConfig {
+        strategy: CheckpointStrategy::Hybrid,
+        cuda_tool: Some(tool),
+        ..CheckpointConfig::new(storage.to_str().unwrap().to_string())
+    });
+
+    let output = Command::new("cargo")
+        .args(["build", "--bin", "gpu-checkpoint"])
+        .output()
+        .expect("Failed to build binary");
+    assert!(output.status.success());
+    let restore = |metadata: &std::path::Path| {
+        let output = Command::new("target/debug/gpu-checkpoint")
+            .args(["restore", "--metadata", metadata.to_str().unwrap()])
+            .output()
+            .expect("Failed to run restore command");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    // Restore writes into the PID the checkpoint was taken of, so use ones
+    // above the kernel's PID limit that no process can have
+    for (pid, problematic) in [(4_194_306, true), (4_194_307, false)] {
+        let state = || std::fs::read_to_string(dir.path().join(format!("state-{pid}"))).unwrap();
+        let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+        detection.add_allocation(GpuAllocation::new(
+            0x100000,
+            0x102000,
+            AllocationType::Standard,
+        ));
+        if problematic {
+            detection.add_allocation(GpuAllocation::new(0x200000, 0x201000, AllocationType::Uvm));
+        }
+        let metadata = engine.checkpoint(pid, &detection).await.unwrap();
+        assert_eq!(state().trim(), "checkpointed");
+
+        // Every hybrid checkpoint keeps its toggle in a manifest, with a
+        // checkpoint file only for the BAR-copied allocations
+        let manifest_path = metadata.manifest.clone().unwrap();
+        let manifest = CheckpointMetadata::read_manifest(&manifest_path).unwrap();
+        assert_eq!(manifest.cuda_toggle, metadata.cuda_toggle);
+        assert_eq!(metadata.path.is_some(), problematic);
+
+        // The toggle is reversed whether the checkpoint file or the
+        // manifest is restored
+        let stdout = restore(metadata.path.as_deref().unwrap_or(&manifest_path));
+        assert!(
+            stdout.contains("CUDA state: checkpointed -> running"),
+            "{stdout}"
+        );
+        if problematic {
+            assert!(stdout.contains("Allocations restored: 1"), "{stdout}");
+        }
+        assert_eq!(state().trim(), "running");
+    }
+}
+
 #[test]
 fn test_checkpoint_restore_integration() {
     let dir = tempdir().unwrap();
@@ -134,6 +787,60 @@ fn test_cli_detect_command() {
     assert!(output.status.success());
 }
 
+#[test]
+fn test_cli_detect_table_format() {
+    let output = Command::new("cargo")
+        .args(["build", "--bin", "gpu-checkpoint"])
+        .output()
+        .expect("Failed to build binary");
+
+    assert!(output.status.success());
+
+    let output = Command::new("target/debug/gpu-checkpoint")
+        .args([
+            "detect",
+            "--pid",
+            &std::process::id().to_string(),
+            "--format",
+            "table",
+        ])
+        .output()
+        .expect("Failed to run detect command");
+
+    assert!(output.status.success());
+}
+
+#[test]
+fn test_cli_detect_filters() {
+    let output = Command::new("cargo")
+        .args(["build", "--bin", "gpu-checkpoint"])
+        .output()
+        .expect("Failed to build binary");
+
+    assert!(output.status.success());
+
+    let detect = |filters: &[&str]| {
+        Command::new("target/debug/gpu-checkpoint")
+            .args(["detect", "--pid", &std::process::id().to_string()])
+            .args(filters)
+            .output()
+            .expect("Failed to run detect command")
+    };
+
+    assert!(detect(&[
+        "--min-size",
+        "2MiB",
+        "--type",
+        "uvm,ipc",
+        "--format",
+        "json"
+    ])
+    .status
+    .success());
+    assert!(!detect(&["--type", "uvm,bogus"]).status.success());
+    assert!(!detect(&["--min-size", "lots"]).status.success());
+}
+
 #[test]
 fn test_cli_checkpoint_command() {
     let dir = tempdir().unwrap();
@@ -162,6 +869,79 @@ fn test_cli_checkpoint_command() {
     assert!(output.status.success());
 }
 
+#[test]
+fn test_cli_verify_command() {
+    let dir = tempdir().unwrap();
+    let checkpoint_path = dir.path().join("verify.ckpt");
+
+    let mut detection = DetectionResult::new(1234, GpuVendor::Nvidia);
+    detection.add_allocation(GpuAllocation::new(
+        0x100000,
+        0x102000,
+        AllocationType::Standard,
+    ));
+    BarSlidingCheckpoint::new()
+        .checkpoint_process(1234, &detection, &checkpoint_path)
+        .unwrap();
+
+    let output = Command::new("cargo")
+        .args(["build", "--bin", "gpu-checkpoint"])
+        .output()
+        .expect("Failed to build binary");
+
+    assert!(output.status.success());
+
+    let verify = || {
+        Command::new("target/debug/gpu-checkpoint")
+            .args(["verify", "--metadata", checkpoint_path.to_str().unwrap()])
+            .output()
+            .expect("Failed to run verify command")
+    };
+
+    let output = verify();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Allocations: 1"));
+
+    // Cut the allocation data short
+    let len = std::fs::metadata(&checkpoint_path).unwrap().len();
+    std::fs::File::options()
+        .write(true)
+        .open(&checkpoint_path)
+        .unwrap()
+        .set_len(len - 100)
+        .unwrap();
+    assert!(!verify().status.success());
+}
+
+#[test]
+fn test_cli_dump_rejects_unknown_address() {
+    let dir = tempdir().unwrap();
+
+    let output = Command::new("cargo")
+        .args(["build", "--bin", "gpu-checkpoint"])
+        .output()
+        .expect("Failed to build binary");
+
+    assert!(output.status.success());
+
+    // This process has no GPU allocations, so no address can be dumped
+    let output = Command::new("target/debug/gpu-checkpoint")
+        .args([
+            "dump",
+            "--pid",
+            &std::process::id().to_string(),
+            "--address",
+            "0x1000",
+            "--output",
+            dir.path().join("dump.bin").to_str().unwrap(),
+        ])
+        .output()
+        .expect("Failed to run dump command");
+
+    assert!(!output.status.success());
+    assert!(!dir.path().join("dump.bin").exists());
+}
+
 #[test]
 fn test_mock_gpu_process() {
     // Build the mock GPU process
@@ -184,7 +964,10 @@ fn test_mock_gpu_process() {
 
     // Try to detect allocations
     let detector = CompositeDetector::new();
-    let results = detector.detect_all(pid).unwrap_or_default();
+    let results = detector
+        .detect_all(pid)
+        .map(|detections| detections.results)
+        .unwrap_or_default();
 
     // Kill the mock process
     mock_process.kill().ok();
@@ -193,3 +976,93 @@ fn test_mock_gpu_process() {
     // We might not detect allocations on all systems, but the detection should not fail
     assert!(results.is_empty() || !results.is_empty());
 }
+
+#[test]
+fn test_restore_into_spawned_process() {
+    let output = Command::new("cargo")
+        .args(["build", "--bin", "mock-gpu-process"])
+        .output()
+        .expect("Failed to build mock-gpu-process");
+
+    assert!(output.status.success());
+
+    let dir = tempdir().unwrap();
+    let checkpoint_path = dir.path().join("spawn.ckpt");
+
+    let mut buffer = memmap2::MmapMut::map_anon(2 * 4096).unwrap();
+    buffer.fill(0x5a);
+    let start = buffer.as_ptr() as u64;
+    let pid = std::process::id();
+    let mut detection = DetectionResult::new(pid, GpuVendor::Nvidia);
+    detection.add_allocation(GpuAllocation::new(
+        start,
+        start + buffer.len() as u64,
+        AllocationType::Standard,
+    ));
+    BarSlidingCheckpoint::new()
+        .checkpoint_process(pid, &detection, &checkpoint_path)
+        .unwrap();
+
+    // The command line of a checkpointed process is captured for --spawn
+    let captured = LaunchCommand::capture(pid).unwrap().unwrap();
+    assert!(captured.argv[0].contains("integration_test"));
+    assert_eq!(captured.cwd, std::env::current_dir().ok());
+
+    // A process that exits cannot be restored into
+    let mut exited = spawn_target(
+        &LaunchCommand {
+            argv: vec!["true".to_string()],
+            cwd: None,
+        },
+        &BTreeMap::new(),
+    )
+    .unwrap();
+    assert!(wait_for_gpu_context(
+        &mut exited,
+        &CompositeDetector::new(),
+        Duration::from_secs(5)
+    )
+    .is_err());
+
+    let mut child = spawn_target(
+        &LaunchCommand {
+            argv: vec!["target/debug/mock-gpu-process".to_string()],
+            cwd: std::env::current_dir().ok(),
+        },
+        &BTreeMap::new(),
+    )
+    .unwrap();
+    let child_pid = child.id();
+
+    // The mock maps no GPU memory, so waiting for its context times out
+    let ready = wait_for_gpu_context(
+        &mut child,
+        &CompositeDetector::new(),
+        Duration::from_millis(300),
+    );
+
+    // Nothing is mapped at the new address in the mock
+    let new_start = 0x1000_0000_0000u64;
+    let restored = BarRestore::new()
+        .with_restore_mode(RestoreMode::MapFresh)
+        .with_address_translation([(start, new_start)].into_iter().collect())
+        .restore_from_checkpoint(&checkpoint_path, Some(child_pid));
+    let mut contents = vec![0u8; buffer.len()];
+    let read = std::fs::File::open(format!("/proc/{child_pid}/mem"))
+        .and_then(|mem| mem.read_exact_at(&mut contents, new_start));
+
+    child.kill().ok();
+    child.wait().ok();
+
+    assert!(!ready.unwrap());
+    match restored {
+        Ok(metadata) => {
+            assert_eq!(metadata.pid, child_pid);
+            read.unwrap();
+            assert!(contents.iter().all(|&b| b == 0x5a));
+        }
+        // ptrace may be disallowed in the test environment
+        Err(gpu_checkpoint::GpuCheckpointError::PermissionDenied) => {}
+        Err(e) => panic!("restore into spawned process failed: {e}"),
+    }
+}